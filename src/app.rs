@@ -1,7 +1,11 @@
 use std::collections::BTreeSet;
 
-use crate::compiler::CompileOptions;
-use crate::gui::{AssemblyOutput, CodeEditor, EditorAction, Examples, InterpreterOptions, Window};
+use crate::backends::Target;
+use crate::compiler::{CompileOptions, LpErr};
+use crate::gui::{
+    AssemblyOutput, CodeEditor, EditorAction, Examples, InterpreterOptions, IrOutput, TargetOutput,
+    Window,
+};
 use eframe::egui::{self, FontData, FontFamily, Modifiers, Ui, ViewportCommand};
 use eframe::epaint::text::{FontInsert, InsertFontFamily};
 use rust_i18n::t;
@@ -38,6 +42,8 @@ pub struct LndwApp {
     interpreter_options: InterpreterOptions,
     asm_unoptimized: AssemblyOutput,
     asm_optimized: AssemblyOutput,
+    ir_output: IrOutput,
+    target_output: TargetOutput,
     examples: Examples,
     result: Option<String>,
     language: String,
@@ -103,6 +109,8 @@ impl eframe::App for LndwApp {
                         add_sidebar_item!(ui, self.open, self.code_editor);
                         add_sidebar_item!(ui, self.open, self.asm_unoptimized);
                         add_sidebar_item!(ui, self.open, self.asm_optimized);
+                        add_sidebar_item!(ui, self.open, self.ir_output);
+                        add_sidebar_item!(ui, self.open, self.target_output);
                         add_sidebar_item!(ui, self.open, self.interpreter_options);
                         add_sidebar_item!(ui, self.open, self.examples);
 
@@ -136,15 +144,23 @@ impl eframe::App for LndwApp {
         for action in self.code_editor.actions.drain(..) {
             match action {
                 EditorAction::Compile => {
-                    if let Ok(vars) = self.asm_unoptimized.compile(
+                    match self.asm_unoptimized.compile(
                         &self.code_editor.code,
                         CompileOptions::default(),
                         self.interpreter_options,
                     ) {
-                        self.code_editor.input_variables =
-                            vars.iter().map(|s| (s.clone(), String::new())).collect();
-                    } else {
-                        self.code_editor.input_variables.clear();
+                        Ok(vars) => {
+                            self.code_editor.input_variables =
+                                vars.iter().map(|s| (s.clone(), String::new())).collect();
+                            self.code_editor.parse_errors.clear();
+                        }
+                        Err(e) => {
+                            self.code_editor.input_variables.clear();
+                            self.code_editor.parse_errors = match e {
+                                LpErr::Parse(errs) => errs,
+                                _ => vec![],
+                            };
+                        }
                     }
 
                     if self.code_editor.compile_options.any() {
@@ -158,6 +174,24 @@ impl eframe::App for LndwApp {
                         set_open(&mut self.open, &self.asm_optimized.name(), true);
                     }
 
+                    self.ir_output.compile(
+                        &self.code_editor.code,
+                        self.code_editor.compile_options,
+                        self.interpreter_options,
+                    );
+                    if self.code_editor.compile_options.use_ssa_ir {
+                        set_open(&mut self.open, &self.ir_output.name(), true);
+                    }
+
+                    self.target_output.compile(
+                        &self.code_editor.code,
+                        self.code_editor.compile_options,
+                        self.interpreter_options,
+                    );
+                    if self.code_editor.compile_options.target != Target::Interpreter {
+                        set_open(&mut self.open, &self.target_output.name(), true);
+                    }
+
                     set_open(&mut self.open, &self.asm_unoptimized.name(), true);
                 }
                 EditorAction::Run(stepwise) => {
@@ -173,6 +207,8 @@ impl eframe::App for LndwApp {
                 EditorAction::Clear => {
                     self.asm_unoptimized.clear();
                     self.asm_optimized.clear();
+                    self.ir_output.clear();
+                    self.target_output.clear();
                     self.result = None;
                 }
             }
@@ -180,6 +216,8 @@ impl eframe::App for LndwApp {
 
         add_window!(ctx, self.open, self.asm_unoptimized);
         add_window!(ctx, self.open, self.asm_optimized);
+        add_window!(ctx, self.open, self.ir_output);
+        add_window!(ctx, self.open, self.target_output);
         add_window!(ctx, self.open, self.interpreter_options);
         add_window!(ctx, self.open, self.examples);
 
@@ -0,0 +1,383 @@
+//! Arithmetic shared by constant folding and the interpreter, so that both compute the same
+//! results, including what happens when a result doesn't fit into a value.
+
+use crate::types::Operator;
+
+/// Number of bits in a value, i.e. in a register or a RAM cell.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Width {
+    W8,
+    W16,
+    #[default]
+    W32,
+    W64,
+}
+
+impl Width {
+    pub const ALL: [Width; 4] = [Width::W8, Width::W16, Width::W32, Width::W64];
+
+    pub fn bits(self) -> u32 {
+        match self {
+            Width::W8 => 8,
+            Width::W16 => 16,
+            Width::W32 => 32,
+            Width::W64 => 64,
+        }
+    }
+
+    /// Number of bits after the binary point of fixed-point numbers, which use half of the bits,
+    /// e.g. Q16.16 with 32 bits.
+    pub fn frac_bits(self) -> u32 {
+        self.bits() / 2
+    }
+}
+
+/// Whether the bits of a value are read as a signed number in two's complement (`0xff` is `-1`
+/// with 8 bits), or as an unsigned number (`0xff` is `255`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Signedness {
+    #[default]
+    Signed,
+    Unsigned,
+}
+
+/// What happens when the result of an operation is too large or too small for a value.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overflow {
+    /// Cut off the bits that don't fit, so that e.g. `MAX + 1` becomes `MIN`.
+    #[default]
+    Wrapping,
+    /// Stop with an error.
+    Trapping,
+    /// Stay at the largest or smallest possible value.
+    Saturating,
+}
+
+/// Reasons why an operation has no result.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArithError {
+    DivisionByZero,
+    /// The result doesn't fit, with [`Overflow::Trapping`].
+    Overflow,
+}
+
+/// The arithmetic of a machine.
+///
+/// Values are stored as words in an `i64`, which holds the number itself if it fits and the bits
+/// of the number otherwise, i.e. for unsigned 64-bit numbers above `i64::MAX`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Arith {
+    pub width: Width,
+    pub signedness: Signedness,
+    pub overflow: Overflow,
+}
+
+impl Arith {
+    /// The smallest number, e.g. `-128` for signed 8 bits.
+    pub fn min(self) -> i128 {
+        match self.signedness {
+            Signedness::Signed => -(1 << (self.width.bits() - 1)),
+            Signedness::Unsigned => 0,
+        }
+    }
+
+    /// The largest number, e.g. `127` for signed 8 bits.
+    pub fn max(self) -> i128 {
+        match self.signedness {
+            Signedness::Signed => (1 << (self.width.bits() - 1)) - 1,
+            Signedness::Unsigned => (1 << self.width.bits()) - 1,
+        }
+    }
+
+    pub fn fits(self, value: i128) -> bool {
+        (self.min()..=self.max()).contains(&value)
+    }
+
+    /// The word storing `value`, if it fits.
+    pub fn word(self, value: i128) -> Option<i64> {
+        self.fits(value).then_some(value as i64)
+    }
+
+    /// The number stored in `word`.
+    pub fn value(self, word: i64) -> i128 {
+        match self.signedness {
+            Signedness::Signed => word.into(),
+            Signedness::Unsigned => (word as u64).into(),
+        }
+    }
+
+    /// The word storing the fixed-point number with the decimal digits `int.frac`, rounded to the
+    /// nearest fixed-point number, if it fits.
+    pub fn fixed_point(self, int: &str, frac: &str) -> Option<i64> {
+        let frac_bits = self.width.frac_bits();
+        // more digits than this don't make a difference, even with 32 fractional bits
+        let frac = &frac[..frac.len().min(18)];
+        let scale = 10i128.pow(frac.len() as u32);
+        let frac = (frac.parse::<i128>().unwrap_or(0) << frac_bits) + scale / 2;
+        let raw = int
+            .parse::<i128>()
+            .ok()?
+            .checked_mul(1 << frac_bits)?
+            .checked_add(frac / scale)?;
+        self.word(raw)
+    }
+
+    /// Show the fixed-point number stored in `word` as a decimal number, with about as many digits
+    /// as the fractional bits can tell apart.
+    pub fn format_fixed_point(self, word: i64) -> String {
+        let frac_bits = self.width.frac_bits();
+        let value = self.value(word) as f64 / (1u64 << frac_bits) as f64;
+        let digits = (frac_bits as f64 * 2f64.log10()).ceil() as usize;
+        let s = format!("{value:.digits$}");
+        s.trim_end_matches('0').trim_end_matches('.').to_string()
+    }
+
+    /// Compute `op a` for a unary operator.
+    pub fn unary(self, op: Operator, a: i64) -> Result<i64, ArithError> {
+        match op {
+            Operator::Sub => self.binary(Operator::Sub, 0, a),
+            Operator::Not => Ok(self.wrap(!self.value(a))),
+            _ => unreachable!("`{op}` is not a unary operator"),
+        }
+    }
+
+    /// Compute `a op b` for a binary operator.
+    pub fn binary(self, op: Operator, a: i64, b: i64) -> Result<i64, ArithError> {
+        if matches!(op, Operator::Div | Operator::Mod) && b == 0 {
+            return Err(ArithError::DivisionByZero);
+        }
+
+        // compute the exact result, which fits into twice the bits, except for the product of
+        // unsigned 64-bit numbers
+        let (a, b) = (self.value(a), self.value(b));
+        let exact = match op {
+            Operator::Add => a.checked_add(b),
+            Operator::Sub => a.checked_sub(b),
+            Operator::Mul => a.checked_mul(b),
+            // only `MIN / -1` overflows
+            Operator::Div => Some(a / b),
+            Operator::Mod => Some(a % b),
+            Operator::Shl | Operator::Shr | Operator::LogicalShr => return self.shift(op, a, b),
+            Operator::And => Some(a & b),
+            Operator::Or => Some(a | b),
+            Operator::Xor => Some(a ^ b),
+            Operator::Eq => Some(i128::from(a == b)),
+            Operator::Ne => Some(i128::from(a != b)),
+            Operator::Lt => Some(i128::from(a < b)),
+            Operator::Le => Some(i128::from(a <= b)),
+            Operator::Gt => Some(i128::from(a > b)),
+            Operator::Ge => Some(i128::from(a >= b)),
+            Operator::Not => unreachable!("`~` is a unary operator"),
+        };
+
+        match exact {
+            Some(exact) => self.fit(exact),
+            // the lowest bits of the wrapped result are still right
+            None => match op {
+                Operator::Add => self.overflow(a.wrapping_add(b), a > 0),
+                Operator::Sub => self.overflow(a.wrapping_sub(b), a > b),
+                Operator::Mul => self.overflow(a.wrapping_mul(b), (a < 0) == (b < 0)),
+                _ => unreachable!("only `+`, `-` and `*` are checked"),
+            },
+        }
+    }
+
+    /// Shift `a` by `b` bits. Shifting by a negative amount or by the number of bits in a value or
+    /// more counts as overflow.
+    ///
+    /// `>>` shifts in copies of the sign bit for signed values (`-8 >> 1 == -4`), while `>>>`
+    /// always shifts in zeros (`-8 >>> 1 == 124` with 8 bits).
+    fn shift(self, op: Operator, a: i128, b: i128) -> Result<i64, ArithError> {
+        let bits = i128::from(self.width.bits());
+        let amount = match self.overflow {
+            _ if (0..bits).contains(&b) => b,
+            // like most processors, only use the lowest bits of the shift amount
+            Overflow::Wrapping => b & (bits - 1),
+            Overflow::Trapping => return Err(ArithError::Overflow),
+            // shifting out all bits saturates on the left and leaves only the sign on the right
+            Overflow::Saturating => b.clamp(0, bits),
+        };
+
+        match op {
+            // only the sign matters once all bits are shifted out
+            Operator::Shl if amount == bits => self.fit(a.signum() << bits),
+            Operator::Shl => self.fit(a << amount),
+            Operator::Shr => self.fit(a >> amount),
+            // shift the bits of `a` as if it was unsigned, so the result can't overflow
+            Operator::LogicalShr => Ok(self.wrap((a & ((1 << bits) - 1)) >> amount)),
+            _ => unreachable!("`{op}` is not a shift"),
+        }
+    }
+
+    /// Turn the exact result of an operation into a word.
+    fn fit(self, exact: i128) -> Result<i64, ArithError> {
+        match self.word(exact) {
+            Some(word) => Ok(word),
+            None => self.overflow(exact, exact > 0),
+        }
+    }
+
+    /// Handle a result that doesn't fit, given its lowest bits and whether it is positive.
+    fn overflow(self, wrapped: i128, positive: bool) -> Result<i64, ArithError> {
+        match self.overflow {
+            Overflow::Wrapping => Ok(self.wrap(wrapped)),
+            Overflow::Trapping => Err(ArithError::Overflow),
+            Overflow::Saturating if positive => Ok(self.max() as i64),
+            Overflow::Saturating => Ok(self.min() as i64),
+        }
+    }
+
+    /// Only keep the lowest bits of `value`, like a register would.
+    fn wrap(self, value: i128) -> i64 {
+        let unused = 128 - self.width.bits();
+        match self.signedness {
+            Signedness::Signed => ((value << unused) >> unused) as i64,
+            Signedness::Unsigned => (((value as u128) << unused) >> unused) as i64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn overflow_modes() {
+        let (min, max) = (i64::from(i32::MIN), i64::from(i32::MAX));
+        let ops = [
+            (Width::W32, Operator::Add, max, 1, min, max),
+            (Width::W32, Operator::Sub, min, 1, max, min),
+            (Width::W32, Operator::Mul, 1 << 20, 1 << 11, min, max),
+            (Width::W32, Operator::Mul, 1 << 20, -(1 << 12), 0, min),
+            (Width::W32, Operator::Div, min, -1, min, max),
+            (Width::W32, Operator::Shl, 3, 31, min, max),
+            (Width::W32, Operator::Shl, -1, 33, -2, min),
+            (Width::W32, Operator::Shr, -8, 40, -1, -1),
+            (Width::W8, Operator::Add, 127, 1, -128, 127),
+            (Width::W8, Operator::Mul, -16, 9, 112, -128),
+            (
+                Width::W16,
+                Operator::Shl,
+                0x4000,
+                1,
+                i64::from(i16::MIN),
+                i64::from(i16::MAX),
+            ),
+            (Width::W64, Operator::Mul, i64::MAX, 2, -2, i64::MAX),
+        ];
+
+        for (width, op, a, b, wrapped, saturated) in ops {
+            let arith = |overflow| Arith {
+                width,
+                overflow,
+                ..Default::default()
+            };
+            assert_eq!(
+                arith(Overflow::Wrapping).binary(op, a, b),
+                Ok(wrapped),
+                "{a} {op} {b}"
+            );
+            assert_eq!(
+                arith(Overflow::Trapping).binary(op, a, b),
+                Err(ArithError::Overflow),
+                "{a} {op} {b}"
+            );
+            assert_eq!(
+                arith(Overflow::Saturating).binary(op, a, b),
+                Ok(saturated),
+                "{a} {op} {b}"
+            );
+        }
+
+        let arith = Arith {
+            overflow: Overflow::Saturating,
+            ..Default::default()
+        };
+        assert_eq!(arith.unary(Operator::Sub, min), Ok(max));
+        assert_eq!(arith.binary(Operator::Shl, -3, 2), Ok(-12));
+        assert_eq!(
+            arith.binary(Operator::Mod, 7, 0),
+            Err(ArithError::DivisionByZero)
+        );
+    }
+
+    #[test]
+    fn fixed_point() {
+        let arith = Arith::default();
+        assert_eq!(arith.fixed_point("1", "5"), Some(3 << 15));
+        assert_eq!(arith.fixed_point("0", "000015"), Some(1));
+        assert_eq!(arith.fixed_point("32767", "99999"), Some(i32::MAX.into()));
+        assert_eq!(arith.fixed_point("32768", "0"), None);
+        assert_eq!(arith.format_fixed_point(3 << 15), "1.5");
+        assert_eq!(arith.format_fixed_point(-(1 << 16) / 3), "-0.33333");
+        assert_eq!(arith.format_fixed_point(2 << 16), "2");
+
+        let small = Arith {
+            width: Width::W8,
+            ..arith
+        };
+        assert_eq!(small.fixed_point("1", "25"), Some(20));
+        assert_eq!(small.format_fixed_point(20), "1.25");
+        assert_eq!(small.fixed_point("8", "0"), None);
+
+        // 2^96 would be shifted out of an `i128` with the 32 fractional bits
+        let large = Arith {
+            width: Width::W64,
+            ..arith
+        };
+        assert_eq!(
+            large.fixed_point("79228162514264337593543950336", "0"),
+            None
+        );
+    }
+
+    #[test]
+    fn signedness() {
+        let signed = Arith {
+            width: Width::W8,
+            ..Default::default()
+        };
+        let unsigned = Arith {
+            signedness: Signedness::Unsigned,
+            ..signed
+        };
+
+        assert_eq!(signed.binary(Operator::Shr, -8, 1), Ok(-4));
+        assert_eq!(signed.binary(Operator::LogicalShr, -8, 1), Ok(124));
+        assert_eq!(signed.binary(Operator::LogicalShr, -1, 0), Ok(-1));
+        assert_eq!(signed.binary(Operator::Div, -3, 2), Ok(-1));
+        assert_eq!(signed.binary(Operator::Lt, -1, 1), Ok(1));
+
+        assert_eq!(unsigned.binary(Operator::Shr, 248, 1), Ok(124));
+        assert_eq!(unsigned.binary(Operator::Add, 200, 100), Ok(44));
+        assert_eq!(unsigned.binary(Operator::Sub, 1, 2), Ok(255));
+        assert_eq!(unsigned.unary(Operator::Not, 0), Ok(255));
+        assert_eq!(unsigned.binary(Operator::Lt, 255, 1), Ok(0));
+
+        let saturating = Arith {
+            overflow: Overflow::Saturating,
+            ..unsigned
+        };
+        assert_eq!(saturating.binary(Operator::Sub, 1, 2), Ok(0));
+        assert_eq!(saturating.binary(Operator::Shl, 3, 7), Ok(255));
+        assert_eq!(saturating.binary(Operator::LogicalShr, 255, 9), Ok(0));
+
+        // unsigned 64-bit values above `i64::MAX` are stored as their bits
+        let wide = Arith {
+            width: Width::W64,
+            ..unsigned
+        };
+        assert_eq!(wide.word(u64::MAX.into()), Some(-1));
+        assert_eq!(wide.value(-1), u64::MAX.into());
+        assert_eq!(wide.binary(Operator::Shr, -1, 63), Ok(1));
+        assert_eq!(wide.binary(Operator::Gt, -1, 1), Ok(1));
+        assert_eq!(
+            Arith {
+                overflow: Overflow::Saturating,
+                ..wide
+            }
+            .binary(Operator::Shl, -1, 70),
+            Ok(-1)
+        );
+    }
+}
@@ -0,0 +1,340 @@
+//! The textual assembly format of the machine, e.g. `add a, b` or `ld [3], c`.
+//!
+//! Each line holds one instruction, whose address is its position among the instructions. Blank
+//! lines and comments starting with `;` are ignored.
+
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+
+use rust_i18n::t;
+
+use crate::types::{Inst, LpErr, Operator, ParseError, Reg};
+
+/// Mnemonics of the instructions computing a binary operator.
+const BINARY: [(&str, Operator); 17] = [
+    ("add", Operator::Add),
+    ("sub", Operator::Sub),
+    ("mul", Operator::Mul),
+    ("div", Operator::Div),
+    ("mod", Operator::Mod),
+    ("shl", Operator::Shl),
+    ("shr", Operator::Shr),
+    ("lshr", Operator::LogicalShr),
+    ("and", Operator::And),
+    ("or", Operator::Or),
+    ("xor", Operator::Xor),
+    ("eq", Operator::Eq),
+    ("ne", Operator::Ne),
+    ("lt", Operator::Lt),
+    ("le", Operator::Le),
+    ("gt", Operator::Gt),
+    ("ge", Operator::Ge),
+];
+
+/// An instruction in the assembly syntax. Unlike the [`Display`] implementation of [`Inst`], which
+/// explains the instruction in prose, this can be read back by [`parse`].
+pub struct Mnemonic<'a>(pub &'a Inst);
+
+impl Display for Mnemonic<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Inst::Add(a, b) => write!(f, "add {a}, {b}"),
+            Inst::Sub(a, b) => write!(f, "sub {a}, {b}"),
+            Inst::Mul(a, b) => write!(f, "mul {a}, {b}"),
+            Inst::Div(a, b) => write!(f, "div {a}, {b}"),
+            Inst::Mod(a, b) => write!(f, "mod {a}, {b}"),
+            Inst::Shl(a, b) => write!(f, "shl {a}, {b}"),
+            Inst::Shr(a, b) => write!(f, "shr {a}, {b}"),
+            Inst::LogicalShr(a, b) => write!(f, "lshr {a}, {b}"),
+            Inst::And(a, b) => write!(f, "and {a}, {b}"),
+            Inst::Or(a, b) => write!(f, "or {a}, {b}"),
+            Inst::Xor(a, b) => write!(f, "xor {a}, {b}"),
+            Inst::Not(r) => write!(f, "not {r}"),
+            Inst::Eq(a, b) => write!(f, "eq {a}, {b}"),
+            Inst::Ne(a, b) => write!(f, "ne {a}, {b}"),
+            Inst::Lt(a, b) => write!(f, "lt {a}, {b}"),
+            Inst::Le(a, b) => write!(f, "le {a}, {b}"),
+            Inst::Gt(a, b) => write!(f, "gt {a}, {b}"),
+            Inst::Ge(a, b) => write!(f, "ge {a}, {b}"),
+            Inst::Jump(target) => write!(f, "jmp {target}"),
+            Inst::JumpIfZero(r, target) => write!(f, "jz {r}, {target}"),
+            Inst::Store(n, r) => write!(f, "li {n}, {r}"),
+            Inst::Transfer(v, r) => write!(f, "in {v}, {r}"),
+            Inst::Copy(a, b) => write!(f, "mov {a}, {b}"),
+            Inst::Result(r) => write!(f, "res {r}"),
+            Inst::Call(target) => write!(f, "call {target}"),
+            Inst::Ret => write!(f, "ret"),
+            Inst::Push(r) => write!(f, "push {r}"),
+            Inst::Pop(r) => write!(f, "pop {r}"),
+            Inst::Write(r, addr) => write!(f, "st {r}, [{addr}]"),
+            Inst::Load(addr, r) => write!(f, "ld [{addr}], {r}"),
+        }
+    }
+}
+
+/// Write `code` in the assembly syntax, one instruction per line.
+pub fn print(code: &[Inst]) -> String {
+    code.iter()
+        .map(|inst| format!("{}\n", Mnemonic(inst)))
+        .collect()
+}
+
+/// Read instructions in the assembly syntax, reporting all lines that can't be read.
+pub fn parse(input: &str) -> Result<Vec<Inst>, LpErr> {
+    let mut code = vec![];
+    let mut errors = vec![];
+    let mut start = 0;
+    for text in input.split_inclusive('\n') {
+        match (Line { text, start }).parse() {
+            Ok(Some(inst)) => code.push(inst),
+            Ok(None) => (),
+            Err(e) => errors.push(e),
+        }
+        start += text.len();
+    }
+
+    if errors.is_empty() {
+        Ok(code)
+    } else {
+        Err(LpErr::Parse(errors))
+    }
+}
+
+/// A line of the input, starting at byte `start`.
+struct Line<'a> {
+    text: &'a str,
+    start: usize,
+}
+
+impl<'a> Line<'a> {
+    /// Byte range of `part`, which has to be a slice of the line, in the whole input.
+    fn span(&self, part: &str) -> Range<usize> {
+        let start = self.start + (part.as_ptr() as usize - self.text.as_ptr() as usize);
+        start..start + part.len()
+    }
+
+    fn error(&self, part: &str, expected: String) -> ParseError {
+        ParseError {
+            span: self.span(part),
+            found: Some(format!("'{part}'")),
+            expected: vec![expected],
+            reason: None,
+        }
+    }
+
+    /// The instruction on this line, or `None` if it is blank or only holds a comment.
+    fn parse(&self) -> Result<Option<Inst>, ParseError> {
+        let code = self.text.split(';').next().unwrap_or_default().trim();
+        if code.is_empty() {
+            return Ok(None);
+        }
+
+        let (mnemonic, rest) = code.split_once(char::is_whitespace).unwrap_or((code, ""));
+        let rest = rest.trim();
+        let operands: Vec<&str> = if rest.is_empty() {
+            vec![]
+        } else {
+            rest.split(',').map(str::trim).collect()
+        };
+
+        if let Some((_, op)) = BINARY.iter().find(|(name, _)| *name == mnemonic) {
+            let [a, b] = self.operands(mnemonic, &operands)?;
+            let inst = Inst::binary(*op, self.reg(a)?, self.reg(b)?);
+            return Ok(Some(inst.expect("only binary operators have a mnemonic")));
+        }
+
+        Ok(Some(match mnemonic {
+            "not" => {
+                let [r] = self.operands(mnemonic, &operands)?;
+                Inst::Not(self.reg(r)?)
+            }
+            "jmp" => {
+                let [target] = self.operands(mnemonic, &operands)?;
+                Inst::Jump(self.target(target)?)
+            }
+            "jz" => {
+                let [r, target] = self.operands(mnemonic, &operands)?;
+                Inst::JumpIfZero(self.reg(r)?, self.target(target)?)
+            }
+            "li" => {
+                let [n, r] = self.operands(mnemonic, &operands)?;
+                Inst::Store(self.number(n)?, self.reg(r)?)
+            }
+            "in" => {
+                let [v, r] = self.operands(mnemonic, &operands)?;
+                Inst::Transfer(self.variable(v)?, self.reg(r)?)
+            }
+            "mov" => {
+                let [a, b] = self.operands(mnemonic, &operands)?;
+                Inst::Copy(self.reg(a)?, self.reg(b)?)
+            }
+            "res" => {
+                let [r] = self.operands(mnemonic, &operands)?;
+                Inst::Result(self.reg(r)?)
+            }
+            "call" => {
+                let [target] = self.operands(mnemonic, &operands)?;
+                Inst::Call(self.target(target)?)
+            }
+            "ret" => {
+                let [] = self.operands(mnemonic, &operands)?;
+                Inst::Ret
+            }
+            "push" => {
+                let [r] = self.operands(mnemonic, &operands)?;
+                Inst::Push(self.reg(r)?)
+            }
+            "pop" => {
+                let [r] = self.operands(mnemonic, &operands)?;
+                Inst::Pop(self.reg(r)?)
+            }
+            "st" => {
+                let [r, addr] = self.operands(mnemonic, &operands)?;
+                Inst::Write(self.reg(r)?, self.address(addr)?)
+            }
+            "ld" => {
+                let [addr, r] = self.operands(mnemonic, &operands)?;
+                Inst::Load(self.address(addr)?, self.reg(r)?)
+            }
+            _ => {
+                return Err(ParseError {
+                    span: self.span(mnemonic),
+                    found: Some(format!("'{mnemonic}'")),
+                    expected: vec![],
+                    reason: Some(t!("assembly.unknown_instruction", m = mnemonic).to_string()),
+                });
+            }
+        }))
+    }
+
+    /// Check that `mnemonic` is followed by `N` operands.
+    fn operands<const N: usize>(
+        &self,
+        mnemonic: &'a str,
+        operands: &[&'a str],
+    ) -> Result<[&'a str; N], ParseError> {
+        <[&str; N]>::try_from(operands).map_err(|_| ParseError {
+            span: self.span(mnemonic),
+            found: None,
+            expected: vec![],
+            reason: Some(
+                t!(
+                    "assembly.operand_count",
+                    m = mnemonic,
+                    expected = N,
+                    got = operands.len()
+                )
+                .to_string(),
+            ),
+        })
+    }
+
+    /// A register, named `a` to `z` or `r` followed by its number.
+    fn reg(&self, part: &str) -> Result<Reg, ParseError> {
+        let mut chars = part.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c @ 'a'..='z'), None) => return Ok(Reg(c as u32 - 'a' as u32)),
+            (Some('r'), Some(_)) => {
+                if let Ok(n) = part[1..].parse() {
+                    return Ok(Reg(n));
+                }
+            }
+            _ => (),
+        }
+        Err(self.error(part, t!("assembly.register").to_string()))
+    }
+
+    fn number(&self, part: &str) -> Result<i64, ParseError> {
+        part.parse()
+            .map_err(|_| self.error(part, t!("parser.label.number").to_string()))
+    }
+
+    /// The address of an instruction.
+    fn target(&self, part: &str) -> Result<usize, ParseError> {
+        part.parse()
+            .map_err(|_| self.error(part, t!("assembly.target").to_string()))
+    }
+
+    /// The address of a RAM cell, in square brackets.
+    fn address(&self, part: &str) -> Result<usize, ParseError> {
+        part.strip_prefix('[')
+            .and_then(|p| p.strip_suffix(']'))
+            .and_then(|p| p.trim().parse().ok())
+            .ok_or_else(|| self.error(part, t!("assembly.address").to_string()))
+    }
+
+    fn variable(&self, part: &str) -> Result<String, ParseError> {
+        let mut chars = part.chars();
+        let valid = chars.next().is_some_and(char::is_alphabetic)
+            && chars.all(|c| c.is_alphanumeric() || c == '_');
+        if valid {
+            Ok(part.to_string())
+        } else {
+            Err(self.error(part, t!("parser.label.variable").to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::{CompileOptions, Compiled, Compiler};
+    use crate::passes::RegisterAllocation;
+
+    #[test]
+    fn round_trip() {
+        let inputs = [
+            "let t = x * 3; t * t + t",
+            "(x << 3) | (x & 5) ^ ~x % 7 >> 1 >>> 2",
+            "let i = 0; let s = 0; while i < x { s = s + i; i = i + 1; } s",
+            "fn sq(n) = n * n; sq(x) - sq(if x > 2 then 1 else x == 3)",
+            "0.5 * x",
+        ];
+        let options = [
+            CompileOptions::default(),
+            CompileOptions {
+                register_allocation: RegisterAllocation::GraphColoring,
+                use_ssa_ir: true,
+                ..Default::default()
+            },
+        ];
+        for input in inputs {
+            for options in options {
+                let Compiled { code, .. } = Compiler::with(options).compile(input).unwrap();
+                let text = print(&code);
+                assert_eq!(parse(&text).unwrap(), code, "{text}");
+            }
+        }
+
+        let code = vec![
+            Inst::Store(-42, Reg(26)),
+            Inst::Write(Reg(26), 3),
+            Inst::Load(3, Reg(63)),
+            Inst::Transfer("x_1".into(), Reg(0)),
+            Inst::Ret,
+        ];
+        assert_eq!(
+            print(&code),
+            "li -42, r26\nst r26, [3]\nld [3], r63\nin x_1, a\nret\n"
+        );
+        assert_eq!(parse(&print(&code)).unwrap(), code);
+    }
+
+    #[test]
+    fn comments_and_blank_lines() {
+        let input = "; the answer\n\n  li 42, a   ; store it\nres a\n";
+        assert_eq!(
+            parse(input).unwrap(),
+            vec![Inst::Store(42, Reg(0)), Inst::Result(Reg(0))]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let Err(LpErr::Parse(errors)) = parse("foo a\nadd a\nld 3, b\nmov a, 7\n") else {
+            panic!("invalid assembly was accepted");
+        };
+        let spans: Vec<Range<usize>> = errors.iter().map(|e| e.span.clone()).collect();
+        assert_eq!(spans, vec![0..3, 6..9, 15..16, 27..28]);
+    }
+}
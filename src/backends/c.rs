@@ -0,0 +1,461 @@
+//! Portable C, translated from the instructions for the
+//! [`Interpreter`](crate::interpreter::Interpreter).
+//!
+//! The program becomes the function `program`, whose parameters are the input variables, sorted by
+//! name. Each register of our computer becomes a local variable (`reg_a`, `reg_b`, ...), the RAM an
+//! array `ram`, and jumps become `goto`s. Calls push their return address onto the stack at the end
+//! of the RAM, just like the interpreter does, and `ret` jumps back with a `switch` over all places
+//! a function returns to.
+//!
+//! Values use the `<stdint.h>` type matching the word size and signedness, and results that don't
+//! fit wrap around, i.e. they are computed like with [`Overflow::Wrapping`](crate::arith::Overflow);
+//! the other overflow modes are rejected. Dividing by zero calls `abort`, where the interpreter
+//! stops with an error.
+
+use std::collections::BTreeSet;
+
+use super::check_arith;
+use crate::arith::{Arith, Signedness, Width};
+use crate::assembly::Mnemonic;
+use crate::types::{Inst, LpErr, Reg};
+
+/// Keywords of C and names used by the generated code, which input variables must not be called.
+const RESERVED: [&str; 38] = [
+    "auto", "break", "case", "char", "const", "continue", "default", "do", "double", "else",
+    "enum", "extern", "float", "for", "goto", "if", "inline", "int", "long", "register",
+    "restrict", "return", "short", "signed", "sizeof", "static", "struct", "switch", "typedef",
+    "union", "unsigned", "void", "volatile", "while", "program", "ram", "sp", "abort",
+];
+
+/// Translate `code`, which reads the input variables `variables` and uses `ram_cells` RAM cells,
+/// for values computed like with `arith`.
+pub fn emit(
+    code: &[Inst],
+    variables: &[String],
+    ram_cells: usize,
+    arith: Arith,
+) -> Result<String, LpErr> {
+    check_arith(arith, &Width::ALL)?;
+    let mut variables = variables.to_vec();
+    variables.sort();
+    let bits = arith.width.bits();
+    let signedness = arith.signedness;
+    let ty = match signedness {
+        Signedness::Signed => format!("int{bits}_t"),
+        Signedness::Unsigned => format!("uint{bits}_t"),
+    };
+    let mut c = Emitter {
+        lines: vec![],
+        ty: ty.clone(),
+        bits,
+        signedness,
+        variables: variables
+            .iter()
+            .map(|v| (v.clone(), param_name(v)))
+            .collect(),
+    };
+
+    // jump targets and the instructions after calls, where functions return to
+    let returns: BTreeSet<usize> = code
+        .iter()
+        .enumerate()
+        .filter(|(_, inst)| matches!(inst, Inst::Call(_)))
+        .map(|(addr, _)| addr + 1)
+        .collect();
+    let mut labels: BTreeSet<usize> = code
+        .iter()
+        .filter_map(|inst| match inst {
+            Inst::Jump(target) | Inst::JumpIfZero(_, target) | Inst::Call(target) => Some(*target),
+            _ => None,
+        })
+        .collect();
+    labels.extend(&returns);
+
+    for (addr, inst) in code.iter().enumerate() {
+        if labels.contains(&addr) {
+            c.lines.push(format!("L{addr}:"));
+        }
+        c.inst(inst, addr, &returns);
+    }
+    if labels.contains(&code.len()) {
+        // a label needs a statement after it
+        c.lines.push(format!("L{}:;", code.len()));
+    }
+
+    let params: Vec<String> = c
+        .variables
+        .iter()
+        .map(|(_, param)| format!("{ty} {param}"))
+        .collect();
+    let mut out = String::from("#include <stdint.h>\n");
+    if code
+        .iter()
+        .any(|inst| matches!(inst, Inst::Div(..) | Inst::Mod(..)))
+    {
+        out += "#include <stdlib.h>\n";
+    }
+    out += "\n";
+    out += &format!(
+        "/* inputs: {} */\n",
+        if variables.is_empty() {
+            "none".to_string()
+        } else {
+            variables.join(", ")
+        }
+    );
+    out += &format!(
+        "{ty} program({}) {{\n",
+        if params.is_empty() {
+            "void".to_string()
+        } else {
+            params.join(", ")
+        }
+    );
+
+    let regs: BTreeSet<Reg> = code
+        .iter()
+        .flat_map(|inst| inst.sources().into_iter().chain(inst.target()))
+        .collect();
+    if !regs.is_empty() {
+        let regs: Vec<String> = regs
+            .into_iter()
+            .map(|r| format!("{} = 0", reg(r)))
+            .collect();
+        out += &format!("    {ty} {};\n", regs.join(", "));
+    }
+    let uses_stack = code.iter().any(|inst| {
+        matches!(
+            inst,
+            Inst::Call(_) | Inst::Ret | Inst::Push(_) | Inst::Pop(_)
+        )
+    });
+    if uses_stack
+        || code
+            .iter()
+            .any(|inst| matches!(inst, Inst::Write(..) | Inst::Load(..)))
+    {
+        out += &format!("    {ty} ram[{}] = {{0}};\n", ram_cells.max(1));
+    }
+    if uses_stack {
+        out += &format!("    int sp = {ram_cells};\n");
+    }
+    out += "\n";
+    for line in c.lines {
+        out += &line;
+        out += "\n";
+    }
+    out += "}\n";
+    Ok(out)
+}
+
+/// The name of the parameter for the input variable `name`.
+fn param_name(name: &str) -> String {
+    if RESERVED.contains(&name) || name.starts_with("reg_") {
+        format!("{name}_")
+    } else {
+        name.to_string()
+    }
+}
+
+fn reg(r: Reg) -> String {
+    format!("reg_{r}")
+}
+
+struct Emitter {
+    lines: Vec<String>,
+    /// The C type of values.
+    ty: String,
+    bits: u32,
+    signedness: Signedness,
+    /// The input variables, with the names of their parameters.
+    variables: Vec<(String, String)>,
+}
+
+impl Emitter {
+    fn emit(&mut self, stmt: String) {
+        self.lines.push(format!("    {stmt}"));
+    }
+
+    /// Translate the instruction at `addr`, where `returns` are the addresses functions return to.
+    fn inst(&mut self, inst: &Inst, addr: usize, returns: &BTreeSet<usize>) {
+        let ty = self.ty.clone();
+        // shifting by the number of bits in a value or more is undefined in C, so only the lowest
+        // bits of the amount are used, like the interpreter does
+        let amount = |r: &Reg| format!("({} & {})", reg(*r), self.bits - 1);
+        // computing with `uint64_t` wraps around instead of overflowing
+        let wrapping = |a: &Reg, op: &str, b: &Reg| {
+            format!(
+                "{} = ({ty})((uint64_t){} {op} (uint64_t){});",
+                reg(*b),
+                reg(*a),
+                reg(*b)
+            )
+        };
+        let binary =
+            |a: &Reg, op: &str, b: &Reg| format!("{} = {} {op} {};", reg(*b), reg(*a), reg(*b));
+
+        let stmt = match inst {
+            // the operators compute `a op b` into `b`
+            Inst::Add(a, b) => wrapping(a, "+", b),
+            Inst::Sub(a, b) => wrapping(a, "-", b),
+            Inst::Mul(a, b) => wrapping(a, "*", b),
+            Inst::Div(a, b) => self.division(a, "/", b),
+            Inst::Mod(a, b) => self.division(a, "%", b),
+            Inst::Shl(a, b) => format!(
+                "{} = ({ty})((uint64_t){} << {});",
+                reg(*b),
+                reg(*a),
+                amount(b)
+            ),
+            Inst::Shr(a, b) => format!("{} = ({ty})({} >> {});", reg(*b), reg(*a), amount(b)),
+            Inst::LogicalShr(a, b) => {
+                format!(
+                    "{} = ({ty})((uint{}_t){} >> {});",
+                    reg(*b),
+                    self.bits,
+                    reg(*a),
+                    amount(b)
+                )
+            }
+            Inst::And(a, b) => binary(a, "&", b),
+            Inst::Or(a, b) => binary(a, "|", b),
+            Inst::Xor(a, b) => binary(a, "^", b),
+            Inst::Not(r) => format!("{0} = ({ty})~{0};", reg(*r)),
+            Inst::Eq(a, b) => binary(a, "==", b),
+            Inst::Ne(a, b) => binary(a, "!=", b),
+            Inst::Lt(a, b) => binary(a, "<", b),
+            Inst::Le(a, b) => binary(a, "<=", b),
+            Inst::Gt(a, b) => binary(a, ">", b),
+            Inst::Ge(a, b) => binary(a, ">=", b),
+            Inst::Jump(target) => format!("goto L{target};"),
+            Inst::JumpIfZero(r, target) => format!("if ({} == 0) goto L{target};", reg(*r)),
+            Inst::Store(n, r) if i32::try_from(*n).is_ok() => format!("{} = {n};", reg(*r)),
+            Inst::Store(n, r) => format!("{} = ({ty})UINT64_C({});", reg(*r), *n as u64),
+            Inst::Transfer(v, r) => {
+                let param = &self
+                    .variables
+                    .iter()
+                    .find(|(name, _)| name == v)
+                    .expect("all variables are known")
+                    .1;
+                format!("{} = {param};", reg(*r))
+            }
+            Inst::Copy(a, b) => format!("{} = {};", reg(*b), reg(*a)),
+            Inst::Result(r) => format!("return {};", reg(*r)),
+            Inst::Call(target) => format!("ram[--sp] = {}; goto L{target};", addr + 1),
+            Inst::Ret => {
+                // functions only return to places they were called from, so the last one can be the
+                // default, which tells the C compiler that execution never gets past the `switch`
+                let last = returns.last();
+                let cases: Vec<String> = returns
+                    .iter()
+                    .map(|addr| {
+                        let case = if Some(addr) == last {
+                            "default".to_string()
+                        } else {
+                            format!("case {addr}")
+                        };
+                        format!("{case}: goto L{addr};")
+                    })
+                    .collect();
+                format!("switch (ram[sp++]) {{ {} }}", cases.join(" "))
+            }
+            Inst::Push(r) => format!("ram[--sp] = {};", reg(*r)),
+            Inst::Pop(r) => format!("{} = ram[sp++];", reg(*r)),
+            Inst::Write(r, addr) => format!("ram[{addr}] = {};", reg(*r)),
+            Inst::Load(addr, r) => format!("{} = ram[{addr}];", reg(*r)),
+        };
+        self.emit(format!("{stmt} /* {} */", Mnemonic(inst)));
+    }
+
+    /// Compute `a op b` into `b` for `/` or `%`, which are undefined in C when dividing by zero or
+    /// the smallest signed value by -1.
+    fn division(&self, a: &Reg, op: &str, b: &Reg) -> String {
+        let (a, b) = (reg(*a), reg(*b));
+        let check = format!("if ({b} == 0) abort(); else");
+        match self.signedness {
+            Signedness::Unsigned => format!("{check} {b} = {a} {op} {b};"),
+            // dividing by -1 negates, which wraps around for the smallest value
+            Signedness::Signed if op == "/" => format!(
+                "{check} {b} = {b} == -1 ? ({})(0 - (uint64_t){a}) : {a} / {b};",
+                self.ty
+            ),
+            Signedness::Signed => format!("{check} {b} = {b} == -1 ? 0 : {a} % {b};"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::process::Command;
+
+    use crate::arith::{Overflow, Signedness, Width};
+    use crate::backends::Target;
+    use crate::compiler::{CompileOptions, Compiled, Compiler};
+    use crate::gui::InterpreterOptions;
+    use crate::interpreter::Interpreter;
+    use crate::types::LpErr;
+
+    fn emit(input: &str, hw: InterpreterOptions) -> String {
+        let options = CompileOptions {
+            target: Target::C,
+            ..Default::default()
+        };
+        Compiler::with(options)
+            .with_interpreter(hw)
+            .emit(input)
+            .unwrap()
+    }
+
+    #[test]
+    fn golden() {
+        let expected = "#include <stdint.h>
+
+/* inputs: int, x */
+int32_t program(int32_t int_, int32_t x) {
+    int32_t reg_a = 0, reg_b = 0;
+    int32_t ram[16] = {0};
+    int sp = 16;
+
+    reg_a = x; /* in x, a */
+    ram[--sp] = reg_a; /* push a */
+    reg_a = ram[sp++]; /* pop a */
+    ram[--sp] = 4; goto L9; /* call 9 */
+L4:
+    reg_b = int_; /* in int, b */
+    reg_b = (int32_t)((uint64_t)reg_a - (uint64_t)reg_b); /* sub a, b */
+    reg_a = 3; /* li 3, a */
+    reg_a = (int32_t)((uint64_t)reg_b << (reg_a & 31)); /* shl b, a */
+    return reg_a; /* res a */
+L9:
+    reg_b = reg_a; /* mov a, b */
+    reg_a = (int32_t)((uint64_t)reg_b * (uint64_t)reg_a); /* mul b, a */
+    switch (ram[sp++]) { default: goto L4; } /* ret */
+}
+";
+        let hw = InterpreterOptions {
+            num_registers: 2,
+            ..Default::default()
+        };
+        assert_eq!(emit("fn sq(n) = n * n; (sq(x) - int) << 3", hw), expected);
+    }
+
+    #[test]
+    fn errors() {
+        // results always wrap around
+        let options = CompileOptions {
+            target: Target::C,
+            ..Default::default()
+        };
+        for overflow in [Overflow::Trapping, Overflow::Saturating] {
+            let hw = InterpreterOptions {
+                overflow,
+                ..Default::default()
+            };
+            assert!(matches!(
+                Compiler::with(options).with_interpreter(hw).emit("x + 1"),
+                Err(LpErr::IR(_))
+            ));
+        }
+    }
+
+    /// Compile the programs with a C compiler and compare their results to the ones of the
+    /// interpreter.
+    #[test]
+    #[ignore = "needs a C compiler, run with `cargo test -- --ignored`"]
+    fn run_like_interpreter() {
+        let inputs = [
+            "x * 3 + y",
+            "(x << 3) | (x & 5) ^ ~y % 7 >> 1 >>> 2",
+            "(x > y) + (x >= y) * 2 + (x < y) * 4 + (x <= y) * 8 + (x == y) * 16 + (x != y) * 32",
+            "let i = 0; let s = 0; while i < x { s = s + i * i; i = i + 1; } s - y",
+            "fn sq(n) = n * n; fn f(a, b) = if a > b then sq(a) / b else sq(b) % a; f(x, y) - f(y, x)",
+            "x * 0.75 - y / 2.5",
+            "-x * 100 * y",
+            "x / y + x % y * 100",
+        ];
+        let dir = std::env::temp_dir().join(format!("lndw-c-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        for width in Width::ALL {
+            for signedness in [Signedness::Signed, Signedness::Unsigned] {
+                let mut args = vec![(5, 3), (7, 7), (2, 9), (100, 17)];
+                if signedness == Signedness::Signed {
+                    let min = -1i64 << (width.bits() - 1);
+                    args.extend([(-100, 7), (min, -1)]);
+                }
+                // all programs go into a single file, each as a function of its own
+                let mut source = String::new();
+                let mut main = String::from("int main(void) {\n");
+                let mut expected = vec![];
+                for (k, input) in inputs.iter().enumerate() {
+                    for num_registers in [2, 6] {
+                        let hw = InterpreterOptions {
+                            num_registers,
+                            word_size: width,
+                            signedness,
+                            ..Default::default()
+                        };
+                        let function = format!("program_{k}_{num_registers}");
+                        source += &emit(input, hw).replace("program(", &format!("{function}("));
+                        let Compiled { code, .. } = Compiler::with(CompileOptions::default())
+                            .with_interpreter(hw)
+                            .compile(input)
+                            .unwrap();
+                        for (x, y) in &args {
+                            // the literal for the smallest 64-bit value would be too big
+                            let literal = |n: &i64| match *n {
+                                i64::MIN => "INT64_MIN".to_string(),
+                                n => n.to_string(),
+                            };
+                            main += &format!(
+                                "    printf(\"%lld\\n\", (long long){function}({}, {}));\n",
+                                literal(x),
+                                literal(y)
+                            );
+                            let vars: HashMap<String, String> =
+                                [("x".into(), x.to_string()), ("y".into(), y.to_string())].into();
+                            let res = Interpreter::with_config(&hw)
+                                .load_instructions(code.clone())
+                                .with_variables(vars)
+                                .ready()
+                                .run_to_end()
+                                .unwrap();
+                            expected.push((
+                                format!("`{input}` with {x}, {y}, {width:?} {signedness:?}"),
+                                res,
+                            ));
+                        }
+                    }
+                }
+                main += "    return 0;\n}\n";
+
+                let file = dir.join(format!("{width:?}_{signedness:?}.c"));
+                let binary = dir.join(format!("{width:?}_{signedness:?}"));
+                std::fs::write(&file, format!("#include <stdio.h>\n{source}{main}")).unwrap();
+                let cc = Command::new("cc")
+                    .args(["-std=c99", "-Wall", "-Wextra", "-pedantic", "-Werror"])
+                    .arg("-o")
+                    .arg(&binary)
+                    .arg(&file)
+                    .output()
+                    .expect("no C compiler found");
+                assert!(
+                    cc.status.success(),
+                    "{}",
+                    String::from_utf8_lossy(&cc.stderr)
+                );
+
+                let output = Command::new(&binary).output().unwrap();
+                let stdout = String::from_utf8(output.stdout).unwrap();
+                let results: Vec<i64> = stdout.lines().map(|line| line.parse().unwrap()).collect();
+                assert_eq!(results.len(), expected.len());
+                for ((what, expected), result) in expected.into_iter().zip(results) {
+                    assert_eq!(result, expected, "{what}");
+                }
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+//! Translations of the optimized AST into code for other processors than the one of the
+//! [`Interpreter`](crate::interpreter::Interpreter).
+
+use rust_i18n::t;
+
+use crate::arith::{Arith, Overflow, Width};
+use crate::types::{Function, LpErr};
+
+pub mod c;
+pub mod riscv;
+pub mod wasm;
+pub mod x86_64;
+
+/// What the program is compiled for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Target {
+    /// The instructions executed by the [`Interpreter`](crate::interpreter::Interpreter).
+    #[default]
+    Interpreter,
+    /// Assembly for RV32IM, the 32-bit RISC-V instruction set with multiplication and division.
+    RiscV,
+    /// Assembly for x86-64, translated from the instructions for the
+    /// [`Interpreter`](crate::interpreter::Interpreter).
+    X86_64,
+    /// WebAssembly in its text format, for a stack machine instead of a register machine.
+    Wasm,
+    /// A C function, translated from the instructions for the
+    /// [`Interpreter`](crate::interpreter::Interpreter).
+    C,
+}
+
+/// Check that a target computing with the word sizes `widths`, whose results wrap around on
+/// overflow, computes the same as the machine with the arithmetic `arith`.
+fn check_arith(arith: Arith, widths: &[Width]) -> Result<(), LpErr> {
+    if !widths.contains(&arith.width) {
+        let supported: Vec<String> = widths.iter().map(|w| w.bits().to_string()).collect();
+        return Err(LpErr::IR(
+            t!(
+                "backends.word_size",
+                supported = supported.join(", "),
+                bits = arith.width.bits()
+            )
+            .to_string(),
+        ));
+    }
+    if arith.overflow != Overflow::Wrapping {
+        return Err(LpErr::IR(t!("backends.overflow").to_string()));
+    }
+    Ok(())
+}
+
+/// The function called by `name` with `args` arguments from the function `current`, or from the
+/// main program if it is `None`. Functions can only call functions defined before them.
+fn resolve_call(
+    functions: &[Function],
+    current: Option<usize>,
+    name: &str,
+    args: usize,
+) -> Result<usize, LpErr> {
+    let visible = current.unwrap_or(functions.len());
+    let Some(idx) = functions[..visible].iter().rposition(|f| f.name == name) else {
+        return Err(LpErr::IR(
+            t!("compiler.error.unknown_function", f = name).to_string(),
+        ));
+    };
+    let expected = functions[idx].params.len();
+    if args != expected {
+        return Err(LpErr::IR(
+            t!(
+                "compiler.error.arg_count",
+                f = name,
+                expected = expected,
+                got = args
+            )
+            .to_string(),
+        ));
+    }
+    Ok(idx)
+}
+
+/// A unique label for each function. Functions redefining an earlier one, or called like the main
+/// program, get their position appended to their name.
+fn function_labels(functions: &[Function]) -> Vec<String> {
+    functions
+        .iter()
+        .enumerate()
+        .map(|(idx, function)| {
+            let taken =
+                function.name == "main" || functions[..idx].iter().any(|f| f.name == function.name);
+            if taken {
+                format!("{}_{idx}", function.name)
+            } else {
+                function.name.clone()
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,513 @@
+//! Assembly for RV32IM.
+//!
+//! The main program becomes the function `main`, which gets the input variables in the argument
+//! registers `a0` to `a7`, sorted by name, and returns its result in `a0`. The functions of the
+//! program follow the same calling convention.
+//!
+//! All bindings live in the stack frame, addressed relative to the frame pointer `s0`. Expressions
+//! are computed in the temporary registers `t0` to `t5`, one for each level of nesting; deeper
+//! operands are kept in the stack frame while `t6` holds the other one.
+
+use rust_i18n::t;
+
+use super::{check_arith, function_labels, resolve_call};
+use crate::arith::{Arith, Signedness, Width};
+use crate::types::{Expr, LpErr, Operator, Program, Stmt};
+
+const TEMPS: [&str; 6] = ["t0", "t1", "t2", "t3", "t4", "t5"];
+const SCRATCH: &str = "t6";
+const ARGS: [&str; 8] = ["a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7"];
+
+/// Translate `program`, whose divisions, remainders and comparisons treat numbers as signed or
+/// unsigned depending on the signedness of `arith`. The registers have 32 bits, and results wrap
+/// around on overflow, so the other word sizes and overflow modes are rejected.
+pub fn emit(program: &Program, arith: Arith) -> Result<String, LpErr> {
+    check_arith(arith, &[Width::W32])?;
+    let signedness = arith.signedness;
+    let labels = function_labels(&program.functions);
+    let mut out = String::from("    .text\n    .globl main\n");
+
+    for (idx, function) in program.functions.iter().enumerate() {
+        if function.params.len() > ARGS.len() {
+            return Err(LpErr::IR(
+                t!("compiler.error.too_many_params", f = function.name).to_string(),
+            ));
+        }
+        let mut generator = Generator::new(program, signedness, &labels, Some(idx));
+        let params = function.params.iter().map(|p| generator.bind(p)).collect();
+        generator.expr(&function.body, 0)?;
+        let comment = format!("fn {}({})", function.name, function.params.join(", "));
+        out += &generator.finish(&labels[idx], &comment, params);
+    }
+
+    let mut generator = Generator::new(program, signedness, &labels, None);
+    generator.stmts(&program.stmts)?;
+    generator.expr(&program.result, 0)?;
+    let mut inputs = std::mem::take(&mut generator.inputs);
+    inputs.sort();
+    if inputs.len() > ARGS.len() {
+        return Err(LpErr::IR(
+            t!(
+                "backends.too_many_inputs",
+                n = inputs.len(),
+                max = ARGS.len()
+            )
+            .to_string(),
+        ));
+    }
+    let names: Vec<String> = inputs
+        .iter()
+        .zip(ARGS)
+        .map(|((name, _), reg)| format!("{name} in {reg}"))
+        .collect();
+    let comment = format!(
+        "inputs: {}",
+        if names.is_empty() {
+            "none".to_string()
+        } else {
+            names.join(", ")
+        }
+    );
+    out += &generator.finish(
+        "main",
+        &comment,
+        inputs.into_iter().map(|(_, slot)| slot).collect(),
+    );
+
+    Ok(out)
+}
+
+/// State of the translation of a single function.
+struct Generator<'a> {
+    program: &'a Program,
+    signedness: Signedness,
+    labels: &'a [String],
+    /// The function being translated, or `None` for the main program.
+    current: Option<usize>,
+    /// Instructions and labels of the function body.
+    lines: Vec<String>,
+    /// Offset of the stack slot of each binding in scope from the frame pointer.
+    bindings: Vec<(String, i32)>,
+    /// The input variables read so far, with their stack slots.
+    inputs: Vec<(String, i32)>,
+    slots: i32,
+    next_label: usize,
+}
+
+impl<'a> Generator<'a> {
+    fn new(
+        program: &'a Program,
+        signedness: Signedness,
+        labels: &'a [String],
+        current: Option<usize>,
+    ) -> Self {
+        Self {
+            program,
+            signedness,
+            labels,
+            current,
+            lines: vec![],
+            bindings: vec![],
+            inputs: vec![],
+            slots: 0,
+            next_label: 0,
+        }
+    }
+
+    /// The body with the prologue and epilogue around it, where the prologue stores the argument
+    /// registers in `args`.
+    fn finish(self, label: &str, comment: &str, args: Vec<i32>) -> String {
+        // the return address and the caller's frame pointer are saved above the slots, and the
+        // stack pointer has to stay aligned to 16 bytes
+        let frame = (8 + 4 * self.slots + 15) / 16 * 16;
+
+        let mut out = format!("\n# {comment}\n{label}:\n");
+        out += &format!("    addi sp, sp, -{frame}\n");
+        out += &format!("    sw ra, {}(sp)\n", frame - 4);
+        out += &format!("    sw s0, {}(sp)\n", frame - 8);
+        out += &format!("    addi s0, sp, {frame}\n");
+        for (slot, reg) in args.into_iter().zip(ARGS) {
+            out += &format!("    sw {reg}, {slot}(s0)\n");
+        }
+        for line in self.lines {
+            out += &line;
+            out += "\n";
+        }
+        out += &format!("    mv a0, {}\n", TEMPS[0]);
+        out += &format!("    lw ra, {}(sp)\n", frame - 4);
+        out += &format!("    lw s0, {}(sp)\n", frame - 8);
+        out += &format!("    addi sp, sp, {frame}\n");
+        out += "    ret\n";
+        out
+    }
+
+    fn emit(&mut self, inst: String) {
+        self.lines.push(format!("    {inst}"));
+    }
+
+    fn new_label(&mut self) -> String {
+        self.next_label += 1;
+        let function = self.current.map_or("main", |idx| &self.labels[idx]);
+        format!(".L{function}_{}", self.next_label - 1)
+    }
+
+    fn place_label(&mut self, label: &str) {
+        self.lines.push(format!("{label}:"));
+    }
+
+    /// A new stack slot, as its offset from the frame pointer.
+    fn new_slot(&mut self) -> i32 {
+        self.slots += 1;
+        -8 - 4 * self.slots
+    }
+
+    /// Bring `name` into scope with a new stack slot.
+    fn bind(&mut self, name: &str) -> i32 {
+        let slot = self.new_slot();
+        self.bindings.push((name.to_string(), slot));
+        slot
+    }
+
+    /// The stack slot holding the variable `name`.
+    fn lookup(&mut self, name: &str) -> Result<i32, LpErr> {
+        if let Some((_, slot)) = self.bindings.iter().rev().find(|(n, _)| n == name) {
+            return Ok(*slot);
+        }
+        if let Some(idx) = self.current {
+            return Err(LpErr::IR(
+                t!(
+                    "compiler.error.not_a_param",
+                    v = name,
+                    f = self.program.functions[idx].name
+                )
+                .to_string(),
+            ));
+        }
+        if let Some((_, slot)) = self.inputs.iter().find(|(n, _)| n == name) {
+            return Ok(*slot);
+        }
+        let slot = self.new_slot();
+        self.inputs.push((name.to_string(), slot));
+        Ok(slot)
+    }
+
+    fn stmts(&mut self, stmts: &[Stmt]) -> Result<(), LpErr> {
+        for stmt in stmts {
+            match stmt {
+                Stmt::Let(name, value) => {
+                    self.expr(value, 0)?;
+                    let slot = self.bind(name);
+                    self.emit(format!("sw {}, {slot}(s0)", TEMPS[0]));
+                }
+                Stmt::Assign(name, value) => {
+                    let Some((_, slot)) = self.bindings.iter().rev().find(|(n, _)| n == name)
+                    else {
+                        return Err(LpErr::IR(
+                            t!("compiler.error.assign_unbound", v = name).to_string(),
+                        ));
+                    };
+                    let slot = *slot;
+                    self.expr(value, 0)?;
+                    self.emit(format!("sw {}, {slot}(s0)", TEMPS[0]));
+                }
+                Stmt::While(cond, body) => {
+                    let (start, end) = (self.new_label(), self.new_label());
+                    self.place_label(&start);
+                    self.expr(cond, 0)?;
+                    self.emit(format!("beqz {}, {end}", TEMPS[0]));
+                    let outer = self.bindings.len();
+                    self.stmts(body)?;
+                    self.bindings.truncate(outer);
+                    self.emit(format!("j {start}"));
+                    self.place_label(&end);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Compute `expr` into the temporary register for the nesting level `depth`.
+    fn expr(&mut self, expr: &Expr, depth: usize) -> Result<(), LpErr> {
+        let rd = TEMPS[depth];
+        match expr {
+            Expr::Num(n) => {
+                let Ok(n) = i32::try_from(*n).or_else(|_| u32::try_from(*n).map(|n| n as i32))
+                else {
+                    return Err(LpErr::IR(
+                        t!("backends.constant_range", n = n, bits = 32).to_string(),
+                    ));
+                };
+                self.emit(format!("li {rd}, {n}"));
+            }
+            Expr::Var(v) => {
+                let slot = self.lookup(v)?;
+                self.emit(format!("lw {rd}, {slot}(s0)"));
+            }
+            Expr::UnaryOp(Operator::Sub, e) => {
+                self.expr(e, depth)?;
+                self.emit(format!("neg {rd}, {rd}"));
+            }
+            Expr::UnaryOp(Operator::Not, e) => {
+                self.expr(e, depth)?;
+                self.emit(format!("not {rd}, {rd}"));
+            }
+            Expr::UnaryOp(op, _) => {
+                return Err(LpErr::IR(
+                    t!("compiler.error.invalid_unary", op = op).to_string(),
+                ));
+            }
+            Expr::BinaryOp(_, Operator::Not, _) => {
+                return Err(LpErr::IR(
+                    t!("compiler.error.invalid_binary", op = Operator::Not).to_string(),
+                ));
+            }
+            Expr::BinaryOp(left, op, right) => {
+                self.expr(left, depth)?;
+                if depth + 1 < TEMPS.len() {
+                    self.expr(right, depth + 1)?;
+                    self.binary(*op, rd, TEMPS[depth + 1]);
+                } else {
+                    // out of registers, the left operand waits in the stack frame
+                    let slot = self.new_slot();
+                    self.emit(format!("sw {rd}, {slot}(s0)"));
+                    self.expr(right, depth)?;
+                    self.emit(format!("mv {SCRATCH}, {rd}"));
+                    self.emit(format!("lw {rd}, {slot}(s0)"));
+                    self.binary(*op, rd, SCRATCH);
+                }
+            }
+            Expr::If(cond, then, otherwise) => {
+                let (else_label, end) = (self.new_label(), self.new_label());
+                self.expr(cond, depth)?;
+                self.emit(format!("beqz {rd}, {else_label}"));
+                self.expr(then, depth)?;
+                self.emit(format!("j {end}"));
+                self.place_label(&else_label);
+                self.expr(otherwise, depth)?;
+                self.place_label(&end);
+            }
+            Expr::Call(name, args) => {
+                let idx = resolve_call(&self.program.functions, self.current, name, args.len())?;
+
+                // the arguments are computed first, since computing one can call other functions
+                let mut arg_slots = Vec::with_capacity(args.len());
+                for arg in args {
+                    self.expr(arg, depth)?;
+                    let slot = self.new_slot();
+                    self.emit(format!("sw {rd}, {slot}(s0)"));
+                    arg_slots.push(slot);
+                }
+                // the called function may change all temporary registers
+                let saved: Vec<(&str, i32)> = TEMPS[..depth]
+                    .iter()
+                    .map(|reg| (*reg, self.new_slot()))
+                    .collect();
+                for (reg, slot) in &saved {
+                    self.emit(format!("sw {reg}, {slot}(s0)"));
+                }
+                for (slot, reg) in arg_slots.into_iter().zip(ARGS) {
+                    self.emit(format!("lw {reg}, {slot}(s0)"));
+                }
+                self.emit(format!("call {}", self.labels[idx]));
+                self.emit(format!("mv {rd}, a0"));
+                for (reg, slot) in &saved {
+                    self.emit(format!("lw {reg}, {slot}(s0)"));
+                }
+            }
+            Expr::FixedPoint(e) => self.expr(e, depth)?,
+        }
+        Ok(())
+    }
+
+    /// Compute `rd op rs` into `rd`.
+    fn binary(&mut self, op: Operator, rd: &str, rs: &str) {
+        let unsigned = self.signedness == Signedness::Unsigned;
+        let less = if unsigned { "sltu" } else { "slt" };
+        match op {
+            Operator::Add => self.emit(format!("add {rd}, {rd}, {rs}")),
+            Operator::Sub => self.emit(format!("sub {rd}, {rd}, {rs}")),
+            Operator::Mul => self.emit(format!("mul {rd}, {rd}, {rs}")),
+            Operator::Div if unsigned => self.emit(format!("divu {rd}, {rd}, {rs}")),
+            Operator::Div => self.emit(format!("div {rd}, {rd}, {rs}")),
+            Operator::Mod if unsigned => self.emit(format!("remu {rd}, {rd}, {rs}")),
+            Operator::Mod => self.emit(format!("rem {rd}, {rd}, {rs}")),
+            Operator::Shl => self.emit(format!("sll {rd}, {rd}, {rs}")),
+            Operator::Shr if unsigned => self.emit(format!("srl {rd}, {rd}, {rs}")),
+            Operator::Shr => self.emit(format!("sra {rd}, {rd}, {rs}")),
+            Operator::LogicalShr => self.emit(format!("srl {rd}, {rd}, {rs}")),
+            Operator::And => self.emit(format!("and {rd}, {rd}, {rs}")),
+            Operator::Or => self.emit(format!("or {rd}, {rd}, {rs}")),
+            Operator::Xor => self.emit(format!("xor {rd}, {rd}, {rs}")),
+            Operator::Eq => {
+                self.emit(format!("sub {rd}, {rd}, {rs}"));
+                self.emit(format!("seqz {rd}, {rd}"));
+            }
+            Operator::Ne => {
+                self.emit(format!("sub {rd}, {rd}, {rs}"));
+                self.emit(format!("snez {rd}, {rd}"));
+            }
+            Operator::Lt => self.emit(format!("{less} {rd}, {rd}, {rs}")),
+            Operator::Gt => self.emit(format!("{less} {rd}, {rs}, {rd}")),
+            Operator::Le => {
+                self.emit(format!("{less} {rd}, {rs}, {rd}"));
+                self.emit(format!("xori {rd}, {rd}, 1"));
+            }
+            Operator::Ge => {
+                self.emit(format!("{less} {rd}, {rd}, {rs}"));
+                self.emit(format!("xori {rd}, {rd}, 1"));
+            }
+            Operator::Not => unreachable!("`~` is checked to be unary"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::arith::{Overflow, Width};
+    use crate::backends::Target;
+    use crate::compiler::{CompileOptions, Compiler};
+    use crate::gui::InterpreterOptions;
+    use crate::types::LpErr;
+
+    fn emit(input: &str) -> String {
+        let options = CompileOptions {
+            target: Target::RiscV,
+            ..Default::default()
+        };
+        Compiler::with(options).emit(input).unwrap()
+    }
+
+    #[test]
+    fn golden_calls() {
+        let expected = "    .text
+    .globl main
+
+# fn sq(n)
+sq:
+    addi sp, sp, -16
+    sw ra, 12(sp)
+    sw s0, 8(sp)
+    addi s0, sp, 16
+    sw a0, -12(s0)
+    lw t0, -12(s0)
+    lw t1, -12(s0)
+    mul t0, t0, t1
+    mv a0, t0
+    lw ra, 12(sp)
+    lw s0, 8(sp)
+    addi sp, sp, 16
+    ret
+
+# inputs: x in a0, y in a1
+main:
+    addi sp, sp, -32
+    sw ra, 28(sp)
+    sw s0, 24(sp)
+    addi s0, sp, 32
+    sw a0, -12(s0)
+    sw a1, -20(s0)
+    lw t0, -12(s0)
+    sw t0, -16(s0)
+    lw a0, -16(s0)
+    call sq
+    mv t0, a0
+    lw t1, -20(s0)
+    sw t1, -24(s0)
+    sw t0, -28(s0)
+    lw a0, -24(s0)
+    call sq
+    mv t1, a0
+    lw t0, -28(s0)
+    add t0, t0, t1
+    mv a0, t0
+    lw ra, 28(sp)
+    lw s0, 24(sp)
+    addi sp, sp, 32
+    ret
+";
+        assert_eq!(emit("fn sq(n) = n * n; sq(x) + sq(y)"), expected);
+    }
+
+    #[test]
+    fn golden_loop() {
+        let expected = "    .text
+    .globl main
+
+# inputs: n in a0
+main:
+    addi sp, sp, -16
+    sw ra, 12(sp)
+    sw s0, 8(sp)
+    addi s0, sp, 16
+    sw a0, -16(s0)
+    li t0, 0
+    sw t0, -12(s0)
+.Lmain_0:
+    lw t0, -12(s0)
+    lw t1, -16(s0)
+    slt t0, t0, t1
+    beqz t0, .Lmain_1
+    lw t0, -12(s0)
+    li t1, 1
+    add t0, t0, t1
+    sw t0, -12(s0)
+    j .Lmain_0
+.Lmain_1:
+    lw t0, -12(s0)
+    mv a0, t0
+    lw ra, 12(sp)
+    lw s0, 8(sp)
+    addi sp, sp, 16
+    ret
+";
+        assert_eq!(emit("let i = 0; while i < n { i = i + 1; } i"), expected);
+    }
+
+    #[test]
+    fn out_of_registers() {
+        let code = emit("x - (x - (x - (x - (x - (x - (x - x))))))");
+        assert!(
+            code.contains("    mv t6, t5\n    lw t5, -16(s0)\n    sub t5, t5, t6\n"),
+            "{code}"
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let options = CompileOptions {
+            target: Target::RiscV,
+            ..Default::default()
+        };
+        assert!(
+            Compiler::with(options)
+                .emit("a + b + c + d + e + f + g + h + i")
+                .is_err()
+        );
+        assert!(
+            Compiler::with(options)
+                .emit("a + b + c + d + e + f + g + h")
+                .is_ok()
+        );
+
+        // the registers only have 32 bits, and results always wrap around
+        for hw in [
+            InterpreterOptions {
+                word_size: Width::W64,
+                ..Default::default()
+            },
+            InterpreterOptions {
+                word_size: Width::W16,
+                ..Default::default()
+            },
+            InterpreterOptions {
+                overflow: Overflow::Saturating,
+                ..Default::default()
+            },
+        ] {
+            assert!(matches!(
+                Compiler::with(options).with_interpreter(hw).emit("x + 1"),
+                Err(LpErr::IR(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,1001 @@
+//! WebAssembly in its text format (WAT).
+//!
+//! Unlike our computer, WebAssembly has no registers: its instructions take their operands from a
+//! stack and put their result back onto it, so `x * 3 + y` becomes `local.get $x`, `i32.const 3`,
+//! `i32.mul`, `local.get $y`, `i32.add`. Bindings become local variables, and loops and
+//! conditionals become nested blocks instead of jumps to addresses.
+//!
+//! The main program becomes the exported function `main`, whose parameters are the input
+//! variables, sorted by name. Values have 64 bits with 64-bit words and 32 bits otherwise; 8- and
+//! 16-bit words are kept sign- or zero-extended in the 32 bits, and are cut down to their bits
+//! again after every operation.
+//!
+//! In the tests, `Module::evaluate` runs the generated code without a browser, to check it against
+//! the [`Interpreter`](crate::interpreter::Interpreter).
+
+#[cfg(test)]
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+use rust_i18n::t;
+
+use super::{check_arith, function_labels, resolve_call};
+use crate::arith::{Arith, Signedness, Width};
+use crate::types::{Expr, LpErr, Operator, Program, Stmt};
+
+/// The type of all values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValType {
+    I32,
+    I64,
+}
+
+impl ValType {
+    #[cfg(test)]
+    fn bits(self) -> u32 {
+        match self {
+            ValType::I32 => 32,
+            ValType::I64 => 64,
+        }
+    }
+
+    /// Cut `value` down to this type, keeping it sign-extended in the `i64`.
+    fn wrap(self, value: i64) -> i64 {
+        match self {
+            ValType::I32 => value as i32 as i64,
+            ValType::I64 => value,
+        }
+    }
+
+    /// The bits of `value` as an unsigned number.
+    #[cfg(test)]
+    fn unsigned(self, value: i64) -> u64 {
+        match self {
+            ValType::I32 => value as u32 as u64,
+            ValType::I64 => value as u64,
+        }
+    }
+}
+
+impl Display for ValType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValType::I32 => write!(f, "i32"),
+            ValType::I64 => write!(f, "i64"),
+        }
+    }
+}
+
+/// An instruction taking two operands from the stack. Comparisons result in an `i32`, whatever the
+/// type of their operands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Numeric {
+    Add,
+    Sub,
+    Mul,
+    DivS,
+    DivU,
+    RemS,
+    RemU,
+    Shl,
+    ShrS,
+    ShrU,
+    And,
+    Or,
+    Xor,
+    Eq,
+    Ne,
+    LtS,
+    LtU,
+    LeS,
+    LeU,
+    GtS,
+    GtU,
+    GeS,
+    GeU,
+}
+
+impl Numeric {
+    fn new(op: Operator, signedness: Signedness) -> Self {
+        let unsigned = signedness == Signedness::Unsigned;
+        match op {
+            Operator::Add => Numeric::Add,
+            Operator::Sub => Numeric::Sub,
+            Operator::Mul => Numeric::Mul,
+            Operator::Div if unsigned => Numeric::DivU,
+            Operator::Div => Numeric::DivS,
+            Operator::Mod if unsigned => Numeric::RemU,
+            Operator::Mod => Numeric::RemS,
+            Operator::Shl => Numeric::Shl,
+            Operator::Shr if unsigned => Numeric::ShrU,
+            Operator::Shr => Numeric::ShrS,
+            Operator::LogicalShr => Numeric::ShrU,
+            Operator::And => Numeric::And,
+            Operator::Or => Numeric::Or,
+            Operator::Xor => Numeric::Xor,
+            Operator::Eq => Numeric::Eq,
+            Operator::Ne => Numeric::Ne,
+            Operator::Lt if unsigned => Numeric::LtU,
+            Operator::Lt => Numeric::LtS,
+            Operator::Le if unsigned => Numeric::LeU,
+            Operator::Le => Numeric::LeS,
+            Operator::Gt if unsigned => Numeric::GtU,
+            Operator::Gt => Numeric::GtS,
+            Operator::Ge if unsigned => Numeric::GeU,
+            Operator::Ge => Numeric::GeS,
+            Operator::Not => unreachable!("`~` is a unary operator"),
+        }
+    }
+
+    fn is_comparison(self) -> bool {
+        matches!(
+            self,
+            Numeric::Eq
+                | Numeric::Ne
+                | Numeric::LtS
+                | Numeric::LtU
+                | Numeric::LeS
+                | Numeric::LeU
+                | Numeric::GtS
+                | Numeric::GtU
+                | Numeric::GeS
+                | Numeric::GeU
+        )
+    }
+
+    /// Compute `a op b` on values of type `ty`, failing where WebAssembly traps.
+    #[cfg(test)]
+    fn apply(self, ty: ValType, a: i64, b: i64) -> Result<i64, LpErr> {
+        let (ua, ub) = (ty.unsigned(a), ty.unsigned(b));
+        let amount = (b as u32) & (ty.bits() - 1);
+        if matches!(
+            self,
+            Numeric::DivS | Numeric::DivU | Numeric::RemS | Numeric::RemU
+        ) && b == 0
+        {
+            return Err(LpErr::Interpret(t!("compiler.error.divzero").to_string()));
+        }
+        let result = match self {
+            Numeric::Add => a.wrapping_add(b),
+            Numeric::Sub => a.wrapping_sub(b),
+            Numeric::Mul => a.wrapping_mul(b),
+            // the only quotient that doesn't fit
+            Numeric::DivS if a == i64::MIN >> (64 - ty.bits()) && b == -1 => {
+                return Err(LpErr::Interpret(t!("backends.wasm_overflow").to_string()));
+            }
+            Numeric::DivS => a / b,
+            Numeric::DivU => (ua / ub) as i64,
+            Numeric::RemS => a.wrapping_rem(b),
+            Numeric::RemU => (ua % ub) as i64,
+            Numeric::Shl => a << amount,
+            Numeric::ShrS => a >> amount,
+            Numeric::ShrU => (ua >> amount) as i64,
+            Numeric::And => a & b,
+            Numeric::Or => a | b,
+            Numeric::Xor => a ^ b,
+            Numeric::Eq => (a == b).into(),
+            Numeric::Ne => (a != b).into(),
+            Numeric::LtS => (a < b).into(),
+            Numeric::LtU => (ua < ub).into(),
+            Numeric::LeS => (a <= b).into(),
+            Numeric::LeU => (ua <= ub).into(),
+            Numeric::GtS => (a > b).into(),
+            Numeric::GtU => (ua > ub).into(),
+            Numeric::GeS => (a >= b).into(),
+            Numeric::GeU => (ua >= ub).into(),
+        };
+        Ok(ty.wrap(result))
+    }
+}
+
+impl Display for Numeric {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Numeric::Add => "add",
+            Numeric::Sub => "sub",
+            Numeric::Mul => "mul",
+            Numeric::DivS => "div_s",
+            Numeric::DivU => "div_u",
+            Numeric::RemS => "rem_s",
+            Numeric::RemU => "rem_u",
+            Numeric::Shl => "shl",
+            Numeric::ShrS => "shr_s",
+            Numeric::ShrU => "shr_u",
+            Numeric::And => "and",
+            Numeric::Or => "or",
+            Numeric::Xor => "xor",
+            Numeric::Eq => "eq",
+            Numeric::Ne => "ne",
+            Numeric::LtS => "lt_s",
+            Numeric::LtU => "lt_u",
+            Numeric::LeS => "le_s",
+            Numeric::LeU => "le_u",
+            Numeric::GtS => "gt_s",
+            Numeric::GtU => "gt_u",
+            Numeric::GeS => "ge_s",
+            Numeric::GeU => "ge_u",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// A WebAssembly instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Instr {
+    Const(i64),
+    /// Push the local variable with the given index in [`Function::locals`].
+    LocalGet(usize),
+    /// Pop a value into the local variable with the given index.
+    LocalSet(usize),
+    Numeric(Numeric),
+    /// Replace a value by 1 if it is 0, and by 0 otherwise, as an `i32`.
+    Eqz,
+    /// Turn the `i32` result of a comparison into an `i64`.
+    Extend,
+    /// Sign-extend the lowest 8 or 16 bits of an `i32`.
+    ExtendS(u32),
+    /// Call the function with the given index in [`Module::functions`].
+    Call(usize),
+    /// Pop a condition and run the first block if it is non-zero, the second one otherwise. Either
+    /// leaves a value on the stack.
+    If(Vec<Instr>, Vec<Instr>),
+    /// A block that branches jump to the end of.
+    Block(Vec<Instr>),
+    /// A block that branches jump to the start of.
+    Loop(Vec<Instr>),
+    /// Leave the given number of enclosing blocks, counting from 0 for the innermost one.
+    Br(u32),
+    /// Pop a condition and branch like [`Instr::Br`] if it is non-zero.
+    BrIf(u32),
+}
+
+/// A function, whose first `params` locals are its parameters.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Function {
+    pub name: String,
+    pub params: usize,
+    pub locals: Vec<String>,
+    pub body: Vec<Instr>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Module {
+    pub ty: ValType,
+    pub signedness: Signedness,
+    pub functions: Vec<Function>,
+    /// The main program, whose parameters are [`Module::inputs`].
+    pub main: Function,
+    /// The input variables, sorted by name.
+    pub inputs: Vec<String>,
+}
+
+/// Translate `program`, using 64-bit values if the words of `arith` have 64 bits and 32-bit ones
+/// otherwise. Results always wrap around, so the other overflow modes are rejected.
+pub fn lower(program: &Program, arith: Arith) -> Result<Module, LpErr> {
+    check_arith(arith, &Width::ALL)?;
+    let (width, signedness) = (arith.width, arith.signedness);
+    let ty = if width == Width::W64 {
+        ValType::I64
+    } else {
+        ValType::I32
+    };
+    let labels = function_labels(&program.functions);
+
+    let mut functions = Vec::with_capacity(program.functions.len());
+    for (idx, function) in program.functions.iter().enumerate() {
+        let mut generator = Generator::new(program, ty, arith, Some(idx));
+        for param in &function.params {
+            generator.bind(param);
+        }
+        generator.expr(&function.body)?;
+        functions.push(generator.finish(&labels[idx], function.params.len()));
+    }
+
+    let mut generator = Generator::new(program, ty, arith, None);
+    generator.stmts(&program.stmts)?;
+    generator.expr(&program.result)?;
+
+    // the inputs become the parameters, which come first among the locals
+    let mut inputs = std::mem::take(&mut generator.inputs);
+    inputs.sort();
+    let mut order: Vec<usize> = inputs.iter().map(|(_, local)| *local).collect();
+    let rest: Vec<usize> = (0..generator.locals.len())
+        .filter(|local| !order.contains(local))
+        .collect();
+    order.extend(rest);
+    let mut position = vec![0; order.len()];
+    for (new, old) in order.iter().enumerate() {
+        position[*old] = new;
+    }
+    generator.locals = order
+        .iter()
+        .map(|old| generator.locals[*old].clone())
+        .collect();
+    map_locals(&mut generator.body, &|local| position[local]);
+    let main = generator.finish("main", inputs.len());
+
+    Ok(Module {
+        ty,
+        signedness,
+        functions,
+        main,
+        inputs: inputs.into_iter().map(|(name, _)| name).collect(),
+    })
+}
+
+/// Replace the index of each local variable accessed in `body` by `f(index)`.
+fn map_locals(body: &mut [Instr], f: &impl Fn(usize) -> usize) {
+    for instr in body {
+        match instr {
+            Instr::LocalGet(local) | Instr::LocalSet(local) => *local = f(*local),
+            Instr::If(then, otherwise) => {
+                map_locals(then, f);
+                map_locals(otherwise, f);
+            }
+            Instr::Block(body) | Instr::Loop(body) => map_locals(body, f),
+            _ => (),
+        }
+    }
+}
+
+/// State of the translation of a single function.
+struct Generator<'a> {
+    program: &'a Program,
+    ty: ValType,
+    width: Width,
+    signedness: Signedness,
+    /// The function being translated, or `None` for the main program.
+    current: Option<usize>,
+    /// Instructions of the blocks being generated, the innermost one last.
+    blocks: Vec<Vec<Instr>>,
+    body: Vec<Instr>,
+    locals: Vec<String>,
+    /// The local variable of each binding in scope.
+    bindings: Vec<(String, usize)>,
+    /// The input variables read so far, with their local variables.
+    inputs: Vec<(String, usize)>,
+}
+
+impl<'a> Generator<'a> {
+    fn new(program: &'a Program, ty: ValType, arith: Arith, current: Option<usize>) -> Self {
+        Self {
+            program,
+            ty,
+            width: arith.width,
+            signedness: arith.signedness,
+            current,
+            blocks: vec![],
+            body: vec![],
+            locals: vec![],
+            bindings: vec![],
+            inputs: vec![],
+        }
+    }
+
+    fn finish(self, name: &str, params: usize) -> Function {
+        Function {
+            name: name.to_string(),
+            params,
+            locals: self.locals,
+            body: self.body,
+        }
+    }
+
+    fn emit(&mut self, instr: Instr) {
+        self.blocks.last_mut().unwrap_or(&mut self.body).push(instr);
+    }
+
+    /// Generate the instructions of `f` into a block of their own.
+    fn block(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<(), LpErr>,
+    ) -> Result<Vec<Instr>, LpErr> {
+        self.blocks.push(vec![]);
+        let res = f(self);
+        let block = self.blocks.pop().expect("the block was pushed above");
+        res.map(|()| block)
+    }
+
+    /// Cut the value on top of the stack down to the bits of an 8- or 16-bit word.
+    fn narrow(&mut self) {
+        let bits = self.width.bits();
+        if bits >= 32 {
+            return;
+        }
+        match self.signedness {
+            Signedness::Signed => self.emit(Instr::ExtendS(bits)),
+            Signedness::Unsigned => {
+                self.emit(Instr::Const((1 << bits) - 1));
+                self.emit(Instr::Numeric(Numeric::And));
+            }
+        }
+    }
+
+    /// A new local variable, named like `name` unless another one is already called that.
+    fn new_local(&mut self, name: &str) -> usize {
+        let mut unique = name.to_string();
+        let mut n = 1;
+        while self.locals.contains(&unique) {
+            unique = format!("{name}_{n}");
+            n += 1;
+        }
+        self.locals.push(unique);
+        self.locals.len() - 1
+    }
+
+    /// Bring `name` into scope with a new local variable.
+    fn bind(&mut self, name: &str) -> usize {
+        let local = self.new_local(name);
+        self.bindings.push((name.to_string(), local));
+        local
+    }
+
+    /// The local variable holding the variable `name`.
+    fn lookup(&mut self, name: &str) -> Result<usize, LpErr> {
+        if let Some((_, local)) = self.bindings.iter().rev().find(|(n, _)| n == name) {
+            return Ok(*local);
+        }
+        if let Some(idx) = self.current {
+            return Err(LpErr::IR(
+                t!(
+                    "compiler.error.not_a_param",
+                    v = name,
+                    f = self.program.functions[idx].name
+                )
+                .to_string(),
+            ));
+        }
+        if let Some((_, local)) = self.inputs.iter().find(|(n, _)| n == name) {
+            return Ok(*local);
+        }
+        let local = self.new_local(name);
+        self.inputs.push((name.to_string(), local));
+        Ok(local)
+    }
+
+    fn stmts(&mut self, stmts: &[Stmt]) -> Result<(), LpErr> {
+        for stmt in stmts {
+            match stmt {
+                Stmt::Let(name, value) => {
+                    self.expr(value)?;
+                    let local = self.bind(name);
+                    self.emit(Instr::LocalSet(local));
+                }
+                Stmt::Assign(name, value) => {
+                    let Some((_, local)) = self.bindings.iter().rev().find(|(n, _)| n == name)
+                    else {
+                        return Err(LpErr::IR(
+                            t!("compiler.error.assign_unbound", v = name).to_string(),
+                        ));
+                    };
+                    let local = *local;
+                    self.expr(value)?;
+                    self.emit(Instr::LocalSet(local));
+                }
+                Stmt::While(cond, body) => {
+                    // leave the outer block once the condition is 0, repeat the inner one otherwise
+                    let inner = self.block(|g| {
+                        g.expr(cond)?;
+                        g.emit(Instr::Eqz);
+                        g.emit(Instr::BrIf(1));
+                        let outer = g.bindings.len();
+                        g.stmts(body)?;
+                        g.bindings.truncate(outer);
+                        g.emit(Instr::Br(0));
+                        Ok(())
+                    })?;
+                    self.emit(Instr::Block(vec![Instr::Loop(inner)]));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Push the value of `expr` onto the stack.
+    fn expr(&mut self, expr: &Expr) -> Result<(), LpErr> {
+        match expr {
+            Expr::Num(n) => {
+                let fits = match self.ty {
+                    ValType::I32 => i32::try_from(*n).is_ok() || u32::try_from(*n).is_ok(),
+                    ValType::I64 => true,
+                };
+                if !fits {
+                    return Err(LpErr::IR(
+                        t!("backends.constant_range", n = n, bits = 32).to_string(),
+                    ));
+                }
+                self.emit(Instr::Const(self.ty.wrap(*n)));
+            }
+            Expr::Var(v) => {
+                let local = self.lookup(v)?;
+                self.emit(Instr::LocalGet(local));
+            }
+            Expr::UnaryOp(Operator::Sub, e) => {
+                self.emit(Instr::Const(0));
+                self.expr(e)?;
+                self.emit(Instr::Numeric(Numeric::Sub));
+                self.narrow();
+            }
+            Expr::UnaryOp(Operator::Not, e) => {
+                self.expr(e)?;
+                self.emit(Instr::Const(-1));
+                self.emit(Instr::Numeric(Numeric::Xor));
+                self.narrow();
+            }
+            Expr::UnaryOp(op, _) => {
+                return Err(LpErr::IR(
+                    t!("compiler.error.invalid_unary", op = op).to_string(),
+                ));
+            }
+            Expr::BinaryOp(_, Operator::Not, _) => {
+                return Err(LpErr::IR(
+                    t!("compiler.error.invalid_binary", op = Operator::Not).to_string(),
+                ));
+            }
+            Expr::BinaryOp(left, Operator::Div, right)
+                if self.signedness == Signedness::Signed && self.width.bits() >= 32 =>
+            {
+                // `div_s` traps on the smallest value divided by -1, where the result wraps
+                // around, so dividing by -1 negates instead
+                self.expr(left)?;
+                let dividend = self.new_local("dividend");
+                self.emit(Instr::LocalSet(dividend));
+                self.expr(right)?;
+                let divisor = self.new_local("divisor");
+                self.emit(Instr::LocalSet(divisor));
+                self.emit(Instr::LocalGet(divisor));
+                self.emit(Instr::Const(-1));
+                self.emit(Instr::Numeric(Numeric::Eq));
+                let negate = vec![
+                    Instr::Const(0),
+                    Instr::LocalGet(dividend),
+                    Instr::Numeric(Numeric::Sub),
+                ];
+                let divide = vec![
+                    Instr::LocalGet(dividend),
+                    Instr::LocalGet(divisor),
+                    Instr::Numeric(Numeric::DivS),
+                ];
+                self.emit(Instr::If(negate, divide));
+            }
+            Expr::BinaryOp(left, op, right) => {
+                let narrow = self.width.bits() < 32;
+                let shift = matches!(op, Operator::Shl | Operator::Shr | Operator::LogicalShr);
+                self.expr(left)?;
+                if narrow && *op == Operator::LogicalShr {
+                    // shift in zeros right above the word, not above the 32 bits
+                    self.emit(Instr::Const((1 << self.width.bits()) - 1));
+                    self.emit(Instr::Numeric(Numeric::And));
+                }
+                self.expr(right)?;
+                if narrow && shift {
+                    // WebAssembly only takes the shift amount modulo 32
+                    self.emit(Instr::Const(i64::from(self.width.bits()) - 1));
+                    self.emit(Instr::Numeric(Numeric::And));
+                }
+                let numeric = Numeric::new(*op, self.signedness);
+                self.emit(Instr::Numeric(numeric));
+                if numeric.is_comparison() && self.ty == ValType::I64 {
+                    self.emit(Instr::Extend);
+                }
+                if matches!(
+                    op,
+                    Operator::Add
+                        | Operator::Sub
+                        | Operator::Mul
+                        | Operator::Div
+                        | Operator::Shl
+                        | Operator::LogicalShr
+                ) {
+                    self.narrow();
+                }
+            }
+            Expr::If(cond, then, otherwise) => {
+                // `if` needs an `i32` condition
+                self.expr(cond)?;
+                self.emit(Instr::Const(0));
+                self.emit(Instr::Numeric(Numeric::Ne));
+                let then = self.block(|g| g.expr(then))?;
+                let otherwise = self.block(|g| g.expr(otherwise))?;
+                self.emit(Instr::If(then, otherwise));
+            }
+            Expr::Call(name, args) => {
+                let idx = resolve_call(&self.program.functions, self.current, name, args.len())?;
+                for arg in args {
+                    self.expr(arg)?;
+                }
+                self.emit(Instr::Call(idx));
+            }
+            Expr::FixedPoint(e) => self.expr(e)?,
+        }
+        Ok(())
+    }
+}
+
+impl Display for Module {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "(module")?;
+        for function in self.functions.iter().chain([&self.main]) {
+            let is_main = std::ptr::eq(function, &self.main);
+            if is_main {
+                let inputs = if self.inputs.is_empty() {
+                    "none".to_string()
+                } else {
+                    self.inputs.join(", ")
+                };
+                writeln!(f, "  ;; inputs: {inputs}")?;
+            }
+            write!(f, "  (func ${}", function.name)?;
+            if is_main {
+                write!(f, " (export \"main\")")?;
+            }
+            for param in &function.locals[..function.params] {
+                write!(f, " (param ${param} {})", self.ty)?;
+            }
+            writeln!(f, " (result {})", self.ty)?;
+            for local in &function.locals[function.params..] {
+                writeln!(f, "    (local ${local} {})", self.ty)?;
+            }
+            self.write_body(f, function, &function.body, 2)?;
+            writeln!(f, "  )")?;
+        }
+        writeln!(f, ")")
+    }
+}
+
+impl Module {
+    fn write_body(
+        &self,
+        f: &mut Formatter<'_>,
+        function: &Function,
+        body: &[Instr],
+        depth: usize,
+    ) -> std::fmt::Result {
+        let indent = "  ".repeat(depth);
+        let ty = self.ty;
+        for instr in body {
+            match instr {
+                Instr::Const(n) => writeln!(f, "{indent}{ty}.const {n}")?,
+                Instr::LocalGet(local) => {
+                    writeln!(f, "{indent}local.get ${}", function.locals[*local])?
+                }
+                Instr::LocalSet(local) => {
+                    writeln!(f, "{indent}local.set ${}", function.locals[*local])?
+                }
+                Instr::Numeric(numeric) => writeln!(f, "{indent}{ty}.{numeric}")?,
+                Instr::Eqz => writeln!(f, "{indent}{ty}.eqz")?,
+                Instr::Extend => writeln!(f, "{indent}i64.extend_i32_u")?,
+                Instr::ExtendS(bits) => writeln!(f, "{indent}i32.extend{bits}_s")?,
+                Instr::Call(idx) => writeln!(f, "{indent}call ${}", self.functions[*idx].name)?,
+                Instr::If(then, otherwise) => {
+                    writeln!(f, "{indent}if (result {ty})")?;
+                    self.write_body(f, function, then, depth + 1)?;
+                    writeln!(f, "{indent}else")?;
+                    self.write_body(f, function, otherwise, depth + 1)?;
+                    writeln!(f, "{indent}end")?;
+                }
+                Instr::Block(body) | Instr::Loop(body) => {
+                    let keyword = if matches!(instr, Instr::Block(_)) {
+                        "block"
+                    } else {
+                        "loop"
+                    };
+                    writeln!(f, "{indent}{keyword}")?;
+                    self.write_body(f, function, body, depth + 1)?;
+                    writeln!(f, "{indent}end")?;
+                }
+                Instr::Br(depth) => writeln!(f, "{indent}br {depth}")?,
+                Instr::BrIf(depth) => writeln!(f, "{indent}br_if {depth}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where execution continues after running a block.
+#[cfg(test)]
+enum Flow {
+    Next,
+    /// Leave the given number of enclosing blocks.
+    Branch(u32),
+}
+
+#[cfg(test)]
+impl Module {
+    /// Run the main program with the given words for the input variables, the way a WebAssembly
+    /// runtime would, giving up after `max_steps` instructions.
+    ///
+    /// The result is a word like the ones of the [`Interpreter`](crate::interpreter::Interpreter),
+    /// i.e. unsigned 32-bit results aren't sign-extended.
+    pub fn evaluate(&self, inputs: &HashMap<String, i64>, max_steps: usize) -> Result<i64, LpErr> {
+        let args = self
+            .inputs
+            .iter()
+            .map(|name| {
+                inputs
+                    .get(name)
+                    .map(|word| self.ty.wrap(*word))
+                    .ok_or_else(|| {
+                        LpErr::Interpret(t!("compiler.error.unknown_var", v = name).to_string())
+                    })
+            })
+            .collect::<Result<Vec<i64>, LpErr>>()?;
+
+        let mut steps = 0;
+        let result = self.call(&self.main, args, &mut steps, max_steps)?;
+        Ok(match self.signedness {
+            Signedness::Signed => result,
+            Signedness::Unsigned => self.ty.unsigned(result) as i64,
+        })
+    }
+
+    fn call(
+        &self,
+        function: &Function,
+        args: Vec<i64>,
+        steps: &mut usize,
+        max_steps: usize,
+    ) -> Result<i64, LpErr> {
+        let mut locals = args;
+        locals.resize(function.locals.len(), 0);
+        let mut stack = vec![];
+        self.run(&function.body, &mut locals, &mut stack, steps, max_steps)?;
+        Ok(stack
+            .pop()
+            .expect("functions leave their result on the stack"))
+    }
+
+    fn run(
+        &self,
+        body: &[Instr],
+        locals: &mut [i64],
+        stack: &mut Vec<i64>,
+        steps: &mut usize,
+        max_steps: usize,
+    ) -> Result<Flow, LpErr> {
+        for instr in body {
+            *steps += 1;
+            if *steps > max_steps {
+                return Err(LpErr::Interpret(
+                    t!("compiler.error.step_limit", n = max_steps).to_string(),
+                ));
+            }
+
+            let flow = match instr {
+                Instr::Const(n) => {
+                    stack.push(*n);
+                    Flow::Next
+                }
+                Instr::LocalGet(local) => {
+                    stack.push(locals[*local]);
+                    Flow::Next
+                }
+                Instr::LocalSet(local) => {
+                    locals[*local] = pop(stack);
+                    Flow::Next
+                }
+                Instr::Numeric(numeric) => {
+                    let b = pop(stack);
+                    let a = pop(stack);
+                    stack.push(numeric.apply(self.ty, a, b)?);
+                    Flow::Next
+                }
+                Instr::Eqz => {
+                    let a = pop(stack);
+                    stack.push((a == 0).into());
+                    Flow::Next
+                }
+                // comparisons already push 0 or 1
+                Instr::Extend => Flow::Next,
+                Instr::ExtendS(bits) => {
+                    let a = pop(stack);
+                    stack.push(if *bits == 8 {
+                        a as i8 as i64
+                    } else {
+                        a as i16 as i64
+                    });
+                    Flow::Next
+                }
+                Instr::Call(idx) => {
+                    let function = &self.functions[*idx];
+                    let args = stack.split_off(stack.len() - function.params);
+                    let result = self.call(function, args, steps, max_steps)?;
+                    stack.push(result);
+                    Flow::Next
+                }
+                Instr::If(then, otherwise) => {
+                    let body = if pop(stack) != 0 { then } else { otherwise };
+                    self.run(body, locals, stack, steps, max_steps)?.leave()
+                }
+                Instr::Block(body) => self.run(body, locals, stack, steps, max_steps)?.leave(),
+                Instr::Loop(body) => loop {
+                    match self.run(body, locals, stack, steps, max_steps)? {
+                        Flow::Branch(0) => continue,
+                        flow => break flow.leave(),
+                    }
+                },
+                Instr::Br(depth) => Flow::Branch(*depth),
+                Instr::BrIf(depth) => {
+                    if pop(stack) != 0 {
+                        Flow::Branch(*depth)
+                    } else {
+                        Flow::Next
+                    }
+                }
+            };
+            if let Flow::Branch(_) = flow {
+                return Ok(flow);
+            }
+        }
+        Ok(Flow::Next)
+    }
+}
+
+#[cfg(test)]
+fn pop(stack: &mut Vec<i64>) -> i64 {
+    stack.pop().expect("the code is well-typed")
+}
+
+#[cfg(test)]
+impl Flow {
+    /// Where execution continues after the block this flow left.
+    fn leave(self) -> Flow {
+        match self {
+            Flow::Next | Flow::Branch(0) => Flow::Next,
+            Flow::Branch(depth) => Flow::Branch(depth - 1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::arith::Overflow;
+    use crate::backends::Target;
+    use crate::compiler::{CompileOptions, Compiled, Compiler};
+    use crate::gui::InterpreterOptions;
+    use crate::interpreter::Interpreter;
+
+    #[test]
+    fn golden() {
+        let expected = r#"(module
+  (func $sq (param $n i64) (result i64)
+    local.get $n
+    local.get $n
+    i64.mul
+  )
+  ;; inputs: n, y
+  (func $main (export "main") (param $n i64) (param $y i64) (result i64)
+    (local $i i64)
+    i64.const 0
+    local.set $i
+    block
+      loop
+        local.get $i
+        local.get $n
+        i64.lt_u
+        i64.extend_i32_u
+        i64.eqz
+        br_if 1
+        local.get $i
+        local.get $y
+        call $sq
+        i64.add
+        local.set $i
+        br 0
+      end
+    end
+    local.get $i
+    i64.const 0
+    i64.ne
+    if (result i64)
+      local.get $i
+    else
+      i64.const 0
+      local.get $i
+      i64.sub
+    end
+  )
+)
+"#;
+        let options = CompileOptions {
+            target: Target::Wasm,
+            ..Default::default()
+        };
+        let hw = InterpreterOptions {
+            word_size: Width::W64,
+            signedness: Signedness::Unsigned,
+            ..Default::default()
+        };
+        let input =
+            "fn sq(n) = n * n; let i = 0; while i < n { i = i + sq(y); } if i then i else -i";
+        assert_eq!(
+            Compiler::with(options)
+                .with_interpreter(hw)
+                .emit(input)
+                .unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn evaluate_like_interpreter() {
+        let inputs = [
+            "x * 3 + y",
+            "(x << 3) | (x & 5) ^ ~y % 7 >> 1 >>> 2",
+            "(x > y) + (x >= y) * 2 + (x < y) * 4 + (x <= y) * 8 + (x == y) * 16 + (x != y) * 32",
+            "let i = 0; let s = 0; while i < x { let t = i * i; s = s + t; i = i + 1; } s - y",
+            "fn sq(n) = n * n; fn f(a, b) = if a > b then sq(a) / b else sq(b) % a; f(x, y) - f(y, x)",
+            "x * 0.75 - y / 2.5",
+            "-x * 100 * y",
+            "x / y + x % y * 100",
+        ];
+        for width in Width::ALL {
+            for signedness in [Signedness::Signed, Signedness::Unsigned] {
+                let hw = InterpreterOptions {
+                    word_size: width,
+                    signedness,
+                    max_steps: 100_000,
+                    ..Default::default()
+                };
+                for input in inputs {
+                    let compiler =
+                        || Compiler::with(CompileOptions::default()).with_interpreter(hw);
+                    let module = compiler().lower_to_wasm(input).unwrap();
+                    let Compiled { code, .. } = compiler().compile(input).unwrap();
+                    let mut args = vec![(5, 3), (7, 7), (2, 9), (100, 17)];
+                    if signedness == Signedness::Signed {
+                        args.push((-100, 7));
+                        args.push((-1i64 << (width.bits() - 1), -1));
+                    }
+                    for (x, y) in args {
+                        let expected = Interpreter::with_config(&hw)
+                            .load_instructions(code.clone())
+                            .with_variables(
+                                [("x".into(), x.to_string()), ("y".into(), y.to_string())].into(),
+                            )
+                            .ready()
+                            .run_to_end()
+                            .unwrap();
+                        let got = module
+                            .evaluate(&[("x".into(), x), ("y".into(), y)].into(), hw.max_steps);
+                        assert_eq!(
+                            got.unwrap(),
+                            expected,
+                            "`{input}` with {x}, {y}, {width:?} {signedness:?}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn traps() {
+        let module = Compiler::with(CompileOptions::default())
+            .lower_to_wasm("x / y")
+            .unwrap();
+        let run = |x, y| module.evaluate(&[("x".into(), x), ("y".into(), y)].into(), 100);
+        assert_eq!(run(7, 2).unwrap(), 3);
+        assert!(run(7, 0).is_err());
+        // the generated code doesn't get to `div_s` for this one
+        assert_eq!(run(i32::MIN.into(), -1).unwrap(), i64::from(i32::MIN));
+        assert!(module.evaluate(&[("x".into(), 1)].into(), 100).is_err());
+
+        let module = Compiler::with(CompileOptions::default())
+            .lower_to_wasm("while 1 { } 1")
+            .unwrap();
+        assert!(module.evaluate(&HashMap::new(), 100).is_err());
+
+        let hw = InterpreterOptions {
+            overflow: Overflow::Trapping,
+            ..Default::default()
+        };
+        assert!(matches!(
+            Compiler::with(CompileOptions::default())
+                .with_interpreter(hw)
+                .lower_to_wasm("x + 1"),
+            Err(LpErr::IR(_))
+        ));
+    }
+}
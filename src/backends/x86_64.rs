@@ -0,0 +1,543 @@
+//! Assembly for x86-64, in Intel syntax.
+//!
+//! Unlike the RISC-V backend, this translates the instructions generated for the interpreter one
+//! by one, so its registers and the values spilled to RAM are exactly the ones chosen for our
+//! computer: the fewer registers it has, the more values go to memory. The registers `a`, `b`, ...
+//! become `rbx`, `rsi`, ..., the RAM becomes slots in the stack frame of `main`, and the stack of
+//! the interpreter is the stack of the processor.
+//!
+//! The main program becomes the function `main`, which gets the input variables in the argument
+//! registers `rdi`, `rsi`, `rdx`, `rcx`, `r8` and `r9`, sorted by name, and returns its result in
+//! `rax`. Values with less than 64 bits are kept sign- or zero-extended to the whole register, and
+//! are cut down to their bits again after every operation.
+
+use std::collections::BTreeSet;
+
+use rust_i18n::t;
+
+use super::check_arith;
+use crate::arith::{Arith, Signedness, Width};
+use crate::assembly::Mnemonic;
+use crate::types::{Inst, LpErr, Reg};
+
+/// The registers standing in for the registers of our computer, with the names of their lower 32,
+/// 16 and 8 bits. `rax`, `rcx` and `rdx` are left out, since division and shifts need them for
+/// their operands.
+const REGS: [[&str; 4]; 11] = [
+    ["rbx", "ebx", "bx", "bl"],
+    ["rsi", "esi", "si", "sil"],
+    ["rdi", "edi", "di", "dil"],
+    ["r8", "r8d", "r8w", "r8b"],
+    ["r9", "r9d", "r9w", "r9b"],
+    ["r10", "r10d", "r10w", "r10b"],
+    ["r11", "r11d", "r11w", "r11b"],
+    ["r12", "r12d", "r12w", "r12b"],
+    ["r13", "r13d", "r13w", "r13b"],
+    ["r14", "r14d", "r14w", "r14b"],
+    ["r15", "r15d", "r15w", "r15b"],
+];
+/// Registers that `main` has to restore before returning.
+const CALLEE_SAVED: [&str; 5] = ["rbx", "r12", "r13", "r14", "r15"];
+const ARGS: [&str; 6] = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
+
+/// Number of registers available for the code of the interpreter.
+pub const NUM_REGISTERS: u8 = REGS.len() as u8;
+
+/// Translate `code`, which reads the input variables `variables` and uses `ram_cells` RAM cells.
+/// Divisions, shifts to the right and comparisons treat numbers as signed or unsigned depending on
+/// the signedness of `arith`. Results always wrap around, so the other overflow modes are rejected.
+pub fn emit(
+    code: &[Inst],
+    variables: &[String],
+    ram_cells: usize,
+    arith: Arith,
+) -> Result<String, LpErr> {
+    check_arith(arith, &Width::ALL)?;
+    let mut variables = variables.to_vec();
+    variables.sort();
+    if variables.len() > ARGS.len() {
+        return Err(LpErr::IR(
+            t!(
+                "backends.too_many_inputs",
+                n = variables.len(),
+                max = ARGS.len()
+            )
+            .to_string(),
+        ));
+    }
+    let mut regs = code
+        .iter()
+        .flat_map(|inst| inst.sources().into_iter().chain(inst.target()));
+    if let Some(reg) = regs.find(|r| r.0 >= NUM_REGISTERS.into()) {
+        return Err(LpErr::IR(
+            t!(
+                "compiler.error.no_such_register",
+                r = reg,
+                n = NUM_REGISTERS
+            )
+            .to_string(),
+        ));
+    }
+
+    let targets: BTreeSet<usize> = code
+        .iter()
+        .filter_map(|inst| match inst {
+            Inst::Jump(target) | Inst::JumpIfZero(_, target) | Inst::Call(target) => Some(*target),
+            _ => None,
+        })
+        .collect();
+
+    let names: Vec<String> = variables
+        .iter()
+        .zip(ARGS)
+        .map(|(name, reg)| format!("{name} in {reg}"))
+        .collect();
+    let mut out = String::from("    .intel_syntax noprefix\n    .text\n    .globl main\n\n");
+    out += &format!(
+        "# inputs: {}\n",
+        if names.is_empty() {
+            "none".to_string()
+        } else {
+            names.join(", ")
+        }
+    );
+    out += "main:\n";
+    for reg in CALLEE_SAVED {
+        out += &format!("    push {reg}\n");
+    }
+    // the RAM cells come first in the stack frame, followed by the input variables
+    out += "    push rbp\n    mov rbp, rsp\n";
+    out += &format!("    sub rsp, {}\n", 8 * (ram_cells + variables.len()));
+    for (i, reg) in ARGS.iter().take(variables.len()).enumerate() {
+        out += &format!(
+            "    mov QWORD PTR [rbp - {}], {reg}\n",
+            8 * (ram_cells + i + 1)
+        );
+    }
+
+    let mut x86 = Emitter {
+        out,
+        arith,
+        ram_cells,
+    };
+    for (addr, inst) in code.iter().enumerate() {
+        if targets.contains(&addr) {
+            x86.out += &format!(".L{addr}:\n");
+        }
+        x86.out += &format!("    # {}\n", Mnemonic(inst));
+        x86.inst(inst, &variables);
+    }
+
+    let mut out = x86.out;
+    out += "\n    .section .note.GNU-stack, \"\", @progbits\n";
+    Ok(out)
+}
+
+struct Emitter {
+    out: String,
+    arith: Arith,
+    ram_cells: usize,
+}
+
+fn reg(r: Reg) -> &'static str {
+    REGS[r.0 as usize][0]
+}
+
+impl Emitter {
+    fn emit(&mut self, inst: String) {
+        self.out += "    ";
+        self.out += &inst;
+        self.out += "\n";
+    }
+
+    fn inst(&mut self, inst: &Inst, variables: &[String]) {
+        let unsigned = self.arith.signedness == Signedness::Unsigned;
+        match inst {
+            // the operators compute `a op b` into `b`
+            Inst::Add(a, b) => self.emit(format!("add {}, {}", reg(*b), reg(*a))),
+            Inst::Sub(a, b) => {
+                self.emit(format!("neg {}", reg(*b)));
+                self.emit(format!("add {}, {}", reg(*b), reg(*a)));
+            }
+            Inst::Mul(a, b) => self.emit(format!("imul {}, {}", reg(*b), reg(*a))),
+            Inst::Div(a, b) => self.divide(*a, *b, "rax"),
+            Inst::Mod(a, b) => self.divide(*a, *b, "rdx"),
+            Inst::Shl(a, b) => self.shift("sal", *a, *b),
+            Inst::Shr(a, b) => self.shift(if unsigned { "shr" } else { "sar" }, *a, *b),
+            Inst::LogicalShr(a, b) => {
+                // the sign-extended bits must not be shifted in
+                self.emit(format!("mov rcx, {}", reg(*b)));
+                self.emit(format!("mov {}, {}", reg(*b), reg(*a)));
+                if !unsigned {
+                    self.extend(*b, Signedness::Unsigned);
+                }
+                self.shift_by_cl("shr", *b);
+            }
+            Inst::And(a, b) => self.emit(format!("and {}, {}", reg(*b), reg(*a))),
+            Inst::Or(a, b) => self.emit(format!("or {}, {}", reg(*b), reg(*a))),
+            Inst::Xor(a, b) => self.emit(format!("xor {}, {}", reg(*b), reg(*a))),
+            Inst::Not(r) => self.emit(format!("not {}", reg(*r))),
+            Inst::Eq(a, b) => self.compare("sete", *a, *b),
+            Inst::Ne(a, b) => self.compare("setne", *a, *b),
+            Inst::Lt(a, b) => self.compare(if unsigned { "setb" } else { "setl" }, *a, *b),
+            Inst::Le(a, b) => self.compare(if unsigned { "setbe" } else { "setle" }, *a, *b),
+            Inst::Gt(a, b) => self.compare(if unsigned { "seta" } else { "setg" }, *a, *b),
+            Inst::Ge(a, b) => self.compare(if unsigned { "setae" } else { "setge" }, *a, *b),
+            Inst::Jump(target) => self.emit(format!("jmp .L{target}")),
+            Inst::JumpIfZero(r, target) => {
+                self.emit(format!("test {0}, {0}", reg(*r)));
+                self.emit(format!("jz .L{target}"));
+            }
+            Inst::Store(n, r) => self.emit(format!("mov {}, {n}", reg(*r))),
+            Inst::Transfer(v, r) => {
+                let idx = variables
+                    .iter()
+                    .position(|name| name == v)
+                    .expect("all variables are known");
+                self.emit(format!(
+                    "mov {}, QWORD PTR [rbp - {}]",
+                    reg(*r),
+                    8 * (self.ram_cells + idx + 1)
+                ));
+            }
+            Inst::Copy(a, b) => self.emit(format!("mov {}, {}", reg(*b), reg(*a))),
+            Inst::Result(r) => {
+                self.emit(format!("mov rax, {}", reg(*r)));
+                // this also drops the return addresses of the functions we are in
+                self.emit("leave".to_string());
+                for saved in CALLEE_SAVED.iter().rev() {
+                    self.emit(format!("pop {saved}"));
+                }
+                self.emit("ret".to_string());
+            }
+            Inst::Call(target) => self.emit(format!("call .L{target}")),
+            Inst::Ret => self.emit("ret".to_string()),
+            Inst::Push(r) => self.emit(format!("push {}", reg(*r))),
+            Inst::Pop(r) => self.emit(format!("pop {}", reg(*r))),
+            Inst::Write(r, addr) => self.emit(format!(
+                "mov QWORD PTR [rbp - {}], {}",
+                8 * (addr + 1),
+                reg(*r)
+            )),
+            Inst::Load(addr, r) => self.emit(format!(
+                "mov {}, QWORD PTR [rbp - {}]",
+                reg(*r),
+                8 * (addr + 1)
+            )),
+        }
+
+        // results that don't fit into a word wrap around, and so do the arguments of `main`;
+        // remainders and shifts to the right always fit
+        match inst {
+            Inst::Add(_, r)
+            | Inst::Sub(_, r)
+            | Inst::Mul(_, r)
+            | Inst::Div(_, r)
+            | Inst::Shl(_, r)
+            | Inst::LogicalShr(_, r)
+            | Inst::Not(r)
+            | Inst::Transfer(_, r) => self.extend(*r, self.arith.signedness),
+            _ => (),
+        }
+    }
+
+    /// Cut the value in `r` down to the bits of a word, and extend it to 64 bits again as a signed
+    /// or unsigned number.
+    fn extend(&mut self, r: Reg, signedness: Signedness) {
+        let [wide, dword, word, byte] = REGS[r.0 as usize];
+        let narrow = match self.arith.width {
+            Width::W8 => byte,
+            Width::W16 => word,
+            Width::W32 => dword,
+            Width::W64 => return,
+        };
+        self.emit(match (self.arith.width, signedness) {
+            (Width::W32, Signedness::Signed) => format!("movsxd {wide}, {dword}"),
+            // writing the lower 32 bits clears the upper ones
+            (Width::W32, Signedness::Unsigned) => format!("mov {dword}, {dword}"),
+            (_, Signedness::Signed) => format!("movsx {wide}, {narrow}"),
+            (_, Signedness::Unsigned) => format!("movzx {wide}, {narrow}"),
+        });
+    }
+
+    /// Divide `a` by `b`, keeping the quotient or remainder, which end up in `result`, in `b`.
+    fn divide(&mut self, a: Reg, b: Reg, result: &str) {
+        self.emit(format!("mov rax, {}", reg(a)));
+        if self.arith.signedness == Signedness::Unsigned {
+            self.emit("xor edx, edx".to_string());
+            self.emit(format!("div {}", reg(b)));
+        } else if self.arith.width == Width::W64 {
+            // `MIN / -1` stops the processor instead of wrapping around, so dividing by -1 is
+            // done by negating. Narrower words can't overflow with 64 bits.
+            self.emit(format!("cmp {}, -1", reg(b)));
+            self.emit("jne 1f".to_string());
+            self.emit("neg rax".to_string());
+            self.emit("xor edx, edx".to_string());
+            self.emit("jmp 2f".to_string());
+            self.out += "1:\n";
+            self.emit("cqo".to_string());
+            self.emit(format!("idiv {}", reg(b)));
+            self.out += "2:\n";
+        } else {
+            self.emit("cqo".to_string());
+            self.emit(format!("idiv {}", reg(b)));
+        }
+        self.emit(format!("mov {}, {result}", reg(b)));
+    }
+
+    /// Shift `a` by `b` bits, storing the result in `b`.
+    fn shift(&mut self, shift: &str, a: Reg, b: Reg) {
+        self.emit(format!("mov rcx, {}", reg(b)));
+        self.emit(format!("mov {}, {}", reg(b), reg(a)));
+        self.shift_by_cl(shift, b);
+    }
+
+    /// Shift `r` by the number of bits in `cl`, of which only as many are used as the machine does.
+    fn shift_by_cl(&mut self, shift: &str, r: Reg) {
+        let bits = self.arith.width.bits();
+        if bits < 64 {
+            self.emit(format!("and ecx, {}", bits - 1));
+        }
+        self.emit(format!("{shift} {}, cl", reg(r)));
+    }
+
+    /// Compare `a` to `b`, storing 1 in `b` if `set` sets a byte for the result and 0 otherwise.
+    fn compare(&mut self, set: &str, a: Reg, b: Reg) {
+        self.emit(format!("cmp {}, {}", reg(a), reg(b)));
+        self.emit(format!("{set} al"));
+        self.emit(format!("movzx {}, al", reg(b)));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::process::Command;
+
+    use crate::arith::{Overflow, Signedness, Width};
+    use crate::backends::Target;
+    use crate::compiler::{CompileOptions, Compiled, Compiler};
+    use crate::gui::InterpreterOptions;
+    use crate::interpreter::Interpreter;
+    use crate::types::LpErr;
+
+    fn emit(input: &str, num_registers: u8) -> String {
+        let hw = InterpreterOptions {
+            num_registers,
+            ..Default::default()
+        };
+        emit_with(input, hw).unwrap()
+    }
+
+    fn emit_with(input: &str, hw: InterpreterOptions) -> Result<String, LpErr> {
+        let options = CompileOptions {
+            target: Target::X86_64,
+            do_shift_replacement: true,
+            ..Default::default()
+        };
+        Compiler::with(options).with_interpreter(hw).emit(input)
+    }
+
+    #[test]
+    fn golden() {
+        let expected = r#"    .intel_syntax noprefix
+    .text
+    .globl main
+
+# inputs: x in rdi, y in rsi
+main:
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    push rbp
+    mov rbp, rsp
+    sub rsp, 144
+    mov QWORD PTR [rbp - 136], rdi
+    mov QWORD PTR [rbp - 144], rsi
+    # in x, a
+    mov rbx, QWORD PTR [rbp - 136]
+    movsxd rbx, ebx
+    # push a
+    push rbx
+    # pop a
+    pop rbx
+    # call 7
+    call .L7
+    # in y, b
+    mov rsi, QWORD PTR [rbp - 144]
+    movsxd rsi, esi
+    # div a, b
+    mov rax, rbx
+    cqo
+    idiv rsi
+    mov rsi, rax
+    movsxd rsi, esi
+    # res b
+    mov rax, rsi
+    leave
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    ret
+.L7:
+    # mov a, b
+    mov rsi, rbx
+    # mul b, a
+    imul rbx, rsi
+    movsxd rbx, ebx
+    # ret
+    ret
+
+    .section .note.GNU-stack, "", @progbits
+"#;
+        assert_eq!(emit("fn sq(n) = n * n; sq(x) / y", 6), expected);
+    }
+
+    #[test]
+    fn spills() {
+        let input = "(x * 8 + y) * (x * 3 - y * 4) * (y * 16 + 1)";
+        let spilled = emit(input, 2);
+        assert!(
+            spilled.contains("mov QWORD PTR [rbp - 8], rbx"),
+            "{spilled}"
+        );
+        assert!(spilled.contains("sal rsi, cl"), "{spilled}");
+        assert!(
+            !spilled.contains("mov rdi") && !spilled.contains("r8"),
+            "{spilled}"
+        );
+
+        let code = emit(input, 11);
+        assert!(!code.contains("QWORD PTR [rbp - 8]"), "{code}");
+        // there are no more registers than on x86-64
+        assert_eq!(emit(input, 64), code);
+    }
+
+    #[test]
+    fn errors() {
+        let options = CompileOptions {
+            target: Target::X86_64,
+            ..Default::default()
+        };
+        assert!(
+            Compiler::with(options)
+                .emit("a + b + c + d + e + f + g")
+                .is_err()
+        );
+        assert!(
+            Compiler::with(options)
+                .emit("a + b + c + d + e + f")
+                .is_ok()
+        );
+
+        // results always wrap around
+        let hw = InterpreterOptions {
+            overflow: Overflow::Trapping,
+            ..Default::default()
+        };
+        assert!(matches!(
+            Compiler::with(options).with_interpreter(hw).emit("x + 1"),
+            Err(LpErr::IR(_))
+        ));
+    }
+
+    /// Assemble the programs with a C compiler and compare their results to the ones of the
+    /// interpreter, for all word sizes.
+    #[test]
+    #[ignore = "needs a C compiler for x86-64, run with `cargo test -- --ignored`"]
+    fn run_like_interpreter() {
+        let inputs = [
+            "x * 3 + y",
+            "(x << 3) | (x & 5) ^ ~y % 7 >> 1 >>> 2",
+            "(x > y) + (x >= y) * 2 + (x < y) * 4 + (x <= y) * 8 + (x == y) * 16 + (x != y) * 32",
+            "let i = 0; let s = 0; while i < x { s = s + i * i; i = i + 1; } s - y",
+            "fn sq(n) = n * n; fn f(a, b) = if a > b then sq(a) / b else sq(b) % a; f(x, y) - f(y, x)",
+            "x / y + x % y * 100",
+            "-x * 100 * y",
+        ];
+        let dir = std::env::temp_dir().join(format!("lndw-x86-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        for width in Width::ALL {
+            for signedness in [Signedness::Signed, Signedness::Unsigned] {
+                let mut args = vec![(5, 3), (7, 7), (2, 9), (100, 17)];
+                if signedness == Signedness::Signed {
+                    let min = -1i64 << (width.bits() - 1);
+                    args.extend([(-100, 7), (min, -1)]);
+                }
+
+                // each program is assembled from a file of its own, since they share labels
+                let mut files = vec![];
+                let mut main = String::from("#include <stdio.h>\nint main(void) {\n");
+                let mut expected = vec![];
+                for (k, input) in inputs.iter().enumerate() {
+                    for num_registers in [2, 6] {
+                        let hw = InterpreterOptions {
+                            num_registers,
+                            word_size: width,
+                            signedness,
+                            ..Default::default()
+                        };
+                        let function = format!("program_{k}_{num_registers}");
+                        let file = dir.join(format!("{width:?}_{signedness:?}_{function}.s"));
+                        let asm = emit_with(input, hw).unwrap().replace("main", &function);
+                        std::fs::write(&file, asm).unwrap();
+                        files.push(file);
+                        main = format!("long long {function}(long long, long long);\n{main}");
+
+                        let Compiled { code, .. } = Compiler::with(CompileOptions::default())
+                            .with_interpreter(hw)
+                            .compile(input)
+                            .unwrap();
+                        for (x, y) in &args {
+                            main +=
+                                &format!("    printf(\"%lld\\n\", {function}({x}LL, {y}LL));\n");
+                            let vars: HashMap<String, String> =
+                                [("x".into(), x.to_string()), ("y".into(), y.to_string())].into();
+                            let res = Interpreter::with_config(&hw)
+                                .load_instructions(code.clone())
+                                .with_variables(vars)
+                                .ready()
+                                .run_to_end()
+                                .unwrap();
+                            expected.push((
+                                format!("`{input}` with {x}, {y}, {width:?} {signedness:?}"),
+                                res,
+                            ));
+                        }
+                    }
+                }
+                main += "    return 0;\n}\n";
+
+                let driver = dir.join(format!("{width:?}_{signedness:?}.c"));
+                let binary = dir.join(format!("{width:?}_{signedness:?}"));
+                std::fs::write(&driver, main).unwrap();
+                let cc = Command::new("cc")
+                    .arg("-o")
+                    .arg(&binary)
+                    .arg(&driver)
+                    .args(&files)
+                    .output()
+                    .expect("no C compiler found");
+                assert!(
+                    cc.status.success(),
+                    "{}",
+                    String::from_utf8_lossy(&cc.stderr)
+                );
+
+                let output = Command::new(&binary).output().unwrap();
+                let stdout = String::from_utf8(output.stdout).unwrap();
+                let results: Vec<i64> = stdout.lines().map(|line| line.parse().unwrap()).collect();
+                assert_eq!(results.len(), expected.len());
+                for ((what, expected), result) in expected.into_iter().zip(results) {
+                    assert_eq!(result, expected, "{what}");
+                }
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,11 +1,17 @@
+use crate::assembly;
+use crate::backends::{self, Target};
+use crate::diagnostics::{Diagnostic, Diagnostics};
 use crate::gui::InterpreterOptions;
 use crate::parser;
 use crate::passes::{
-    CommonFactorElimination, ConstantFold, ShiftReplacement, run_cache_optimization,
+    CommonFactorElimination, ConstantFold, FixedPointLowering, RegisterAllocation,
+    ShiftReplacement, VIRTUAL_REGISTERS, allocate_registers, run_cache_optimization,
 };
+use crate::ssa;
 pub use crate::types::*;
 use rust_i18n::t;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
 use std::vec;
 
 #[derive(Copy, Clone, Default)]
@@ -14,6 +20,17 @@ pub struct CompileOptions {
     pub run_cache_optimization: bool,
     pub do_common_factor_elimination: bool,
     pub do_shift_replacement: bool,
+    pub register_allocation: RegisterAllocation,
+    /// Compute the operand that needs more registers first.
+    pub do_sethi_ullman: bool,
+    /// Generate the code via the SSA form instead of directly from the AST.
+    pub use_ssa_ir: bool,
+    /// Compute structurally equal subexpressions only once.
+    pub do_common_subexpression_elimination: bool,
+    /// Transfer each input variable only once and keep it in a register or in RAM afterwards.
+    pub load_inputs_once: bool,
+    /// The processor the program is compiled for by [`Compiler::emit`].
+    pub target: Target,
 }
 
 impl CompileOptions {
@@ -22,9 +39,28 @@ impl CompileOptions {
             || self.run_cache_optimization
             || self.do_common_factor_elimination
             || self.do_shift_replacement
+            || self.register_allocation != RegisterAllocation::RoundRobin
+            || self.do_sethi_ullman
+            || self.use_ssa_ir
+            || self.do_common_subexpression_elimination
+            || self.load_inputs_once
     }
 }
 
+/// A compiled program.
+#[derive(Debug)]
+pub struct Compiled {
+    pub code: Vec<Inst>,
+    /// Input variables read by the program.
+    pub variables: HashSet<String>,
+    /// Instructions whose result is a fixed-point number.
+    pub fixed_point: HashSet<InstAddr>,
+    /// Problems found while compiling.
+    pub diagnostics: Diagnostics,
+    /// Number of registers needed to compute the most demanding expression without spilling.
+    pub min_registers: usize,
+}
+
 pub struct Compiler {
     options: CompileOptions,
     hw: InterpreterOptions,
@@ -43,256 +79,2239 @@ impl Compiler {
         self
     }
 
-    pub fn compile(self, input: &str) -> Result<(Vec<Inst>, HashSet<String>), LpErr> {
-        let mut ast = parser::run_parser(input)?;
+    pub fn compile(self, input: &str) -> Result<Compiled, LpErr> {
+        let mut diagnostics = Diagnostics::default();
+        let program = self.run_ast_passes(input, &mut diagnostics)?;
+
+        let mut compiled = if self.options.use_ssa_ir {
+            self.generate_from_ssa(&program, diagnostics)?
+        } else {
+            self.generate_ir(&program, diagnostics)?
+        };
+
+        if self.options.run_cache_optimization {
+            let (code, new_addr) = run_cache_optimization(compiled.code);
+            compiled.code = code;
+            compiled.fixed_point = compiled
+                .fixed_point
+                .iter()
+                .map(|addr| new_addr[*addr])
+                .collect();
+        }
+
+        Ok(compiled)
+    }
+
+    /// The assembly of `input` for the target of the options, after the same passes on the AST as
+    /// when compiling it.
+    pub fn emit(self, input: &str) -> Result<String, LpErr> {
+        match self.options.target {
+            Target::Interpreter => self
+                .compile(input)
+                .map(|compiled| assembly::print(&compiled.code)),
+            Target::RiscV => {
+                let program = self.run_ast_passes(input, &mut Diagnostics::default())?;
+                backends::riscv::emit(&program, self.hw.arith())
+            }
+            Target::X86_64 => {
+                // the code for our computer is translated instruction by instruction, so it may
+                // only use as many registers as there are left over on x86-64
+                let hw = InterpreterOptions {
+                    num_registers: self.hw.num_registers.min(backends::x86_64::NUM_REGISTERS),
+                    ..self.hw
+                };
+                let compiled = Compiler { hw, ..self }.compile(input)?;
+                let variables: Vec<String> = compiled.variables.into_iter().collect();
+                backends::x86_64::emit(&compiled.code, &variables, hw.num_cachelines, hw.arith())
+            }
+            Target::Wasm => self.lower_to_wasm(input).map(|module| module.to_string()),
+            Target::C => {
+                let hw = self.hw;
+                let compiled = self.compile(input)?;
+                let variables: Vec<String> = compiled.variables.into_iter().collect();
+                backends::c::emit(&compiled.code, &variables, hw.num_cachelines, hw.arith())
+            }
+        }
+    }
+
+    /// The SSA form of `input`, after the same passes as when compiling it.
+    pub fn lower_to_ssa(&self, input: &str) -> Result<ssa::Program, LpErr> {
+        let program = self.run_ast_passes(input, &mut Diagnostics::default())?;
+        let mut ssa = ssa::lower(&program)?;
+        ssa.optimize(self.hw.arith(), &self.options);
+        Ok(ssa)
+    }
+
+    /// The WebAssembly module for `input`, after the same passes as when compiling it.
+    pub fn lower_to_wasm(&self, input: &str) -> Result<backends::wasm::Module, LpErr> {
+        let program = self.run_ast_passes(input, &mut Diagnostics::default())?;
+        backends::wasm::lower(&program, self.hw.arith())
+    }
+
+    /// Check the hardware options, parse `input` and run the enabled passes on the AST.
+    fn run_ast_passes(&self, input: &str, diagnostics: &mut Diagnostics) -> Result<Program, LpErr> {
+        self.hw.validate().map_err(LpErr::IR)?;
+        let mut program = parser::run_parser(input, self.hw.arith(), diagnostics)?
+            // not an optimization, the machine only knows integers
+            .lower_fixed_point(self.hw.arith())?;
         if self.options.do_constant_folding {
-            ast = ast.run_constant_fold(); // first time
+            program = program.run_constant_fold(self.hw.arith(), diagnostics); // first time
         }
 
         if self.options.do_common_factor_elimination {
-            ast = ast.extract_common_factors();
+            program = program.extract_common_factors();
         }
 
         if self.options.do_shift_replacement {
-            ast = ast.replace_multiplications_with_bitshifts();
+            program = program.replace_multiplications_with_bitshifts(self.hw.signedness);
         }
 
         if self.options.do_constant_folding {
-            ast = ast.run_constant_fold(); // second time
+            program = program.run_constant_fold(self.hw.arith(), diagnostics); // second time
         }
 
-        let (mut instructions, variables) = self.generate_ir(&ast)?;
+        Ok(program)
+    }
 
-        if self.options.run_cache_optimization {
-            instructions = run_cache_optimization(instructions);
+    /// Generate code by lowering `program` into SSA form, optimizing it there and selecting
+    /// instructions for it, whose registers are allocated afterwards.
+    fn generate_from_ssa(
+        &self,
+        program: &Program,
+        diagnostics: Diagnostics,
+    ) -> Result<Compiled, LpErr> {
+        let mut ssa = ssa::lower(program)?;
+        ssa.optimize(self.hw.arith(), &self.options);
+        if let Some(function) = ssa
+            .functions
+            .iter()
+            .find(|f| f.params > self.hw.num_registers as usize)
+        {
+            return Err(LpErr::IR(
+                t!("compiler.error.too_many_params", f = function.name).to_string(),
+            ));
         }
 
-        Ok((instructions, variables))
+        let selected = ssa::select(&ssa);
+        let entries: Vec<InstAddr> = selected.entries.iter().map(|(e, _)| *e).collect();
+        // allocating registers doesn't push anything, so the stack stays the same
+        let stack = stack_size(&selected.code, &entries);
+
+        // there is no code generation handing out registers in turn, so linear scan takes its place
+        let strategy = match self.options.register_allocation {
+            RegisterAllocation::RoundRobin => RegisterAllocation::LinearScan,
+            strategy => strategy,
+        };
+        let allocated = allocate_registers(
+            &selected.code,
+            &selected.entries,
+            self.hw.num_registers,
+            strategy,
+            0,
+        );
+        let Some(allocated) = allocated else {
+            let mut diagnostics = diagnostics;
+            diagnostics.push(Diagnostic::warning(
+                "alloc-fallback",
+                "diagnostics.alloc_fallback",
+            ));
+            let options = CompileOptions {
+                register_allocation: RegisterAllocation::RoundRobin,
+                use_ssa_ir: false,
+                ..self.options
+            };
+            return Compiler::with(options)
+                .with_interpreter(self.hw)
+                .generate_ir(program, diagnostics);
+        };
+
+        if allocated.spill_cells + stack > self.hw.num_cachelines {
+            return Err(LpErr::IR(
+                t!(
+                    "compiler.error.out_of_memory",
+                    needed = allocated.spill_cells + stack,
+                    available = self.hw.num_cachelines
+                )
+                .to_string(),
+            ));
+        }
+
+        Ok(Compiled {
+            code: allocated.code,
+            variables: selected.variables,
+            fixed_point: selected
+                .fixed_point
+                .iter()
+                .filter_map(|addr| allocated.new_addr[*addr])
+                .collect(),
+            diagnostics,
+            min_registers: program_register_need(program),
+        })
     }
 
-    fn create_write<'a>(
-        &self,
-        exp: &'a Expr,
-        ram_idx: &mut usize,
-        code: &mut Vec<Inst>,
-        mmap: &mut HashMap<&'a Expr, Location>,
-    ) {
-        if let Some(val) = mmap.get_mut(exp) {
-            if let Location::Reg(r) = val {
-                code.push(Inst::Write(u8tochar(*r), *ram_idx));
-                *val = Location::Ram(*ram_idx);
-                *ram_idx = (*ram_idx + 1) % self.hw.num_cachelines;
-                if *ram_idx == 0 {
-                    eprintln!("RAM overrun detected");
+    fn generate_ir(&self, program: &Program, diagnostics: Diagnostics) -> Result<Compiled, LpErr> {
+        let allocate = self.options.register_allocation != RegisterAllocation::RoundRobin;
+        let mut codegen = CodeGen::new(
+            self.hw,
+            self.options,
+            &program.functions,
+            diagnostics.clone(),
+        );
+        codegen.input_uses = count_inputs(program);
+        codegen.stmts(&program.stmts, Some(&program.result))?;
+
+        let result_reg = codegen.expr(&program.result)?;
+        codegen.code.push(Inst::Result(Reg::from(result_reg)));
+
+        // the functions are placed behind the main program, which ends with the result
+        let mut entries = Vec::with_capacity(program.functions.len());
+        for idx in 0..program.functions.len() {
+            entries.push(codegen.code.len());
+            codegen.function(idx)?;
+        }
+        for (pos, idx) in codegen.calls.drain(..) {
+            codegen.code[pos] = Inst::Call(entries[idx]);
+        }
+
+        let stack = stack_size(&codegen.code, &entries);
+
+        // the code was generated with more registers than there are, which are mapped to the real
+        // ones now
+        let mut ram_used = codegen.ram_used;
+        if allocate {
+            let entries: Vec<_> = entries
+                .iter()
+                .zip(&program.functions)
+                .map(|(e, f)| (*e, f.params.len()))
+                .collect();
+            let allocated = allocate_registers(
+                &codegen.code,
+                &entries,
+                self.hw.num_registers,
+                self.options.register_allocation,
+                codegen.ram_used,
+            );
+            let Some(allocated) = allocated else {
+                let mut diagnostics = diagnostics;
+                diagnostics.push(Diagnostic::warning(
+                    "alloc-fallback",
+                    "diagnostics.alloc_fallback",
+                ));
+                let options = CompileOptions {
+                    register_allocation: RegisterAllocation::RoundRobin,
+                    ..self.options
+                };
+                return Compiler::with(options)
+                    .with_interpreter(self.hw)
+                    .generate_ir(program, diagnostics);
+            };
+
+            codegen.code = allocated.code;
+            codegen.fixed_point = codegen
+                .fixed_point
+                .iter()
+                .filter_map(|addr| allocated.new_addr[*addr])
+                .collect();
+            ram_used += allocated.spill_cells;
+        }
+
+        // the stack grows down from the end of the RAM, so it must not reach the spilled values
+        if ram_used + stack > self.hw.num_cachelines {
+            return Err(LpErr::IR(
+                t!(
+                    "compiler.error.out_of_memory",
+                    needed = ram_used + stack,
+                    available = self.hw.num_cachelines
+                )
+                .to_string(),
+            ));
+        }
+
+        Ok(Compiled {
+            code: codegen.code,
+            variables: codegen.variables,
+            fixed_point: codegen.fixed_point,
+            diagnostics: codegen.diagnostics,
+            min_registers: program_register_need(program),
+        })
+    }
+}
+
+/// A node of the AST, compared by identity instead of structure: structurally equal subtrees
+/// (like the two `1`s in `1 + 1`) are still computed separately and live in separate places.
+#[derive(Clone, Copy, Debug)]
+struct Node<'a>(&'a Expr);
+
+impl PartialEq for Node<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self.0, other.0)
+    }
+}
+
+impl Eq for Node<'_> {}
+
+impl Hash for Node<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::ptr::hash(self.0, state)
+    }
+}
+
+/// A value that can occupy a register.
+#[derive(Clone, Copy, PartialEq)]
+enum Value<'a> {
+    /// The result of a (sub-)expression.
+    Expr(Node<'a>),
+    /// A named value, as index into [`CodeGen::bindings`].
+    Binding(usize),
+    /// An input variable, as index into [`CodeGen::inputs`].
+    Input(usize),
+}
+
+/// A value bound to a name by a `let` statement.
+struct Binding {
+    name: String,
+    loc: Location,
+    /// Number of uses that haven't been compiled yet.
+    uses_left: usize,
+    /// Number of loops around the binding.
+    depth: usize,
+}
+
+/// An input variable that was transferred before.
+struct Input {
+    name: String,
+    /// The register the value was last put into. It is still there if the register map says so.
+    reg: u8,
+    /// The RAM cell holding the value, once it had to leave its register.
+    home: Option<MemAddr>,
+    /// Number of uses that haven't been compiled yet.
+    uses_left: usize,
+}
+
+/// Hands out RAM cells for spilled values, reusing the cells of values that aren't needed anymore.
+#[derive(Clone, Default)]
+struct RamSlots {
+    /// Cells below `next` that were freed again.
+    free: BTreeSet<usize>,
+    /// All cells from here on are free.
+    next: usize,
+}
+
+impl RamSlots {
+    /// An allocator that only hands out cells from `start` on, leaving the ones below untouched.
+    fn starting_at(start: usize) -> Self {
+        Self {
+            free: BTreeSet::new(),
+            next: start,
+        }
+    }
+
+    /// The lowest free cell, which is now occupied.
+    fn alloc(&mut self) -> usize {
+        self.free.pop_first().unwrap_or_else(|| {
+            self.next += 1;
+            self.next - 1
+        })
+    }
+
+    /// Make the cell `addr` available again.
+    fn release(&mut self, addr: usize) {
+        self.free.insert(addr);
+    }
+}
+
+/// Where all values are stored at some point of the code generation.
+type Snapshot<'a> = (
+    HashMap<Node<'a>, Location>,
+    Vec<Location>,
+    RamSlots,
+    Vec<Option<MemAddr>>,
+);
+
+/// State of the code generation for a single program.
+struct CodeGen<'a> {
+    hw: InterpreterOptions,
+    options: CompileOptions,
+    next_reg: u8,
+    /// RAM cells for spilled values.
+    ram: RamSlots,
+    /// Number of RAM cells used for spilled values.
+    ram_used: usize,
+    code: Vec<Inst>,
+    variables: HashSet<String>,
+    /// Where the result of each (sub-)expression is stored.
+    mmap: HashMap<Node<'a>, Location>,
+    /// Which value each register currently holds.
+    rmap: HashMap<u8, Value<'a>>,
+    /// Results that were already used. They stay in their register until it is needed for
+    /// something else, but nobody loads them again.
+    consumed: HashSet<Node<'a>>,
+    /// All bindings in scope; later bindings shadow earlier ones with the same name.
+    bindings: Vec<Binding>,
+    /// How often each subexpression of the current expression occurs, without the parts of
+    /// repeated ones. Only counted for common subexpression elimination.
+    occurrences: HashMap<&'a Expr, usize>,
+    /// The binding holding the value of each repeated subexpression computed so far.
+    subexpressions: HashMap<&'a Expr, usize>,
+    /// The input variables transferred so far, if they are only transferred once.
+    inputs: Vec<Input>,
+    /// Number of uses of each input variable in the main program.
+    input_uses: HashMap<String, usize>,
+    /// Number of loops around the code currently generated.
+    loop_depth: usize,
+    /// All functions of the program.
+    functions: Vec<&'a Function>,
+    /// The function whose body is currently generated, if any.
+    current_function: Option<usize>,
+    /// Position of each call instruction, and the function it calls. The addresses of the
+    /// functions are only known once all code is generated.
+    calls: Vec<(InstAddr, usize)>,
+    /// Instructions whose result is a fixed-point number.
+    fixed_point: HashSet<InstAddr>,
+    diagnostics: Diagnostics,
+}
+
+impl<'a> CodeGen<'a> {
+    fn new(
+        hw: InterpreterOptions,
+        options: CompileOptions,
+        functions: &'a [Function],
+        diagnostics: Diagnostics,
+    ) -> Self {
+        Self {
+            hw,
+            options,
+            next_reg: 0,
+            ram: RamSlots::default(),
+            ram_used: 0,
+            code: vec![],
+            variables: HashSet::new(),
+            mmap: HashMap::new(),
+            rmap: HashMap::new(),
+            consumed: HashSet::new(),
+            bindings: vec![],
+            occurrences: HashMap::new(),
+            subexpressions: HashMap::new(),
+            inputs: vec![],
+            input_uses: HashMap::new(),
+            loop_depth: 0,
+            functions: functions.iter().collect(),
+            current_function: None,
+            calls: vec![],
+            fixed_point: HashSet::new(),
+            diagnostics,
+        }
+    }
+
+    /// Whether the code uses [`VIRTUAL_REGISTERS`] registers, which are allocated afterwards.
+    fn virtual_registers(&self) -> bool {
+        self.options.register_allocation != RegisterAllocation::RoundRobin
+    }
+
+    /// Number of registers the code is generated with.
+    fn num_registers(&self) -> u8 {
+        if self.virtual_registers() {
+            VIRTUAL_REGISTERS
+        } else {
+            self.hw.num_registers
+        }
+    }
+
+    fn create_write(&mut self, value: Value<'a>) {
+        let loc = match value {
+            // a value that was already used is dead, so its register is simply given up
+            Value::Expr(exp) if self.consumed.contains(&exp) => {
+                self.mmap.remove(&exp);
+                return;
+            }
+            Value::Expr(exp) => self.mmap.get_mut(&exp),
+            Value::Binding(idx) => self.bindings.get_mut(idx).map(|b| &mut b.loc),
+            Value::Input(idx) => {
+                // an input never changes, so once it is in RAM, it can stay there
+                let input = &mut self.inputs[idx];
+                if input.home.is_none() && input.uses_left > 0 {
+                    let addr = self.ram.alloc();
+                    self.code.push(Inst::Write(Reg::from(input.reg), addr));
+                    input.home = Some(addr);
+                    self.ram_used = self.ram_used.max(addr + 1);
                 }
+                return;
+            }
+        };
+
+        if let Some(val) = loc {
+            if let Location::Reg(r) = val {
+                // running out of RAM is reported once the whole program is generated
+                let addr = self.ram.alloc();
+                self.code.push(Inst::Write(Reg::from(*r), addr));
+                *val = Location::Ram(addr);
+                self.ram_used = self.ram_used.max(addr + 1);
             } else {
-                eprintln!("tried to push RAM to RAM??");
+                self.diagnostics.push(Diagnostic::error(
+                    "write-from-ram",
+                    "diagnostics.write_from_ram",
+                ));
             }
         } else {
-            eprintln!("tried to create write for non-existent expression?");
+            self.diagnostics.push(Diagnostic::error(
+                "write-unknown",
+                "diagnostics.write_unknown",
+            ));
         }
     }
 
-    fn create_load<'a>(
-        &self,
-        exp: &'a Expr,
-        target_reg: &mut u8,
-        code: &mut Vec<Inst>,
-        mmap: &mut HashMap<&'a Expr, Location>,
-    ) {
-        if let Some(val) = mmap.get_mut(exp) {
-            if let Location::Ram(r) = val {
-                code.push(Inst::Load(*r, u8tochar(*target_reg)));
-                *val = Location::Reg(*target_reg);
-                *target_reg = (*target_reg + 1) % self.hw.num_registers;
+    fn create_load(&mut self, exp: &'a Expr) {
+        if let Some(val) = self.mmap.get_mut(&Node(exp)) {
+            if let Location::Ram(r) = *val {
+                self.code.push(Inst::Load(r, Reg::from(self.next_reg)));
+                *val = Location::Reg(self.next_reg);
+                // the result of an expression is only used once, so this was its last load
+                self.ram.release(r);
+                self.next_reg = (self.next_reg + 1) % self.num_registers();
             } else {
-                eprintln!("tried to load register to register??");
+                self.diagnostics.push(Diagnostic::error(
+                    "load-from-reg",
+                    "diagnostics.load_from_reg",
+                ));
             }
         } else {
-            eprintln!("tried to create load for non-existent expression?");
+            self.diagnostics.push(Diagnostic::error(
+                "load-unknown",
+                "diagnostics.load_unknown",
+            ));
         }
     }
 
-    fn fetch_if_necessary<'a>(
-        &self,
-        cur_reg: &mut u8,
-        e: &'a Expr,
-        next_reg: &mut u8,
-        ram_idx: &mut usize,
-        code: &mut Vec<Inst>,
-        mmap: &mut HashMap<&'a Expr, Location>,
-        rmap: &mut HashMap<u8, &'a Expr>,
-    ) {
-        if *rmap.get(cur_reg).unwrap() != e {
+    /// Reserve the next register for `value` and (potentially) evict an existing entry to RAM.
+    fn reserve_reg(&mut self, value: Value<'a>) -> u8 {
+        let reg = self.next_reg;
+        if let Some(old) = self.rmap.insert(reg, value) {
+            self.create_write(old);
+        }
+        self.next_reg = (self.next_reg + 1) % self.num_registers();
+        reg
+    }
+
+    fn fetch_if_necessary(&mut self, cur_reg: &mut u8, e: &'a Expr) {
+        if self.rmap.get(cur_reg) != Some(&Value::Expr(Node(e))) {
             // the entry was evicted -> need a store (maybe) & load
-            rmap.entry(*next_reg)
-                .and_modify(|expr| {
-                    self.create_write(expr, ram_idx, code, mmap);
-                    *expr = e;
-                })
-                .or_insert(e);
+            if let Some(old) = self.rmap.insert(self.next_reg, Value::Expr(Node(e))) {
+                self.create_write(old);
+            }
 
-            *cur_reg = *next_reg;
-            self.create_load(e, next_reg, code, mmap);
+            *cur_reg = self.next_reg;
+            self.create_load(e);
         }
     }
 
-    fn ast_to_ir<'a>(
-        &self,
-        ast: &'a Expr,
-        next_reg: &mut u8,
-        ram_idx: &mut usize,
-        code: &mut Vec<Inst>,
-        variables: &mut HashSet<String>,
-        mmap: &mut HashMap<&'a Expr, Location>,
-        rmap: &mut HashMap<u8, &'a Expr>,
-    ) -> Result<u8, LpErr> {
-        match ast {
-            Expr::Num(n) => {
-                let reg = *next_reg;
-
-                // reserve a register for the result and (potentially) evict an existing entry to RAM.
-                rmap.entry(reg)
-                    .and_modify(|expr| {
-                        self.create_write(expr, ram_idx, code, mmap);
-                        *expr = ast;
-                    })
-                    .or_insert(ast);
-
-                code.push(Inst::Store(*n, u8tochar(reg)));
-                if mmap.contains_key(ast) {
-                    eprintln!(
-                        "Tried overwriting existing MMAP value -- duplicate expression {ast:?}"
-                    );
-                } else {
-                    mmap.insert(ast, Location::Reg(reg));
+    /// Make sure both operands of an operation are in registers, without one of them evicting the
+    /// other.
+    fn fetch_operands(
+        &mut self,
+        left_reg: &mut u8,
+        left: &'a Expr,
+        right_reg: &mut u8,
+        right: &'a Expr,
+    ) {
+        self.fetch_if_necessary(left_reg, left);
+        if self.next_reg == *left_reg && self.rmap.get(right_reg) != Some(&Value::Expr(Node(right)))
+        {
+            self.next_reg = (self.next_reg + 1) % self.num_registers();
+        }
+        self.fetch_if_necessary(right_reg, right);
+    }
+
+    /// Write all values held in registers to RAM and forget about the register contents.
+    ///
+    /// This is done before code branches, so that all paths start and end with the same (empty)
+    /// register contents and we always know where to find a value afterwards.
+    fn spill_all(&mut self) {
+        let mut occupied: Vec<_> = self.rmap.drain().collect();
+        occupied.sort_by_key(|(reg, _)| *reg);
+        for (_, value) in occupied {
+            self.create_write(value);
+        }
+    }
+
+    /// Where all values are stored, to be restored after generating code for a branch.
+    ///
+    /// The RAM cells are part of it, so that cells freed in one branch stay occupied in the other
+    /// one, and cells occupied in a branch are free again afterwards.
+    fn snapshot(&self) -> Snapshot<'a> {
+        (
+            self.mmap.clone(),
+            self.bindings.iter().map(|b| b.loc).collect(),
+            self.ram.clone(),
+            self.inputs.iter().map(|i| i.home).collect(),
+        )
+    }
+
+    /// Return to the state after [`Self::spill_all`], as recorded by [`Self::snapshot`].
+    fn restore(&mut self, (mmap, locs, ram, homes): &Snapshot<'a>) {
+        self.rmap.clear();
+        self.mmap = mmap.clone();
+        self.ram = ram.clone();
+        for (binding, loc) in self.bindings.iter_mut().zip(locs) {
+            binding.loc = *loc;
+        }
+        // inputs transferred since then might not have been transferred on the other path
+        for (i, input) in self.inputs.iter_mut().enumerate() {
+            input.home = homes.get(i).copied().flatten();
+        }
+    }
+
+    /// Remember the register holding the result of `ast`.
+    fn insert_result(&mut self, ast: &'a Expr, reg: u8) {
+        self.mmap.insert(Node(ast), Location::Reg(reg));
+    }
+
+    /// Generate code for a list of statements, followed by `result` if there is one.
+    fn stmts(&mut self, stmts: &'a [Stmt], result: Option<&'a Expr>) -> Result<(), LpErr> {
+        for (i, stmt) in stmts.iter().enumerate() {
+            match stmt {
+                Stmt::Let(name, value) => {
+                    let (mut uses, shadowed) = count_uses(name, &stmts[i + 1..]);
+                    if !shadowed && let Some(result) = result {
+                        uses += result.count_var(name);
+                    }
+                    self.bind(name, value, uses)?;
+                }
+                Stmt::Assign(name, value) => self.assign(name, value)?,
+                Stmt::While(cond, body) => self.while_loop(cond, body)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Generate the body of the function `idx`, ending with a return to the caller.
+    ///
+    /// Calling convention: the caller passes the arguments in the first registers, in order, and
+    /// the function leaves its result in the first register. All other registers may be
+    /// overwritten, so the caller has to save the ones it still needs.
+    fn function(&mut self, idx: usize) -> Result<(), LpErr> {
+        let function = self.functions[idx];
+        if function.params.len() > self.hw.num_registers as usize {
+            return Err(LpErr::IR(
+                t!("compiler.error.too_many_params", f = function.name).to_string(),
+            ));
+        }
+
+        self.current_function = Some(idx);
+        // the function can be called while any of the cells used so far are occupied
+        self.ram = RamSlots::starting_at(self.ram_used);
+        self.rmap.clear();
+        self.mmap.clear();
+        self.bindings.clear();
+        for (i, param) in function.params.iter().enumerate() {
+            let uses = function.body.count_var(param);
+            self.bindings.push(Binding {
+                name: param.clone(),
+                loc: Location::Reg(i as u8),
+                uses_left: uses,
+                depth: 0,
+            });
+            if uses > 0 {
+                self.rmap.insert(i as u8, Value::Binding(i));
+            }
+        }
+        self.next_reg = function.params.len() as u8 % self.num_registers();
+
+        let mut reg = self.expr(&function.body)?;
+        self.fetch_if_necessary(&mut reg, &function.body);
+        if reg != 0 {
+            self.code.push(Inst::Copy(Reg::from(reg), Reg(0)));
+        }
+        self.code.push(Inst::Ret);
+        Ok(())
+    }
+
+    /// Call the function `name` with `args`, for the expression `ast`. The result ends up in the
+    /// first register.
+    fn call(&mut self, name: &str, args: &'a [Expr], ast: &'a Expr) -> Result<u8, LpErr> {
+        // functions can only call functions defined before them, so there is no recursion
+        let visible = self.current_function.unwrap_or(self.functions.len());
+        let Some(idx) = self.functions[..visible]
+            .iter()
+            .rposition(|f| f.name == name)
+        else {
+            return Err(LpErr::IR(
+                t!("compiler.error.unknown_function", f = name).to_string(),
+            ));
+        };
+        let expected = self.functions[idx].params.len();
+        if args.len() != expected {
+            return Err(LpErr::IR(
+                t!(
+                    "compiler.error.arg_count",
+                    f = name,
+                    expected = expected,
+                    got = args.len()
+                )
+                .to_string(),
+            ));
+        }
+
+        // the result is returned in the first register, so nothing may be restored there
+        if let Some(old) = self.rmap.remove(&0) {
+            self.create_write(old);
+        }
+
+        // the function may overwrite any register, so save the occupied ones on the stack
+        let mut saved: Vec<u8> = self.rmap.keys().copied().collect();
+        saved.sort();
+        for reg in &saved {
+            self.code.push(Inst::Push(Reg::from(*reg)));
+        }
+        let snapshot = self.snapshot();
+        let live = self.rmap.clone();
+
+        // computing an argument might overwrite the previous ones, so they wait on the stack until
+        // all of them are known
+        for arg in args {
+            let mut reg = self.ast_to_ir(arg)?;
+            self.fetch_if_necessary(&mut reg, arg);
+            self.code.push(Inst::Push(Reg::from(reg)));
+            self.rmap.remove(&reg);
+        }
+        for i in (0..args.len()).rev() {
+            self.code.push(Inst::Pop(Reg::from(i as u8)));
+        }
+
+        self.calls.push((self.code.len(), idx));
+        self.code.push(Inst::Call(0));
+
+        for reg in saved.iter().rev() {
+            self.code.push(Inst::Pop(Reg::from(*reg)));
+        }
+        // the registers hold the same values as before the call again
+        self.restore(&snapshot);
+        self.rmap = live;
+        self.forget_subexpressions(snapshot.1.len());
+
+        self.rmap.insert(0, Value::Expr(Node(ast)));
+        self.insert_result(ast, 0);
+        Ok(0)
+    }
+
+    /// Compute the value of a `let` statement and keep it in its register for `uses` later uses.
+    fn bind(&mut self, name: &str, value: &'a Expr, uses: usize) -> Result<(), LpErr> {
+        let reg = self.expr(value)?;
+        // from now on, the register holds the binding instead of a temporary result
+        self.mmap.remove(&Node(value));
+        let idx = self.bindings.len();
+        self.bindings.push(Binding {
+            name: name.to_string(),
+            loc: Location::Reg(reg),
+            uses_left: uses,
+            depth: self.loop_depth,
+        });
+
+        if uses > 0 {
+            self.rmap.insert(reg, Value::Binding(idx));
+        } else {
+            // nobody needs the value, so the register is free to be overwritten.
+            self.rmap.remove(&reg);
+        }
+        Ok(())
+    }
+
+    /// Compute a new value for the binding `name`.
+    fn assign(&mut self, name: &str, value: &'a Expr) -> Result<(), LpErr> {
+        let Some(idx) = self.bindings.iter().rposition(|b| b.name == name) else {
+            return Err(LpErr::IR(
+                t!("compiler.error.assign_unbound", v = name).to_string(),
+            ));
+        };
+
+        let reg = self.expr(value)?;
+        self.mmap.remove(&Node(value));
+        // the old value isn't needed anymore
+        if let Location::Reg(old) = self.bindings[idx].loc
+            && self.rmap.get(&old) == Some(&Value::Binding(idx))
+        {
+            self.rmap.remove(&old);
+        }
+        // a binding from outside a loop has to return to its cell at the end of each iteration
+        if let Location::Ram(old) = self.bindings[idx].loc
+            && self.bindings[idx].depth == self.loop_depth
+        {
+            self.ram.release(old);
+        }
+        self.bindings[idx].loc = Location::Reg(reg);
+        self.rmap.insert(reg, Value::Binding(idx));
+        Ok(())
+    }
+
+    /// Generate a loop running `body` as long as `cond` is non-zero.
+    ///
+    /// All bindings are written to RAM before the loop, and each iteration has to leave them in the
+    /// same place again, so that the loop's code works no matter how often it ran before.
+    fn while_loop(&mut self, cond: &'a Expr, body: &'a [Stmt]) -> Result<(), LpErr> {
+        // between statements, only bindings and inputs are still needed
+        self.rmap
+            .retain(|_, value| matches!(value, Value::Binding(_) | Value::Input(_)));
+        self.spill_all();
+        let snapshot = self.snapshot();
+        let start = self.code.len();
+        self.loop_depth += 1;
+
+        let mut cond_reg = self.expr(cond)?;
+        self.fetch_if_necessary(&mut cond_reg, cond);
+        self.rmap.remove(&cond_reg);
+        self.mmap.remove(&Node(cond));
+        let exit = self.code.len();
+        self.code.push(Inst::JumpIfZero(Reg::from(cond_reg), 0));
+
+        let outer = self.bindings.len();
+        self.stmts(body, None)?;
+        // bindings of the body go out of scope
+        self.rmap
+            .retain(|_, value| !matches!(value, Value::Binding(idx) if *idx >= outer));
+        self.bindings.truncate(outer);
+
+        // move bindings that changed their location back to where they were before the loop.
+        // Bindings that were still in a register aren't used anymore, so they can be ignored.
+        for (idx, loc) in snapshot.1.iter().enumerate() {
+            let Location::Ram(home) = *loc else {
+                continue;
+            };
+            match self.bindings[idx].loc {
+                Location::Reg(r) => self.code.push(Inst::Write(Reg::from(r), home)),
+                Location::Ram(addr) if addr != home => {
+                    let reg = self.reserve_reg(Value::Binding(idx));
+                    self.rmap.remove(&reg);
+                    self.code.push(Inst::Load(addr, Reg::from(reg)));
+                    self.code.push(Inst::Write(Reg::from(reg), home));
+                }
+                Location::Ram(_) => (),
+            }
+        }
+
+        self.code.push(Inst::Jump(start));
+        self.code[exit] = Inst::JumpIfZero(Reg::from(cond_reg), self.code.len());
+        self.loop_depth -= 1;
+        self.restore(&snapshot);
+        Ok(())
+    }
+
+    /// Provide a register holding the value of the binding `idx`, used for the expression `ast`.
+    fn use_binding(&mut self, idx: usize, ast: &'a Expr) -> u8 {
+        self.bindings[idx].uses_left -= 1;
+        let last_use = self.bindings[idx].uses_left == 0;
+
+        // on the last use, the expression can simply take over the binding's register. Inside a
+        // loop, the "last" use runs again in the next iteration, unless the binding is part of the
+        // loop as well.
+        if let Location::Reg(r) = self.bindings[idx].loc
+            && last_use
+            && self.bindings[idx].depth == self.loop_depth
+        {
+            self.rmap.insert(r, Value::Expr(Node(ast)));
+            self.insert_result(ast, r);
+            return r;
+        }
+
+        let reg = self.reserve_reg(Value::Expr(Node(ast)));
+        // reserving the register might have evicted the binding itself, so check its location now.
+        match self.bindings[idx].loc {
+            Location::Reg(r) => self.code.push(Inst::Copy(Reg::from(r), Reg::from(reg))),
+            Location::Ram(addr) => {
+                self.code.push(Inst::Load(addr, Reg::from(reg)));
+                if last_use && self.bindings[idx].depth == self.loop_depth {
+                    self.ram.release(addr);
                 }
+            }
+        }
+        self.insert_result(ast, reg);
+        reg
+    }
 
-                *next_reg = (*next_reg + 1) % self.hw.num_registers;
+    /// Provide a register holding the input variable `name`, used for the expression `ast`. It is
+    /// only transferred the first time, and kept in a register or its home cell in RAM for later
+    /// uses.
+    fn use_input(&mut self, name: &str, ast: &'a Expr) -> u8 {
+        self.variables.insert(name.to_string());
+        let idx = match self.inputs.iter().position(|i| i.name == name) {
+            Some(idx) => idx,
+            None => {
+                self.inputs.push(Input {
+                    name: name.to_string(),
+                    reg: 0,
+                    home: None,
+                    uses_left: self.input_uses.get(name).copied().unwrap_or(0),
+                });
+                self.inputs.len() - 1
+            }
+        };
+        // uses that weren't counted, e.g. after shadowing in a loop, just keep it around longer
+        self.inputs[idx].uses_left = self.inputs[idx].uses_left.saturating_sub(1);
+        let last_use = self.inputs[idx].uses_left == 0;
+        let cached = |cg: &Self| {
+            let reg = cg.inputs[idx].reg;
+            (cg.rmap.get(&reg) == Some(&Value::Input(idx))).then_some(reg)
+        };
+
+        // like a binding, the last use takes over the register
+        if let Some(r) = cached(self)
+            && last_use
+        {
+            self.rmap.insert(r, Value::Expr(Node(ast)));
+            self.insert_result(ast, r);
+            return r;
+        }
+
+        if cached(self).is_none() && self.inputs[idx].home.is_none() && !last_use {
+            let reg = self.reserve_reg(Value::Input(idx));
+            self.code
+                .push(Inst::Transfer(name.to_string(), Reg::from(reg)));
+            self.inputs[idx].reg = reg;
+        }
+
+        // the copy shouldn't evict the input right before copying it
+        if cached(self) == Some(self.next_reg) {
+            self.next_reg = (self.next_reg + 1) % self.num_registers();
+        }
+        let reg = self.reserve_reg(Value::Expr(Node(ast)));
+        // reserving the register might have evicted the input itself, so check where it is now
+        match (cached(self), self.inputs[idx].home) {
+            (Some(r), _) => self.code.push(Inst::Copy(Reg::from(r), Reg::from(reg))),
+            (None, Some(addr)) => self.code.push(Inst::Load(addr, Reg::from(reg))),
+            (None, None) => self
+                .code
+                .push(Inst::Transfer(name.to_string(), Reg::from(reg))),
+        }
+        self.insert_result(ast, reg);
+        reg
+    }
+
+    /// Generate code for an expression that is not part of a bigger one.
+    fn expr(&mut self, ast: &'a Expr) -> Result<u8, LpErr> {
+        self.occurrences.clear();
+        if self.options.do_common_subexpression_elimination {
+            count_subexpressions(ast, &mut self.occurrences);
+        }
+
+        let outer = self.bindings.len();
+        let reg = self.ast_to_ir(ast)?;
+        // the last use already freed the cell
+        for binding in &self.bindings[outer..] {
+            if let Location::Ram(addr) = binding.loc
+                && binding.uses_left > 0
+            {
+                self.ram.release(addr);
+            }
+        }
+        self.forget_subexpressions(outer);
+        Ok(reg)
+    }
+
+    /// Forget the values of repeated subexpressions computed since there were `outer` bindings, e.g.
+    /// because the code computing them doesn't run on all paths.
+    fn forget_subexpressions(&mut self, outer: usize) {
+        self.rmap
+            .retain(|_, value| !matches!(value, Value::Binding(idx) if *idx >= outer));
+        self.bindings.truncate(outer);
+        self.subexpressions.retain(|_, idx| *idx < outer);
+    }
+
+    /// Provide a register holding the value of `ast`. A subexpression that occurs repeatedly is
+    /// only computed the first time and then kept like a binding without a name.
+    fn ast_to_ir(&mut self, ast: &'a Expr) -> Result<u8, LpErr> {
+        if let Some(idx) = self.subexpressions.get(ast) {
+            return Ok(self.use_binding(*idx, ast));
+        }
+
+        let reg = self.generate(ast)?;
+        let uses = self.occurrences.get(ast).copied().unwrap_or(0);
+        // numbers and variables are as cheap to get again as to copy
+        if uses < 2 || matches!(ast, Expr::Num(_) | Expr::Var(_)) {
+            return Ok(reg);
+        }
+
+        self.mmap.remove(&Node(ast));
+        let idx = self.bindings.len();
+        self.bindings.push(Binding {
+            // no variable can refer to it
+            name: String::new(),
+            loc: Location::Reg(reg),
+            uses_left: uses,
+            depth: self.loop_depth,
+        });
+        self.rmap.insert(reg, Value::Binding(idx));
+        self.subexpressions.insert(ast, idx);
+        Ok(self.use_binding(idx, ast))
+    }
+
+    fn generate(&mut self, ast: &'a Expr) -> Result<u8, LpErr> {
+        match ast {
+            Expr::Num(n) => {
+                let reg = self.reserve_reg(Value::Expr(Node(ast)));
+                self.code.push(Inst::Store(*n, Reg::from(reg)));
+                self.insert_result(ast, reg);
                 Ok(reg)
             }
             Expr::Var(v) => {
-                let reg = *next_reg; // TODO: avoid duplicate register mapping+transfer
-
-                // reserve a register for the result and (potentially) evict an existing entry to RAM.
-                rmap.entry(reg)
-                    .and_modify(|expr| {
-                        self.create_write(expr, ram_idx, code, mmap);
-                        *expr = ast;
-                    })
-                    .or_insert(ast);
-
-                code.push(Inst::Transfer(v.clone(), u8tochar(reg)));
-                if mmap.contains_key(ast) {
-                    eprintln!(
-                        "[warn] Tried overwriting existing MMAP value -- duplicate expression {ast:?}"
-                    );
-                } else {
-                    mmap.insert(ast, Location::Reg(reg));
+                if let Some(idx) = self.bindings.iter().rposition(|b| &b.name == v) {
+                    return Ok(self.use_binding(idx, ast));
+                }
+
+                if let Some(idx) = self.current_function {
+                    return Err(LpErr::IR(
+                        t!(
+                            "compiler.error.not_a_param",
+                            v = v,
+                            f = self.functions[idx].name
+                        )
+                        .to_string(),
+                    ));
+                }
+
+                if self.options.load_inputs_once {
+                    return Ok(self.use_input(v, ast));
                 }
 
-                variables.insert(v.clone());
-                *next_reg = (*next_reg + 1) % self.hw.num_registers;
+                let reg = self.reserve_reg(Value::Expr(Node(ast)));
+                self.code.push(Inst::Transfer(v.clone(), Reg::from(reg)));
+                self.insert_result(ast, reg);
+
+                self.variables.insert(v.clone());
                 Ok(reg)
             }
             Expr::UnaryOp(Operator::Sub, e) => {
-                // TODO: optimization potential -> do the right register first to avoid collisions
-                let mut left_reg = self.ast_to_ir(
-                    &Expr::Num(0),
-                    next_reg,
-                    ram_idx,
-                    code,
-                    variables,
-                    mmap,
-                    rmap,
-                )?;
-                let mut right_reg =
-                    self.ast_to_ir(e, next_reg, ram_idx, code, variables, mmap, rmap)?;
-
-                self.fetch_if_necessary(
-                    &mut left_reg,
-                    &Expr::Num(0),
-                    next_reg,
-                    ram_idx,
-                    code,
-                    mmap,
-                    rmap,
-                );
+                // the 0 only needs a register for itself, so computing the operand first avoids
+                // holding on to it meanwhile
+                let right_first = self.options.do_sethi_ullman && e.register_need() > 1;
+                let right_reg = if right_first {
+                    Some(self.ast_to_ir(e)?)
+                } else {
+                    None
+                };
 
-                self.fetch_if_necessary(&mut right_reg, e, next_reg, ram_idx, code, mmap, rmap);
+                // the 0 has no node of its own, so it is tracked as `ast` until the result replaces it
+                let mut left_reg = self.reserve_reg(Value::Expr(Node(ast)));
+                self.code.push(Inst::Store(0, Reg::from(left_reg)));
+                self.insert_result(ast, left_reg);
+                let mut right_reg = match right_reg {
+                    Some(reg) => reg,
+                    None => self.ast_to_ir(e)?,
+                };
+
+                self.fetch_operands(&mut left_reg, ast, &mut right_reg, e);
 
-                code.push(Inst::Sub(u8tochar(left_reg), u8tochar(right_reg)));
+                self.code
+                    .push(Inst::Sub(Reg::from(left_reg), Reg::from(right_reg)));
 
-                rmap.entry(right_reg).and_modify(|val| *val = ast);
+                self.rmap.remove(&left_reg);
+                self.rmap.insert(right_reg, Value::Expr(Node(ast)));
                 // forced insert here because register is more useful than a potential hit in RAM
-                mmap.insert(ast, Location::Reg(right_reg));
+                self.mmap.insert(Node(ast), Location::Reg(right_reg));
 
                 Ok(right_reg)
             }
+            Expr::UnaryOp(Operator::Not, e) => {
+                let mut reg = self.ast_to_ir(e)?;
+                self.fetch_if_necessary(&mut reg, e);
+
+                self.code.push(Inst::Not(Reg::from(reg)));
+
+                self.rmap.insert(reg, Value::Expr(Node(ast)));
+                self.mmap.insert(Node(ast), Location::Reg(reg));
+
+                Ok(reg)
+            }
             Expr::UnaryOp(op, _) => Err(LpErr::IR(
                 t!("compiler.error.invalid_unary", op = op).to_string(),
             )),
             Expr::BinaryOp(left, op, right) => {
-                let mut left_reg =
-                    self.ast_to_ir(left, next_reg, ram_idx, code, variables, mmap, rmap)?;
-                let mut right_reg =
-                    self.ast_to_ir(right, next_reg, ram_idx, code, variables, mmap, rmap)?;
-
-                self.fetch_if_necessary(&mut left_reg, left, next_reg, ram_idx, code, mmap, rmap);
-                self.fetch_if_necessary(&mut right_reg, right, next_reg, ram_idx, code, mmap, rmap);
-
-                let inst = match op {
-                    Operator::Add => Inst::Add(u8tochar(left_reg), u8tochar(right_reg)),
-                    Operator::Sub => Inst::Sub(u8tochar(left_reg), u8tochar(right_reg)),
-                    Operator::Mul => Inst::Mul(u8tochar(left_reg), u8tochar(right_reg)),
-                    Operator::Div => Inst::Div(u8tochar(left_reg), u8tochar(right_reg)),
-                    Operator::Shl => Inst::Shl(u8tochar(left_reg), u8tochar(right_reg)),
-                    Operator::Shr => Inst::Shr(u8tochar(left_reg), u8tochar(right_reg)),
+                // while computing the second operand, the first one occupies a register, so the
+                // operand that needs more registers goes first
+                let (mut left_reg, mut right_reg) = if self.options.do_sethi_ullman
+                    && right.register_need() > left.register_need()
+                {
+                    let right_reg = self.ast_to_ir(right)?;
+                    (self.ast_to_ir(left)?, right_reg)
+                } else {
+                    let left_reg = self.ast_to_ir(left)?;
+                    (left_reg, self.ast_to_ir(right)?)
+                };
+
+                self.fetch_operands(&mut left_reg, left, &mut right_reg, right);
+
+                let Some(inst) = Inst::binary(*op, Reg::from(left_reg), Reg::from(right_reg))
+                else {
+                    return Err(LpErr::IR(
+                        t!("compiler.error.invalid_binary", op = op).to_string(),
+                    ));
                 };
 
-                code.push(inst);
+                self.code.push(inst);
 
-                rmap.entry(right_reg).and_modify(|val| *val = ast);
+                self.consumed.insert(Node(left));
+                // with plenty of registers, it would take long until the register is reused, and
+                // the allocator would have to keep the dead value around until then
+                if self.virtual_registers() && left_reg != right_reg {
+                    self.rmap.remove(&left_reg);
+                }
+                self.rmap.insert(right_reg, Value::Expr(Node(ast)));
                 // forced insert here because register is more useful than a potential hit in RAM
-                mmap.insert(ast, Location::Reg(right_reg));
+                self.mmap.insert(Node(ast), Location::Reg(right_reg));
 
                 Ok(right_reg)
             }
+            Expr::If(cond, then, otherwise) => {
+                let mut cond_reg = self.ast_to_ir(cond)?;
+                self.fetch_if_necessary(&mut cond_reg, cond);
+                // the condition is consumed by the jump, so it doesn't need to be saved
+                self.rmap.remove(&cond_reg);
+                self.mmap.remove(&Node(cond));
+
+                self.spill_all();
+                let branch = self.code.len();
+                self.code.push(Inst::JumpIfZero(Reg::from(cond_reg), 0));
+                let snapshot = self.snapshot();
+
+                let mut result_reg = self.ast_to_ir(then)?;
+                self.fetch_if_necessary(&mut result_reg, then);
+                let jump_to_end = self.code.len();
+                self.code.push(Inst::Jump(0));
+
+                self.code[branch] = Inst::JumpIfZero(Reg::from(cond_reg), self.code.len());
+                self.restore(&snapshot);
+                self.forget_subexpressions(snapshot.1.len());
+                let mut else_reg = self.ast_to_ir(otherwise)?;
+                self.fetch_if_necessary(&mut else_reg, otherwise);
+                // both branches need to leave their result in the same register
+                if else_reg != result_reg {
+                    self.code
+                        .push(Inst::Copy(Reg::from(else_reg), Reg::from(result_reg)));
+                }
+
+                self.code[jump_to_end] = Inst::Jump(self.code.len());
+                self.restore(&snapshot);
+                self.forget_subexpressions(snapshot.1.len());
+                self.rmap.insert(result_reg, Value::Expr(Node(ast)));
+                self.mmap.insert(Node(ast), Location::Reg(result_reg));
+
+                Ok(result_reg)
+            }
+            Expr::Call(name, args) => self.call(name, args, ast),
+            Expr::FixedPoint(raw) => {
+                let start = self.code.len();
+                let mut reg = self.ast_to_ir(raw)?;
+                self.fetch_if_necessary(&mut reg, raw);
+
+                // if no code computed the value here, it was already marked where it was computed
+                if self.code.len() > start
+                    && self.code.last().and_then(Inst::target) == Some(Reg::from(reg))
+                {
+                    self.fixed_point.insert(self.code.len() - 1);
+                }
+
+                self.rmap.insert(reg, Value::Expr(Node(ast)));
+                self.mmap.insert(Node(ast), Location::Reg(reg));
+
+                Ok(reg)
+            }
+        }
+    }
+}
+
+/// Number of registers needed to compute the most demanding expression of `program` without spilling.
+fn program_register_need(program: &Program) -> usize {
+    fn stmts_need(stmts: &[Stmt]) -> usize {
+        stmts
+            .iter()
+            .map(|stmt| match stmt {
+                Stmt::Let(_, value) | Stmt::Assign(_, value) => value.register_need(),
+                Stmt::While(cond, body) => cond.register_need().max(stmts_need(body)),
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    program
+        .functions
+        .iter()
+        .map(|f| f.body.register_need())
+        .chain([stmts_need(&program.stmts), program.result.register_need()])
+        .max()
+        .unwrap_or(0)
+}
+
+/// Count how often each subexpression of `ast` occurs. Repeated subexpressions are only computed
+/// once, so their parts are only counted for the first occurrence.
+fn count_subexpressions<'a>(ast: &'a Expr, counts: &mut HashMap<&'a Expr, usize>) {
+    let count = counts.entry(ast).or_default();
+    *count += 1;
+    if *count > 1 {
+        return;
+    }
+
+    match ast {
+        Expr::Num(_) | Expr::Var(_) => (),
+        Expr::UnaryOp(_, e) | Expr::FixedPoint(e) => count_subexpressions(e, counts),
+        Expr::BinaryOp(l, _, r) => {
+            count_subexpressions(l, counts);
+            count_subexpressions(r, counts);
+        }
+        Expr::If(c, a, b) => {
+            count_subexpressions(c, counts);
+            count_subexpressions(a, counts);
+            count_subexpressions(b, counts);
+        }
+        Expr::Call(_, args) => args.iter().for_each(|a| count_subexpressions(a, counts)),
+    }
+}
+
+/// Count the uses of each input variable in the main program, until it is shadowed by a binding.
+fn count_inputs(program: &Program) -> HashMap<String, usize> {
+    fn vars(ast: &Expr, names: &mut HashSet<String>) {
+        match ast {
+            Expr::Num(_) => (),
+            Expr::Var(v) => {
+                names.insert(v.clone());
+            }
+            Expr::UnaryOp(_, e) | Expr::FixedPoint(e) => vars(e, names),
+            Expr::BinaryOp(l, _, r) => {
+                vars(l, names);
+                vars(r, names);
+            }
+            Expr::If(c, a, b) => [c, a, b].into_iter().for_each(|e| vars(e, names)),
+            Expr::Call(_, args) => args.iter().for_each(|a| vars(a, names)),
+        }
+    }
+    fn stmt_vars(stmts: &[Stmt], names: &mut HashSet<String>) {
+        for stmt in stmts {
+            match stmt {
+                Stmt::Let(_, value) | Stmt::Assign(_, value) => vars(value, names),
+                Stmt::While(cond, body) => {
+                    vars(cond, names);
+                    stmt_vars(body, names);
+                }
+            }
         }
     }
 
-    fn generate_ir(&self, ast: &Expr) -> Result<(Vec<Inst>, HashSet<String>), LpErr> {
-        let mut reg_counter = 0;
-        let mut ram_idx = 0;
-        let mut code: Vec<Inst> = vec![];
-        let mut variables = HashSet::new();
+    let mut names = HashSet::new();
+    stmt_vars(&program.stmts, &mut names);
+    vars(&program.result, &mut names);
+    names
+        .into_iter()
+        .map(|name| {
+            let (mut uses, shadowed) = count_uses(&name, &program.stmts);
+            if !shadowed {
+                uses += program.result.count_var(&name);
+            }
+            (name, uses)
+        })
+        .collect()
+}
 
-        let mut mmap = HashMap::new();
-        let mut rmap = HashMap::new();
+/// Count the uses of the binding `name` in the statements following it, until it is shadowed by
+/// another binding of the same name. Also returns whether it was shadowed.
+fn count_uses(name: &str, stmts: &[Stmt]) -> (usize, bool) {
+    let mut uses = 0;
+    for stmt in stmts {
+        match stmt {
+            Stmt::Let(other, value) => {
+                uses += value.count_var(name);
+                if other == name {
+                    return (uses, true);
+                }
+            }
+            Stmt::Assign(_, value) => uses += value.count_var(name),
+            // shadowing inside the body ends with the loop
+            Stmt::While(cond, body) => uses += cond.count_var(name) + count_uses(name, body).0,
+        }
+    }
+    (uses, false)
+}
 
-        let result_reg = self.ast_to_ir(
-            ast,
-            &mut reg_counter,
-            &mut ram_idx,
-            &mut code,
-            &mut variables,
-            &mut mmap,
-            &mut rmap,
-        )?;
-        code.push(Inst::Result(u8tochar(result_reg)));
-        Ok((code, variables))
+/// Maximum number of stack cells used by the main program, whose code ends where the first
+/// function starts at `entries[0]`.
+fn stack_size(code: &[Inst], entries: &[InstAddr]) -> usize {
+    // functions only call functions defined before them, so the stack size of each callee is
+    // already known when it is called
+    let mut sizes = Vec::with_capacity(entries.len());
+    for (i, start) in entries.iter().enumerate() {
+        let end = entries.get(i + 1).copied().unwrap_or(code.len());
+        sizes.push(frame_size(&code[*start..end], entries, &sizes));
     }
+
+    let main_end = entries.first().copied().unwrap_or(code.len());
+    frame_size(&code[..main_end], entries, &sizes)
 }
 
-pub fn u8tochar(reg: u8) -> char {
-    // Converts to base 36, i.e. [0..9, a, b, ..z]
-    // +10 means that it maps [0, 1, ..] to [a, b, ..].
-    assert!(reg <= 26, "max 26 registers supported (a..z)");
-    char::from_digit(reg as u32 + 10, 36).unwrap()
+/// Maximum number of stack cells used by `code` and the functions it calls, given the stack size
+/// of each function in `sizes`.
+fn frame_size(code: &[Inst], entries: &[InstAddr], sizes: &[usize]) -> usize {
+    let (mut cur, mut max) = (0, 0);
+    for inst in code {
+        match inst {
+            Inst::Push(_) => cur += 1,
+            Inst::Pop(_) => cur -= 1,
+            Inst::Call(target) => {
+                // the callee's stack starts after the return address
+                let callee = entries.iter().position(|e| e == target).unwrap();
+                max = max.max(cur + 1 + sizes[callee]);
+            }
+            _ => (),
+        }
+        max = max.max(cur);
+    }
+    max
 }
 
 /// Describes a memory address either as register or RAM address
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Location {
     Ram(MemAddr),
     Reg(u8),
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::arith::{Overflow, Signedness, Width};
+    use crate::diagnostics::Severity;
+    use crate::interpreter::Interpreter;
+
+    /// Compile and run `input` with the given options and input variables.
+    fn run(
+        input: &str,
+        options: CompileOptions,
+        hw: InterpreterOptions,
+        vars: &[(&str, i64)],
+    ) -> i64 {
+        let Compiled { code, .. } = Compiler::with(options)
+            .with_interpreter(hw)
+            .compile(input)
+            .unwrap_or_else(|e| panic!("`{input}` failed to compile: {e}"));
+
+        Interpreter::with_config(&hw)
+            .load_instructions(code)
+            .with_variables(
+                vars.iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            )
+            .ready()
+            .run_to_end()
+            .unwrap_or_else(|e| panic!("`{input}` failed to run: {e}"))
+    }
+
+    fn all_options() -> CompileOptions {
+        CompileOptions {
+            do_constant_folding: true,
+            run_cache_optimization: true,
+            do_common_factor_elimination: true,
+            do_shift_replacement: true,
+            register_allocation: RegisterAllocation::RoundRobin,
+            do_sethi_ullman: true,
+            use_ssa_ir: false,
+            do_common_subexpression_elimination: true,
+            load_inputs_once: true,
+            target: Target::Interpreter,
+        }
+    }
+
+    fn small_machine() -> InterpreterOptions {
+        InterpreterOptions {
+            num_registers: 3,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn let_bindings() {
+        let input = "let t = x * 3; t * t + t";
+        for options in [CompileOptions::default(), all_options()] {
+            for hw in [InterpreterOptions::default(), small_machine()] {
+                assert_eq!(run(input, options, hw, &[("x", 2)]), 42);
+            }
+        }
+    }
+
+    #[test]
+    fn let_shadowing() {
+        let input = "let x = 5; let y = x + a; let x = y * 2; x - y";
+        for options in [CompileOptions::default(), all_options()] {
+            for hw in [InterpreterOptions::default(), small_machine()] {
+                assert_eq!(run(input, options, hw, &[("a", 1)]), 6);
+            }
+        }
+    }
+
+    #[test]
+    fn bitwise_operators() {
+        let x = 13;
+        let expected = (x << 3) | ((x & 5) ^ ((!x % 7) >> 1));
+        for input in [
+            "(x << 3) | (x & 5) ^ ~x % 7 >> 1",
+            "(13 << 3) | (13 & 5) ^ ~13 % 7 >> 1",
+        ] {
+            for options in [CompileOptions::default(), all_options()] {
+                assert_eq!(run(input, options, small_machine(), &[("x", x)]), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn conditionals() {
+        let input =
+            "let t = x * 2; 1 + t * (if x < 3 then t + 1 else (x > 5 ? t - y : y)) + (x == y)";
+        let expected = |x: i64, y: i64| {
+            let t = x * 2;
+            1 + t
+                * (if x < 3 {
+                    t + 1
+                } else if x > 5 {
+                    t - y
+                } else {
+                    y
+                })
+                + i64::from(x == y)
+        };
+
+        for (x, y) in [(1, 1), (4, 2), (7, 7)] {
+            for options in [CompileOptions::default(), all_options()] {
+                for hw in [InterpreterOptions::default(), small_machine()] {
+                    assert_eq!(
+                        run(input, options, hw, &[("x", x), ("y", y)]),
+                        expected(x, y)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn loops() {
+        let input = "let f = 1; let i = n; while i > 1 { f = f * i; i = i - 1; } \
+                     let s = 0; repeat n { let t = s + f; repeat 2 { s = s + 1; } s = t % 1000; } s - i";
+        let expected = |n: i64| {
+            let f: i64 = (1..=n).product();
+            let mut s = 0;
+            for _ in 0..n {
+                s = (s + f) % 1000;
+            }
+            s - n.min(1)
+        };
+
+        for n in [0, 1, 5] {
+            for options in [CompileOptions::default(), all_options()] {
+                for hw in [InterpreterOptions::default(), small_machine()] {
+                    assert_eq!(run(input, options, hw, &[("n", n)]), expected(n), "n = {n}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn assignment_needs_binding() {
+        assert!(matches!(
+            Compiler::with(CompileOptions::default()).compile("x = 1; x"),
+            Err(LpErr::IR(_))
+        ));
+    }
+
+    #[test]
+    fn endless_loops_are_stopped() {
+        let hw = InterpreterOptions::default();
+        let Compiled { code, .. } = Compiler::with(all_options())
+            .compile("while 1 { } 1")
+            .unwrap();
+        let res = Interpreter::with_config(&hw)
+            .load_instructions(code)
+            .with_variables(HashMap::new())
+            .ready()
+            .run_to_end();
+
+        assert!(matches!(res, Err(LpErr::Interpret(_))));
+    }
+
+    #[test]
+    fn functions() {
+        // the calls need some room on the stack
+        let roomy = |hw: InterpreterOptions| InterpreterOptions {
+            num_cachelines: 64,
+            ..hw
+        };
+        let input = "fn sq(a) = a * a; fn dist(a, b) = a > b ? a - b : b - a; fn norm(a, b, c) = sq(a) + sq(b) + c; \
+                     let t = x + 1; let s = 0; repeat 3 { s = s + dist(t, y); } \
+                     t * norm(dist(x, sq(y)), t + 1, s) + sq(t - y)";
+        let expected = |x: i64, y: i64| {
+            let t = x + 1;
+            let s = 3 * (t - y).abs();
+            let norm = (x - y * y).pow(2) + (t + 1).pow(2) + s;
+            t * norm + (t - y).pow(2)
+        };
+
+        for (x, y) in [(1, 1), (4, 2), (-3, 5)] {
+            for options in [CompileOptions::default(), all_options()] {
+                for hw in [InterpreterOptions::default(), small_machine()].map(roomy) {
+                    assert_eq!(
+                        run(input, options, hw, &[("x", x), ("y", y)]),
+                        expected(x, y)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_calls() {
+        let inputs = [
+            "fn f(a) = a; g(1)",
+            "fn f(a) = f(a); f(1)",
+            "fn f(a) = g(a); fn g(a) = a; f(1)",
+            "fn f(a) = a; f(1, 2)",
+            "fn f(a) = a + x; f(1)",
+            "fn f(a, b, c, d) = a; f(1, 2, 3, 4)",
+            // the stack doesn't fit into the RAM next to the spilled values
+            "fn f(a) = a * a; fn g(a) = f(a) + f(a + 1); fn h(a) = g(a) * g(a + 1); \
+             fn i(a) = h(a) - h(a * 2); fn j(a) = i(a) + i(a + 3); fn k(a) = j(a) * j(a + 4); \
+             (x + 1) * (x + 2) * (x + 3) * (x + 4) * k(x)",
+        ];
+        for input in inputs {
+            let res = Compiler::with(CompileOptions::default())
+                .with_interpreter(small_machine())
+                .compile(input);
+            assert!(matches!(res, Err(LpErr::IR(_))), "`{input}` should fail");
+        }
+    }
+
+    #[test]
+    fn constant_bindings_are_propagated() {
+        let Compiled {
+            code,
+            variables: vars,
+            ..
+        } = Compiler::with(all_options())
+            .compile("let a = 2 * 3; let b = a + 1; b * x")
+            .unwrap();
+
+        assert_eq!(vars, HashSet::from(["x".to_string()]));
+        assert!(matches!(
+            code[..],
+            [
+                Inst::Store(7, _),
+                Inst::Transfer(..),
+                Inst::Mul(..),
+                Inst::Result(_)
+            ]
+        ));
+    }
+
+    #[test]
+    fn overflow_modes() {
+        // expression, value of x, wrapped result, saturated result
+        let cases = [
+            ("x + 1", i32::MAX.into(), i32::MIN.into(), i32::MAX.into()),
+            ("x * 4 - 1", 1 << 30, -1, i64::from(i32::MAX) - 1),
+            (
+                "0 - x - 2",
+                i32::MAX.into(),
+                i32::MAX.into(),
+                i32::MIN.into(),
+            ),
+        ];
+
+        for (expr, x, wrapped, saturated) in cases {
+            // with a constant x, the whole expression can be folded
+            let folded = format!("let x = {x}; {expr}");
+            for (input, vars) in [(expr, &[("x", x)][..]), (&folded, &[])] {
+                for options in [CompileOptions::default(), all_options()] {
+                    let hw = |overflow| InterpreterOptions {
+                        overflow,
+                        ..Default::default()
+                    };
+                    assert_eq!(
+                        run(input, options, hw(Overflow::Wrapping), vars),
+                        wrapped,
+                        "`{input}`"
+                    );
+                    assert_eq!(
+                        run(input, options, hw(Overflow::Saturating), vars),
+                        saturated,
+                        "`{input}`"
+                    );
+
+                    let Compiled { code, .. } = Compiler::with(options)
+                        .with_interpreter(hw(Overflow::Trapping))
+                        .compile(input)
+                        .unwrap();
+                    let res = Interpreter::with_config(&hw(Overflow::Trapping))
+                        .load_instructions(code)
+                        .with_variables(
+                            vars.iter()
+                                .map(|(k, v)| (k.to_string(), v.to_string()))
+                                .collect(),
+                        )
+                        .ready()
+                        .run_to_end();
+                    assert!(
+                        matches!(res, Err(LpErr::Interpret(_))),
+                        "`{input}` should trap"
+                    );
+                }
+            }
+        }
+
+        // the exact product of unsigned 64-bit numbers doesn't even fit into an `i128`
+        let max = u64::MAX;
+        for input in [format!("{max} * {max}"), format!("let x = {max}; x * x")] {
+            for options in [CompileOptions::default(), all_options()] {
+                let hw = |overflow| InterpreterOptions {
+                    overflow,
+                    word_size: Width::W64,
+                    signedness: Signedness::Unsigned,
+                    ..Default::default()
+                };
+                assert_eq!(run(&input, options, hw(Overflow::Wrapping), &[]), 1);
+                assert_eq!(
+                    run(&input, options, hw(Overflow::Saturating), &[]),
+                    max as i64
+                );
+
+                let compiled = Compiler::with(options)
+                    .with_interpreter(hw(Overflow::Trapping))
+                    .compile(&input);
+                let res = compiled.and_then(|Compiled { code, .. }| {
+                    Interpreter::with_config(&hw(Overflow::Trapping))
+                        .load_instructions(code)
+                        .with_variables(HashMap::new())
+                        .ready()
+                        .run_to_end()
+                });
+                assert!(res.is_err(), "`{input}` should trap");
+            }
+        }
+    }
+
+    #[test]
+    fn word_sizes() {
+        let hw = |word_size| InterpreterOptions {
+            word_size,
+            ..Default::default()
+        };
+
+        for options in [CompileOptions::default(), all_options()] {
+            for input in ["x + 1", "let x = 127; x + 1"] {
+                assert_eq!(run(input, options, hw(Width::W8), &[("x", 127)]), -128);
+                assert_eq!(run(input, options, hw(Width::W16), &[("x", 127)]), 128);
+            }
+            assert_eq!(
+                run(
+                    "x * 0x1_0000 * 0x1_0000",
+                    options,
+                    hw(Width::W32),
+                    &[("x", 1)]
+                ),
+                0
+            );
+            assert_eq!(
+                run(
+                    "x * 0x1_0000 * 0x1_0000",
+                    options,
+                    hw(Width::W64),
+                    &[("x", 1)]
+                ),
+                1 << 32
+            );
+        }
+
+        // inputs have to fit as well
+        let Compiled { code, .. } = Compiler::with(CompileOptions::default())
+            .with_interpreter(hw(Width::W8))
+            .compile("x")
+            .unwrap();
+        let res = Interpreter::with_config(&hw(Width::W8))
+            .load_instructions(code)
+            .with_variables(HashMap::from([("x".to_string(), "200".to_string())]))
+            .ready()
+            .run_to_end();
+        assert!(matches!(res, Err(LpErr::Interpret(_))));
+    }
+
+    #[test]
+    fn signedness() {
+        let unsigned = InterpreterOptions {
+            word_size: Width::W8,
+            signedness: Signedness::Unsigned,
+            ..Default::default()
+        };
+
+        for options in [CompileOptions::default(), all_options()] {
+            // a shift would round down to -2
+            assert_eq!(
+                run(
+                    "x / 2",
+                    options,
+                    InterpreterOptions::default(),
+                    &[("x", -3)]
+                ),
+                -1
+            );
+            assert_eq!(
+                run(
+                    "x >> 1",
+                    options,
+                    InterpreterOptions::default(),
+                    &[("x", -3)]
+                ),
+                -2
+            );
+            assert_eq!(
+                run(
+                    "x >>> 28",
+                    options,
+                    InterpreterOptions::default(),
+                    &[("x", -3)]
+                ),
+                15
+            );
+
+            assert_eq!(run("x / 4 + 100", options, unsigned, &[("x", 250)]), 162);
+            assert_eq!(run("x - 1 > 100", options, unsigned, &[("x", 0)]), 1);
+            assert_eq!(run("~(x + 2) / 2", options, unsigned, &[("x", 2)]), 125);
+            let unsigned = InterpreterOptions {
+                word_size: Width::W16,
+                ..unsigned
+            };
+            assert_eq!(run("~x", options, unsigned, &[("x", 4)]), 65531);
+        }
+    }
+
+    #[test]
+    fn fixed_point() {
+        let cases = [
+            ("1 / 3.0", "0.33333"),
+            ("1.5 * 2.5", "3.75"),
+            ("-x * 0.5 / 4", "-0.375"),
+            (
+                "let s = 0.0; let i = 0; while i < x { s = s + 0.5; i = i + 1; } s * i",
+                "4.5",
+            ),
+            ("fn half(a) = a * 0.5; half(x) + half(x + 1)", "3.5"),
+            ("x > 1 ? 0.25 : 2", "0.25"),
+            ("1.5 > x", "0"),
+            // small numbers aren't shifted out of the operands; 0.001 is stored as 66 / 2^16
+            ("x / 0.001", "2978.90909"),
+            ("0.5 * 0.001", "0.0005"),
+            ("0.001 / (x * 0.001)", "0.33333"),
+        ];
+
+        for (input, expected) in cases {
+            for options in [CompileOptions::default(), all_options()] {
+                // the small machine moves fixed-point numbers through the RAM and the stack
+                for hw in [InterpreterOptions::default(), small_machine()] {
+                    let hw = InterpreterOptions {
+                        num_cachelines: 64,
+                        ..hw
+                    };
+                    let Compiled {
+                        code, fixed_point, ..
+                    } = Compiler::with(options)
+                        .with_interpreter(hw)
+                        .compile(input)
+                        .unwrap_or_else(|e| panic!("`{input}` failed to compile: {e}"));
+                    let mut interpreter = Interpreter::with_config(&hw)
+                        .load_instructions(code)
+                        .with_fixed_point(fixed_point)
+                        .with_variables(HashMap::from([("x".to_string(), "3".to_string())]))
+                        .ready();
+                    let res = interpreter.run_to_end().unwrap();
+
+                    let res = if interpreter.is_fixed_point_result() {
+                        hw.arith().format_fixed_point(res)
+                    } else {
+                        res.to_string()
+                    };
+                    assert_eq!(res, expected, "`{input}`");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn diagnostics() {
+        let codes = |options, hw, input| {
+            let Compiled { diagnostics, .. } = Compiler::with(options)
+                .with_interpreter(hw)
+                .compile(input)
+                .unwrap_or_else(|e| panic!("`{input}` failed to compile: {e}"));
+            diagnostics
+                .iter()
+                .map(|d| (d.severity, d.code))
+                .collect::<Vec<_>>()
+        };
+        let hw = InterpreterOptions::default();
+
+        // the failing operations are only found when folding
+        assert_eq!(codes(CompileOptions::default(), hw, "x + 1 / 0"), []);
+        assert_eq!(
+            codes(all_options(), hw, "x + 1 / 0"),
+            [(Severity::Warning, "fold-divzero")]
+        );
+        let trapping = InterpreterOptions {
+            overflow: Overflow::Trapping,
+            ..hw
+        };
+        let folding = CompileOptions {
+            do_constant_folding: true,
+            ..Default::default()
+        };
+        assert_eq!(codes(folding, hw, "x + 0x7fff_ffff * 2"), []);
+        assert_eq!(
+            codes(folding, trapping, "x + 0x7fff_ffff * 2"),
+            [(Severity::Warning, "fold-overflow")]
+        );
+    }
+
+    #[test]
+    fn ram_slots() {
+        let input = "(x + 1) * (x + 2) + (x + 3) * (x + 4) + (x + 5) * (x + 6) + (x + 7) * (x + 8) \
+                     + (x > 2 ? (x + 9) * (x + 10) : (x + 11) * (x + 12))";
+        let expected = |x: i64| {
+            (1..=7)
+                .step_by(2)
+                .map(|i| (x + i) * (x + i + 1))
+                .sum::<i64>()
+                + if x > 2 {
+                    (x + 9) * (x + 10)
+                } else {
+                    (x + 11) * (x + 12)
+                }
+        };
+
+        let hw = |num_cachelines| InterpreterOptions {
+            num_cachelines,
+            ..small_machine()
+        };
+        for options in [CompileOptions::default(), all_options()] {
+            let compile = |num_cachelines| {
+                Compiler::with(options)
+                    .with_interpreter(hw(num_cachelines))
+                    .compile(input)
+            };
+            let needed = (1..).find(|n| compile(*n).is_ok()).unwrap();
+            let writes = compile(needed)
+                .unwrap()
+                .code
+                .iter()
+                .filter(|i| matches!(i, Inst::Write(..)))
+                .count();
+            // spilled values that aren't needed anymore make room for new ones
+            assert!(needed < writes, "{needed} cells for {writes} writes");
+            assert!(matches!(compile(needed - 1), Err(LpErr::IR(_))));
+
+            for x in [1, 5] {
+                assert_eq!(run(input, options, hw(needed), &[("x", x)]), expected(x));
+            }
+        }
+    }
+
+    #[test]
+    fn register_allocation() {
+        // the calls need some room on the stack
+        let roomy = |hw: InterpreterOptions| InterpreterOptions {
+            num_cachelines: 64,
+            ..hw
+        };
+        let inputs = [
+            "let t = x * 3; t * t + t",
+            "let x = 5; let y = x + a; let x = y * 2; x - y",
+            "(x << 3) | (x & 5) ^ ~x % 7 >> 1",
+            "let t = x * 2; 1 + t * (if x < 3 then t + 1 else (x > 5 ? t - a : a)) + (x == a)",
+            "let f = 1; let i = x; while i > 1 { f = f * i; i = i - 1; } \
+             let s = 0; repeat x { let t = s + f; repeat 2 { s = s + 1; } s = t % 1000; } s - i",
+            "fn sq(a) = a * a; fn dist(a, b) = a > b ? a - b : b - a; fn norm(a, b, c) = sq(a) + sq(b) + c; \
+             let t = x + 1; let s = 0; repeat 3 { s = s + dist(t, a); } \
+             t * norm(dist(x, sq(a)), t + 1, s) + sq(t - a)",
+            "let s = 0.0; let i = 0; while i < x { s = s + 0.5; i = i + 1; } s * i",
+        ];
+
+        for input in inputs {
+            for hw in [InterpreterOptions::default(), small_machine()].map(roomy) {
+                for (x, a) in [(1, 1), (4, 2), (7, -3)] {
+                    let vars = [("x", x), ("a", a)];
+                    let expected = run(input, CompileOptions::default(), hw, &vars);
+                    for register_allocation in [
+                        RegisterAllocation::LinearScan,
+                        RegisterAllocation::GraphColoring,
+                    ] {
+                        for options in [CompileOptions::default(), all_options()] {
+                            let options = CompileOptions {
+                                register_allocation,
+                                ..options
+                            };
+                            let Compiled {
+                                code, diagnostics, ..
+                            } = Compiler::with(options)
+                                .with_interpreter(hw)
+                                .compile(input)
+                                .unwrap();
+                            assert!(
+                                diagnostics.is_empty(),
+                                "`{input}` with {register_allocation:?}"
+                            );
+                            let regs: Vec<Reg> = (0..hw.num_registers).map(Reg::from).collect();
+                            assert!(
+                                code.iter()
+                                    .flat_map(|i| i.sources().into_iter().chain(i.target()))
+                                    .all(|r| regs.contains(&r)),
+                                "`{input}` with {register_allocation:?} uses too many registers"
+                            );
+                            assert_eq!(
+                                run(input, options, hw, &vars),
+                                expected,
+                                "`{input}` with {register_allocation:?}"
+                            );
+                        }
+                    }
+                }
+            }
+        }
+
+        // values that are needed at the same time stay in their registers
+        let input = "(a + b) * (c + d) - (a - b) * (c - d) + (a * c - b * d)";
+        let spills = |register_allocation| {
+            let options = CompileOptions {
+                register_allocation,
+                ..Default::default()
+            };
+            let hw = InterpreterOptions {
+                num_registers: 4,
+                ..Default::default()
+            };
+            let Compiled { code, .. } = Compiler::with(options)
+                .with_interpreter(hw)
+                .compile(input)
+                .unwrap();
+            code.iter()
+                .filter(|i| matches!(i, Inst::Write(..) | Inst::Load(..)))
+                .count()
+        };
+        let round_robin = spills(RegisterAllocation::RoundRobin);
+        assert!(spills(RegisterAllocation::LinearScan) < round_robin);
+        assert!(spills(RegisterAllocation::GraphColoring) < round_robin);
+    }
+
+    #[test]
+    fn common_subexpression_elimination() {
+        let cse = CompileOptions {
+            do_common_subexpression_elimination: true,
+            ..Default::default()
+        };
+        let count = |input: &str, options: CompileOptions, f: fn(&Inst) -> bool| {
+            let Compiled { code, .. } = Compiler::with(options).compile(input).unwrap();
+            code.iter().filter(|i| f(i)).count()
+        };
+        let is_mul = |i: &Inst| matches!(i, Inst::Mul(..));
+        let is_call = |i: &Inst| matches!(i, Inst::Call(_));
+        assert_eq!(
+            count("(a * b) + (a * b)", CompileOptions::default(), is_mul),
+            2
+        );
+        assert_eq!(count("(a * b) + (a * b)", cse, is_mul), 1);
+        assert_eq!(
+            count("(x * y + 1) * (x * y + 1) - (x * y + 1)", cse, is_mul),
+            2
+        );
+        assert_eq!(
+            count("fn sq(a) = a * a; sq(x) + sq(x) * sq(x + 1)", cse, is_call),
+            2
+        );
+        // only computed in one branch, so it has to be computed again afterwards
+        assert_eq!(count("(x > 1 ? x * y : 0) + x * y", cse, is_mul), 2);
+
+        let inputs = [
+            "(a * x) + (a * x)",
+            "(x * a + 1) * (x * a + 1) - (x * a + 1) / (x * a)",
+            "let t = x * a; (t - 1) * (t - 1) + (t - 1) * x * a",
+            "(x > 1 ? x * a : 0) + x * a + (x < 5 ? x * a : (x * a) * (x * a))",
+            "fn sq(a) = a * a + a * a; sq(x) + sq(x) * sq(a + x) - sq(x + a)",
+            "let s = 0; let i = 0; while i * a < x * a { s = s + (i + 1) * (i + 1); i = i + 1; } s",
+            "(x * 0.5 + a) * (x * 0.5 + a)",
+        ];
+        let roomy = |hw: InterpreterOptions| InterpreterOptions {
+            num_cachelines: 64,
+            ..hw
+        };
+        for input in inputs {
+            for hw in [InterpreterOptions::default(), small_machine()].map(roomy) {
+                for (x, a) in [(1, 1), (4, 2), (7, -3)] {
+                    let vars = [("x", x), ("a", a)];
+                    let expected = run(input, CompileOptions::default(), hw, &vars);
+                    for options in [
+                        cse,
+                        all_options(),
+                        CompileOptions {
+                            use_ssa_ir: true,
+                            ..all_options()
+                        },
+                    ] {
+                        assert_eq!(run(input, options, hw, &vars), expected, "`{input}`");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn load_inputs_once() {
+        let once = CompileOptions {
+            load_inputs_once: true,
+            ..Default::default()
+        };
+        let transfers = |input: &str, options: CompileOptions, hw: InterpreterOptions| {
+            let Compiled { code, .. } = Compiler::with(options)
+                .with_interpreter(hw)
+                .compile(input)
+                .unwrap();
+            code.iter()
+                .filter(|i| matches!(i, Inst::Transfer(..)))
+                .count()
+        };
+        let hw = InterpreterOptions::default();
+        assert_eq!(transfers("x * x + x", CompileOptions::default(), hw), 3);
+        assert_eq!(transfers("x * x + x", once, hw), 1);
+        assert_eq!(transfers("let t = x + 1; t * x - a * x", once, hw), 2);
+        // evicted inputs come back from RAM instead of being transferred again
+        assert_eq!(
+            transfers("x + (a * (a + (a * (a - x))))", once, small_machine()),
+            2
+        );
+        assert_eq!(
+            transfers(
+                "x * x + x",
+                CompileOptions {
+                    use_ssa_ir: true,
+                    ..once
+                },
+                hw
+            ),
+            1
+        );
+
+        let inputs = [
+            "x * x + x",
+            "x + (a * (a + (a * (a - x)))) - x * a",
+            "let t = x + 1; t * x - a * x",
+            "(x > 1 ? x * a : a) + x * (a > 0 ? x : 2)",
+            "let s = 0; let i = 0; while i < x { s = s + i * a + x; i = i + 1; } s + a",
+            "let x = x * 2; let s = 0; repeat a { s = s + x; let x = s; s = s + x; } s + x",
+            "fn f(a, b) = a * b + a; f(x, a) + x * f(a, x) - a",
+            "x * 0.5 + x * a",
+        ];
+        let roomy = |hw: InterpreterOptions| InterpreterOptions {
+            num_cachelines: 64,
+            ..hw
+        };
+        for input in inputs {
+            for hw in [InterpreterOptions::default(), small_machine()].map(roomy) {
+                for (x, a) in [(1, 1), (4, 2), (7, 3)] {
+                    let vars = [("x", x), ("a", a)];
+                    let expected = run(input, CompileOptions::default(), hw, &vars);
+                    for options in [
+                        once,
+                        all_options(),
+                        CompileOptions {
+                            use_ssa_ir: true,
+                            ..all_options()
+                        },
+                    ] {
+                        assert_eq!(run(input, options, hw, &vars), expected, "`{input}`");
+                    }
+                    assert!(
+                        transfers(input, once, hw)
+                            <= transfers(input, CompileOptions::default(), hw)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ssa_ir() {
+        let roomy = |hw: InterpreterOptions| InterpreterOptions {
+            num_cachelines: 64,
+            ..hw
+        };
+        let inputs = [
+            "let t = x * 3; t * t + t",
+            "let x = 5; let y = x + a; let x = y * 2; x - y",
+            "(x << 3) | (x & 5) ^ ~x % 7 >> 1 - -a",
+            "let t = x * 2; 1 + t * (if x < 3 then t + 1 else (x > 5 ? t - a : a)) + (x == a)",
+            "let f = 1; let i = x; while i > 1 { f = f * i; i = i - 1; } \
+             let s = 0; repeat x { let t = s + f; repeat 2 { s = s + 1; } s = t % 1000; } s - i",
+            // the phi functions of the loop swap their values
+            "let p = 1; let q = 2; repeat x { let t = p; p = q; q = t + a; } p * 10 + q",
+            "let s = 0; let i = 0; while i < x { s = s + (i > 2 ? i * a : 1); i = i + 1; } s",
+            "fn sq(a) = a * a; fn dist(a, b) = a > b ? a - b : b - a; fn norm(a, b, c) = sq(a) + sq(b) + c; \
+             let t = x + 1; let s = 0; repeat 3 { s = s + dist(t, a); } \
+             t * norm(dist(x, sq(a)), t + 1, s) + sq(t - a)",
+            "let s = 0.0; let i = 0; while i < x { s = s + 0.5; i = i + 1; } s * i",
+        ];
+
+        for input in inputs {
+            for hw in [InterpreterOptions::default(), small_machine()].map(roomy) {
+                for (x, a) in [(1, 1), (4, 2), (7, -3)] {
+                    let vars = [("x", x), ("a", a)];
+                    let expected = run(input, CompileOptions::default(), hw, &vars);
+                    for register_allocation in [
+                        RegisterAllocation::RoundRobin,
+                        RegisterAllocation::LinearScan,
+                        RegisterAllocation::GraphColoring,
+                    ] {
+                        for options in [CompileOptions::default(), all_options()] {
+                            let options = CompileOptions {
+                                register_allocation,
+                                use_ssa_ir: true,
+                                ..options
+                            };
+                            let Compiled {
+                                code, diagnostics, ..
+                            } = Compiler::with(options)
+                                .with_interpreter(hw)
+                                .compile(input)
+                                .unwrap();
+                            assert!(
+                                diagnostics.is_empty(),
+                                "`{input}` with {register_allocation:?}"
+                            );
+                            let regs: Vec<Reg> = (0..hw.num_registers).map(Reg::from).collect();
+                            assert!(
+                                code.iter()
+                                    .flat_map(|i| i.sources().into_iter().chain(i.target()))
+                                    .all(|r| regs.contains(&r)),
+                                "`{input}` with {register_allocation:?} uses too many registers"
+                            );
+                            assert_eq!(
+                                run(input, options, hw, &vars),
+                                expected,
+                                "`{input}` with {register_allocation:?}"
+                            );
+                        }
+                    }
+                }
+            }
+        }
+
+        // bindings that don't change in the loop need no phi function, and constants are folded
+        let options = CompileOptions {
+            do_constant_folding: true,
+            use_ssa_ir: true,
+            ..Default::default()
+        };
+        let ir = Compiler::with(options)
+            .lower_to_ssa("let c = 2; let i = 0; while i < x { i = i + c * 3; } i")
+            .unwrap()
+            .to_string();
+        assert_eq!(ir.matches('φ').count(), 1, "{ir}");
+        assert!(ir.contains(" = 6\n"), "{ir}");
+        assert!(!ir.contains('*'), "{ir}");
+    }
+
+    #[test]
+    fn sethi_ullman() {
+        let need = |input| {
+            Compiler::with(CompileOptions::default())
+                .compile(input)
+                .unwrap()
+                .min_registers
+        };
+        assert_eq!(need("x"), 1);
+        assert_eq!(need("(a + b) * (c + d)"), 3);
+        assert_eq!(need("x + y * (x - y * (x + y * (x - y)))"), 2);
+        assert_eq!(need("let t = (a + b) * (c + d); t + 1"), 3);
+
+        // computed from the left, every operand on the left waits in a register
+        let input = "x + y * (x - y * (x + y * (x - y)))";
+        let expected = 3 + 4 * (3 - 4 * (3 + 4 * (3 - 4)));
+        let hw = InterpreterOptions {
+            num_registers: 2,
+            ..Default::default()
+        };
+        let spills = |options: CompileOptions| {
+            let Compiled { code, .. } = Compiler::with(options)
+                .with_interpreter(hw)
+                .compile(input)
+                .unwrap();
+            code.iter()
+                .filter(|i| matches!(i, Inst::Write(..) | Inst::Load(..)))
+                .count()
+        };
+        for register_allocation in [
+            RegisterAllocation::RoundRobin,
+            RegisterAllocation::LinearScan,
+            RegisterAllocation::GraphColoring,
+        ] {
+            let options = CompileOptions {
+                register_allocation,
+                ..Default::default()
+            };
+            let ordered = CompileOptions {
+                do_sethi_ullman: true,
+                ..options
+            };
+            assert!(spills(ordered) < spills(options), "{register_allocation:?}");
+            if register_allocation != RegisterAllocation::RoundRobin {
+                assert_eq!(spills(ordered), 0, "{register_allocation:?}");
+            }
+            assert_eq!(run(input, ordered, hw, &[("x", 3), ("y", 4)]), expected);
+        }
+    }
+
+    #[test]
+    fn register_file() {
+        assert_eq!(Reg(0).to_string(), "a");
+        assert_eq!(Reg(25).to_string(), "z");
+        assert_eq!(Reg(26).to_string(), "r26");
+
+        // with a single register, both operands of `+` would have to be in it
+        for num_registers in [0, 1, MAX_REGISTERS + 1] {
+            let hw = InterpreterOptions {
+                num_registers,
+                ..Default::default()
+            };
+            assert!(
+                Compiler::with(CompileOptions::default())
+                    .with_interpreter(hw)
+                    .compile("1 + 2")
+                    .is_err()
+            );
+        }
+
+        // every operand on the left waits in a register, so the code needs more than `a..z`
+        let names: Vec<String> = (0..30u8)
+            .map(|i| format!("v{}{}", (b'a' + i / 26) as char, Reg::from(i % 26)))
+            .collect();
+        let input = names.join(" + (") + &")".repeat(names.len() - 1);
+        let vars: Vec<(&str, i64)> = names
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (n.as_str(), i as i64))
+            .collect();
+        let hw = InterpreterOptions {
+            num_registers: 40,
+            ..Default::default()
+        };
+        for options in [CompileOptions::default(), all_options()] {
+            assert_eq!(run(&input, options, hw, &vars), (0..30).sum::<i64>());
+        }
+        let Compiled { code, .. } = Compiler::with(CompileOptions::default())
+            .with_interpreter(hw)
+            .compile(&input)
+            .unwrap();
+        assert!(code.iter().filter_map(Inst::target).any(|r| r.0 >= 26));
+
+        // the interpreter only knows the registers of the machine it is configured with
+        let code = vec![Inst::Store(1, Reg(6)), Inst::Result(Reg(6))];
+        let mut interpreter = Interpreter::with_config(&InterpreterOptions::default())
+            .load_instructions(code)
+            .ready();
+        assert!(interpreter.run_to_end().is_err());
+    }
+}
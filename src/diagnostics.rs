@@ -0,0 +1,85 @@
+use rust_i18n::t;
+use std::ops::Range;
+
+/// How serious a diagnostic is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// The program works, but maybe not as expected.
+    Warning,
+    /// The generated code is most likely wrong.
+    Error,
+}
+
+/// A problem found while compiling that doesn't stop the compilation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Short identifier that stays the same across languages, e.g. `fold-divzero`.
+    pub code: &'static str,
+    /// Locale key of the message.
+    pub key: &'static str,
+    /// Values for the placeholders in the message.
+    pub args: Vec<(&'static str, String)>,
+    /// Where in the input the problem is, if known.
+    pub span: Option<Range<usize>>,
+}
+
+impl Diagnostic {
+    pub fn warning(code: &'static str, key: &'static str) -> Self {
+        Self::new(Severity::Warning, code, key)
+    }
+
+    pub fn error(code: &'static str, key: &'static str) -> Self {
+        Self::new(Severity::Error, code, key)
+    }
+
+    fn new(severity: Severity, code: &'static str, key: &'static str) -> Self {
+        Self {
+            severity,
+            code,
+            key,
+            args: vec![],
+            span: None,
+        }
+    }
+
+    /// Fill the placeholder `name` in the message with `value`.
+    pub fn arg(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.args.push((name, value.to_string()));
+        self
+    }
+
+    /// Point to the problem at `span` in the input.
+    pub fn span(mut self, span: Range<usize>) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    /// The message in the current language. It is only translated when shown, so that it follows
+    /// changes of the language.
+    pub fn message(&self) -> String {
+        let (names, values): (Vec<_>, Vec<_>) = self.args.iter().cloned().unzip();
+        rust_i18n::replace_patterns(&t!(self.key), &names, &values)
+    }
+}
+
+/// Collects the diagnostics of a compilation, in the order they are found.
+#[derive(Clone, Debug, Default)]
+pub struct Diagnostics(Vec<Diagnostic>);
+
+impl Diagnostics {
+    /// Add `diagnostic`, unless it was already reported, e.g. by a pass that runs twice.
+    pub fn push(&mut self, diagnostic: Diagnostic) {
+        if !self.0.contains(&diagnostic) {
+            self.0.push(diagnostic);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.0.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
@@ -0,0 +1,316 @@
+//! The binary encoding of the instructions, in which each one is a 64-bit word:
+//!
+//! ```text
+//! 63      58 57     52 51     46 45                                   0
+//! | opcode  | reg a   | reg b   | operand                              |
+//! ```
+//!
+//! The registers are the ones named first and second in the assembly syntax. The operand holds a
+//! number, the address of an instruction or RAM cell, or the position of an input variable in the
+//! table of variables stored next to the code.
+
+use std::ops::Range;
+
+use rust_i18n::t;
+
+use crate::types::{Inst, LpErr, MAX_REGISTERS, Reg};
+
+/// A part of an instruction word.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Field {
+    Opcode,
+    RegA,
+    RegB,
+    Operand,
+}
+
+impl Field {
+    pub const ALL: [Field; 4] = [Field::Opcode, Field::RegA, Field::RegB, Field::Operand];
+
+    /// Positions of the bits holding this field, counted from the least significant one.
+    pub fn bits(self) -> Range<u32> {
+        match self {
+            Field::Opcode => 58..64,
+            Field::RegA => 52..58,
+            Field::RegB => 46..52,
+            Field::Operand => 0..46,
+        }
+    }
+
+    /// The value of this field in `word`.
+    pub fn get(self, word: u64) -> u64 {
+        let bits = self.bits();
+        (word >> bits.start) & ((1 << bits.len()) - 1)
+    }
+}
+
+/// Encoded instructions, together with the names of the input variables they transfer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Encoded {
+    pub words: Vec<u64>,
+    pub variables: Vec<String>,
+}
+
+/// The fields of an instruction, before they are put together into a word.
+struct Parts {
+    opcode: u64,
+    a: Option<Reg>,
+    b: Option<Reg>,
+    operand: Option<i64>,
+}
+
+impl Parts {
+    fn new(opcode: u64) -> Self {
+        Parts {
+            opcode,
+            a: None,
+            b: None,
+            operand: None,
+        }
+    }
+
+    fn regs(opcode: u64, a: Reg, b: Reg) -> Self {
+        Parts {
+            a: Some(a),
+            b: Some(b),
+            ..Parts::new(opcode)
+        }
+    }
+
+    fn reg(opcode: u64, a: Reg) -> Self {
+        Parts {
+            a: Some(a),
+            ..Parts::new(opcode)
+        }
+    }
+
+    fn operand(opcode: u64, a: Option<Reg>, operand: i64) -> Self {
+        Parts {
+            a,
+            operand: Some(operand),
+            ..Parts::new(opcode)
+        }
+    }
+}
+
+/// Split `inst` into its fields, adding the variable it transfers to `variables` if needed.
+fn split(inst: &Inst, variables: &mut Vec<String>) -> Parts {
+    match inst {
+        Inst::Add(a, b) => Parts::regs(0, *a, *b),
+        Inst::Sub(a, b) => Parts::regs(1, *a, *b),
+        Inst::Mul(a, b) => Parts::regs(2, *a, *b),
+        Inst::Div(a, b) => Parts::regs(3, *a, *b),
+        Inst::Mod(a, b) => Parts::regs(4, *a, *b),
+        Inst::Shl(a, b) => Parts::regs(5, *a, *b),
+        Inst::Shr(a, b) => Parts::regs(6, *a, *b),
+        Inst::LogicalShr(a, b) => Parts::regs(7, *a, *b),
+        Inst::And(a, b) => Parts::regs(8, *a, *b),
+        Inst::Or(a, b) => Parts::regs(9, *a, *b),
+        Inst::Xor(a, b) => Parts::regs(10, *a, *b),
+        Inst::Not(r) => Parts::reg(11, *r),
+        Inst::Eq(a, b) => Parts::regs(12, *a, *b),
+        Inst::Ne(a, b) => Parts::regs(13, *a, *b),
+        Inst::Lt(a, b) => Parts::regs(14, *a, *b),
+        Inst::Le(a, b) => Parts::regs(15, *a, *b),
+        Inst::Gt(a, b) => Parts::regs(16, *a, *b),
+        Inst::Ge(a, b) => Parts::regs(17, *a, *b),
+        Inst::Jump(target) => Parts::operand(18, None, *target as i64),
+        Inst::JumpIfZero(r, target) => Parts::operand(19, Some(*r), *target as i64),
+        Inst::Store(n, r) => Parts::operand(20, Some(*r), *n),
+        Inst::Transfer(v, r) => {
+            let idx = variables
+                .iter()
+                .position(|name| name == v)
+                .unwrap_or_else(|| {
+                    variables.push(v.clone());
+                    variables.len() - 1
+                });
+            Parts::operand(21, Some(*r), idx as i64)
+        }
+        Inst::Copy(a, b) => Parts::regs(22, *a, *b),
+        Inst::Result(r) => Parts::reg(23, *r),
+        Inst::Call(target) => Parts::operand(24, None, *target as i64),
+        Inst::Ret => Parts::new(25),
+        Inst::Push(r) => Parts::reg(26, *r),
+        Inst::Pop(r) => Parts::reg(27, *r),
+        Inst::Write(r, addr) => Parts::operand(28, Some(*r), *addr as i64),
+        Inst::Load(addr, r) => Parts::operand(29, Some(*r), *addr as i64),
+    }
+}
+
+/// The fields `inst` makes use of.
+pub fn fields(inst: &Inst) -> Vec<Field> {
+    let parts = split(inst, &mut vec![]);
+    let used = [
+        true,
+        parts.a.is_some(),
+        parts.b.is_some(),
+        parts.operand.is_some(),
+    ];
+    Field::ALL
+        .into_iter()
+        .zip(used)
+        .filter(|(_, used)| *used)
+        .map(|(field, _)| field)
+        .collect()
+}
+
+/// Encode `code`, failing if a register or an operand doesn't fit into its field.
+pub fn encode(code: &[Inst]) -> Result<Encoded, LpErr> {
+    let operand_bits = Field::Operand.bits().len() as u32;
+    let operand_range = -(1i64 << (operand_bits - 1))..(1i64 << (operand_bits - 1));
+
+    let mut variables = vec![];
+    let mut words = Vec::with_capacity(code.len());
+    for inst in code {
+        let parts = split(inst, &mut variables);
+        let mut word = parts.opcode << Field::Opcode.bits().start;
+        for (field, reg) in [(Field::RegA, parts.a), (Field::RegB, parts.b)] {
+            let Some(reg) = reg else { continue };
+            if reg.0 >= MAX_REGISTERS.into() {
+                return Err(LpErr::IR(t!("encoding.register", r = reg).to_string()));
+            }
+            word |= u64::from(reg.0) << field.bits().start;
+        }
+        if let Some(operand) = parts.operand {
+            if !operand_range.contains(&operand) {
+                return Err(LpErr::IR(
+                    t!("encoding.operand", n = operand, bits = operand_bits).to_string(),
+                ));
+            }
+            word |= operand as u64 & ((1 << operand_bits) - 1);
+        }
+        words.push(word);
+    }
+    Ok(Encoded { words, variables })
+}
+
+/// Decode instructions encoded by [`encode`].
+#[cfg(test)]
+pub fn decode(encoded: &Encoded) -> Result<Vec<Inst>, LpErr> {
+    let operand_bits = Field::Operand.bits().len() as u32;
+
+    encoded
+        .words
+        .iter()
+        .map(|word| {
+            let a = Reg(Field::RegA.get(*word) as u32);
+            let b = Reg(Field::RegB.get(*word) as u32);
+            let unsigned = Field::Operand.get(*word);
+            // sign-extend the operand for numbers
+            let n = ((unsigned << (64 - operand_bits)) as i64) >> (64 - operand_bits);
+            let addr = unsigned as usize;
+
+            Ok(match Field::Opcode.get(*word) {
+                0 => Inst::Add(a, b),
+                1 => Inst::Sub(a, b),
+                2 => Inst::Mul(a, b),
+                3 => Inst::Div(a, b),
+                4 => Inst::Mod(a, b),
+                5 => Inst::Shl(a, b),
+                6 => Inst::Shr(a, b),
+                7 => Inst::LogicalShr(a, b),
+                8 => Inst::And(a, b),
+                9 => Inst::Or(a, b),
+                10 => Inst::Xor(a, b),
+                11 => Inst::Not(a),
+                12 => Inst::Eq(a, b),
+                13 => Inst::Ne(a, b),
+                14 => Inst::Lt(a, b),
+                15 => Inst::Le(a, b),
+                16 => Inst::Gt(a, b),
+                17 => Inst::Ge(a, b),
+                18 => Inst::Jump(addr),
+                19 => Inst::JumpIfZero(a, addr),
+                20 => Inst::Store(n, a),
+                21 => {
+                    let Some(name) = encoded.variables.get(addr) else {
+                        return Err(LpErr::IR(t!("encoding.variable", n = addr).to_string()));
+                    };
+                    Inst::Transfer(name.clone(), a)
+                }
+                22 => Inst::Copy(a, b),
+                23 => Inst::Result(a),
+                24 => Inst::Call(addr),
+                25 => Inst::Ret,
+                26 => Inst::Push(a),
+                27 => Inst::Pop(a),
+                28 => Inst::Write(a, addr),
+                29 => Inst::Load(addr, a),
+                opcode => return Err(LpErr::IR(t!("encoding.opcode", op = opcode).to_string())),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::{CompileOptions, Compiled, Compiler};
+
+    #[test]
+    fn round_trip() {
+        let inputs = [
+            "let t = x * 3; t * t + t",
+            "(x << 3) | (x & 5) ^ ~x % 7 >> 1 >>> 2",
+            "let i = 0; let s = 0; while i < x { s = s + i; i = i + 1; } s",
+            "fn sq(n) = n * n; sq(x) - sq(if x > 2 then 1 else x == 3)",
+            "-x * 0.5 / 4 + y",
+        ];
+        for input in inputs {
+            let Compiled { code, .. } = Compiler::with(CompileOptions::default())
+                .compile(input)
+                .unwrap();
+            let encoded = encode(&code).unwrap();
+            assert_eq!(encoded.words.len(), code.len());
+            assert_eq!(decode(&encoded).unwrap(), code, "{input}");
+        }
+
+        let code = vec![
+            Inst::Store(-(1 << 45), Reg(63)),
+            Inst::Store((1 << 45) - 1, Reg(0)),
+            Inst::Transfer("y".into(), Reg(1)),
+            Inst::Transfer("x".into(), Reg(2)),
+            Inst::Transfer("y".into(), Reg(3)),
+            Inst::Ge(Reg(4), Reg(5)),
+            Inst::Ret,
+        ];
+        let encoded = encode(&code).unwrap();
+        assert_eq!(encoded.variables, ["y", "x"]);
+        assert_eq!(decode(&encoded).unwrap(), code);
+    }
+
+    #[test]
+    fn layout() {
+        let encoded = encode(&[Inst::Load(3, Reg(2)), Inst::Copy(Reg(1), Reg(4))]).unwrap();
+        assert_eq!(
+            encoded.words,
+            [29 << 58 | 2 << 52 | 3, 22 << 58 | 1 << 52 | 4 << 46]
+        );
+        assert_eq!(
+            fields(&Inst::Load(3, Reg(2))),
+            [Field::Opcode, Field::RegA, Field::Operand]
+        );
+        assert_eq!(fields(&Inst::Ret), [Field::Opcode]);
+    }
+
+    #[test]
+    fn out_of_range() {
+        assert!(encode(&[Inst::Store(1 << 45, Reg(0))]).is_err());
+        assert!(encode(&[Inst::Copy(Reg(0), Reg(64))]).is_err());
+        assert!(
+            decode(&Encoded {
+                words: vec![63 << 58],
+                variables: vec![]
+            })
+            .is_err()
+        );
+        assert!(
+            decode(&Encoded {
+                words: vec![21 << 58],
+                variables: vec![]
+            })
+            .is_err()
+        );
+    }
+}
@@ -1,25 +1,79 @@
 use crate::{
-    compiler::{CompileOptions, Compiler, Inst, u8tochar},
+    arith::Arith,
+    assembly::{self, Mnemonic},
+    compiler::{CompileOptions, Compiled, Compiler, Inst, InstAddr, LpErr, Reg},
+    diagnostics::{Diagnostics, Severity},
+    encoding::{self, Encoded, Field},
     gui::InterpreterOptions,
     interpreter::Interpreter,
 };
-use eframe::egui::Id;
 use eframe::egui::{self, Widget};
+use eframe::egui::{Id, pos2, vec2};
 use rust_i18n::t;
 use std::collections::{HashMap, HashSet};
 
+/// Number system used to display the contents of registers and RAM.
+#[derive(Clone, Copy, Default, PartialEq)]
+enum Radix {
+    #[default]
+    Dec,
+    Hex,
+    Bin,
+}
+
+/// How the instructions are listed.
+#[derive(Clone, Copy, Default, PartialEq)]
+enum Listing {
+    /// Explained in words.
+    #[default]
+    Prose,
+    /// In the assembly syntax, which can be copied and read back.
+    Mnemonic,
+}
+
+impl Radix {
+    /// Format the number stored in `word`; hex and binary show all bits of the word, i.e. negative
+    /// numbers in two's complement and fixed-point numbers as the integer storing them.
+    fn format(self, word: i64, arith: Arith, fixed_point: bool) -> String {
+        let bits = arith.width.bits() as usize;
+        let unsigned = word as u64 & (u64::MAX >> (64 - bits));
+        match self {
+            Radix::Dec if fixed_point => arith.format_fixed_point(word),
+            Radix::Dec => arith.value(word).to_string(),
+            Radix::Hex => format!("{unsigned:#0w$x}", w = bits / 4 + 2),
+            Radix::Bin => format!("{unsigned:#0w$b}", w = bits + 2),
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct AssemblyOutput {
     heading: String,
     asm: Option<Vec<(Inst, f32)>>,
     error: Option<String>,
-    program_result: Option<i32>,
+    /// The result of the program, and whether it is a fixed-point number.
+    program_result: Option<(i64, bool)>,
+    /// Instructions whose result is a fixed-point number.
+    fixed_point: HashSet<InstAddr>,
+    diagnostics: Diagnostics,
+    /// Number of registers the program needs to get by without spilling.
+    min_registers: Option<usize>,
     interpreter: Option<Interpreter>,
     hw: Option<InterpreterOptions>,
     running: bool,
     stepwise: bool,
     step_triggered: bool,
+    /// Index of the instruction that is currently animated.
+    current: Option<usize>,
     total_time: f32,
+    radix: Radix,
+    listing: Listing,
+    /// Assembly being edited, which replaces the instructions once it is loaded.
+    edited: Option<String>,
+    load_error: Option<String>,
+    /// The instructions in their binary encoding, or why they can't be encoded.
+    encoded: Option<Result<Encoded, String>>,
+    show_machine_code: bool,
 }
 
 impl AssemblyOutput {
@@ -43,14 +97,33 @@ impl AssemblyOutput {
     /// Clear any assembly and error message.
     pub fn clear(&mut self) {
         self.asm = None;
+        self.encoded = None;
         self.error = None;
         self.program_result = None;
+        self.fixed_point.clear();
+        self.diagnostics = Diagnostics::default();
+        self.min_registers = None;
         self.running = false;
         self.total_time = 0.0;
         self.hw = None;
         self.interpreter = None;
         self.stepwise = false;
         self.step_triggered = false;
+        self.current = None;
+        self.edited = None;
+        self.load_error = None;
+    }
+
+    /// Replace the instructions with the ones written in the assembly syntax in `text`, to run a
+    /// program changed by hand. The hardware stays the same.
+    fn load(&mut self, text: &str) -> Result<(), LpErr> {
+        let code = assembly::parse(text)?;
+        let hw = self.hw;
+        self.clear();
+        self.hw = hw;
+        self.encoded = Some(encoding::encode(&code).map_err(|e| e.to_string()));
+        self.asm = Some(code.into_iter().map(|i| (i, 0.0)).collect());
+        Ok(())
     }
 
     pub fn instructions(&self) -> Vec<Inst> {
@@ -68,16 +141,28 @@ impl AssemblyOutput {
         input: &str,
         opts: CompileOptions,
         hw: InterpreterOptions,
-    ) -> Result<HashSet<String>, ()> {
+    ) -> Result<HashSet<String>, LpErr> {
         self.clear();
         let r = Compiler::with(opts).with_interpreter(hw).compile(input);
         self.hw = Some(hw);
 
-        r.map(|(asm, vars)| {
-            self.asm = Some(asm.iter().map(|i| (i.clone(), 0.0)).collect());
-            vars
-        })
-        .map_err(|e| {
+        r.map(
+            |Compiled {
+                 code,
+                 variables,
+                 fixed_point,
+                 diagnostics,
+                 min_registers,
+             }| {
+                self.asm = Some(code.iter().map(|i| (i.clone(), 0.0)).collect());
+                self.encoded = Some(encoding::encode(&code).map_err(|e| e.to_string()));
+                self.fixed_point = fixed_point;
+                self.diagnostics = diagnostics;
+                self.min_registers = Some(min_registers);
+                variables
+            },
+        )
+        .inspect_err(|e| {
             self.error = Some(format!("Compile error: {e}"));
         })
     }
@@ -87,6 +172,7 @@ impl AssemblyOutput {
         self.stepwise = stepwise;
         // fix for the step being falsely triggered
         self.step_triggered = false;
+        self.current = None;
 
         if self.asm.is_none() {
             return;
@@ -94,20 +180,21 @@ impl AssemblyOutput {
 
         let hw = self.hw.unwrap();
 
-        match Interpreter::with_config(&hw)
+        let mut interpreter = Interpreter::with_config(&hw)
             .load_instructions(self.instructions())
+            .with_fixed_point(self.fixed_point.clone())
             .with_variables(vars.to_owned())
-            .ready()
-            .run_to_end()
-        {
+            .ready();
+        match interpreter.run_to_end() {
             Ok(r) => {
-                self.program_result = Some(r);
+                self.program_result = Some((r, interpreter.is_fixed_point_result()));
                 self.running = true;
                 // don't overwrite the interpreter
                 if self.interpreter.is_none() {
                     self.interpreter = Some(
                         Interpreter::with_config(&hw)
                             .load_instructions(self.instructions())
+                            .with_fixed_point(self.fixed_point.clone())
                             .with_variables(vars.to_owned())
                             .with_tracing()
                             .ready(),
@@ -131,43 +218,131 @@ impl AssemblyOutput {
             return;
         }
 
+        if !self.diagnostics.is_empty() {
+            for diagnostic in self.diagnostics.iter() {
+                let (color, severity) = match diagnostic.severity {
+                    Severity::Warning => (ui.visuals().warn_fg_color, t!("diagnostics.warning")),
+                    Severity::Error => (ui.visuals().error_fg_color, t!("diagnostics.error")),
+                };
+                let position = diagnostic.span.as_ref().map_or(String::new(), |span| {
+                    format!(" {}..{}", span.start, span.end)
+                });
+                ui.colored_label(
+                    color,
+                    format!(
+                        "{severity} [{}]{position}: {}",
+                        diagnostic.code,
+                        diagnostic.message()
+                    ),
+                );
+            }
+            ui.separator();
+        }
+
+        // the register allocation decides how many values have to go through the RAM
+        let insts = self.asm.iter().flatten().map(|(inst, _)| inst);
+        let writes = insts
+            .clone()
+            .filter(|inst| matches!(inst, Inst::Write(..)))
+            .count();
+        let loads = insts
+            .clone()
+            .filter(|inst| matches!(inst, Inst::Load(..)))
+            .count();
+        let transfers = insts
+            .filter(|inst| matches!(inst, Inst::Transfer(..)))
+            .count();
+        ui.label(t!("output.spills", writes = writes, loads = loads));
+        ui.label(t!("output.transfers", n = transfers));
+        if let (Some(needed), Some(hw)) = (self.min_registers, self.hw) {
+            let text = t!(
+                "output.min_registers",
+                n = needed,
+                available = hw.num_registers
+            );
+            if needed > hw.num_registers as usize {
+                ui.colored_label(ui.visuals().warn_fg_color, text);
+            } else {
+                ui.label(text);
+            }
+        }
+
         self.step_triggered = self.step_triggered || !self.stepwise;
 
         // TODO: this mixed UI-interpreter logic is very bad practice, especially since we have a
         // TODO: great `Interpreter` struct that could handle this for us. We should decouple this.
         let asm = self.asm.as_mut().unwrap();
-        let mut done = false;
-        if self.running && self.step_triggered {
-            let curr_inst = asm.iter_mut().find(|(_, p)| p < &1.0);
-            if let Some((inst, progress)) = curr_inst {
-                if progress == &0.0 {
-                    // advance the interpreter
-                    let _ = self.interpreter.as_mut().unwrap().step();
-                }
-                let progress_increment = match inst {
-                    Inst::Add(_, _) => 0.03333,
-                    Inst::Sub(_, _) => 0.03333,
-                    Inst::Mul(_, _) => 0.01667,
-                    Inst::Div(_, _) => 0.00833,
-                    Inst::Shl(_, _) => 0.03333,
-                    Inst::Shr(_, _) => 0.03333,
-                    Inst::Store(_, _) => 0.0667,
-                    Inst::Transfer(_, _) => 0.0667,
-                    Inst::Result(_) => 0.0667,
-                    Inst::Write(_, _) => 0.0033,
-                    Inst::Load(_, _) => 0.0033,
-                };
-                *progress += progress_increment;
-                if *progress >= 1.0 {
-                    self.step_triggered = false;
+        if self.running && self.step_triggered && self.current.is_none() {
+            // start animating the next instruction, if there is one
+            if let Some(interpreter) = self.interpreter.as_mut().filter(|i| i.is_running()) {
+                let pc = interpreter.program_counter();
+                // advance the interpreter
+                let _ = interpreter.step();
+                if let Some((_, progress)) = asm.get_mut(pc) {
+                    // jumps can lead to instructions being executed repeatedly
+                    *progress = 0.0;
+                    self.current = Some(pc);
                 }
-                self.total_time += 0.016667; // 60 fps?
-            } else {
-                done = true;
             }
         }
 
+        if let Some(pc) = self.current {
+            let (inst, progress) = &mut asm[pc];
+            let progress_increment = match inst {
+                Inst::Add(_, _) => 0.03333,
+                Inst::Sub(_, _) => 0.03333,
+                Inst::Mul(_, _) => 0.01667,
+                Inst::Div(_, _) => 0.00833,
+                Inst::Mod(_, _) => 0.00833,
+                Inst::Shl(_, _) => 0.03333,
+                Inst::Shr(_, _) => 0.03333,
+                Inst::LogicalShr(_, _) => 0.03333,
+                Inst::And(_, _) => 0.03333,
+                Inst::Or(_, _) => 0.03333,
+                Inst::Xor(_, _) => 0.03333,
+                Inst::Not(_) => 0.03333,
+                Inst::Eq(_, _) => 0.03333,
+                Inst::Ne(_, _) => 0.03333,
+                Inst::Lt(_, _) => 0.03333,
+                Inst::Le(_, _) => 0.03333,
+                Inst::Gt(_, _) => 0.03333,
+                Inst::Ge(_, _) => 0.03333,
+                Inst::Jump(_) => 0.0667,
+                Inst::JumpIfZero(_, _) => 0.0667,
+                Inst::Store(_, _) => 0.0667,
+                Inst::Transfer(_, _) => 0.0667,
+                Inst::Copy(_, _) => 0.0667,
+                Inst::Result(_) => 0.0667,
+                Inst::Call(_) => 0.0033,
+                Inst::Ret => 0.0033,
+                Inst::Push(_) => 0.0033,
+                Inst::Pop(_) => 0.0033,
+                Inst::Write(_, _) => 0.0033,
+                Inst::Load(_, _) => 0.0033,
+            };
+            *progress += progress_increment;
+            if *progress >= 1.0 {
+                self.current = None;
+                self.step_triggered = false;
+            }
+            self.total_time += 0.016667; // 60 fps?
+        }
+
+        let done = self.running
+            && self.current.is_none()
+            && !self
+                .interpreter
+                .as_ref()
+                .is_some_and(Interpreter::is_running);
+
         ui.vertical_centered(|ui| {
+            ui.horizontal(|ui| {
+                ui.label(t!("output.radix"));
+                ui.selectable_value(&mut self.radix, Radix::Dec, t!("output.radix.dec"));
+                ui.selectable_value(&mut self.radix, Radix::Hex, t!("output.radix.hex"));
+                ui.selectable_value(&mut self.radix, Radix::Bin, t!("output.radix.bin"));
+            });
+
             // Table showing register contents, expands horizontally
             ui.heading(t!("output.registers"));
             egui::Grid::new("register_layout")
@@ -175,22 +350,26 @@ impl AssemblyOutput {
                 .spacing([5.0, 5.0])
                 .show(ui, |ui| {
                     let reg_count = self.hw.as_ref().unwrap().num_registers;
+                    let arith = self.hw.as_ref().unwrap().arith();
                     for num in 0..reg_count {
-                        let reg = u8tochar(num);
+                        let reg = Reg::from(num);
                         ui.label(format!("{reg}"));
                     }
                     ui.end_row();
                     for num in 0..reg_count {
-                        let reg = u8tochar(num);
-                        let mut txt = format!(
-                            "{}",
-                            self.interpreter
-                                .as_ref()
-                                .map_or(&0, |i| i.reg_store.get(&reg).unwrap_or(&0))
-                        );
+                        let reg = Reg::from(num);
+                        let (word, fixed_point) =
+                            self.interpreter.as_ref().map_or((0, false), |i| {
+                                (
+                                    *i.reg_store.get(&reg).unwrap_or(&0),
+                                    i.is_fixed_point_reg(&reg),
+                                )
+                            });
+                        let mut txt = self.radix.format(word, arith, fixed_point);
+                        let width = (txt.len() as f32 * 8.0).max(30.0);
                         egui::TextEdit::singleline(&mut txt)
                             .interactive(false)
-                            .desired_width(30.0)
+                            .desired_width(width)
                             .ui(ui);
                     }
                 });
@@ -205,7 +384,14 @@ impl AssemblyOutput {
 
                     // Some logic to not have to print all RAM cells if they're unused.
                     let ram_size = self.hw.as_ref().unwrap().num_cachelines;
-                    // Find out what is the highest-index nonzero ram cell
+                    let arith = self.hw.as_ref().unwrap().arith();
+                    // The stack grows down from the end of the RAM
+                    let stack_pointer = self
+                        .interpreter
+                        .as_ref()
+                        .map_or(ram_size, Interpreter::stack_pointer);
+                    // Find out what is the highest-index nonzero ram cell, the whole RAM is shown
+                    // while the stack is in use
                     let end = self.interpreter.as_ref().map_or(0, |i| {
                         i.ram
                             .iter()
@@ -214,12 +400,17 @@ impl AssemblyOutput {
                             .find_map(|(i, r)| if *r != 0 { Some(i) } else { None })
                             .unwrap_or(0)
                     });
+                    let end = if stack_pointer < ram_size {
+                        ram_size - 1
+                    } else {
+                        end
+                    };
 
                     // Print at least one more cell after the last nonzero one, but within [4, size]
                     let ram_size_display = (end + 1).max(4).min(ram_size);
 
                     egui::Grid::new("ram_layout")
-                        .num_columns(2)
+                        .num_columns(3)
                         .spacing([10.0, 5.0])
                         .show(ui, |ui| {
                             ui.label(t!("output.ram.cell"));
@@ -227,10 +418,20 @@ impl AssemblyOutput {
                             ui.end_row();
                             for num in 0..ram_size_display {
                                 ui.label(num.to_string());
-                                ui.label(format!(
-                                    "{}",
-                                    self.interpreter.as_ref().map_or(0, |i| i.ram[num])
-                                ));
+                                let (word, fixed_point) =
+                                    self.interpreter.as_ref().map_or((0, false), |i| {
+                                        (i.ram[num], i.is_fixed_point_ram(num))
+                                    });
+                                let content = self.radix.format(word, arith, fixed_point);
+                                if num >= stack_pointer {
+                                    ui.strong(content);
+                                } else {
+                                    ui.label(content);
+                                }
+                                if num == stack_pointer {
+                                    ui.weak(t!("output.ram.stack_top"))
+                                        .on_hover_text(t!("output.ram.stack_desc"));
+                                }
                                 ui.end_row();
                             }
                             if ram_size_display < ram_size {
@@ -308,28 +509,100 @@ impl AssemblyOutput {
 
         ui.separator();
 
+        ui.horizontal(|ui| {
+            ui.label(t!("output.listing"));
+            ui.selectable_value(
+                &mut self.listing,
+                Listing::Prose,
+                t!("output.listing.prose"),
+            );
+            ui.selectable_value(
+                &mut self.listing,
+                Listing::Mnemonic,
+                t!("output.listing.mnemonic"),
+            );
+            if self.listing == Listing::Mnemonic {
+                let code: Vec<Inst> = asm.iter().map(|(inst, _)| inst.clone()).collect();
+                if ui.button(t!("output.listing.copy")).clicked() {
+                    ui.ctx().copy_text(assembly::print(&code));
+                }
+                if ui.button(t!("output.listing.edit")).clicked() {
+                    self.edited = Some(assembly::print(&code));
+                }
+            }
+        });
+        let (mut load, mut cancel) = (None, false);
+        if let Some(text) = &mut self.edited {
+            ui.add(
+                egui::TextEdit::multiline(text)
+                    .code_editor()
+                    .desired_rows(8)
+                    .desired_width(f32::INFINITY),
+            );
+            ui.horizontal(|ui| {
+                if ui.button(t!("output.listing.load")).clicked() {
+                    load = Some(text.clone());
+                }
+                cancel = ui.button(t!("output.listing.cancel")).clicked();
+            });
+            if let Some(e) = &self.load_error {
+                ui.colored_label(ui.visuals().error_fg_color, e);
+            }
+        }
+        ui.checkbox(&mut self.show_machine_code, t!("output.machine_code"))
+            .on_hover_text(t!("output.machine_code.desc"));
+        let encoded = match &self.encoded {
+            Some(Ok(encoded)) if self.show_machine_code => Some(encoded),
+            Some(Err(e)) if self.show_machine_code => {
+                ui.colored_label(egui::Color32::RED, e);
+                None
+            }
+            _ => None,
+        };
+        if let Some(encoded) = encoded.filter(|e| !e.variables.is_empty()) {
+            let vars: Vec<String> = encoded
+                .variables
+                .iter()
+                .enumerate()
+                .map(|(i, v)| format!("{i} = {v}"))
+                .collect();
+            ui.label(t!("output.machine_code.variables", vars = vars.join(", ")));
+        }
+
         egui::ScrollArea::vertical()
             .max_height(ui.available_height() - 50.0)
             .show(ui, |ui| {
+                let mut rows = Vec::with_capacity(asm.len());
                 egui::Grid::new(self.heading.clone())
-                    .num_columns(2)
+                    .num_columns(if encoded.is_some() { 5 } else { 3 })
                     .spacing([10.0, 4.0])
                     .min_col_width(30.0)
                     .show(ui, |ui| {
-                        for (inst, progress) in asm {
+                        for (i, (inst, progress)) in asm.iter().enumerate() {
                             let bar = egui::ProgressBar::new(*progress)
                                 .animate(true)
                                 .desired_width(30.0)
                                 .desired_height(7.5);
-                            let v = progress > &mut 0.0;
+                            let v = progress > &0.0;
                             ui.add_visible(v, bar);
-                            let label = ui.label(format!("{inst}"));
+                            ui.weak(i.to_string());
+                            let label = match self.listing {
+                                Listing::Prose => ui.label(format!("{inst}")),
+                                Listing::Mnemonic => ui.monospace(Mnemonic(inst).to_string()),
+                            };
                             if *progress > 0.0 && *progress < 1.0 {
                                 label.scroll_to_me(None);
                             }
+                            rows.push(label.rect);
+                            if let Some(word) = encoded.map(|e| e.words[i]) {
+                                let executing = *progress > 0.0 && *progress < 1.0;
+                                ui.monospace(format!("{word:016x}"));
+                                ui.label(machine_code(ui, inst, word, executing));
+                            }
                             ui.end_row();
                         }
                     });
+                draw_jump_arrows(ui, asm, &rows);
             });
 
         if self.running {
@@ -338,8 +611,72 @@ impl AssemblyOutput {
         }
         if done {
             ui.separator();
-            ui.label(t!("output.result", res = self.program_result.unwrap()));
+            let arith = self.hw.unwrap().arith();
+            let res = match self.program_result.unwrap() {
+                (word, true) => arith.format_fixed_point(word),
+                (word, false) => arith.value(word).to_string(),
+            };
+            ui.label(t!("output.result", res = res));
+        }
+
+        if cancel {
+            self.edited = None;
+            self.load_error = None;
         }
+        if let Some(text) = load {
+            self.load_error = self.load(&text).err().map(|e| e.to_string());
+        }
+    }
+}
+
+/// The bits of the instruction word `word` encoding `inst`, grouped by field. The fields the
+/// instruction uses are colored while it is executed.
+fn machine_code(ui: &egui::Ui, inst: &Inst, word: u64, executing: bool) -> egui::text::LayoutJob {
+    let used = encoding::fields(inst);
+    let mut job = egui::text::LayoutJob::default();
+    for field in Field::ALL {
+        let color = match field {
+            _ if !used.contains(&field) => ui.visuals().weak_text_color(),
+            _ if !executing => ui.visuals().text_color(),
+            Field::Opcode => egui::Color32::from_rgb(230, 120, 60),
+            Field::RegA => egui::Color32::from_rgb(80, 160, 230),
+            Field::RegB => egui::Color32::from_rgb(80, 180, 120),
+            Field::Operand => egui::Color32::from_rgb(190, 120, 220),
+        };
+        let text = format!("{:0w$b} ", field.get(word), w = field.bits().len());
+        job.append(
+            &text,
+            0.0,
+            egui::TextFormat::simple(egui::FontId::monospace(10.0), color),
+        );
+    }
+    job
+}
+
+/// Draw an arrow from each jump instruction to its target, right of the instruction list.
+fn draw_jump_arrows(ui: &egui::Ui, asm: &[(Inst, f32)], rows: &[egui::Rect]) {
+    let Some(right) = rows.iter().map(|r| r.right()).reduce(f32::max) else {
+        return;
+    };
+    let stroke = egui::Stroke::new(1.5, ui.visuals().weak_text_color());
+    let painter = ui.painter();
+
+    // every arrow gets its own lane, so that they don't overlap
+    let mut x = right + 8.0;
+    for (from, (inst, _)) in asm.iter().enumerate() {
+        let to = match inst {
+            Inst::Jump(target) | Inst::JumpIfZero(_, target) | Inst::Call(target) => *target,
+            _ => continue,
+        };
+        let (Some(src), Some(dst)) = (rows.get(from), rows.get(to)) else {
+            continue;
+        };
+
+        x += 8.0;
+        let (y_src, y_dst) = (src.center().y, dst.center().y);
+        painter.line_segment([pos2(src.right() + 4.0, y_src), pos2(x, y_src)], stroke);
+        painter.line_segment([pos2(x, y_src), pos2(x, y_dst)], stroke);
+        painter.arrow(pos2(x, y_dst), vec2(dst.right() + 4.0 - x, 0.0), stroke);
     }
 }
 
@@ -1,7 +1,11 @@
 use std::collections::HashMap;
+use std::ops::Range;
 
-use crate::compiler::CompileOptions;
-use eframe::egui::{self, Align, Id, Layout, Modifiers};
+use crate::backends::Target;
+use crate::compiler::{CompileOptions, ParseError};
+use crate::passes::RegisterAllocation;
+use eframe::egui::text::LayoutJob;
+use eframe::egui::{self, Align, Id, Layout, Modifiers, Stroke, TextFormat};
 use rust_i18n::t;
 
 /// Actions that can be triggered by the editor window.
@@ -21,6 +25,8 @@ pub struct CodeEditor {
     pub actions: Vec<EditorAction>,
     pub input_variables: HashMap<String, String>,
     pub disable_run: bool,
+    /// Errors of the last compilation, underlined in the editor until the code is changed.
+    pub parse_errors: Vec<ParseError>,
 }
 
 impl Default for CodeEditor {
@@ -35,6 +41,7 @@ impl Default for CodeEditor {
             actions: vec![],
             input_variables: HashMap::new(),
             disable_run: false,
+            parse_errors: vec![],
         }
     }
 }
@@ -68,16 +75,51 @@ impl CodeEditor {
         });
 
         egui::ScrollArea::vertical().show(ui, |ui| {
-            ui.add(
-                egui::TextEdit::multiline(&mut self.code)
-                    .font(egui::TextStyle::Monospace) // for cursor height
-                    .code_editor()
-                    .desired_rows(10)
-                    .lock_focus(true)
-                    .desired_width(f32::INFINITY),
-            );
+            let errors = &self.parse_errors;
+            let mut layouter = |ui: &egui::Ui, text: &str, wrap_width: f32| {
+                let mut job = underline_errors(ui, text, errors);
+                job.wrap.max_width = wrap_width;
+                ui.fonts(|f| f.layout_job(job))
+            };
+
+            let output = egui::TextEdit::multiline(&mut self.code)
+                .font(egui::TextStyle::Monospace) // for cursor height
+                .code_editor()
+                .desired_rows(10)
+                .lock_focus(true)
+                .desired_width(f32::INFINITY)
+                .layouter(&mut layouter)
+                .show(ui);
+
+            if output.response.changed() {
+                self.parse_errors.clear();
+            }
+
+            // explain the error below the mouse pointer, if any
+            if let Some(pos) = output.response.hover_pos() {
+                let cursor = output.galley.cursor_from_pos(pos - output.galley_pos);
+                let byte_idx = self
+                    .code
+                    .char_indices()
+                    .nth(cursor.ccursor.index)
+                    .map_or(self.code.len(), |(i, _)| i);
+
+                let hovered: Vec<String> = self
+                    .parse_errors
+                    .iter()
+                    .filter(|e| underline_range(&self.code, &e.span).contains(&byte_idx))
+                    .map(ParseError::message)
+                    .collect();
+                if !hovered.is_empty() {
+                    output.response.on_hover_text_at_pointer(hovered.join("\n"));
+                }
+            }
         });
 
+        if !self.parse_errors.is_empty() {
+            ui.colored_label(ui.visuals().error_fg_color, t!("editor.errors"));
+        }
+
         ui.vertical(|ui| {
             ui.checkbox(
                 &mut self.compile_options.do_constant_folding,
@@ -95,6 +137,49 @@ impl CodeEditor {
                 &mut self.compile_options.do_shift_replacement,
                 t!("editor.replace_mul_with_shift"),
             );
+            ui.checkbox(
+                &mut self.compile_options.do_common_subexpression_elimination,
+                t!("editor.common_subexpression_elimination"),
+            );
+            ui.checkbox(
+                &mut self.compile_options.load_inputs_once,
+                t!("editor.load_inputs_once"),
+            );
+            ui.checkbox(
+                &mut self.compile_options.do_sethi_ullman,
+                t!("editor.sethi_ullman"),
+            );
+            ui.checkbox(&mut self.compile_options.use_ssa_ir, t!("editor.ssa_ir"));
+
+            ui.label(t!("editor.register_allocation"));
+            ui.horizontal(|ui| {
+                let allocation = &mut self.compile_options.register_allocation;
+                ui.radio_value(
+                    allocation,
+                    RegisterAllocation::RoundRobin,
+                    t!("editor.round_robin"),
+                );
+                ui.radio_value(
+                    allocation,
+                    RegisterAllocation::LinearScan,
+                    t!("editor.linear_scan"),
+                );
+                ui.radio_value(
+                    allocation,
+                    RegisterAllocation::GraphColoring,
+                    t!("editor.graph_coloring"),
+                );
+            });
+
+            ui.label(t!("editor.target"));
+            ui.horizontal(|ui| {
+                let target = &mut self.compile_options.target;
+                ui.radio_value(target, Target::Interpreter, t!("editor.target_interpreter"));
+                ui.radio_value(target, Target::RiscV, t!("editor.target_riscv"));
+                ui.radio_value(target, Target::X86_64, t!("editor.target_x86_64"));
+                ui.radio_value(target, Target::Wasm, t!("editor.target_wasm"));
+                ui.radio_value(target, Target::C, t!("editor.target_c"));
+            });
         });
 
         ui.with_layout(Layout::left_to_right(Align::Min), |ui| {
@@ -140,3 +225,55 @@ impl CodeEditor {
         }
     }
 }
+
+/// The part of the text that is underlined for an error. Errors at the end of the input have an
+/// empty span, so we underline the last character instead.
+fn underline_range(text: &str, span: &Range<usize>) -> Range<usize> {
+    let end = span.end.min(text.len());
+    let start = span.start.min(end);
+    if start < end {
+        return start..end;
+    }
+
+    match text[..start].char_indices().next_back() {
+        Some((i, _)) => i..start,
+        None => 0..text.chars().next().map_or(0, char::len_utf8),
+    }
+}
+
+/// Lay out the editor text with all error ranges underlined.
+fn underline_errors(ui: &egui::Ui, text: &str, errors: &[ParseError]) -> LayoutJob {
+    let normal = TextFormat {
+        font_id: egui::TextStyle::Monospace.resolve(ui.style()),
+        color: ui.visuals().text_color(),
+        ..Default::default()
+    };
+    let erroneous = TextFormat {
+        underline: Stroke::new(2.0, ui.visuals().error_fg_color),
+        ..normal.clone()
+    };
+
+    let ranges: Vec<Range<usize>> = errors
+        .iter()
+        .map(|e| underline_range(text, &e.span))
+        .collect();
+
+    // split the text at every start and end of an error range
+    let mut bounds: Vec<usize> = ranges.iter().flat_map(|r| [r.start, r.end]).collect();
+    bounds.extend([0, text.len()]);
+    bounds.retain(|b| text.is_char_boundary(*b));
+    bounds.sort_unstable();
+    bounds.dedup();
+
+    let mut job = LayoutJob::default();
+    for w in bounds.windows(2) {
+        let (start, end) = (w[0], w[1]);
+        let format = if ranges.iter().any(|r| r.start <= start && end <= r.end) {
+            erroneous.clone()
+        } else {
+            normal.clone()
+        };
+        job.append(&text[start..end], 0.0, format);
+    }
+    job
+}
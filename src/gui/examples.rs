@@ -2,7 +2,9 @@ use eframe::egui::{self, Label, Layout, RichText, Widget};
 use eframe::egui::{Align, Id};
 use rust_i18n::t;
 
+use crate::backends::Target;
 use crate::compiler::CompileOptions;
+use crate::passes::RegisterAllocation;
 
 pub struct Example {
     title: &'static str,
@@ -71,12 +73,7 @@ impl Examples {
             title: "examples.basic.title",
             desc: "examples.basic.desc",
             input: "3 + 2 + 1",
-            options: CompileOptions {
-                do_constant_folding: false,
-                run_cache_optimization: false,
-                do_common_factor_elimination: false,
-                do_shift_replacement: false,
-            },
+            options: CompileOptions::default(),
         });
 
         res.examples.push(Example {
@@ -85,9 +82,7 @@ impl Examples {
             input: "1000 * 2 + 4 * 5 + (15 / 3) + x * 13 - y * 2",
             options: CompileOptions {
                 do_constant_folding: true,
-                run_cache_optimization: false,
-                do_common_factor_elimination: false,
-                do_shift_replacement: false,
+                ..CompileOptions::default()
             },
         });
 
@@ -96,10 +91,8 @@ impl Examples {
             desc: "examples.ram_opt.desc",
             input: "(1000 + 2) * (4 * 5 + (15 / 3) + 17 * 13 - 8 * 2)",
             options: CompileOptions {
-                do_constant_folding: false,
                 run_cache_optimization: true,
-                do_common_factor_elimination: false,
-                do_shift_replacement: false,
+                ..CompileOptions::default()
             },
         });
 
@@ -108,22 +101,142 @@ impl Examples {
             desc: "examples.shift_mul.desc",
             input: "16 / 2 * 4 / 4",
             options: CompileOptions {
-                do_constant_folding: false,
                 run_cache_optimization: true,
-                do_common_factor_elimination: false,
                 do_shift_replacement: true,
+                ..CompileOptions::default()
+            },
+        });
+
+        res.examples.push(Example {
+            title: "examples.bindings.title",
+            desc: "examples.bindings.desc",
+            input: "let t = x * 3;\nlet u = t + 1;\nt * t + u",
+            options: CompileOptions {
+                run_cache_optimization: true,
+                ..CompileOptions::default()
+            },
+        });
+
+        res.examples.push(Example {
+            title: "examples.conditional.title",
+            desc: "examples.conditional.desc",
+            input: "if x < y then y - x else x - y",
+            options: CompileOptions::default(),
+        });
+
+        res.examples.push(Example {
+            title: "examples.loop.title",
+            desc: "examples.loop.desc",
+            input: "let p = 1;\nrepeat n {\n  p = p * 2;\n}\np",
+            options: CompileOptions {
+                do_shift_replacement: true,
+                ..CompileOptions::default()
             },
         });
 
+        res.examples.push(Example {
+            title: "examples.functions.title",
+            desc: "examples.functions.desc",
+            input: "fn sq(a) = a * a;\nlet t = x + 1;\nt * sq(t) + sq(y)",
+            options: CompileOptions::default(),
+        });
+
         res.examples.push(Example {
             title: "examples.factorization.title",
             desc: "examples.factorization.desc",
             input: "t * 16 + t * (3 + 2)",
             options: CompileOptions {
-                do_constant_folding: false,
                 run_cache_optimization: true,
                 do_common_factor_elimination: true,
-                do_shift_replacement: false,
+                ..CompileOptions::default()
+            },
+        });
+
+        res.examples.push(Example {
+            title: "examples.fixed_point.title",
+            desc: "examples.fixed_point.desc",
+            input: "1 / 3.0 + 1.5 * x",
+            options: CompileOptions {
+                do_constant_folding: true,
+                ..CompileOptions::default()
+            },
+        });
+
+        res.examples.push(Example {
+            title: "examples.register_allocation.title",
+            desc: "examples.register_allocation.desc",
+            input: "(a + b) * (c + d) - (a - b) * (c - d) + (a * c - b * d)",
+            options: CompileOptions {
+                register_allocation: RegisterAllocation::GraphColoring,
+                ..CompileOptions::default()
+            },
+        });
+
+        res.examples.push(Example {
+            title: "examples.ssa.title",
+            desc: "examples.ssa.desc",
+            input: "let a = 1; let b = x; let i = 0; while i < 5 { a = a + b; i = i + 1; } a * (2 + 3)",
+            options: CompileOptions {
+                do_constant_folding: true,
+                register_allocation: RegisterAllocation::LinearScan,
+                use_ssa_ir: true,
+                ..CompileOptions::default()
+            },
+        });
+
+        res.examples.push(Example {
+            title: "examples.cse.title",
+            desc: "examples.cse.desc",
+            input: "(x * y + 1) * (x * y + 1) - (x * y + 1)",
+            options: CompileOptions {
+                do_common_subexpression_elimination: true,
+                ..CompileOptions::default()
+            },
+        });
+
+        res.examples.push(Example {
+            title: "examples.riscv.title",
+            desc: "examples.riscv.desc",
+            input: "fn sq(n) = n * n; sq(x) + sq(y)",
+            options: CompileOptions {
+                do_constant_folding: true,
+                target: Target::RiscV,
+                ..CompileOptions::default()
+            },
+        });
+
+        res.examples.push(Example {
+            title: "examples.x86_64.title",
+            desc: "examples.x86_64.desc",
+            input: "(x * 8 + y) * (x * 3 - y * 4) * (y * 16 + 1)",
+            options: CompileOptions {
+                do_constant_folding: true,
+                do_shift_replacement: true,
+                target: Target::X86_64,
+                ..CompileOptions::default()
+            },
+        });
+
+        res.examples.push(Example {
+            title: "examples.wasm.title",
+            desc: "examples.wasm.desc",
+            input: "let i = 0; let s = 0; while i < n { s = s + i * i; i = i + 1; } s",
+            options: CompileOptions {
+                do_constant_folding: true,
+                target: Target::Wasm,
+                ..CompileOptions::default()
+            },
+        });
+
+        res.examples.push(Example {
+            title: "examples.c.title",
+            desc: "examples.c.desc",
+            input: "fn avg(a, b) = (a + b) / 2; avg(x, y) * avg(x, 10)",
+            options: CompileOptions {
+                do_constant_folding: true,
+                do_shift_replacement: true,
+                target: Target::C,
+                ..CompileOptions::default()
             },
         });
 
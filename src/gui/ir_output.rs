@@ -0,0 +1,62 @@
+use crate::compiler::{CompileOptions, Compiler};
+use crate::gui::InterpreterOptions;
+use eframe::egui::{self, Id};
+use rust_i18n::t;
+
+/// Shows the SSA form of the program, which lies between the AST and the assembly.
+#[derive(Default)]
+pub struct IrOutput {
+    ir: Option<String>,
+    error: Option<String>,
+}
+
+impl IrOutput {
+    /// Lower `input` into SSA form, with the same options as when compiling it.
+    pub fn compile(&mut self, input: &str, opts: CompileOptions, hw: InterpreterOptions) {
+        self.clear();
+        match Compiler::with(opts)
+            .with_interpreter(hw)
+            .lower_to_ssa(input)
+        {
+            Ok(program) => self.ir = Some(program.to_string()),
+            Err(e) => self.error = Some(format!("Compile error: {e}")),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.ir = None;
+        self.error = None;
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        if let Some(error) = &self.error {
+            ui.colored_label(egui::Color32::RED, error);
+            return;
+        }
+
+        let Some(ir) = &self.ir else {
+            ui.label(t!("output.empty"));
+            return;
+        };
+
+        ui.label(t!("ir.explain"));
+        ui.separator();
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            ui.monospace(ir);
+        });
+    }
+}
+
+impl crate::gui::Window for IrOutput {
+    fn name(&self) -> String {
+        "ir.name".into()
+    }
+
+    fn show(&mut self, ctx: &egui::Context, open: &mut bool) {
+        egui::Window::new(t!(self.name()))
+            .id(Id::new(self.name()))
+            .open(open)
+            .default_height(500.0)
+            .show(ctx, |ui| self.ui(ui));
+    }
+}
@@ -1,13 +1,17 @@
 mod assembly_output;
 mod code_editor;
 mod examples;
+mod ir_output;
 mod options;
+mod target_output;
 
 pub use assembly_output::*;
 pub use code_editor::*;
 use eframe::egui;
 pub use examples::*;
+pub use ir_output::*;
 pub use options::*;
+pub use target_output::*;
 
 pub trait Window {
     /// Name of the window
@@ -2,10 +2,24 @@ use eframe::egui;
 use eframe::egui::Id;
 use rust_i18n::t;
 
+use crate::arith::{Arith, Overflow, Signedness, Width};
+use crate::types::{MAX_REGISTERS, MIN_REGISTERS};
+
+/// Largest number of RAM cells a machine can have.
+pub const MAX_CACHELINES: usize = 256;
+
 #[derive(Copy, Clone)]
 pub struct InterpreterOptions {
     pub num_registers: u8,
     pub num_cachelines: usize,
+    /// Number of instructions the interpreter executes before giving up, e.g. in an endless loop.
+    pub max_steps: usize,
+    /// Number of bits in a register or RAM cell.
+    pub word_size: Width,
+    /// Whether values are signed or unsigned numbers.
+    pub signedness: Signedness,
+    /// What arithmetic does with results that don't fit into a register.
+    pub overflow: Overflow,
 }
 
 impl Default for InterpreterOptions {
@@ -13,10 +27,51 @@ impl Default for InterpreterOptions {
         Self {
             num_registers: 6,
             num_cachelines: 16,
+            max_steps: 10_000,
+            word_size: Width::default(),
+            signedness: Signedness::default(),
+            overflow: Overflow::default(),
         }
     }
 }
 
+impl InterpreterOptions {
+    /// The arithmetic of this machine.
+    pub fn arith(&self) -> Arith {
+        Arith {
+            width: self.word_size,
+            signedness: self.signedness,
+            overflow: self.overflow,
+        }
+    }
+
+    /// Check that a machine can be built with these options, e.g. that it has at least two
+    /// registers.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(MIN_REGISTERS..=MAX_REGISTERS).contains(&self.num_registers) {
+            return Err(t!(
+                "interp_opts.invalid_registers",
+                n = self.num_registers,
+                min = MIN_REGISTERS,
+                max = MAX_REGISTERS
+            )
+            .to_string());
+        }
+        if self.num_cachelines > MAX_CACHELINES {
+            return Err(t!(
+                "interp_opts.invalid_cachelines",
+                n = self.num_cachelines,
+                max = MAX_CACHELINES
+            )
+            .to_string());
+        }
+        if self.max_steps == 0 {
+            return Err(t!("interp_opts.invalid_max_steps").to_string());
+        }
+        Ok(())
+    }
+}
+
 impl crate::gui::Window for InterpreterOptions {
     fn name(&self) -> String {
         "interp_opts.name".into()
@@ -37,6 +92,9 @@ impl crate::gui::Window for InterpreterOptions {
 impl InterpreterOptions {
     pub fn ui(&mut self, ui: &mut egui::Ui) {
         ui.label(t!("interp_opts.label"));
+        if let Err(e) = self.validate() {
+            ui.colored_label(egui::Color32::RED, e);
+        }
 
         ui.add_space(12.0);
 
@@ -45,11 +103,10 @@ impl InterpreterOptions {
             .spacing([40.0, 4.0])
             .show(ui, |ui| {
                 ui.label(t!("interp_opts.n_regs"));
-                let mut reg_count = format!("{}", self.num_registers);
-                ui.text_edit_singleline(&mut reg_count);
-                if let Ok(val) = reg_count.parse() {
-                    self.num_registers = val;
-                }
+                ui.add(egui::Slider::new(
+                    &mut self.num_registers,
+                    MIN_REGISTERS..=MAX_REGISTERS,
+                ));
                 ui.end_row();
             });
 
@@ -67,11 +124,10 @@ impl InterpreterOptions {
             .spacing([40.0, 4.0])
             .show(ui, |ui| {
                 ui.label(t!("interp_opts.cache_size"));
-                let mut num_cachelines = format!("{}", self.num_cachelines);
-                ui.text_edit_singleline(&mut num_cachelines);
-                if let Ok(val) = num_cachelines.parse() {
-                    self.num_cachelines = val;
-                }
+                ui.add(egui::Slider::new(
+                    &mut self.num_cachelines,
+                    0..=MAX_CACHELINES,
+                ));
                 ui.end_row();
             });
 
@@ -81,5 +137,116 @@ impl InterpreterOptions {
             .show(ui, |ui| {
                 ui.label(t!("interp_opts.cache_label"));
             });
+
+        ui.add_space(12.0);
+
+        egui::Grid::new("max_steps")
+            .num_columns(2)
+            .spacing([40.0, 4.0])
+            .show(ui, |ui| {
+                ui.label(t!("interp_opts.max_steps"));
+                let mut max_steps = format!("{}", self.max_steps);
+                ui.text_edit_singleline(&mut max_steps);
+                if let Ok(val) = max_steps.parse() {
+                    self.max_steps = val;
+                }
+                ui.end_row();
+            });
+
+        egui::CollapsingHeader::new(t!("interp_opts.explanation"))
+            .id_salt("interp_opts.max_steps_label")
+            .default_open(true)
+            .show(ui, |ui| {
+                ui.label(t!("interp_opts.max_steps_label"));
+            });
+
+        ui.add_space(12.0);
+
+        egui::Grid::new("word_size")
+            .num_columns(2)
+            .spacing([40.0, 4.0])
+            .show(ui, |ui| {
+                ui.label(t!("interp_opts.word_size"));
+                ui.horizontal(|ui| {
+                    for width in Width::ALL {
+                        ui.radio_value(
+                            &mut self.word_size,
+                            width,
+                            t!("interp_opts.bits", n = width.bits()),
+                        );
+                    }
+                });
+                ui.end_row();
+            });
+
+        egui::CollapsingHeader::new(t!("interp_opts.explanation"))
+            .id_salt("interp_opts.word_size_label")
+            .default_open(true)
+            .show(ui, |ui| {
+                ui.label(t!("interp_opts.word_size_label"));
+            });
+
+        ui.add_space(12.0);
+
+        egui::Grid::new("signedness")
+            .num_columns(2)
+            .spacing([40.0, 4.0])
+            .show(ui, |ui| {
+                ui.label(t!("interp_opts.signedness"));
+                ui.horizontal(|ui| {
+                    ui.radio_value(
+                        &mut self.signedness,
+                        Signedness::Signed,
+                        t!("interp_opts.signed"),
+                    );
+                    ui.radio_value(
+                        &mut self.signedness,
+                        Signedness::Unsigned,
+                        t!("interp_opts.unsigned"),
+                    );
+                });
+                ui.end_row();
+            });
+
+        egui::CollapsingHeader::new(t!("interp_opts.explanation"))
+            .id_salt("interp_opts.signedness_label")
+            .default_open(true)
+            .show(ui, |ui| {
+                ui.label(t!("interp_opts.signedness_label"));
+            });
+
+        ui.add_space(12.0);
+
+        egui::Grid::new("overflow")
+            .num_columns(2)
+            .spacing([40.0, 4.0])
+            .show(ui, |ui| {
+                ui.label(t!("interp_opts.overflow"));
+                ui.vertical(|ui| {
+                    ui.radio_value(
+                        &mut self.overflow,
+                        Overflow::Wrapping,
+                        t!("interp_opts.overflow_wrapping"),
+                    );
+                    ui.radio_value(
+                        &mut self.overflow,
+                        Overflow::Trapping,
+                        t!("interp_opts.overflow_trapping"),
+                    );
+                    ui.radio_value(
+                        &mut self.overflow,
+                        Overflow::Saturating,
+                        t!("interp_opts.overflow_saturating"),
+                    );
+                });
+                ui.end_row();
+            });
+
+        egui::CollapsingHeader::new(t!("interp_opts.explanation"))
+            .id_salt("interp_opts.overflow_label")
+            .default_open(true)
+            .show(ui, |ui| {
+                ui.label(t!("interp_opts.overflow_label"));
+            });
     }
 }
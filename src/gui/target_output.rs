@@ -0,0 +1,61 @@
+use crate::backends::Target;
+use crate::compiler::{CompileOptions, Compiler};
+use crate::gui::InterpreterOptions;
+use eframe::egui::{self, Id};
+use rust_i18n::t;
+
+/// Shows the program compiled for another processor than the one of the interpreter.
+#[derive(Default)]
+pub struct TargetOutput {
+    code: Option<String>,
+    error: Option<String>,
+}
+
+impl TargetOutput {
+    /// Compile `input` for the target selected in `opts`, if it isn't the interpreter.
+    pub fn compile(&mut self, input: &str, opts: CompileOptions, hw: InterpreterOptions) {
+        self.clear();
+        if opts.target == Target::Interpreter {
+            return;
+        }
+        match Compiler::with(opts).with_interpreter(hw).emit(input) {
+            Ok(code) => self.code = Some(code),
+            Err(e) => self.error = Some(format!("Compile error: {e}")),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.code = None;
+        self.error = None;
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        if let Some(error) = &self.error {
+            ui.colored_label(egui::Color32::RED, error);
+            return;
+        }
+
+        let Some(code) = &self.code else {
+            ui.label(t!("target.empty"));
+            return;
+        };
+
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            ui.monospace(code);
+        });
+    }
+}
+
+impl crate::gui::Window for TargetOutput {
+    fn name(&self) -> String {
+        "target.name".into()
+    }
+
+    fn show(&mut self, ctx: &egui::Context, open: &mut bool) {
+        egui::Window::new(t!(self.name()))
+            .id(Id::new(self.name()))
+            .open(open)
+            .default_height(500.0)
+            .show(ctx, |ui| self.ui(ui));
+    }
+}
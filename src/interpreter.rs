@@ -1,10 +1,10 @@
 use rust_i18n::t;
-use std::collections::HashMap;
-use std::ops::{Add, Div, Mul, Shl, Shr, Sub};
+use std::collections::{HashMap, HashSet};
 
 use crate::{
+    arith::{Arith, ArithError},
     gui::InterpreterOptions,
-    types::{Inst, LpErr, Reg},
+    types::{Inst, InstAddr, LpErr, Operator, Reg},
 };
 
 /// State of the interpreter after executing a single execution step.
@@ -12,11 +12,11 @@ pub enum InterpreterState {
     /// Continue execution with the next instruction.
     Continue,
     /// The execution terminated successfully.
-    Finished(i32),
+    Finished(i64),
 }
 
-impl From<i32> for InterpreterState {
-    fn from(value: i32) -> Self {
+impl From<i64> for InterpreterState {
+    fn from(value: i64) -> Self {
         InterpreterState::Finished(value)
     }
 }
@@ -26,14 +26,35 @@ impl From<i32> for InterpreterState {
 /// The interpreters stores the memory layout at each step and thus enables introspection.
 pub struct Interpreter {
     /// The register store.
-    pub reg_store: HashMap<Reg, i32>,
+    pub reg_store: HashMap<Reg, i64>,
+    /// Number of registers, named from `a` upwards.
+    num_registers: u8,
     /// Slow cache used for out-of-register storage.
-    pub ram: Vec<i32>,
+    ///
+    /// The end of the RAM also hosts the stack, which grows towards lower addresses.
+    pub ram: Vec<i64>,
+    /// Address of the topmost value on the stack; the stack is empty if this is `ram.len()`.
+    stack_pointer: usize,
 
     /// Instruction list to be executed.
     instructions: Vec<Inst>,
     /// Program counter pointing to the next instruction to be executed.
     program_counter: usize,
+    /// Number of instructions executed so far.
+    steps: usize,
+    /// Number of instructions after which execution is aborted.
+    max_steps: usize,
+    /// How values are computed, e.g. what happens when a result doesn't fit into a register.
+    arith: Arith,
+
+    /// Instructions whose result is a fixed-point number.
+    fixed_point: HashSet<InstAddr>,
+    /// Registers currently holding fixed-point numbers.
+    fixed_point_regs: HashSet<Reg>,
+    /// RAM cells currently holding fixed-point numbers.
+    fixed_point_ram: HashSet<usize>,
+    /// Whether the result of the program is a fixed-point number.
+    fixed_point_result: bool,
 
     /// Input variable mapping.
     input_variables: Option<HashMap<String, String>>,
@@ -55,10 +76,19 @@ impl Interpreter {
     pub fn with_config(hw: &InterpreterOptions) -> Self {
         Self {
             reg_store: Default::default(),
+            num_registers: hw.num_registers,
             ram: vec![0; hw.num_cachelines],
+            stack_pointer: hw.num_cachelines,
             instructions: Vec::with_capacity(0),
             str_repr: String::with_capacity(0),
             program_counter: 0,
+            steps: 0,
+            max_steps: hw.max_steps,
+            arith: hw.arith(),
+            fixed_point: HashSet::new(),
+            fixed_point_regs: HashSet::new(),
+            fixed_point_ram: HashSet::new(),
+            fixed_point_result: false,
             input_variables: None,
             running: false,
             repr_enabled: false,
@@ -80,6 +110,12 @@ impl Interpreter {
         self
     }
 
+    /// Marks the instructions whose result is a fixed-point number.
+    pub fn with_fixed_point(mut self, fixed_point: HashSet<InstAddr>) -> Self {
+        self.fixed_point = fixed_point;
+        self
+    }
+
     pub fn with_tracing(mut self) -> Self {
         self.repr_enabled = true;
         if !self.instructions.is_empty() {
@@ -95,7 +131,7 @@ impl Interpreter {
     }
 
     /// Executes the instruction list until the interpreter either terminates or encounters a critical error.
-    pub fn run_to_end(mut self) -> Result<i32, LpErr> {
+    pub fn run_to_end(&mut self) -> Result<i64, LpErr> {
         loop {
             match self.step()? {
                 InterpreterState::Continue => (),
@@ -108,6 +144,50 @@ impl Interpreter {
         self.running
     }
 
+    /// Index of the next instruction to be executed.
+    pub fn program_counter(&self) -> usize {
+        self.program_counter
+    }
+
+    /// Address of the topmost value on the stack, or the size of the RAM if the stack is empty.
+    pub fn stack_pointer(&self) -> usize {
+        self.stack_pointer
+    }
+
+    /// Whether the register `r` holds a fixed-point number.
+    pub fn is_fixed_point_reg(&self, r: &Reg) -> bool {
+        self.fixed_point_regs.contains(r)
+    }
+
+    /// Whether the RAM cell `addr` holds a fixed-point number.
+    pub fn is_fixed_point_ram(&self, addr: usize) -> bool {
+        self.fixed_point_ram.contains(&addr)
+    }
+
+    /// Whether the result of the program is a fixed-point number.
+    pub fn is_fixed_point_result(&self) -> bool {
+        self.fixed_point_result
+    }
+
+    fn push(&mut self, val: i64) -> Result<(), LpErr> {
+        if self.stack_pointer == 0 {
+            return Err(LpErr::Interpret(
+                t!("compiler.error.stack_overflow").to_string(),
+            ));
+        }
+        self.stack_pointer -= 1;
+        self.ram[self.stack_pointer] = val;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<i64, LpErr> {
+        let val = *self.ram.get(self.stack_pointer).ok_or(LpErr::Interpret(
+            t!("compiler.error.stack_underflow").to_string(),
+        ))?;
+        self.stack_pointer += 1;
+        Ok(val)
+    }
+
     /// Executes a single step of the program.
     pub fn step(&mut self) -> Result<InterpreterState, LpErr> {
         if !self.running {
@@ -120,22 +200,80 @@ impl Interpreter {
             return Err(LpErr::Interpret("no result found".to_string()));
         }
 
+        if self.steps >= self.max_steps {
+            self.running = false;
+            return Err(LpErr::Interpret(
+                t!("compiler.error.step_limit", n = self.max_steps).to_string(),
+            ));
+        }
+        self.steps += 1;
+
         if self.repr_enabled {
             self.str_repr = self.cur_as_string();
         }
+        self.track_fixed_point();
 
-        match &self.instructions[self.program_counter] {
-            Inst::Add(a, b) => run_binop(*a, *b, i32::add, &mut self.reg_store)?,
-            Inst::Sub(a, b) => run_binop(*a, *b, i32::sub, &mut self.reg_store)?,
-            Inst::Mul(a, b) => run_binop(*a, *b, i32::mul, &mut self.reg_store)?,
-            Inst::Div(a, b) => {
-                if let Some(0) = self.reg_store.get(b) {
-                    return Err(LpErr::Interpret(t!("compiler.error.divzero").to_string()));
+        let inst = &self.instructions[self.program_counter];
+        let outside = |r: &Reg| r.0 >= self.num_registers.into();
+        if let Some(reg) = inst
+            .sources()
+            .into_iter()
+            .chain(inst.target())
+            .find(outside)
+        {
+            self.running = false;
+            return Err(LpErr::Interpret(
+                t!(
+                    "compiler.error.no_such_register",
+                    r = reg,
+                    n = self.num_registers
+                )
+                .to_string(),
+            ));
+        }
+
+        match inst {
+            Inst::Add(a, b) => self.run_binop(*a, *b, Operator::Add)?,
+            Inst::Sub(a, b) => self.run_binop(*a, *b, Operator::Sub)?,
+            Inst::Mul(a, b) => self.run_binop(*a, *b, Operator::Mul)?,
+            Inst::Div(a, b) => self.run_binop(*a, *b, Operator::Div)?,
+            Inst::Mod(a, b) => self.run_binop(*a, *b, Operator::Mod)?,
+            Inst::Shl(a, b) => self.run_binop(*a, *b, Operator::Shl)?,
+            Inst::Shr(a, b) => self.run_binop(*a, *b, Operator::Shr)?,
+            Inst::LogicalShr(a, b) => self.run_binop(*a, *b, Operator::LogicalShr)?,
+            Inst::And(a, b) => self.run_binop(*a, *b, Operator::And)?,
+            Inst::Or(a, b) => self.run_binop(*a, *b, Operator::Or)?,
+            Inst::Xor(a, b) => self.run_binop(*a, *b, Operator::Xor)?,
+            Inst::Not(r) => {
+                let val = self
+                    .reg_store
+                    .get_mut(r)
+                    .ok_or(LpErr::Interpret(format!("no such reg `{r}`")))?;
+                *val = self
+                    .arith
+                    .unary(Operator::Not, *val)
+                    .expect("`~` always fits into a word");
+            }
+            Inst::Eq(a, b) => self.run_binop(*a, *b, Operator::Eq)?,
+            Inst::Ne(a, b) => self.run_binop(*a, *b, Operator::Ne)?,
+            Inst::Lt(a, b) => self.run_binop(*a, *b, Operator::Lt)?,
+            Inst::Le(a, b) => self.run_binop(*a, *b, Operator::Le)?,
+            Inst::Gt(a, b) => self.run_binop(*a, *b, Operator::Gt)?,
+            Inst::Ge(a, b) => self.run_binop(*a, *b, Operator::Ge)?,
+            Inst::Jump(target) => {
+                self.program_counter = *target;
+                return Ok(InterpreterState::Continue);
+            }
+            Inst::JumpIfZero(r, target) => {
+                let val = self
+                    .reg_store
+                    .get(r)
+                    .ok_or(LpErr::Interpret(format!("register `{r}` is empty")))?;
+                if *val == 0 {
+                    self.program_counter = *target;
+                    return Ok(InterpreterState::Continue);
                 }
-                run_binop(*a, *b, i32::div, &mut self.reg_store)?
             }
-            Inst::Shl(a, b) => run_binop(*a, *b, i32::shl, &mut self.reg_store)?,
-            Inst::Shr(a, b) => run_binop(*a, *b, i32::shr, &mut self.reg_store)?,
             Inst::Store(n, reg) => {
                 if self.reg_store.insert(*reg, *n).is_some() {
                     eprintln!("Warning: overwriting register `{reg}`.");
@@ -147,21 +285,43 @@ impl Interpreter {
                     .as_ref()
                     .ok_or(LpErr::Interpret("No variables loaded".into()))?;
                 if !vars.contains_key(var) {
-                    return Err(LpErr::Interpret(t!("compiler.error.unknown_var", v = var).into()));
+                    return Err(LpErr::Interpret(
+                        t!("compiler.error.unknown_var", v = var).into(),
+                    ));
                 }
 
                 let val_str = vars[var].clone();
                 if val_str.is_empty() {
-                    return Err(LpErr::Interpret(t!("compiler.error.empty_var", v = var).into()));
+                    return Err(LpErr::Interpret(
+                        t!("compiler.error.empty_var", v = var).into(),
+                    ));
                 }
 
-                let val = val_str.parse::<i32>().map_err(|_| {
+                let val = val_str.parse::<i128>().map_err(|_| {
                     LpErr::Interpret(t!("compiler.error.nan_var", var = var, val = val_str).into())
                 })?;
+                let val = self.arith.word(val).ok_or_else(|| {
+                    LpErr::Interpret(
+                        t!(
+                            "compiler.error.var_range",
+                            var = var,
+                            val = val,
+                            bits = self.arith.width.bits()
+                        )
+                        .into(),
+                    )
+                })?;
                 if self.reg_store.insert(*reg, val).is_some() {
                     eprintln!("Warning: overwriting register `{reg}`.");
                 }
             }
+            Inst::Copy(a, b) => {
+                let val = *self
+                    .reg_store
+                    .get(a)
+                    .ok_or(LpErr::Interpret(format!("no such reg `{a}`")))?;
+                self.reg_store.insert(*b, val);
+            }
             Inst::Result(r) => {
                 self.program_counter += 1;
                 self.running = false;
@@ -171,6 +331,28 @@ impl Interpreter {
                     .ok_or(LpErr::Interpret(format!("register `{r}` is empty")))?)
                 .into());
             }
+            Inst::Call(target) => {
+                let target = *target;
+                self.push(self.program_counter as i64 + 1)?;
+                self.program_counter = target;
+                return Ok(InterpreterState::Continue);
+            }
+            Inst::Ret => {
+                self.program_counter = self.pop()? as usize;
+                return Ok(InterpreterState::Continue);
+            }
+            Inst::Push(r) => {
+                let val = *self
+                    .reg_store
+                    .get(r)
+                    .ok_or(LpErr::Interpret(format!("register `{r}` is empty")))?;
+                self.push(val)?;
+            }
+            Inst::Pop(r) => {
+                let r = *r;
+                let val = self.pop()?;
+                self.reg_store.insert(r, val);
+            }
             Inst::Write(_, addr) | Inst::Load(addr, _) if addr >= &self.ram.len() => {
                 return Err(LpErr::Interpret(format!(
                     "requested RAM address {addr} doesn't exist."
@@ -198,11 +380,32 @@ impl Interpreter {
             Inst::Sub(a, b) => self.display_binop(a, b, "-"),
             Inst::Mul(a, b) => self.display_binop(a, b, "*"),
             Inst::Div(a, b) => self.display_binop(a, b, "/"),
+            Inst::Mod(a, b) => self.display_binop(a, b, "%"),
             Inst::Shl(a, b) => self.display_binop(a, b, "<<"),
             Inst::Shr(a, b) => self.display_binop(a, b, ">>"),
+            Inst::LogicalShr(a, b) => self.display_binop(a, b, ">>>"),
+            Inst::And(a, b) => self.display_binop(a, b, "&"),
+            Inst::Or(a, b) => self.display_binop(a, b, "|"),
+            Inst::Xor(a, b) => self.display_binop(a, b, "^"),
+            Inst::Not(a) => format!("~{}", self.reg_store.get(a).unwrap()),
+            Inst::Eq(a, b) => self.display_binop(a, b, "=="),
+            Inst::Ne(a, b) => self.display_binop(a, b, "!="),
+            Inst::Lt(a, b) => self.display_binop(a, b, "<"),
+            Inst::Le(a, b) => self.display_binop(a, b, "<="),
+            Inst::Gt(a, b) => self.display_binop(a, b, ">"),
+            Inst::Ge(a, b) => self.display_binop(a, b, ">="),
+            Inst::Jump(target) => format!("⤷ {target}"),
+            Inst::JumpIfZero(a, target) => {
+                format!("{} = 0 ? ⤷ {target}", self.reg_store.get(a).unwrap())
+            }
             Inst::Store(num, a) => format!("{num} ➡ [{a}]"),
             Inst::Transfer(var, a) => format!("{var} ➡ [{a}]"),
+            Inst::Copy(a, b) => format!("[{a}] ➡ [{b}]"),
             Inst::Result(a) => format!("= {}", self.reg_store.get(a).unwrap()),
+            Inst::Call(target) => format!("⤷ {target}"),
+            Inst::Ret => format!("⤶ {}", self.ram.get(self.stack_pointer).unwrap_or(&0)),
+            Inst::Push(a) => format!("⭳ [{a}] ➡ [{}]", self.stack_pointer.saturating_sub(1)),
+            Inst::Pop(a) => format!("⭱ [{a}] ⬅ [{}]", self.stack_pointer),
             Inst::Write(reg, addr) => format!("⎘ [{reg}] ➡ [{addr}]"),
             Inst::Load(addr, reg) => format!("⎗ [{reg}] ⬅ [{addr}]"),
         }
@@ -211,8 +414,8 @@ impl Interpreter {
     fn display_binop(&self, a: &Reg, b: &Reg, op: &str) -> String {
         format!(
             "{} {op} {}",
-            self.reg_store.get(a).unwrap(),
-            self.reg_store.get(b).unwrap()
+            self.arith.value(self.reg_store[a]),
+            self.arith.value(self.reg_store[b])
         )
     }
 
@@ -223,21 +426,80 @@ impl Interpreter {
     #[allow(dead_code)]
     pub fn reset(&mut self) {
         self.program_counter = 0;
+        self.stack_pointer = self.ram.len();
+        self.steps = 0;
         self.ram = self.ram.iter().map(|_| 0).collect();
         self.reg_store.clear();
+        self.fixed_point_regs.clear();
+        self.fixed_point_ram.clear();
+        self.fixed_point_result = false;
     }
-}
 
-fn run_binop(
-    a: Reg,
-    b: Reg,
-    op: impl FnOnce(i32, i32) -> i32,
-    reg_store: &mut HashMap<Reg, i32>,
-) -> Result<(), LpErr> {
-    match (reg_store.get(&a).cloned(), reg_store.get_mut(&b)) {
-        (Some(a), Some(b)) => *b = op(a, *b),
-        (None, _) => return Err(LpErr::Interpret(format!("no such reg `{a}`"))),
-        (_, None) => return Err(LpErr::Interpret(format!("no such reg `{b}`"))),
-    }
-    Ok(())
+    /// Update which registers and RAM cells hold fixed-point numbers for the next instruction,
+    /// before it is executed. Values moved around keep their type, all other results are
+    /// fixed-point numbers if the compiler marked their instruction.
+    fn track_fixed_point(&mut self) {
+        fn mark<T: Eq + std::hash::Hash>(set: &mut HashSet<T>, key: T, fixed_point: bool) {
+            if fixed_point {
+                set.insert(key);
+            } else {
+                set.remove(&key);
+            }
+        }
+
+        let (regs, ram, sp) = (
+            &self.fixed_point_regs,
+            &self.fixed_point_ram,
+            self.stack_pointer,
+        );
+        // the register or RAM cell receiving a value, and whether that value is a fixed-point number
+        let (reg, cell) = match &self.instructions[self.program_counter] {
+            Inst::Copy(a, b) => (Some((*b, regs.contains(a))), None),
+            Inst::Write(r, addr) => (None, Some((*addr, regs.contains(r)))),
+            Inst::Load(addr, r) => (Some((*r, ram.contains(addr))), None),
+            Inst::Push(r) => (None, Some((sp.wrapping_sub(1), regs.contains(r)))),
+            // the return address
+            Inst::Call(_) => (None, Some((sp.wrapping_sub(1), false))),
+            Inst::Pop(r) => (Some((*r, ram.contains(&sp))), None),
+            Inst::Result(r) => {
+                self.fixed_point_result = regs.contains(r);
+                (None, None)
+            }
+            inst => (
+                inst.target()
+                    .map(|r| (r, self.fixed_point.contains(&self.program_counter))),
+                None,
+            ),
+        };
+
+        if let Some((r, fixed_point)) = reg {
+            mark(&mut self.fixed_point_regs, r, fixed_point);
+        }
+        if let Some((addr, fixed_point)) = cell {
+            mark(&mut self.fixed_point_ram, addr, fixed_point);
+        }
+    }
+
+    /// Computes `a op b` and stores the result in `b`.
+    fn run_binop(&mut self, a: Reg, b: Reg, op: Operator) -> Result<(), LpErr> {
+        let lhs = *self
+            .reg_store
+            .get(&a)
+            .ok_or(LpErr::Interpret(format!("no such reg `{a}`")))?;
+        let rhs = self
+            .reg_store
+            .get_mut(&b)
+            .ok_or(LpErr::Interpret(format!("no such reg `{b}`")))?;
+        let res = self.arith.binary(op, lhs, *rhs).map_err(|e| match e {
+            ArithError::DivisionByZero => t!("compiler.error.divzero"),
+            ArithError::Overflow => t!(
+                "compiler.error.overflow",
+                a = self.arith.value(lhs),
+                op = op,
+                b = self.arith.value(*rhs)
+            ),
+        });
+        *rhs = res.map_err(|e| LpErr::Interpret(e.to_string()))?;
+        Ok(())
+    }
 }
@@ -1,14 +1,20 @@
 mod app;
+mod arith;
+mod assembly;
+mod backends;
 mod compiler;
+mod diagnostics;
+mod encoding;
 mod gui;
 mod interpreter;
 mod parser;
 mod passes;
+mod ssa;
 mod types;
 
-use std::sync::Arc;
 use crate::app::LndwApp;
 use rust_i18n::t;
+use std::sync::Arc;
 
 rust_i18n::i18n!("locales", fallback = "en");
 
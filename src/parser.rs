@@ -1,78 +1,407 @@
+use crate::arith::Arith;
+use crate::diagnostics::{Diagnostic, Diagnostics};
 use crate::types::*;
+use chumsky::error::RichPattern;
+use chumsky::extra::SimpleState;
 use chumsky::prelude::*;
+use rust_i18n::t;
 
-pub fn run_parser(input: &str) -> Result<Expr, LpErr> {
-    parse_expr()
-        .parse(input)
-        .into_result()
-        .map_err(|parse_errs| {
-            LpErr::Parse(
-                parse_errs
-                    .first()
-                    .map(|e| e.to_string())
-                    .unwrap_or("[unknown error]".into()),
-            )
+/// Errors stop the compilation, while the diagnostics collected in the state don't.
+type Extra<'a> = extra::Full<Rich<'a, char>, SimpleState<Vec<Diagnostic>>, ()>;
+
+/// Parses a program for a machine with the given arithmetic, which limits the range of literals.
+/// Problems that don't stop the compilation are added to `diagnostics`.
+pub fn run_parser(
+    input: &str,
+    arith: Arith,
+    diagnostics: &mut Diagnostics,
+) -> Result<Program, LpErr> {
+    let mut found = SimpleState(vec![]);
+    let (program, errs) = parse_program(arith)
+        .parse_with_state(input, &mut found)
+        .into_output_errors();
+    for diagnostic in found.0 {
+        diagnostics.push(diagnostic);
+    }
+
+    match program {
+        Some(program) if errs.is_empty() => Ok(program),
+        _ => Err(LpErr::Parse(
+            errs.into_iter().map(ParseError::from).collect(),
+        )),
+    }
+}
+
+impl From<Rich<'_, char>> for ParseError {
+    fn from(err: Rich<'_, char>) -> Self {
+        let span = err.span().into_range();
+        let reason = match err.reason() {
+            chumsky::error::RichReason::Custom(msg) => Some(msg.clone()),
+            _ => None,
+        };
+
+        ParseError {
+            span,
+            found: err.found().map(|c| format!("'{c}'")),
+            expected: err.expected().map(describe_pattern).collect(),
+            reason,
+        }
+    }
+}
+
+fn describe_pattern(pattern: &RichPattern<'_, char>) -> String {
+    match pattern {
+        RichPattern::Token(c) => format!("'{}'", **c),
+        RichPattern::Label(label) if KEYWORDS.contains(&label.as_ref()) => format!("'{label}'"),
+        RichPattern::Label(label) => t!(format!("parser.label.{label}")).to_string(),
+        RichPattern::Identifier(i) => format!("'{i}'"),
+        RichPattern::Any => t!("parser.any").to_string(),
+        RichPattern::SomethingElse => t!("parser.something_else").to_string(),
+        RichPattern::EndOfInput => t!("parser.end_of_input").to_string(),
+    }
+}
+
+fn binop(lhs: Expr, (op, rhs): (Operator, Expr)) -> Expr {
+    Expr::BinaryOp(Box::new(lhs), op, Box::new(rhs))
+}
+
+/// Keywords that can't be used as variable names.
+const KEYWORDS: [&str; 7] = ["let", "if", "then", "else", "while", "repeat", "fn"];
+
+/// A keyword, which must not be followed by further identifier characters (`letter` isn't `let`).
+fn keyword<'a>(kw: &'static str) -> impl Parser<'a, &'a str, (), Extra<'a>> + Clone {
+    text::ascii::ident()
+        .filter(move |name: &&str| *name == kw)
+        .ignored()
+        .padded()
+        .labelled(kw)
+}
+
+fn ident<'a>() -> impl Parser<'a, &'a str, &'a str, Extra<'a>> + Clone {
+    text::ascii::ident()
+        .try_map(|name: &str, span| {
+            if KEYWORDS.contains(&name) {
+                Err(Rich::custom(span, t!("parser.keyword", kw = name)))
+            } else {
+                Ok(name)
+            }
         })
+        .padded()
+        .labelled("variable")
+}
+
+/// An integer literal, either decimal or with a `0x`, `0b` or `0o` prefix, optionally with `_`
+/// between the digits (`0xFFFF_FFFF`). Literals that don't fit into a value are reported, but
+/// parsing continues.
+fn literal<'a>(arith: Arith) -> impl Parser<'a, &'a str, i64, Extra<'a>> + Clone {
+    choice((
+        just("0x")
+            .ignore_then(digits("0123456789abcdefABCDEF"))
+            .map(|d| (d, 16)),
+        just("0b").ignore_then(digits("01")).map(|d| (d, 2)),
+        just("0o").ignore_then(digits("01234567")).map(|d| (d, 8)),
+        digits("0123456789").map(|d| (d, 10)),
+    ))
+    .validate(move |(digits, radix): (&str, u32), e, emitter| {
+        i128::from_str_radix(&digits.replace('_', ""), radix)
+            .ok()
+            .and_then(|n| arith.word(n))
+            .unwrap_or_else(|| {
+                emitter.emit(Rich::custom(
+                    e.span(),
+                    t!("parser.literal_range", lit = e.slice(), max = arith.max()),
+                ));
+                0
+            })
+    })
+}
+
+/// A fixed-point literal like `1.5`, see [`Expr::FixedPoint`]. Literals that don't fit into a
+/// value are reported, but parsing continues. Literals that are too small for the fractional bits
+/// become zero with a warning.
+fn fixed_point_literal<'a>(arith: Arith) -> impl Parser<'a, &'a str, i64, Extra<'a>> + Clone {
+    digits("0123456789")
+        .then_ignore(just('.'))
+        .then(digits("0123456789"))
+        .validate(move |(int, frac): (&str, &str), e, emitter| {
+            let raw = arith
+                .fixed_point(&int.replace('_', ""), &frac.replace('_', ""))
+                .unwrap_or_else(|| {
+                    let max = arith.format_fixed_point(arith.max() as i64);
+                    emitter.emit(Rich::custom(
+                        e.span(),
+                        t!("parser.fixed_point_range", lit = e.slice(), max = max),
+                    ));
+                    0
+                });
+            if raw == 0
+                && e.slice()
+                    .contains(['1', '2', '3', '4', '5', '6', '7', '8', '9'])
+            {
+                let slice = e.slice();
+                let span = e.span().into_range();
+                e.state().push(
+                    Diagnostic::warning("fixed-point-zero", "diagnostics.fixed_point_zero")
+                        .arg("lit", slice)
+                        .span(span),
+                );
+            }
+            raw
+        })
+}
+
+/// One or more digits from `digits`, optionally separated by `_`.
+fn digits<'a>(digits: &'static str) -> impl Parser<'a, &'a str, &'a str, Extra<'a>> + Clone {
+    one_of(digits)
+        .then(one_of(digits).or(just('_')).repeated())
+        .to_slice()
+        .labelled("digit")
+}
+
+/// A program consists of function definitions and any number of statements, followed by the result
+/// expression:
+///
+/// ```text
+/// fn sq(a) = a * a;
+/// let t = sq(x);
+/// repeat 2 {
+///     t = t * t;
+/// }
+/// t + 1
+/// ```
+fn parse_program<'a>(arith: Arith) -> impl Parser<'a, &'a str, Program, Extra<'a>> {
+    let function = keyword("fn")
+        .ignore_then(ident())
+        .then(
+            ident()
+                .map(String::from)
+                .separated_by(just(',').padded())
+                .collect::<Vec<_>>()
+                .delimited_by(just('(').padded(), just(')').padded()),
+        )
+        .then_ignore(just('=').padded())
+        .then(parse_expr(arith))
+        .then_ignore(just(';').padded())
+        .map(|((name, params), body)| Function {
+            name: name.to_string(),
+            params,
+            body,
+        });
+
+    function
+        .repeated()
+        .collect::<Vec<_>>()
+        .then(parse_stmts(arith))
+        .then(parse_expr(arith))
+        .map(|((functions, stmts), result)| Program {
+            functions,
+            stmts,
+            result,
+        })
+}
+
+fn parse_stmts<'a>(arith: Arith) -> impl Parser<'a, &'a str, Vec<Stmt>, Extra<'a>> + Clone {
+    recursive(|stmts| {
+        let block = stmts.delimited_by(just('{').padded(), just('}').padded());
+
+        let binding = keyword("let")
+            .ignore_then(ident())
+            .then_ignore(just('=').padded())
+            .then(parse_expr(arith))
+            .then_ignore(just(';').padded())
+            .map(|(name, value)| vec![Stmt::Let(name.to_string(), value)]);
+
+        // `=` must not be the start of `==`, so that the result expression `x == y` isn't mistaken
+        // for an assignment
+        let assignment = ident()
+            .then_ignore(just('=').then(just('=').not()).padded())
+            .then(parse_expr(arith))
+            .then_ignore(just(';').padded())
+            .map(|(name, value)| vec![Stmt::Assign(name.to_string(), value)]);
+
+        let while_loop = keyword("while")
+            .ignore_then(parse_expr(arith))
+            .then(block.clone())
+            .map(|(cond, body)| vec![Stmt::While(cond, body)]);
+
+        // `repeat n { body }` counts down a hidden binding, which is named after the loop's
+        // position, so that it can't clash with any variable or other loop
+        let repeat_loop = keyword("repeat")
+            .ignore_then(parse_expr(arith))
+            .then(block)
+            .map_with(|(count, mut body), e| {
+                let span: SimpleSpan = e.span();
+                let counter = format!("repeat@{}", span.start);
+                let var = || Box::new(Expr::Var(counter.clone()));
+                body.insert(
+                    0,
+                    Stmt::Assign(
+                        counter.clone(),
+                        Expr::BinaryOp(var(), Operator::Sub, Box::new(Expr::Num(1))),
+                    ),
+                );
+                vec![
+                    Stmt::Let(counter.clone(), count),
+                    Stmt::While(
+                        Expr::BinaryOp(var(), Operator::Gt, Box::new(Expr::Num(0))),
+                        body,
+                    ),
+                ]
+            });
+
+        choice((binding, while_loop, repeat_loop, assignment))
+            .repeated()
+            .collect::<Vec<_>>()
+            .map(|stmts| stmts.into_iter().flatten().collect())
+    })
 }
 
-fn parse_expr<'a>() -> impl Parser<'a, &'a str, Expr> {
+fn parse_expr<'a>(arith: Arith) -> impl Parser<'a, &'a str, Expr, Extra<'a>> + Clone {
     recursive(|expr| {
-        let ident = text::ascii::ident().padded();
+        let ident = ident();
 
-        let int = text::int(10)
-            .map(|s: &str| s.parse().unwrap())
-            .map(Expr::Num);
+        let int = literal(arith).map(Expr::Num).labelled("number");
+        let fixed_point = fixed_point_literal(arith)
+            .map(|n| Expr::FixedPoint(Box::new(Expr::Num(n))))
+            .labelled("number");
 
-        // a single atom, either an integer, a parenthesized expression or an identifier
-        let atom = int
-            .or(expr.delimited_by(just('('), just(')')))
+        let call = ident
+            .clone()
+            .then(
+                expr.clone()
+                    .separated_by(just(','))
+                    .collect::<Vec<_>>()
+                    .delimited_by(just('('), just(')')),
+            )
+            .map(|(name, args)| Expr::Call(name.to_string(), args));
+
+        // a single atom, either a (fixed-point or integer) number, a parenthesized expression, a
+        // function call or an identifier. `1.5` has to be tried before `1`.
+        let atom = fixed_point
+            .or(int)
+            .or(expr.clone().delimited_by(just('('), just(')')))
+            .or(call)
             .or(ident.map(String::from).map(Expr::Var))
+            // Error recovery, so that we can report more than one error per compilation.
+            // The fallback values are never compiled, since any error aborts compilation.
+            .recover_with(via_parser(nested_delimiters('(', ')', [], |_| {
+                Expr::Num(0)
+            })))
+            .recover_with(skip_then_retry_until(
+                any().ignored(),
+                one_of(");:{},").ignored().or(end()),
+            ))
             .padded();
 
         // operations, both unary and binary
-        let mul_op = one_of("*/").map(Operator::try_from).map(Result::unwrap);
+        let mul_op = one_of("*/%").map(Operator::try_from).map(Result::unwrap);
         let add_op = one_of("+-").map(Operator::try_from).map(Result::unwrap);
+        let shift_op = choice((
+            just("<<").to(Operator::Shl),
+            just(">>>").to(Operator::LogicalShr),
+            just(">>").to(Operator::Shr),
+        ));
+        let cmp_op = choice((
+            just("<=").to(Operator::Le),
+            just(">=").to(Operator::Ge),
+            just('<').to(Operator::Lt),
+            just('>').to(Operator::Gt),
+        ));
+        let eq_op = just("==").to(Operator::Eq).or(just("!=").to(Operator::Ne));
 
         // ====== THE ACTUAL PARSER =====
-        // we define parsers for operations based on precedence
+        // we define parsers for operations based on precedence, just like in C:
         // First, unary expressions, which may occur 0..N times
-        // Second, multiplications,
-        // Third, additions.
+        // Second, multiplications, divisions and remainders,
+        // Third, additions,
+        // Fourth, shifts,
+        // Fifth, comparisons (first <, <=, > and >=, then == and !=),
+        // Sixth, the bitwise operations &, ^ and | (in that order),
+        // and finally conditionals, either as `if c then a else b` or `c ? a : b`.
         //
-        // Each of the three steps repeatedly looks for the pattern and then moves on.
-        // For example, in addition we look for the pattern: 1 unary expression,
-        // followed by an arbitrary number of tuples of (op, unary_op), like so:
+        // Each of the steps repeatedly looks for the pattern and then moves on.
+        // For example, in addition we look for the pattern: 1 product,
+        // followed by an arbitrary number of tuples of (op, product), like so:
         //
-        // expr = unary + (op + unary)*
+        // sum = product + (op + product)*
 
-        let unary = just('-')
-            .padded()
-            .repeated()
-            .foldr(atom, |_op, rhs| Expr::UnaryOp(Operator::Sub, Box::new(rhs)));
+        let unary = one_of("-~").padded().repeated().foldr(atom, |op, rhs| {
+            Expr::UnaryOp(Operator::try_from(op).unwrap(), Box::new(rhs))
+        });
+
+        let product = unary.clone().foldl(mul_op.then(unary).repeated(), binop);
 
-        let product = unary
+        let sum = product
             .clone()
-            .foldl(mul_op.then(unary).repeated(), |lhs, (op, rhs)| {
-                Expr::BinaryOp(Box::new(lhs), op, Box::new(rhs))
-            });
+            .foldl(add_op.then(product).repeated(), binop);
 
-        
+        let shift = sum
+            .clone()
+            .foldl(shift_op.then(sum).repeated(), binop)
+            .boxed();
+
+        let comparison = shift.clone().foldl(cmp_op.then(shift).repeated(), binop);
 
-        product
+        let equality = comparison
             .clone()
-            .foldl(add_op.then(product).repeated(), |lhs, (op, rhs)| {
-                Expr::BinaryOp(Box::new(lhs), op, Box::new(rhs))
-            })
+            .foldl(eq_op.then(comparison).repeated(), binop)
+            .boxed();
+
+        let bit_and = equality
+            .clone()
+            .foldl(just('&').to(Operator::And).then(equality).repeated(), binop);
+
+        let bit_xor = bit_and
+            .clone()
+            .foldl(just('^').to(Operator::Xor).then(bit_and).repeated(), binop);
+
+        let bit_or = bit_xor
+            .clone()
+            .foldl(just('|').to(Operator::Or).then(bit_xor).repeated(), binop);
+
+        let if_expr = keyword("if")
+            .ignore_then(expr.clone())
+            .then_ignore(keyword("then"))
+            .then(expr.clone())
+            .then_ignore(keyword("else"))
+            .then(expr.clone())
+            .map(|((c, a), b)| Expr::If(Box::new(c), Box::new(a), Box::new(b)));
+
+        // the conditional operator is right-associative: `a ? b : c ? d : e` is `a ? b : (c ? d : e)`
+        let ternary = bit_or
+            .then(
+                just('?')
+                    .padded()
+                    .ignore_then(expr.clone())
+                    .then_ignore(just(':').padded())
+                    .then(expr)
+                    .or_not(),
+            )
+            .map(|(c, branches)| match branches {
+                Some((a, b)) => Expr::If(Box::new(c), Box::new(a), Box::new(b)),
+                None => c,
+            });
+
+        if_expr.or(ternary).labelled("expression")
     })
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::arith::{Signedness, Width};
+
+    fn parse(input: &str) -> Result<Program, LpErr> {
+        parse_with(input, Arith::default())
+    }
+
+    fn parse_with(input: &str, arith: Arith) -> Result<Program, LpErr> {
+        run_parser(input, arith, &mut Diagnostics::default())
+    }
 
     #[test]
     fn parse_simple_expr() -> Result<(), LpErr> {
-        let expr = run_parser("(1 + 2)")?;
+        let expr = parse("(1 + 2)")?.result;
 
         assert_eq!(
             expr,
@@ -87,7 +416,7 @@ mod test {
 
     #[test]
     fn parse_simple_sym() -> Result<(), LpErr> {
-        let expr = run_parser("(1 + a)")?;
+        let expr = parse("(1 + a)")?.result;
 
         assert_eq!(
             expr,
@@ -102,7 +431,7 @@ mod test {
 
     #[test]
     fn parse_nested_parens() -> Result<(), LpErr> {
-        let expr = run_parser("((((1 + 2))))")?;
+        let expr = parse("((((1 + 2))))")?.result;
 
         assert_eq!(
             expr,
@@ -127,14 +456,363 @@ mod test {
         ];
 
         for input in inputs {
-            assert!(run_parser(input).is_err(), "`{input}` should fail but got `{:?}`", run_parser(input));
+            assert!(
+                parse(input).is_err(),
+                "`{input}` should fail but got `{:?}`",
+                parse(input)
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parse_errors_have_spans() {
+        let Err(LpErr::Parse(errs)) = parse("1 + * 2") else {
+            panic!("`1 + * 2` should not parse");
+        };
+
+        assert_eq!(errs.len(), 1);
+        assert_eq!(errs[0].span, 4..5);
+        assert_eq!(errs[0].found.as_deref(), Some("'*'"));
+    }
+
+    #[test]
+    fn tiny_fixed_point_literals_warn() -> Result<(), LpErr> {
+        let mut diagnostics = Diagnostics::default();
+        run_parser("x * 0.000001 + 0.0", Arith::default(), &mut diagnostics)?;
+        let found: Vec<_> = diagnostics
+            .iter()
+            .map(|d| (d.code, d.span.clone()))
+            .collect();
+        assert_eq!(found, [("fixed-point-zero", Some(4..12))]);
+
+        // with more fractional bits, the literal isn't zero
+        let mut diagnostics = Diagnostics::default();
+        let wide = Arith {
+            width: Width::W64,
+            ..Arith::default()
+        };
+        run_parser("x * 0.000001", wide, &mut diagnostics)?;
+        assert!(diagnostics.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_literals() -> Result<(), LpErr> {
+        let program = parse("0x1F + 0b1010_1010 + 0o17 + 1_000_000 + 0xffff_ffF + 2147483647")?;
+        let sum = [0x1F, 0b1010_1010, 0o17, 1_000_000, 0x0fff_ffff, 2147483647]
+            .map(|n| Box::new(Expr::Num(n)))
+            .into_iter()
+            .reduce(|l, r| Box::new(Expr::BinaryOp(l, Operator::Add, r)))
+            .unwrap();
+        assert_eq!(program.result, *sum);
+
+        for (input, span) in [
+            ("1 + 99999999999", 4..15),
+            ("0x1_0000_0000 * 2", 0..13),
+            ("2147483648", 0..10),
+        ] {
+            let Err(LpErr::Parse(errs)) = parse(input) else {
+                panic!("`{input}` should not parse");
+            };
+            assert_eq!(errs.len(), 1, "got {errs:?}");
+            assert_eq!(errs[0].span, span);
+            assert!(errs[0].reason.is_some());
+        }
+
+        for input in ["0x", "0b12", "1 + 0o8"] {
+            assert!(parse(input).is_err(), "`{input}` should fail");
+        }
+
+        let program = parse("1.5 * 2 + 0.25")?;
+        let fixed = |n| Box::new(Expr::FixedPoint(Box::new(Expr::Num(n))));
+        assert_eq!(
+            program.result,
+            Expr::BinaryOp(
+                Box::new(Expr::BinaryOp(
+                    fixed(3 << 15),
+                    Operator::Mul,
+                    Box::new(Expr::Num(2))
+                )),
+                Operator::Add,
+                fixed(1 << 14)
+            )
+        );
+        assert!(parse("40000.0").is_err());
+        assert!(parse("1.").is_err());
+
+        // the limit depends on the machine's word size and signedness
+        let arith = |width, signedness| Arith {
+            width,
+            signedness,
+            ..Default::default()
+        };
+        assert!(parse_with("127", arith(Width::W8, Signedness::Signed)).is_ok());
+        assert!(parse_with("128", arith(Width::W8, Signedness::Signed)).is_err());
+        assert!(parse_with("255", arith(Width::W8, Signedness::Unsigned)).is_ok());
+        assert!(
+            parse_with(
+                "0x7fff_ffff_ffff_ffff",
+                arith(Width::W64, Signedness::Signed)
+            )
+            .is_ok()
+        );
+        assert!(
+            parse_with(
+                "0x8000_0000_0000_0000",
+                arith(Width::W64, Signedness::Signed)
+            )
+            .is_err()
+        );
+        let program = parse_with(
+            "0xffff_ffff_ffff_ffff",
+            arith(Width::W64, Signedness::Unsigned),
+        )?;
+        assert_eq!(program.result, Expr::Num(-1));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_reports_all_errors() {
+        let Err(LpErr::Parse(errs)) = parse("(1 + *) * (2 - )") else {
+            panic!("input should not parse");
+        };
+
+        assert_eq!(errs.len(), 2, "got {errs:?}");
+        assert_eq!(errs[0].span, 5..6);
+        assert_eq!(errs[1].span, 15..16);
+    }
+
+    #[test]
+    fn parse_let_bindings() -> Result<(), LpErr> {
+        let program = parse("let t = x * 3;\nlet u = t;\nt * u")?;
+
+        assert_eq!(
+            program.stmts,
+            vec![
+                Stmt::Let(
+                    "t".to_string(),
+                    Expr::BinaryOp(
+                        Box::new(Expr::Var("x".to_string())),
+                        Operator::Mul,
+                        Box::new(Expr::Num(3))
+                    )
+                ),
+                Stmt::Let("u".to_string(), Expr::Var("t".to_string())),
+            ]
+        );
+        assert_eq!(
+            program.result,
+            Expr::BinaryOp(
+                Box::new(Expr::Var("t".to_string())),
+                Operator::Mul,
+                Box::new(Expr::Var("u".to_string()))
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_invalid_let() {
+        let inputs = vec![
+            "let x = 1;",
+            "let = 1; 2",
+            "let x = 1 x",
+            "let let = 1; 2",
+            "1 + let",
+            "if 1 then 2",
+            "if then 1 else 2",
+            "1 ? 2",
+            "a < b < c < ",
+            "x = ; 1",
+            "x = 1",
+            "while x { x = 1; ",
+            "while { } 1",
+            "repeat 3 { 1 } 2",
+            "fn f(a) = a; fn g(a) = a",
+            "fn f(a, 1) = a; 2",
+            "fn (a) = a; 2",
+            "f(1, ) + 1",
+            "let x = 1; fn f() = 1; 2",
+        ];
+
+        for input in inputs {
+            assert!(
+                parse(input).is_err(),
+                "`{input}` should fail but got `{:?}`",
+                parse(input)
+            );
         }
+    }
+
+    #[test]
+    fn parse_c_precedence() -> Result<(), LpErr> {
+        let expr = parse("a | b ^ c & 1 << 2 + 3 % ~4")?.result;
+
+        let var = |v: &str| Box::new(Expr::Var(v.to_string()));
+        let num = |n| Box::new(Expr::Num(n));
+        assert_eq!(
+            expr,
+            Expr::BinaryOp(
+                var("a"),
+                Operator::Or,
+                Box::new(Expr::BinaryOp(
+                    var("b"),
+                    Operator::Xor,
+                    Box::new(Expr::BinaryOp(
+                        var("c"),
+                        Operator::And,
+                        Box::new(Expr::BinaryOp(
+                            num(1),
+                            Operator::Shl,
+                            Box::new(Expr::BinaryOp(
+                                num(2),
+                                Operator::Add,
+                                Box::new(Expr::BinaryOp(
+                                    num(3),
+                                    Operator::Mod,
+                                    Box::new(Expr::UnaryOp(Operator::Not, num(4)))
+                                ))
+                            ))
+                        ))
+                    ))
+                ))
+            )
+        );
+
+        // `>>>` isn't `>>` followed by `>`
+        let expr = parse("a >>> 2 >> 1")?.result;
+        assert_eq!(
+            expr,
+            Expr::BinaryOp(
+                Box::new(Expr::BinaryOp(var("a"), Operator::LogicalShr, num(2))),
+                Operator::Shr,
+                num(1)
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_conditionals() -> Result<(), LpErr> {
+        let var = |v: &str| Box::new(Expr::Var(v.to_string()));
+        let num = |n| Box::new(Expr::Num(n));
+        let cond = || Box::new(Expr::BinaryOp(var("x"), Operator::Lt, num(3)));
+
+        let expected = Expr::If(cond(), num(1), Box::new(Expr::If(cond(), num(2), num(3))));
+        assert_eq!(
+            parse("if x < 3 then 1 else if x < 3 then 2 else 3")?.result,
+            expected
+        );
+        assert_eq!(parse("x < 3 ? 1 : x < 3 ? 2 : 3")?.result, expected);
+
+        let expected = Expr::BinaryOp(
+            num(1),
+            Operator::Add,
+            Box::new(Expr::If(
+                Box::new(Expr::BinaryOp(var("a"), Operator::Eq, var("b"))),
+                var("a"),
+                num(0),
+            )),
+        );
+        assert_eq!(parse("1 + (if a == b then a else 0)")?.result, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_loops() -> Result<(), LpErr> {
+        let var = |v: &str| Box::new(Expr::Var(v.to_string()));
+        let num = |n| Box::new(Expr::Num(n));
+
+        let program = parse("let i = 0; while i < 3 { i = i + 1; } i == 3")?;
+        assert_eq!(
+            program.stmts,
+            vec![
+                Stmt::Let("i".to_string(), Expr::Num(0)),
+                Stmt::While(
+                    Expr::BinaryOp(var("i"), Operator::Lt, num(3)),
+                    vec![Stmt::Assign(
+                        "i".to_string(),
+                        Expr::BinaryOp(var("i"), Operator::Add, num(1))
+                    )],
+                ),
+            ]
+        );
+        assert_eq!(
+            program.result,
+            Expr::BinaryOp(var("i"), Operator::Eq, num(3))
+        );
+
+        let program = parse(
+            "let x = 1;
+repeat 4 { x = x * 2; }
+x",
+        )?;
+        assert_eq!(
+            program.stmts,
+            vec![
+                Stmt::Let("x".to_string(), Expr::Num(1)),
+                Stmt::Let("repeat@11".to_string(), Expr::Num(4)),
+                Stmt::While(
+                    Expr::BinaryOp(var("repeat@11"), Operator::Gt, num(0)),
+                    vec![
+                        Stmt::Assign(
+                            "repeat@11".to_string(),
+                            Expr::BinaryOp(var("repeat@11"), Operator::Sub, num(1))
+                        ),
+                        Stmt::Assign(
+                            "x".to_string(),
+                            Expr::BinaryOp(var("x"), Operator::Mul, num(2))
+                        ),
+                    ],
+                ),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_functions() -> Result<(), LpErr> {
+        let var = |v: &str| Expr::Var(v.to_string());
+
+        let program =
+            parse("fn sq(a) = a * a;\nfn add(a, b) = a + b;\nfn one() = 1;\nadd(sq(x), one())")?;
+        assert_eq!(
+            program.functions,
+            vec![
+                Function {
+                    name: "sq".to_string(),
+                    params: vec!["a".to_string()],
+                    body: Expr::BinaryOp(Box::new(var("a")), Operator::Mul, Box::new(var("a"))),
+                },
+                Function {
+                    name: "add".to_string(),
+                    params: vec!["a".to_string(), "b".to_string()],
+                    body: Expr::BinaryOp(Box::new(var("a")), Operator::Add, Box::new(var("b"))),
+                },
+                Function {
+                    name: "one".to_string(),
+                    params: vec![],
+                    body: Expr::Num(1),
+                },
+            ]
+        );
+        assert_eq!(
+            program.result,
+            Expr::Call(
+                "add".to_string(),
+                vec![
+                    Expr::Call("sq".to_string(), vec![var("x")]),
+                    Expr::Call("one".to_string(), vec![])
+                ]
+            )
+        );
         Ok(())
     }
 
     #[test]
     fn parse_nested_1() -> Result<(), LpErr> {
-        let expr = run_parser("(1 + (2 * 3))")?;
+        let expr = parse("(1 + (2 * 3))")?.result;
 
         assert_eq!(
             expr,
@@ -153,7 +831,7 @@ mod test {
 
     #[test]
     fn parse_nested_2() -> Result<(), LpErr> {
-        let expr = run_parser("((1 + 2) * 3)")?;
+        let expr = parse("((1 + 2) * 3)")?.result;
 
         assert_eq!(
             expr,
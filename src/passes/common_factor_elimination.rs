@@ -1,9 +1,15 @@
-use crate::types::{Expr, Operator};
+use crate::types::{Expr, Operator, Program};
 
 pub trait CommonFactorElimination {
     fn extract_common_factors(self) -> Self;
 }
 
+impl CommonFactorElimination for Program {
+    fn extract_common_factors(self) -> Self {
+        self.map_exprs(Expr::extract_common_factors)
+    }
+}
+
 impl CommonFactorElimination for Expr {
     fn extract_common_factors(self) -> Self {
         match self {
@@ -37,6 +43,16 @@ impl CommonFactorElimination for Expr {
                 let expr = expr.extract_common_factors();
                 Expr::UnaryOp(op, Box::new(expr))
             }
+            Expr::If(cond, then, otherwise) => Expr::If(
+                Box::new(cond.extract_common_factors()),
+                Box::new(then.extract_common_factors()),
+                Box::new(otherwise.extract_common_factors()),
+            ),
+            Expr::Call(name, args) => Expr::Call(
+                name,
+                args.into_iter().map(Expr::extract_common_factors).collect(),
+            ),
+            Expr::FixedPoint(raw) => Expr::FixedPoint(Box::new(raw.extract_common_factors())),
             _ => self,
         }
     }
@@ -78,6 +94,15 @@ fn expressions_equal(a: &Expr, b: &Expr) -> bool {
         (Expr::BinaryOp(l1, op1, r1), Expr::BinaryOp(l2, op2, r2)) => {
             op1 == op2 && expressions_equal(l1, l2) && expressions_equal(r1, r2)
         }
+        (Expr::If(c1, t1, o1), Expr::If(c2, t2, o2)) => {
+            expressions_equal(c1, c2) && expressions_equal(t1, t2) && expressions_equal(o1, o2)
+        }
+        (Expr::Call(f1, a1), Expr::Call(f2, a2)) => {
+            f1 == f2
+                && a1.len() == a2.len()
+                && a1.iter().zip(a2).all(|(x, y)| expressions_equal(x, y))
+        }
+        (Expr::FixedPoint(r1), Expr::FixedPoint(r2)) => expressions_equal(r1, r2),
         _ => false,
     }
 }
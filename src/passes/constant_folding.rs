@@ -1,56 +1,199 @@
-use std::ops::Neg;
+use std::collections::{HashMap, HashSet};
 
-use crate::types::{Expr, Operator};
+use crate::arith::{Arith, ArithError};
+use crate::diagnostics::{Diagnostic, Diagnostics};
+use crate::types::{Expr, Function, Operator, Program, Stmt};
 
 pub trait ConstantFold {
-    fn run_constant_fold(self) -> Self;
+    /// Folds with the same arithmetic as the interpreter, so that folding never changes a result.
+    /// Operations that fail are left to the interpreter and reported in `diagnostics`.
+    fn run_constant_fold(self, arith: Arith, diagnostics: &mut Diagnostics) -> Self;
+}
+
+impl ConstantFold for Program {
+    /// Folds all expressions and propagates bindings with a constant value into the rest of the
+    /// program. Propagated bindings are removed, since they aren't used anymore. Bindings that are
+    /// assigned to are never propagated, since their value changes.
+    fn run_constant_fold(self, arith: Arith, diagnostics: &mut Diagnostics) -> Self {
+        let mut assigned = HashSet::new();
+        collect_assigned(&self.stmts, &mut assigned);
+
+        // the constant value of each binding in scope, if any
+        let mut constants = HashMap::new();
+        let stmts = fold_stmts(self.stmts, &mut constants, &assigned, arith, diagnostics);
+
+        // function bodies only see their parameters
+        let functions = self
+            .functions
+            .into_iter()
+            .map(|f| Function {
+                body: fold(f.body, &HashMap::new(), arith, diagnostics),
+                ..f
+            })
+            .collect();
+
+        Program {
+            functions,
+            stmts,
+            result: fold(self.result, &constants, arith, diagnostics),
+        }
+    }
+}
+
+fn fold_stmts(
+    stmts: Vec<Stmt>,
+    constants: &mut HashMap<String, i64>,
+    assigned: &HashSet<String>,
+    arith: Arith,
+    diagnostics: &mut Diagnostics,
+) -> Vec<Stmt> {
+    let mut res = vec![];
+
+    for stmt in stmts {
+        match stmt {
+            Stmt::Let(name, value) => {
+                let value = fold(value, constants, arith, diagnostics);
+                match constant(&value) {
+                    Some(n) if !assigned.contains(&name) => {
+                        constants.insert(name, n);
+                    }
+                    _ => {
+                        // the new binding shadows any constant of the same name
+                        constants.remove(&name);
+                        res.push(Stmt::Let(name, value));
+                    }
+                }
+            }
+            Stmt::Assign(name, value) => res.push(Stmt::Assign(
+                name,
+                fold(value, constants, arith, diagnostics),
+            )),
+            Stmt::While(cond, body) => {
+                let cond = fold(cond, constants, arith, diagnostics);
+                if cond == Expr::Num(0) {
+                    // the loop never runs
+                    continue;
+                }
+                // bindings inside the body go out of scope after the loop
+                let body = fold_stmts(body, &mut constants.clone(), assigned, arith, diagnostics);
+                res.push(Stmt::While(cond, body));
+            }
+        }
+    }
+
+    res
+}
+
+/// The value of a constant, with fixed-point numbers represented by the integer storing them.
+fn constant(expr: &Expr) -> Option<i64> {
+    match expr {
+        Expr::Num(n) => Some(*n),
+        Expr::FixedPoint(raw) => constant(raw),
+        _ => None,
+    }
+}
+
+/// Collect the names of all bindings that are assigned to anywhere in `stmts`.
+fn collect_assigned(stmts: &[Stmt], assigned: &mut HashSet<String>) {
+    for stmt in stmts {
+        match stmt {
+            Stmt::Let(..) => (),
+            Stmt::Assign(name, _) => {
+                assigned.insert(name.clone());
+            }
+            Stmt::While(_, body) => collect_assigned(body, assigned),
+        }
+    }
 }
 
 impl ConstantFold for Expr {
-    fn run_constant_fold(self) -> Self {
-        match self {
-            Expr::Num(_) | Expr::Var(_) =>
-            /* no work to be done */
+    fn run_constant_fold(self, arith: Arith, diagnostics: &mut Diagnostics) -> Self {
+        fold(self, &HashMap::new(), arith, diagnostics)
+    }
+}
+
+fn fold(
+    expr: Expr,
+    constants: &HashMap<String, i64>,
+    arith: Arith,
+    diagnostics: &mut Diagnostics,
+) -> Expr {
+    match expr {
+        Expr::Num(_) =>
+        /* no work to be done */
+        {
+            expr
+        }
+        Expr::Var(ref v) => constants.get(v).map_or(expr, |n| Expr::Num(*n)),
+        Expr::UnaryOp(operator, expr) => {
+            let e = fold(*expr, constants, arith, diagnostics);
+
+            if let Expr::Num(n) = e
+                && let Some(res) = evaluate(arith.unary(operator, n), operator, diagnostics)
             {
-                self
+                return Expr::Num(res);
             }
-            Expr::UnaryOp(operator, expr) => {
-                let e = expr.run_constant_fold();
 
-                if let Expr::Num(n) = e
-                    && operator == Operator::Sub
-                {
-                    return Expr::Num(n.neg());
-                }
+            Expr::UnaryOp(operator, Box::new(e))
+        }
+        Expr::BinaryOp(lhs, operator, rhs) => {
+            let l = fold(*lhs, constants, arith, diagnostics);
+            let r = fold(*rhs, constants, arith, diagnostics);
 
-                Expr::UnaryOp(operator, Box::new(e))
+            if let Expr::Num(left) = l
+                && let Expr::Num(right) = r
+                && let Some(res) =
+                    evaluate(arith.binary(operator, left, right), operator, diagnostics)
+            {
+                return res.into();
             }
-            Expr::BinaryOp(lhs, operator, rhs) => {
-                let l = lhs.run_constant_fold();
-                let r = rhs.run_constant_fold();
-
-                if let Expr::Num(left) = l
-                    && let Expr::Num(right) = r
-                {
-                    let res = match operator {
-                        Operator::Add => left + right,
-                        Operator::Sub => left - right,
-                        Operator::Mul => left * right,
-                        Operator::Div => {
-                            if right == 0 {
-                                eprintln!("Warning: detected division by zero during constant folding; not folding.");
-                                return Expr::BinaryOp(Box::new(l), operator, Box::new(r));
-                            }
-                            left / right
-                        }
-                        Operator::Shl => left << right,
-                        Operator::Shr => left >> right,
-                    };
-                    return res.into();
-                }
 
-                Expr::BinaryOp(Box::new(l), operator, Box::new(r))
+            Expr::BinaryOp(Box::new(l), operator, Box::new(r))
+        }
+        Expr::If(cond, then, otherwise) => {
+            let c = fold(*cond, constants, arith, diagnostics);
+
+            // with a known condition, only one of the branches is ever executed
+            match c {
+                Expr::Num(0) => fold(*otherwise, constants, arith, diagnostics),
+                Expr::Num(_) => fold(*then, constants, arith, diagnostics),
+                _ => Expr::If(
+                    Box::new(c),
+                    Box::new(fold(*then, constants, arith, diagnostics)),
+                    Box::new(fold(*otherwise, constants, arith, diagnostics)),
+                ),
             }
         }
+        Expr::Call(name, args) => Expr::Call(
+            name,
+            args.into_iter()
+                .map(|a| fold(a, constants, arith, diagnostics))
+                .collect(),
+        ),
+        Expr::FixedPoint(raw) => match fold(*raw, constants, arith, diagnostics) {
+            // a branch of a folded `if` is already marked
+            raw @ Expr::FixedPoint(_) => raw,
+            raw => Expr::FixedPoint(Box::new(raw)),
+        },
     }
 }
+
+/// The folded value, or `None` if the operation fails and has to be left to the interpreter, which
+/// reports the error when (and if) the operation is actually executed.
+fn evaluate(
+    res: Result<i64, ArithError>,
+    op: Operator,
+    diagnostics: &mut Diagnostics,
+) -> Option<i64> {
+    let diagnostic = match res {
+        Ok(n) => return Some(n),
+        Err(ArithError::DivisionByZero) => {
+            Diagnostic::warning("fold-divzero", "diagnostics.fold_divzero")
+        }
+        Err(ArithError::Overflow) => {
+            Diagnostic::warning("fold-overflow", "diagnostics.fold_overflow")
+        }
+    };
+    diagnostics.push(diagnostic.arg("op", op));
+    None
+}
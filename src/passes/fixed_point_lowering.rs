@@ -0,0 +1,336 @@
+use rust_i18n::t;
+
+use crate::arith::Arith;
+use crate::types::{Expr, Function, LpErr, Operator, Program, Stmt};
+
+pub trait FixedPointLowering: Sized {
+    /// Replaces arithmetic on fixed-point numbers with integer arithmetic on the integers storing
+    /// them, converting integers that are mixed with fixed-point numbers. Afterwards, each
+    /// fixed-point value is marked with [`Expr::FixedPoint`].
+    fn lower_fixed_point(self, arith: Arith) -> Result<Self, LpErr>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Type {
+    Int,
+    FixedPoint,
+}
+
+struct Lowering {
+    frac_bits: i64,
+    /// The type of each binding in scope; later bindings shadow earlier ones with the same name.
+    bindings: Vec<(String, Type)>,
+    /// Name and result type of each function lowered so far.
+    functions: Vec<(String, Type)>,
+}
+
+impl FixedPointLowering for Program {
+    fn lower_fixed_point(self, arith: Arith) -> Result<Self, LpErr> {
+        let mut lowering = Lowering {
+            frac_bits: arith.width.frac_bits().into(),
+            bindings: vec![],
+            functions: vec![],
+        };
+
+        let mut functions = Vec::with_capacity(self.functions.len());
+        for function in self.functions {
+            // parameters are always integers
+            lowering.bindings = function
+                .params
+                .iter()
+                .map(|p| (p.clone(), Type::Int))
+                .collect();
+            let (body, ty) = lowering.expr(function.body)?;
+            lowering.functions.push((function.name.clone(), ty));
+            functions.push(Function { body, ..function });
+        }
+
+        lowering.bindings.clear();
+        let stmts = lowering.stmts(self.stmts)?;
+        let (result, _) = lowering.expr(self.result)?;
+
+        Ok(Program {
+            functions,
+            stmts,
+            result,
+        })
+    }
+}
+
+/// Mark `raw` as the integer storing a fixed-point number.
+fn fixed_point(raw: Expr) -> (Expr, Type) {
+    (Expr::FixedPoint(Box::new(raw)), Type::FixedPoint)
+}
+
+fn binop(lhs: Expr, op: Operator, rhs: Expr) -> Expr {
+    Expr::BinaryOp(Box::new(lhs), op, Box::new(rhs))
+}
+
+fn unsupported(op: Operator) -> LpErr {
+    LpErr::IR(t!("compiler.error.fixed_point_op", op = op).to_string())
+}
+
+impl Lowering {
+    /// The product of the fixed-point numbers stored in `a` and `b`, which has twice the fractional
+    /// bits. Shifting the product back would overflow for numbers that fit into a value, so the
+    /// factors are split into their upper and lower halves `a = ah * 2^h + al`, and
+    /// `a * b / 2^f = ah * bh + ah * bl / 2^h + al * bh / 2^h`, leaving out `al * bl / 2^f`,
+    /// which is less than one. None of the partial products overflows.
+    fn product(&self, a: Expr, b: Expr) -> Expr {
+        let half = self.frac_bits / 2;
+        let high = |e: &Expr| binop(e.clone(), Operator::Shr, Expr::Num(half));
+        let low = |e: &Expr| binop(e.clone(), Operator::And, Expr::Num((1 << half) - 1));
+        let cross = |h, l| binop(binop(h, Operator::Mul, l), Operator::Shr, Expr::Num(half));
+
+        binop(
+            binop(
+                binop(high(&a), Operator::Mul, high(&b)),
+                Operator::Add,
+                cross(high(&a), low(&b)),
+            ),
+            Operator::Add,
+            cross(low(&a), high(&b)),
+        )
+    }
+
+    /// The quotient of the fixed-point numbers stored in `a` and `b`, which has no fractional bits
+    /// left. The dividend would overflow when shifted by all of them beforehand, so the quotient
+    /// is computed by long division instead, bringing down half of the bits of the remainder at a
+    /// time: `a * 2^f / b = q * 2^f + q1 * 2^h + q2` with `a = q * b + r`,
+    /// `r * 2^h = q1 * b + r1` and `r1 * 2^h = q2 * b + r2`. The divisor is never shifted, so
+    /// small divisors stay exact; the remainders only overflow for divisors of at least
+    /// `2^(h - 1)`.
+    fn quotient(&self, a: Expr, b: Expr) -> Expr {
+        let half = self.frac_bits / 2;
+        let shl = |e, n| binop(e, Operator::Shl, Expr::Num(n));
+
+        let r = binop(a.clone(), Operator::Mod, b.clone());
+        let r1 = binop(shl(r.clone(), half), Operator::Mod, b.clone());
+        let q = binop(a, Operator::Div, b.clone());
+        let q1 = binop(shl(r, half), Operator::Div, b.clone());
+        let q2 = binop(shl(r1, half), Operator::Div, b);
+
+        binop(
+            binop(shl(q, self.frac_bits), Operator::Add, shl(q1, half)),
+            Operator::Add,
+            q2,
+        )
+    }
+
+    /// Type of the binding `name`; input variables are integers.
+    fn binding(&self, name: &str) -> Type {
+        self.bindings
+            .iter()
+            .rev()
+            .find(|(n, _)| n == name)
+            .map_or(Type::Int, |(_, ty)| *ty)
+    }
+
+    /// The integer storing `expr` as a fixed-point number, converting integers.
+    fn raw(&self, (expr, ty): (Expr, Type)) -> Expr {
+        match (expr, ty) {
+            (Expr::FixedPoint(raw), _) => *raw,
+            (expr, Type::FixedPoint) => expr,
+            (expr, Type::Int) => binop(expr, Operator::Shl, Expr::Num(self.frac_bits)),
+        }
+    }
+
+    fn stmts(&mut self, stmts: Vec<Stmt>) -> Result<Vec<Stmt>, LpErr> {
+        let mut res = Vec::with_capacity(stmts.len());
+        for stmt in stmts {
+            res.push(match stmt {
+                Stmt::Let(name, value) => {
+                    let (value, ty) = self.expr(value)?;
+                    self.bindings.push((name.clone(), ty));
+                    Stmt::Let(name, value)
+                }
+                Stmt::Assign(name, value) => {
+                    // a binding keeps the type of its first value
+                    let value = match (self.binding(&name), self.expr(value)?) {
+                        (Type::Int, (_, Type::FixedPoint)) => {
+                            return Err(LpErr::IR(
+                                t!("compiler.error.fixed_point_assign", v = name).to_string(),
+                            ));
+                        }
+                        (Type::Int, (value, Type::Int)) => value,
+                        (Type::FixedPoint, value) => fixed_point(self.raw(value)).0,
+                    };
+                    Stmt::Assign(name, value)
+                }
+                Stmt::While(cond, body) => {
+                    let (cond, _) = self.expr(cond)?;
+                    // bindings inside the body go out of scope after the loop
+                    let outer = self.bindings.len();
+                    let body = self.stmts(body)?;
+                    self.bindings.truncate(outer);
+                    Stmt::While(cond, body)
+                }
+            });
+        }
+        Ok(res)
+    }
+
+    fn expr(&self, expr: Expr) -> Result<(Expr, Type), LpErr> {
+        Ok(match expr {
+            Expr::Num(_) => (expr, Type::Int),
+            // a literal
+            Expr::FixedPoint(_) => (expr, Type::FixedPoint),
+            Expr::Var(ref v) => match self.binding(v) {
+                Type::Int => (expr, Type::Int),
+                Type::FixedPoint => fixed_point(expr),
+            },
+            Expr::UnaryOp(op, e) => match (op, self.expr(*e)?) {
+                (_, (e, Type::Int)) => (Expr::UnaryOp(op, Box::new(e)), Type::Int),
+                (Operator::Sub, e) => fixed_point(Expr::UnaryOp(op, Box::new(self.raw(e)))),
+                _ => return Err(unsupported(op)),
+            },
+            Expr::BinaryOp(lhs, op, rhs) => self.binary(self.expr(*lhs)?, op, self.expr(*rhs)?)?,
+            Expr::If(cond, then, otherwise) => {
+                // a fixed-point number is zero exactly if the integer storing it is
+                let (cond, _) = self.expr(*cond)?;
+                match (self.expr(*then)?, self.expr(*otherwise)?) {
+                    ((then, Type::Int), (otherwise, Type::Int)) => (
+                        Expr::If(Box::new(cond), Box::new(then), Box::new(otherwise)),
+                        Type::Int,
+                    ),
+                    // both branches are marked, since each of them computes the result
+                    (then, otherwise) => fixed_point(Expr::If(
+                        Box::new(cond),
+                        Box::new(fixed_point(self.raw(then)).0),
+                        Box::new(fixed_point(self.raw(otherwise)).0),
+                    )),
+                }
+            }
+            Expr::Call(name, args) => {
+                let mut int_args = Vec::with_capacity(args.len());
+                for arg in args {
+                    match self.expr(arg)? {
+                        (arg, Type::Int) => int_args.push(arg),
+                        (_, Type::FixedPoint) => {
+                            return Err(LpErr::IR(
+                                t!("compiler.error.fixed_point_argument", f = name).to_string(),
+                            ));
+                        }
+                    }
+                }
+
+                let ty = self
+                    .functions
+                    .iter()
+                    .rev()
+                    .find(|(f, _)| *f == name)
+                    .map_or(Type::Int, |(_, ty)| *ty);
+                let call = Expr::Call(name, int_args);
+                match ty {
+                    Type::Int => (call, Type::Int),
+                    Type::FixedPoint => fixed_point(call),
+                }
+            }
+        })
+    }
+
+    fn binary(
+        &self,
+        lhs: (Expr, Type),
+        op: Operator,
+        rhs: (Expr, Type),
+    ) -> Result<(Expr, Type), LpErr> {
+        Ok(match (lhs.1, op, rhs.1) {
+            (Type::Int, _, Type::Int) => (binop(lhs.0, op, rhs.0), Type::Int),
+            // scaling by an integer doesn't move the binary point
+            (Type::FixedPoint, Operator::Mul | Operator::Div, Type::Int) => {
+                fixed_point(binop(self.raw(lhs), op, rhs.0))
+            }
+            (Type::Int, Operator::Mul, Type::FixedPoint) => {
+                fixed_point(binop(lhs.0, op, self.raw(rhs)))
+            }
+            (_, Operator::Shl | Operator::Shr | Operator::LogicalShr, Type::Int) => {
+                fixed_point(binop(self.raw(lhs), op, rhs.0))
+            }
+            (_, Operator::Mul, _) => fixed_point(self.product(self.raw(lhs), self.raw(rhs))),
+            (_, Operator::Div, _) => fixed_point(self.quotient(self.raw(lhs), self.raw(rhs))),
+            (_, Operator::Add | Operator::Sub | Operator::Mod, _) => {
+                fixed_point(binop(self.raw(lhs), op, self.raw(rhs)))
+            }
+            (
+                _,
+                Operator::Eq
+                | Operator::Ne
+                | Operator::Lt
+                | Operator::Le
+                | Operator::Gt
+                | Operator::Ge,
+                _,
+            ) => (binop(self.raw(lhs), op, self.raw(rhs)), Type::Int),
+            // bitwise operations and shifts by fixed-point numbers make no sense
+            _ => return Err(unsupported(op)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::diagnostics::Diagnostics;
+    use crate::parser::run_parser;
+
+    fn lower(input: &str) -> Result<Program, LpErr> {
+        run_parser(input, Arith::default(), &mut Diagnostics::default())?
+            .lower_fixed_point(Arith::default())
+    }
+
+    #[test]
+    fn lowering() -> Result<(), LpErr> {
+        let num = |n| Box::new(Expr::Num(n));
+        let fixed = |e| Expr::FixedPoint(Box::new(e));
+
+        // the integer is converted, the sum is a fixed-point number
+        let program = lower("1.5 + 2")?;
+        assert_eq!(
+            program.result,
+            fixed(Expr::BinaryOp(
+                num(3 << 15),
+                Operator::Add,
+                Box::new(Expr::BinaryOp(num(2), Operator::Shl, num(16)))
+            ))
+        );
+
+        // fixed-point bindings are marked where they are used
+        let program = lower("let h = 0.5; h * h < 1")?;
+        let h = |op, n| Box::new(Expr::BinaryOp(Box::new(Expr::Var("h".into())), op, num(n)));
+        let mul = |a, b| Box::new(Expr::BinaryOp(a, Operator::Mul, b));
+        let cross = |a, b| Box::new(Expr::BinaryOp(mul(a, b), Operator::Shr, num(8)));
+        let high = || h(Operator::Shr, 8);
+        let low = || h(Operator::And, 255);
+        assert_eq!(
+            program.result,
+            Expr::BinaryOp(
+                Box::new(Expr::BinaryOp(
+                    Box::new(Expr::BinaryOp(
+                        mul(high(), high()),
+                        Operator::Add,
+                        cross(high(), low())
+                    )),
+                    Operator::Add,
+                    cross(low(), high())
+                )),
+                Operator::Lt,
+                Box::new(Expr::BinaryOp(num(1), Operator::Shl, num(16)))
+            )
+        );
+
+        for input in [
+            "1.5 & 1",
+            "~0.5",
+            "1 << 0.5",
+            "fn f(a) = a; f(0.5)",
+            "let i = 0; i = i + 0.5; i",
+        ] {
+            assert!(
+                matches!(lower(input), Err(LpErr::IR(_))),
+                "`{input}` should fail"
+            );
+        }
+        Ok(())
+    }
+}
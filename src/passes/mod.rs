@@ -1,16 +1,23 @@
-use crate::types::Inst;
+use crate::types::{Inst, InstAddr};
 use std::collections::HashSet;
 
 mod common_factor_elimination;
 mod constant_folding;
+mod fixed_point_lowering;
+mod register_allocation;
 mod shift_replacement;
 
 pub use common_factor_elimination::CommonFactorElimination;
 pub use constant_folding::ConstantFold;
+pub use fixed_point_lowering::FixedPointLowering;
+pub use register_allocation::{
+    RegisterAllocation, VIRTUAL_REGISTERS, allocate_registers, virtual_register,
+};
 pub use shift_replacement::ShiftReplacement;
 
-/// Remove cache writes of lines that are never loaded
-pub fn run_cache_optimization(instructions: Vec<Inst>) -> Vec<Inst> {
+/// Remove cache writes of lines that are never loaded. Also returns the new position of each
+/// instruction, see [`remove_instructions`].
+pub fn run_cache_optimization(instructions: Vec<Inst>) -> (Vec<Inst>, Vec<InstAddr>) {
     let loaded_lines: HashSet<usize> = instructions
         .iter()
         .filter_map(|i| match i {
@@ -19,11 +26,40 @@ pub fn run_cache_optimization(instructions: Vec<Inst>) -> Vec<Inst> {
         })
         .collect();
 
-    instructions
+    remove_instructions(instructions, |i| match i {
+        Inst::Write(_, target_addr) => !loaded_lines.contains(target_addr),
+        _ => false,
+    })
+}
+
+/// Remove all instructions matching `remove`, adjusting jump targets to the new positions.
+/// Jumps to a removed instruction continue at the next remaining one.
+///
+/// Also returns the new position of each instruction, or of the next remaining one if it was removed.
+pub fn remove_instructions(
+    instructions: Vec<Inst>,
+    remove: impl Fn(&Inst) -> bool,
+) -> (Vec<Inst>, Vec<InstAddr>) {
+    // new_addr[i] is the new position of the first remaining instruction at or after i
+    let mut new_addr = Vec::with_capacity(instructions.len() + 1);
+    let mut kept = 0;
+    for inst in &instructions {
+        new_addr.push(kept);
+        if !remove(inst) {
+            kept += 1;
+        }
+    }
+    new_addr.push(kept);
+
+    let instructions = instructions
         .into_iter()
-        .filter(|i| match i {
-            Inst::Write(_, target_addr) => loaded_lines.contains(target_addr),
-            _ => true,
+        .filter(|i| !remove(i))
+        .map(|i| match i {
+            Inst::Jump(target) => Inst::Jump(new_addr[target]),
+            Inst::JumpIfZero(r, target) => Inst::JumpIfZero(r, new_addr[target]),
+            Inst::Call(target) => Inst::Call(new_addr[target]),
+            _ => i,
         })
-        .collect()
+        .collect();
+    (instructions, new_addr)
 }
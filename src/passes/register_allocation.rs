@@ -0,0 +1,678 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::types::{Inst, InstAddr, MAX_REGISTERS, MemAddr, Reg};
+
+/// How the values of a program are assigned to registers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RegisterAllocation {
+    /// Hand out the registers in turn while generating code, evicting whatever is in the next one.
+    #[default]
+    RoundRobin,
+    /// Walk over the live ranges in the order they start, spilling the one that ends last when
+    /// running out of registers.
+    LinearScan,
+    /// Color the graph of live ranges that overlap, spilling the ones that are used least when
+    /// there are not enough colors.
+    GraphColoring,
+}
+
+/// Number of registers the code is generated with before it is mapped to the real ones.
+pub const VIRTUAL_REGISTERS: u8 = MAX_REGISTERS;
+
+/// Number of times the allocation is retried after spilling before giving up.
+const MAX_ROUNDS: usize = 8;
+
+// Registers introduced by the allocator. They are far outside of the registers a machine can have,
+// so they can't be confused with the registers of the generated code.
+/// Registers fixed by the calling convention.
+const FIXED_BASE: u32 = 0x1000;
+/// Live ranges of the generated code.
+const RANGE_BASE: u32 = 0x10000;
+/// Short-lived registers holding a spilled value between its load or write and its use.
+const TEMP_BASE: u32 = 0x80000;
+/// Registers of code that was generated without any limit on the number of registers.
+const VIRTUAL_BASE: u32 = 0x40000;
+
+fn name(base: u32, n: usize) -> Reg {
+    Reg(base + n as u32)
+}
+
+/// The `n`th of arbitrarily many registers code can be generated with before it is passed to
+/// [`allocate_registers`].
+pub fn virtual_register(n: usize) -> Reg {
+    name(VIRTUAL_BASE, n)
+}
+
+fn is_temp(reg: Reg) -> bool {
+    reg.0 >= TEMP_BASE
+}
+
+/// Code whose registers were allocated by [`allocate_registers`].
+pub struct Allocated {
+    pub code: Vec<Inst>,
+    /// The new position of each instruction, or `None` if it was removed.
+    pub new_addr: Vec<Option<InstAddr>>,
+    /// Number of RAM cells used for spilled values.
+    pub spill_cells: usize,
+}
+
+/// An instruction of the code being allocated, together with the registers it reads and writes.
+#[derive(Clone)]
+struct Item {
+    /// `None` at the entry of a function, where the parameters are defined.
+    inst: Option<Inst>,
+    uses: Vec<Reg>,
+    defs: Vec<Reg>,
+    /// The instruction of the original code this one belongs to. Jumps to that instruction lead to
+    /// the first item belonging to it.
+    group: InstAddr,
+    /// Whether this is the original instruction itself, as opposed to one inserted for it.
+    orig: bool,
+}
+
+impl Item {
+    fn new(inst: Inst, group: InstAddr) -> Self {
+        Self {
+            uses: inst.sources(),
+            defs: inst.target().into_iter().collect(),
+            inst: Some(inst),
+            group,
+            orig: false,
+        }
+    }
+}
+
+/// The values live before and after each item.
+struct Liveness {
+    live_in: Vec<BTreeSet<Reg>>,
+    live_out: Vec<BTreeSet<Reg>>,
+}
+
+/// Map the registers of `code`, which was generated with [`VIRTUAL_REGISTERS`] registers or with
+/// [`virtual_register`]s, to `num_registers` registers. Values that don't fit are spilled to RAM cells from `first_cell` on.
+///
+/// `entries` holds the address and number of parameters of each function. Returns `None` if the
+/// allocation doesn't succeed within a few rounds of spilling.
+pub fn allocate_registers(
+    code: &[Inst],
+    entries: &[(InstAddr, usize)],
+    num_registers: u8,
+    strategy: RegisterAllocation,
+    first_cell: MemAddr,
+) -> Option<Allocated> {
+    let (mut items, precolored) = split_fixed_registers(code, entries);
+    let precolored = rename_live_ranges(&mut items, &precolored, code.len());
+    let live = liveness(&items, code.len());
+    remove_dead_copies(&mut items, &live);
+
+    let mut spill_cells = 0;
+    let mut temps = 0;
+    // the callee may overwrite any register, so values still needed after a call wait in RAM
+    let live = liveness(&items, code.len());
+    let across_calls: BTreeSet<Reg> = items
+        .iter()
+        .enumerate()
+        .filter(|(_, item)| matches!(item.inst, Some(Inst::Call(_))))
+        .flat_map(|(i, item)| live.live_out[i].iter().filter(|r| !item.defs.contains(r)))
+        .copied()
+        .collect();
+    for reg in across_calls {
+        spill(&mut items, reg, first_cell + spill_cells, &mut temps);
+        spill_cells += 1;
+    }
+    for _ in 0..MAX_ROUNDS {
+        let live = liveness(&items, code.len());
+        let colors = match strategy {
+            RegisterAllocation::RoundRobin => {
+                unreachable!("round-robin allocation happens during code generation")
+            }
+            RegisterAllocation::LinearScan => {
+                linear_scan(&items, &live, &precolored, num_registers)
+            }
+            RegisterAllocation::GraphColoring => {
+                graph_coloring(&items, &live, &precolored, num_registers)
+            }
+        };
+
+        match colors {
+            Ok(colors) => return Some(emit(&items, &colors, code.len(), spill_cells)),
+            // nothing left that could be spilled
+            Err(spilled) if spilled.is_empty() => return None,
+            Err(spilled) => {
+                for reg in spilled {
+                    spill(&mut items, reg, first_cell + spill_cells, &mut temps);
+                    spill_cells += 1;
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Turn `code` into items, moving the registers fixed by the calling convention into registers of
+/// their own. These only live for a single instruction, from where they are copied from or to the
+/// registers of the generated code, which are free to end up anywhere.
+///
+/// Also returns the register each of the fixed ones has to end up in.
+fn split_fixed_registers(
+    code: &[Inst],
+    entries: &[(InstAddr, usize)],
+) -> (Vec<Item>, BTreeMap<Reg, u8>) {
+    let mut items: Vec<Item> = Vec::with_capacity(code.len());
+    let mut precolored = BTreeMap::new();
+    let mut fixed = |color: u8| {
+        let reg = name(FIXED_BASE, precolored.len());
+        precolored.insert(reg, color);
+        reg
+    };
+    let arity = |target: InstAddr| entries.iter().find(|(e, _)| *e == target).map(|(_, n)| *n);
+
+    for (addr, inst) in code.iter().enumerate() {
+        // the parameters arrive in the first registers
+        if let Some(params) = arity(addr) {
+            let params: Vec<Reg> = (0..params).map(|i| fixed(i as u8)).collect();
+            items.push(Item {
+                inst: None,
+                uses: vec![],
+                defs: params.clone(),
+                group: addr,
+                orig: false,
+            });
+            for (i, param) in params.into_iter().enumerate() {
+                items.push(Item::new(Inst::Copy(param, Reg::from(i as u8)), addr));
+            }
+        }
+
+        match inst {
+            Inst::Call(target) => {
+                // the arguments are popped into their registers right before the call
+                let arity = arity(*target).unwrap_or(0);
+                let mut args = vec![];
+                let first = items.len() - arity;
+                for (i, item) in items[first..].iter_mut().rev().enumerate() {
+                    let arg = fixed(i as u8);
+                    *item = Item {
+                        orig: true,
+                        ..Item::new(Inst::Pop(arg), item.group)
+                    };
+                    args.push(arg);
+                }
+
+                let result = fixed(0);
+                items.push(Item {
+                    inst: Some(inst.clone()),
+                    uses: args,
+                    defs: vec![result],
+                    group: addr,
+                    orig: true,
+                });
+                items.push(Item::new(Inst::Copy(result, Reg(0)), addr));
+            }
+            Inst::Ret => {
+                let result = fixed(0);
+                items.push(Item::new(Inst::Copy(Reg(0), result), addr));
+                items.push(Item {
+                    inst: Some(Inst::Ret),
+                    uses: vec![result],
+                    defs: vec![],
+                    group: addr,
+                    orig: true,
+                });
+            }
+            _ => items.push(Item {
+                orig: true,
+                ..Item::new(inst.clone(), addr)
+            }),
+        }
+    }
+    (items, precolored)
+}
+
+/// Index of the first item belonging to each instruction of the original code, including one
+/// behind the last instruction.
+fn group_starts(items: &[Item], len: usize) -> Vec<usize> {
+    let mut starts = vec![items.len(); len + 1];
+    for (i, item) in items.iter().enumerate().rev() {
+        starts[item.group] = i;
+    }
+    starts
+}
+
+/// The items that can run directly after each item. Calls continue after the call, since the
+/// callee leaves no value behind except for its result.
+fn successors(items: &[Item], len: usize) -> Vec<Vec<usize>> {
+    let starts = group_starts(items, len);
+    items
+        .iter()
+        .enumerate()
+        .map(|(i, item)| {
+            let next = Some(i + 1);
+            let succs = match item.inst {
+                Some(Inst::Jump(target)) => vec![Some(starts[target])],
+                Some(Inst::JumpIfZero(_, target)) => vec![next, Some(starts[target])],
+                Some(Inst::Result(_) | Inst::Ret) => vec![],
+                _ => vec![next],
+            };
+            succs
+                .into_iter()
+                .flatten()
+                .filter(|s| *s < items.len())
+                .collect()
+        })
+        .collect()
+}
+
+/// Give every live range its own register name, so that unrelated values that happened to share
+/// a register in the generated code can be allocated separately. A live range consists of all
+/// writes of a register that reach a common read, together with those reads.
+///
+/// Returns the register each of the `precolored` live ranges has to end up in.
+fn rename_live_ranges(
+    items: &mut [Item],
+    precolored: &BTreeMap<Reg, u8>,
+    len: usize,
+) -> BTreeMap<Reg, u8> {
+    let n = items.len();
+    let succs = successors(items, len);
+    let mut preds = vec![vec![]; n];
+    for (i, succ) in succs.iter().enumerate() {
+        for s in succ {
+            preds[*s].push(i);
+        }
+    }
+
+    // all definitions, and the ones of each item
+    let mut defs = vec![];
+    let mut item_defs = vec![vec![]; n];
+    for (i, item) in items.iter().enumerate() {
+        for reg in &item.defs {
+            item_defs[i].push(defs.len());
+            defs.push(*reg);
+        }
+    }
+
+    // reaching definitions
+    let reach_in = |i: usize, reach_out: &[BTreeSet<usize>]| -> BTreeSet<usize> {
+        preds[i]
+            .iter()
+            .flat_map(|p| reach_out[*p].iter().copied())
+            .collect()
+    };
+    let mut reach_out = vec![BTreeSet::new(); n];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in 0..n {
+            let out: BTreeSet<usize> = reach_in(i, &reach_out)
+                .into_iter()
+                .filter(|d| !items[i].defs.contains(&defs[*d]))
+                .chain(item_defs[i].iter().copied())
+                .collect();
+            if out != reach_out[i] {
+                reach_out[i] = out;
+                changed = true;
+            }
+        }
+    }
+
+    // join the definitions reaching the same use
+    let mut parent: Vec<usize> = (0..defs.len()).collect();
+    fn find(parent: &mut [usize], x: usize) -> usize {
+        if parent[x] != x {
+            parent[x] = find(parent, parent[x]);
+        }
+        parent[x]
+    }
+    fn union(parent: &mut [usize], a: usize, b: usize) {
+        let (a, b) = (find(parent, a), find(parent, b));
+        parent[a.max(b)] = a.min(b);
+    }
+
+    let mut use_ranges = vec![BTreeMap::new(); n];
+    for i in 0..n {
+        let reaching = reach_in(i, &reach_out);
+        for reg in items[i].uses.clone() {
+            let mut from = reaching.iter().filter(|d| defs[**d] == reg);
+            let range = match from.next() {
+                Some(first) => {
+                    for d in from {
+                        union(&mut parent, *first, *d);
+                    }
+                    *first
+                }
+                // read without being written, it doesn't matter what the register contains
+                None => {
+                    parent.push(parent.len());
+                    parent.len() - 1
+                }
+            };
+            // an instruction reading and writing the same register changes the value in place
+            if let Some(d) = item_defs[i].iter().find(|d| defs[**d] == reg) {
+                union(&mut parent, range, *d);
+            }
+            use_ranges[i].insert(reg, range);
+        }
+    }
+
+    let mut renamed = BTreeMap::new();
+    for (i, item) in items.iter_mut().enumerate() {
+        let mut names = BTreeMap::new();
+        for (reg, range) in &use_ranges[i] {
+            names.insert(*reg, name(RANGE_BASE, find(&mut parent, *range)));
+        }
+        for (reg, d) in item.defs.iter().zip(&item_defs[i]) {
+            let new = name(RANGE_BASE, find(&mut parent, *d));
+            names.insert(*reg, new);
+            if let Some(color) = precolored.get(reg) {
+                renamed.insert(new, *color);
+            }
+        }
+
+        let rename = |reg: Reg| names[&reg];
+        item.inst = item.inst.as_ref().map(|inst| inst.map_regs(rename));
+        item.uses = item.uses.iter().map(|r| rename(*r)).collect();
+        item.defs = item.defs.iter().map(|r| rename(*r)).collect();
+    }
+    renamed
+}
+
+fn liveness(items: &[Item], len: usize) -> Liveness {
+    let succs = successors(items, len);
+    let mut live_in = vec![BTreeSet::new(); items.len()];
+    let mut live_out = vec![BTreeSet::new(); items.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in (0..items.len()).rev() {
+            let out: BTreeSet<Reg> = succs[i]
+                .iter()
+                .flat_map(|s| live_in[*s].iter().copied())
+                .collect();
+            let in_: BTreeSet<Reg> = items[i]
+                .uses
+                .iter()
+                .copied()
+                .chain(out.iter().filter(|r| !items[i].defs.contains(r)).copied())
+                .collect();
+            if in_ != live_in[i] || out != live_out[i] {
+                live_in[i] = in_;
+                live_out[i] = out;
+                changed = true;
+            }
+        }
+    }
+    Liveness { live_in, live_out }
+}
+
+/// Remove the copies inserted for the calling convention whose value is never used, e.g. for
+/// unused parameters.
+fn remove_dead_copies(items: &mut Vec<Item>, live: &Liveness) {
+    let mut i = 0;
+    items.retain(|item| {
+        let dead = !item.orig
+            && matches!(item.inst, Some(Inst::Copy(_, b)) if !live.live_out[i].contains(&b));
+        i += 1;
+        !dead
+    });
+}
+
+/// Linear scan allocation, where each live range occupies its register from the first to the last
+/// item it is live at. Returns the register of each live range, or the ones to spill.
+fn linear_scan(
+    items: &[Item],
+    live: &Liveness,
+    precolored: &BTreeMap<Reg, u8>,
+    num_registers: u8,
+) -> Result<BTreeMap<Reg, u8>, BTreeSet<Reg>> {
+    // each item reads its operands at `2 * i` and writes its result at `2 * i + 1`, so that a value
+    // read for the last time can share its register with the result
+    let mut intervals: BTreeMap<Reg, (usize, usize)> = BTreeMap::new();
+    for (i, item) in items.iter().enumerate() {
+        let read = item.uses.iter().chain(&live.live_in[i]).map(|r| (r, 2 * i));
+        let write = item
+            .defs
+            .iter()
+            .chain(&live.live_out[i])
+            .map(|r| (r, 2 * i + 1));
+        for (reg, pos) in read.chain(write) {
+            let interval = intervals.entry(*reg).or_insert((pos, pos));
+            interval.1 = interval.1.max(pos);
+        }
+    }
+    let overlap = |a: (usize, usize), b: (usize, usize)| a.0 <= b.1 && b.0 <= a.1;
+
+    let mut order: Vec<(usize, usize, Reg)> = intervals
+        .iter()
+        .map(|(reg, (s, e))| (*s, *e, *reg))
+        .collect();
+    order.sort();
+
+    let mut colors = BTreeMap::new();
+    let mut spilled = BTreeSet::new();
+    // the live ranges occupying a register, with their end
+    let mut active: Vec<(usize, Reg)> = vec![];
+    for (start, end, reg) in order {
+        active.retain(|(e, _)| *e >= start);
+        if let Some(color) = precolored.get(&reg) {
+            colors.insert(reg, *color);
+            active.push((end, reg));
+            continue;
+        }
+
+        // registers needed by the calling convention while this one is live
+        let reserved: BTreeSet<u8> = precolored
+            .iter()
+            .filter(|(r, _)| intervals.get(r).is_some_and(|i| overlap(*i, (start, end))))
+            .map(|(_, color)| *color)
+            .collect();
+        let taken: BTreeSet<u8> = active.iter().map(|(_, r)| colors[r]).collect();
+        if let Some(color) =
+            (0..num_registers).find(|c| !taken.contains(c) && !reserved.contains(c))
+        {
+            colors.insert(reg, color);
+            active.push((end, reg));
+            continue;
+        }
+
+        // spill whichever live range occupies its register the longest
+        let victim = active
+            .iter()
+            .filter(|(_, r)| {
+                !precolored.contains_key(r) && !is_temp(*r) && !reserved.contains(&colors[r])
+            })
+            .max_by_key(|(e, _)| *e)
+            .copied();
+        match victim {
+            Some((victim_end, victim)) if victim_end > end || is_temp(reg) => {
+                let color = colors.remove(&victim).unwrap();
+                colors.insert(reg, color);
+                spilled.insert(victim);
+                active.retain(|(_, r)| *r != victim);
+                active.push((end, reg));
+            }
+            _ if !is_temp(reg) => {
+                spilled.insert(reg);
+            }
+            _ => return Err(BTreeSet::new()),
+        }
+    }
+
+    if spilled.is_empty() {
+        Ok(colors)
+    } else {
+        Err(spilled)
+    }
+}
+
+fn add_edge(graph: &mut BTreeMap<Reg, BTreeSet<Reg>>, a: Reg, b: Reg) {
+    if a != b {
+        graph.entry(a).or_default().insert(b);
+        graph.entry(b).or_default().insert(a);
+    }
+}
+
+/// Graph coloring allocation, where live ranges that are live at the same time get different
+/// registers. Returns the register of each live range, or the ones to spill.
+fn graph_coloring(
+    items: &[Item],
+    live: &Liveness,
+    precolored: &BTreeMap<Reg, u8>,
+    num_registers: u8,
+) -> Result<BTreeMap<Reg, u8>, BTreeSet<Reg>> {
+    let mut graph: BTreeMap<Reg, BTreeSet<Reg>> = BTreeMap::new();
+    // spilling a live range costs a load or write for each of its uses and definitions
+    let mut cost: BTreeMap<Reg, usize> = BTreeMap::new();
+    for (i, item) in items.iter().enumerate() {
+        for reg in item.uses.iter().chain(&item.defs) {
+            *cost.entry(*reg).or_default() += 1;
+            graph.entry(*reg).or_default();
+        }
+        // a value written here must not overwrite one that is still needed
+        for d in &item.defs {
+            for l in &live.live_out[i] {
+                add_edge(&mut graph, *d, *l);
+            }
+        }
+        // the operands of an instruction are needed at the same time
+        for a in &item.uses {
+            for b in &item.uses {
+                add_edge(&mut graph, *a, *b);
+            }
+        }
+    }
+
+    // remove the live ranges one by one, each one with fewer neighbors left than there are
+    // registers. Once there is none, remove the cheapest one to spill and hope that its neighbors
+    // end up sharing registers.
+    let mut remaining: BTreeSet<Reg> = graph
+        .keys()
+        .filter(|r| !precolored.contains_key(r))
+        .copied()
+        .collect();
+    let mut stack = vec![];
+    while !remaining.is_empty() {
+        let degree = |reg: &Reg| {
+            graph[reg]
+                .iter()
+                .filter(|n| remaining.contains(n) || precolored.contains_key(n))
+                .count()
+        };
+        let next = remaining
+            .iter()
+            .find(|r| degree(r) < num_registers as usize)
+            .or_else(|| {
+                remaining
+                    .iter()
+                    .min_by_key(|r| (is_temp(**r), cost[*r] * 1000 / degree(r).max(1)))
+            })
+            .copied()
+            .unwrap();
+        remaining.remove(&next);
+        stack.push(next);
+    }
+
+    // put them back in reverse order, each one in a register none of its neighbors has
+    let mut colors = precolored.clone();
+    let mut spilled = BTreeSet::new();
+    while let Some(reg) = stack.pop() {
+        let used: BTreeSet<u8> = graph[&reg]
+            .iter()
+            .filter_map(|n| colors.get(n))
+            .copied()
+            .collect();
+        match (0..num_registers).find(|c| !used.contains(c)) {
+            Some(color) => {
+                colors.insert(reg, color);
+            }
+            None if is_temp(reg) => return Err(BTreeSet::new()),
+            None => {
+                spilled.insert(reg);
+            }
+        }
+    }
+
+    if spilled.is_empty() {
+        Ok(colors)
+    } else {
+        Err(spilled)
+    }
+}
+
+/// Keep the live range `reg` in the RAM cell `cell`, loading it into a new register before each
+/// use and writing it back after each definition.
+fn spill(items: &mut Vec<Item>, reg: Reg, cell: MemAddr, temps: &mut usize) {
+    let mut res = Vec::with_capacity(items.len());
+    for mut item in items.drain(..) {
+        let used = item.uses.contains(&reg);
+        let defined = item.defs.contains(&reg);
+        if !used && !defined {
+            res.push(item);
+            continue;
+        }
+
+        let temp = name(TEMP_BASE, *temps);
+        *temps += 1;
+        let rename = |r: Reg| if r == reg { temp } else { r };
+        item.inst = item.inst.map(|inst| inst.map_regs(rename));
+        item.uses = item.uses.iter().map(|r| rename(*r)).collect();
+        item.defs = item.defs.iter().map(|r| rename(*r)).collect();
+
+        let group = item.group;
+        if used {
+            res.push(Item::new(Inst::Load(cell, temp), group));
+        }
+        res.push(item);
+        if defined {
+            res.push(Item::new(Inst::Write(temp, cell), group));
+        }
+    }
+    *items = res;
+}
+
+/// Generate the code with the allocated registers, leaving out copies within the same register.
+fn emit(items: &[Item], colors: &BTreeMap<Reg, u8>, len: usize, spill_cells: usize) -> Allocated {
+    let emitted = |item: &Item| match &item.inst {
+        Some(Inst::Copy(a, b)) => colors[a] != colors[b],
+        Some(_) => true,
+        None => false,
+    };
+
+    // position of the first emitted item of each group, or of the next one if there is none
+    let mut starts = vec![None; len + 1];
+    let mut new_addr = vec![None; len];
+    let mut pos = 0;
+    for item in items {
+        starts[item.group].get_or_insert(pos);
+        if emitted(item) {
+            if item.orig {
+                new_addr[item.group] = Some(pos);
+            }
+            pos += 1;
+        }
+    }
+    let target = |t: InstAddr| starts[t].unwrap_or(pos);
+
+    let code = items
+        .iter()
+        .filter(|item| emitted(item))
+        .map(|item| {
+            match item
+                .inst
+                .as_ref()
+                .unwrap()
+                .map_regs(|r| Reg::from(colors[&r]))
+            {
+                Inst::Jump(t) => Inst::Jump(target(t)),
+                Inst::JumpIfZero(r, t) => Inst::JumpIfZero(r, target(t)),
+                Inst::Call(t) => Inst::Call(target(t)),
+                inst => inst,
+            }
+        })
+        .collect();
+
+    Allocated {
+        code,
+        new_addr,
+        spill_cells,
+    }
+}
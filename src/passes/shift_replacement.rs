@@ -1,55 +1,102 @@
-use crate::types::{Expr, Operator};
+use crate::arith::Signedness;
+use crate::types::{Expr, Operator, Program};
 
 pub trait ShiftReplacement {
-    fn replace_multiplications_with_bitshifts(self) -> Self;
+    /// Replaces multiplications by powers of two with left shifts. Divisions are only replaced with
+    /// unsigned values, since a right shift rounds negative values down (`-3 >> 1 == -2`) while a
+    /// division rounds towards zero (`-3 / 2 == -1`).
+    fn replace_multiplications_with_bitshifts(self, signedness: Signedness) -> Self;
+}
+
+impl ShiftReplacement for Program {
+    fn replace_multiplications_with_bitshifts(self, signedness: Signedness) -> Self {
+        self.map_exprs(|e| e.replace_multiplications_with_bitshifts(signedness))
+    }
+}
+
+/// Whether `n` is a power of two.
+fn is_power_of_two(n: i64) -> bool {
+    // this is actually a neat trick since 1000 & 0111 == 0 and that holds true for all powers of 2
+    n > 0 && (n & (n - 1)) == 0
 }
 
 impl ShiftReplacement for Expr {
-    fn replace_multiplications_with_bitshifts(self) -> Self {
+    fn replace_multiplications_with_bitshifts(self, signedness: Signedness) -> Self {
         match self {
             Expr::Num(_) | Expr::Var(_) => self,
-            Expr::UnaryOp(op, expr) => {
-                Expr::UnaryOp(op, Box::new(expr.replace_multiplications_with_bitshifts()))
-            }
+            Expr::UnaryOp(op, expr) => Expr::UnaryOp(
+                op,
+                Box::new(expr.replace_multiplications_with_bitshifts(signedness)),
+            ),
+            Expr::If(cond, then, otherwise) => Expr::If(
+                Box::new(cond.replace_multiplications_with_bitshifts(signedness)),
+                Box::new(then.replace_multiplications_with_bitshifts(signedness)),
+                Box::new(otherwise.replace_multiplications_with_bitshifts(signedness)),
+            ),
+            Expr::Call(name, args) => Expr::Call(
+                name,
+                args.into_iter()
+                    .map(|a| a.replace_multiplications_with_bitshifts(signedness))
+                    .collect(),
+            ),
+            Expr::FixedPoint(raw) => Expr::FixedPoint(Box::new(
+                raw.replace_multiplications_with_bitshifts(signedness),
+            )),
             Expr::BinaryOp(
                 lhs,
-                o @ (Operator::Add | Operator::Sub | Operator::Shl | Operator::Shr),
+                o @ (Operator::Add
+                | Operator::Sub
+                | Operator::Mod
+                | Operator::Shl
+                | Operator::Shr
+                | Operator::LogicalShr
+                | Operator::And
+                | Operator::Or
+                | Operator::Xor
+                | Operator::Not
+                | Operator::Eq
+                | Operator::Ne
+                | Operator::Lt
+                | Operator::Le
+                | Operator::Gt
+                | Operator::Ge),
                 rhs,
             ) => Expr::BinaryOp(
-                Box::new(lhs.replace_multiplications_with_bitshifts()),
+                Box::new(lhs.replace_multiplications_with_bitshifts(signedness)),
                 o,
-                Box::new(rhs.replace_multiplications_with_bitshifts()),
+                Box::new(rhs.replace_multiplications_with_bitshifts(signedness)),
             ),
             Expr::BinaryOp(left, op @ (Operator::Mul | Operator::Div), right) => {
-                // this is actually a neat trick since 1000 & 0111 == 0 and that holds true for all powers of 2
                 if let &Expr::Num(lhs) = left.as_ref()
-                    && (lhs & (lhs - 1)) == 0 && !matches!(op, Operator::Div)
+                    && is_power_of_two(lhs)
+                    && !matches!(op, Operator::Div)
                 {
                     // mul only
                     Expr::BinaryOp(
-                        right,
+                        Box::new(right.replace_multiplications_with_bitshifts(signedness)),
                         Operator::Shl,
-                        Box::new(Expr::Num(lhs.ilog2() as i32)),
+                        Box::new(Expr::Num(lhs.ilog2() as i64)),
                     )
                 } else if let &Expr::Num(rhs) = right.as_ref()
-                    && (rhs & (rhs - 1)) == 0
+                    && is_power_of_two(rhs)
+                    && (op == Operator::Mul || signedness == Signedness::Unsigned)
                 {
                     // mul + div
                     Expr::BinaryOp(
-                        Box::new(left.replace_multiplications_with_bitshifts()),
+                        Box::new(left.replace_multiplications_with_bitshifts(signedness)),
                         match op {
                             Operator::Mul => Operator::Shl,
                             Operator::Div => Operator::Shr,
                             _ => unreachable!(),
                         },
-                        Box::new(Expr::Num(rhs.ilog2() as i32)),
+                        Box::new(Expr::Num(rhs.ilog2() as i64)),
                     )
                 } else {
                     // mul + div
                     Expr::BinaryOp(
-                        Box::new(left.replace_multiplications_with_bitshifts()),
+                        Box::new(left.replace_multiplications_with_bitshifts(signedness)),
                         op,
-                        Box::new(right.replace_multiplications_with_bitshifts()),
+                        Box::new(right.replace_multiplications_with_bitshifts(signedness)),
                     )
                 }
             }
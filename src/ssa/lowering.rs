@@ -0,0 +1,268 @@
+use std::collections::BTreeSet;
+
+use rust_i18n::t;
+
+use super::{Block, BlockId, Def, Function, Op, Program, Terminator, Value};
+use crate::types::{self, Expr, LpErr, Operator, Stmt};
+
+/// Translate `program` into SSA form.
+pub fn lower(program: &types::Program) -> Result<Program, LpErr> {
+    let mut functions = Vec::with_capacity(program.functions.len());
+    for (idx, function) in program.functions.iter().enumerate() {
+        let mut builder = Builder::new(&program.functions, Some(idx));
+        for (i, param) in function.params.iter().enumerate() {
+            let value = builder.def(Op::Param(i));
+            builder.bindings.push((param.clone(), value));
+        }
+        let result = builder.expr(&function.body)?;
+        functions.push(builder.finish(&function.name, function.params.len(), result));
+    }
+
+    let mut builder = Builder::new(&program.functions, None);
+    builder.stmts(&program.stmts)?;
+    let result = builder.expr(&program.result)?;
+    Ok(Program {
+        functions,
+        main: builder.finish("main", 0, result),
+    })
+}
+
+/// State of the translation of a single function.
+struct Builder<'a> {
+    /// All functions of the program.
+    functions: &'a [types::Function],
+    /// The function being translated, if any.
+    current_function: Option<usize>,
+    /// The definitions and terminator of each block. The terminator is only known once the block
+    /// is complete.
+    blocks: Vec<(Vec<Def>, Option<Terminator>)>,
+    /// The block definitions are currently added to.
+    current: BlockId,
+    num_values: usize,
+    /// The current value of each binding in scope; later bindings shadow earlier ones with the same
+    /// name.
+    bindings: Vec<(String, Value)>,
+    fixed_point: BTreeSet<Value>,
+}
+
+impl<'a> Builder<'a> {
+    fn new(functions: &'a [types::Function], current_function: Option<usize>) -> Self {
+        Self {
+            functions,
+            current_function,
+            blocks: vec![(vec![], None)],
+            current: BlockId(0),
+            num_values: 0,
+            bindings: vec![],
+            fixed_point: BTreeSet::new(),
+        }
+    }
+
+    fn finish(mut self, name: &str, params: usize, result: Value) -> Function {
+        self.terminate(Terminator::Return(result));
+        Function {
+            name: name.to_string(),
+            params,
+            blocks: self
+                .blocks
+                .into_iter()
+                .map(|(defs, term)| Block {
+                    defs,
+                    term: term.expect("all blocks are terminated"),
+                })
+                .collect(),
+            num_values: self.num_values,
+            fixed_point: self.fixed_point,
+        }
+    }
+
+    fn new_value(&mut self) -> Value {
+        self.num_values += 1;
+        Value(self.num_values - 1)
+    }
+
+    /// Add a definition computing `op` to the current block.
+    fn def(&mut self, op: Op) -> Value {
+        let value = self.new_value();
+        self.blocks[self.current.0].0.push(Def { value, op });
+        value
+    }
+
+    fn new_block(&mut self) -> BlockId {
+        self.blocks.push((vec![], None));
+        BlockId(self.blocks.len() - 1)
+    }
+
+    /// End the current block with `term`.
+    fn terminate(&mut self, term: Terminator) {
+        self.blocks[self.current.0].1 = Some(term);
+    }
+
+    fn stmts(&mut self, stmts: &[Stmt]) -> Result<(), LpErr> {
+        for stmt in stmts {
+            match stmt {
+                Stmt::Let(name, value) => {
+                    let value = self.expr(value)?;
+                    self.bindings.push((name.clone(), value));
+                }
+                Stmt::Assign(name, value) => {
+                    let Some(idx) = self.bindings.iter().rposition(|(n, _)| n == name) else {
+                        return Err(LpErr::IR(
+                            t!("compiler.error.assign_unbound", v = name).to_string(),
+                        ));
+                    };
+                    self.bindings[idx].1 = self.expr(value)?;
+                }
+                Stmt::While(cond, body) => self.while_loop(cond, body)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Translate a loop. Each binding gets a phi function at the start of the loop, choosing
+    /// between the value before the loop and the one after the previous iteration. The phi
+    /// functions of bindings that don't change in the loop are removed again by the optimization.
+    fn while_loop(&mut self, cond: &Expr, body: &[Stmt]) -> Result<(), LpErr> {
+        let before = self.current;
+        let header = self.new_block();
+        self.terminate(Terminator::Jump(header));
+        self.current = header;
+
+        let initial: Vec<Value> = self.bindings.iter().map(|(_, v)| *v).collect();
+        let phis: Vec<Value> = (0..initial.len()).map(|_| self.new_value()).collect();
+        for (binding, phi) in self.bindings.iter_mut().zip(&phis) {
+            binding.1 = *phi;
+        }
+
+        let cond = self.expr(cond)?;
+        let body_block = self.new_block();
+        let exit = self.new_block();
+        self.terminate(Terminator::Branch(cond, body_block, exit));
+
+        self.current = body_block;
+        let outer = self.bindings.len();
+        self.stmts(body)?;
+        self.bindings.truncate(outer);
+        let latch = self.current;
+        self.terminate(Terminator::Jump(header));
+
+        let defs =
+            phis.iter()
+                .zip(&initial)
+                .zip(&self.bindings)
+                .map(|((phi, initial), (_, updated))| Def {
+                    value: *phi,
+                    op: Op::Phi(vec![(before, *initial), (latch, *updated)]),
+                });
+        self.blocks[header.0]
+            .0
+            .splice(0..0, defs.collect::<Vec<_>>());
+
+        // after the loop, the bindings have the values they had when the condition was checked
+        for (binding, phi) in self.bindings.iter_mut().zip(&phis) {
+            binding.1 = *phi;
+        }
+        self.current = exit;
+        Ok(())
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Result<Value, LpErr> {
+        Ok(match expr {
+            Expr::Num(n) => self.def(Op::Const(*n)),
+            Expr::Var(v) => {
+                if let Some((_, value)) = self.bindings.iter().rev().find(|(n, _)| n == v) {
+                    return Ok(*value);
+                }
+                if let Some(idx) = self.current_function {
+                    return Err(LpErr::IR(
+                        t!(
+                            "compiler.error.not_a_param",
+                            v = v,
+                            f = self.functions[idx].name
+                        )
+                        .to_string(),
+                    ));
+                }
+                self.def(Op::Input(v.clone()))
+            }
+            Expr::UnaryOp(Operator::Sub, e) => {
+                let zero = self.def(Op::Const(0));
+                let e = self.expr(e)?;
+                self.def(Op::Binary(Operator::Sub, zero, e))
+            }
+            Expr::UnaryOp(Operator::Not, e) => {
+                let e = self.expr(e)?;
+                self.def(Op::Not(e))
+            }
+            Expr::UnaryOp(op, _) => {
+                return Err(LpErr::IR(
+                    t!("compiler.error.invalid_unary", op = op).to_string(),
+                ));
+            }
+            Expr::BinaryOp(_, Operator::Not, _) => {
+                return Err(LpErr::IR(
+                    t!("compiler.error.invalid_binary", op = Operator::Not).to_string(),
+                ));
+            }
+            Expr::BinaryOp(left, op, right) => {
+                let left = self.expr(left)?;
+                let right = self.expr(right)?;
+                self.def(Op::Binary(*op, left, right))
+            }
+            Expr::If(cond, then, otherwise) => {
+                let cond = self.expr(cond)?;
+                let (then_block, else_block, join) =
+                    (self.new_block(), self.new_block(), self.new_block());
+                self.terminate(Terminator::Branch(cond, then_block, else_block));
+
+                self.current = then_block;
+                let then = self.expr(then)?;
+                let then_end = self.current;
+                self.terminate(Terminator::Jump(join));
+
+                self.current = else_block;
+                let otherwise = self.expr(otherwise)?;
+                let else_end = self.current;
+                self.terminate(Terminator::Jump(join));
+
+                self.current = join;
+                self.def(Op::Phi(vec![(then_end, then), (else_end, otherwise)]))
+            }
+            Expr::Call(name, args) => {
+                // functions can only call functions defined before them, so there is no recursion
+                let visible = self.current_function.unwrap_or(self.functions.len());
+                let Some(idx) = self.functions[..visible]
+                    .iter()
+                    .rposition(|f| f.name == *name)
+                else {
+                    return Err(LpErr::IR(
+                        t!("compiler.error.unknown_function", f = name).to_string(),
+                    ));
+                };
+                let expected = self.functions[idx].params.len();
+                if args.len() != expected {
+                    return Err(LpErr::IR(
+                        t!(
+                            "compiler.error.arg_count",
+                            f = name,
+                            expected = expected,
+                            got = args.len()
+                        )
+                        .to_string(),
+                    ));
+                }
+
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args {
+                    values.push(self.expr(arg)?);
+                }
+                self.def(Op::Call(idx, values))
+            }
+            Expr::FixedPoint(e) => {
+                let value = self.expr(e)?;
+                self.fixed_point.insert(value);
+                value
+            }
+        })
+    }
+}
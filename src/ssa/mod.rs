@@ -0,0 +1,193 @@
+//! A mid-level intermediate representation between the AST and the machine instructions.
+//!
+//! Each value is computed by exactly one definition (static single assignment, SSA) and lives in a
+//! register of its own, of which there are arbitrarily many. Where control flow joins, a phi
+//! function picks the value depending on where execution came from. This makes it easy to see
+//! where each value comes from and where it is used, without caring about registers yet.
+
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter};
+
+use crate::types::Operator;
+
+mod lowering;
+mod optimization;
+mod selection;
+
+pub use lowering::lower;
+pub use selection::select;
+
+/// A value computed exactly once, in a virtual register of its own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Value(pub usize);
+
+/// A basic block, as index into [`Function::blocks`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BlockId(pub usize);
+
+/// How a value is computed.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Op {
+    Const(i64),
+    /// An input variable.
+    Input(String),
+    /// A parameter of the function, by position.
+    Param(usize),
+    Binary(Operator, Value, Value),
+    /// Bitwise negation.
+    Not(Value),
+    /// Call the function with the given index in [`Program::functions`].
+    Call(usize, Vec<Value>),
+    /// The value from the given predecessor block that execution came from.
+    Phi(Vec<(BlockId, Value)>),
+}
+
+impl Op {
+    /// The values this operation reads.
+    pub fn operands(&self) -> Vec<Value> {
+        match self {
+            Op::Const(_) | Op::Input(_) | Op::Param(_) => vec![],
+            Op::Binary(_, a, b) => vec![*a, *b],
+            Op::Not(a) => vec![*a],
+            Op::Call(_, args) => args.clone(),
+            Op::Phi(incoming) => incoming.iter().map(|(_, v)| *v).collect(),
+        }
+    }
+
+    /// Replace each operand `v` by `f(v)`.
+    pub fn map_operands(&mut self, f: impl Fn(Value) -> Value) {
+        match self {
+            Op::Const(_) | Op::Input(_) | Op::Param(_) => (),
+            Op::Binary(_, a, b) => {
+                *a = f(*a);
+                *b = f(*b);
+            }
+            Op::Not(a) => *a = f(*a),
+            Op::Call(_, args) => args.iter_mut().for_each(|a| *a = f(*a)),
+            Op::Phi(incoming) => incoming.iter_mut().for_each(|(_, v)| *v = f(*v)),
+        }
+    }
+}
+
+/// The definition of a value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Def {
+    pub value: Value,
+    pub op: Op,
+}
+
+/// How a block ends.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Terminator {
+    Jump(BlockId),
+    /// Continue at the first block if the value is non-zero, otherwise at the second one.
+    Branch(Value, BlockId, BlockId),
+    /// End the function, or the program, with the value as result.
+    Return(Value),
+}
+
+/// A sequence of definitions that always runs from start to end. Phi functions come first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Block {
+    pub defs: Vec<Def>,
+    pub term: Terminator,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Function {
+    pub name: String,
+    pub params: usize,
+    /// The blocks, starting with the entry.
+    pub blocks: Vec<Block>,
+    /// Number of values, which are numbered from 0.
+    pub num_values: usize,
+    /// Values that are fixed-point numbers.
+    pub fixed_point: BTreeSet<Value>,
+}
+
+impl Function {
+    fn defs(&self) -> impl Iterator<Item = &Def> {
+        self.blocks.iter().flat_map(|b| &b.defs)
+    }
+
+    /// Replace all uses of `from` by `to`.
+    fn replace_uses(&mut self, from: Value, to: Value) {
+        let f = |v| if v == from { to } else { v };
+        for block in &mut self.blocks {
+            for def in &mut block.defs {
+                def.op.map_operands(f);
+            }
+            match &mut block.term {
+                Terminator::Branch(v, ..) | Terminator::Return(v) => *v = f(*v),
+                Terminator::Jump(_) => (),
+            }
+        }
+        if self.fixed_point.remove(&from) {
+            self.fixed_point.insert(to);
+        }
+    }
+}
+
+/// A program in SSA form.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Program {
+    pub functions: Vec<Function>,
+    pub main: Function,
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+impl Display for BlockId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "b{}", self.0)
+    }
+}
+
+impl Display for Program {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = |idx: usize| &self.functions[idx].name;
+        for function in self.functions.iter().chain([&self.main]) {
+            let params: Vec<String> = (0..function.params).map(|i| format!("p{i}")).collect();
+            writeln!(f, "{}({}):", function.name, params.join(", "))?;
+
+            for (idx, block) in function.blocks.iter().enumerate() {
+                writeln!(f, "{}:", BlockId(idx))?;
+                for def in &block.defs {
+                    let op = match &def.op {
+                        Op::Const(n) => n.to_string(),
+                        Op::Input(var) => format!("input {var}"),
+                        Op::Param(i) => format!("p{i}"),
+                        Op::Binary(op, a, b) => format!("{a} {op} {b}"),
+                        Op::Not(a) => format!("~{a}"),
+                        Op::Call(idx, args) => {
+                            let args: Vec<String> = args.iter().map(Value::to_string).collect();
+                            format!("{}({})", name(*idx), args.join(", "))
+                        }
+                        Op::Phi(incoming) => {
+                            let incoming: Vec<String> =
+                                incoming.iter().map(|(b, v)| format!("{b}: {v}")).collect();
+                            format!("φ({})", incoming.join(", "))
+                        }
+                    };
+                    let fixed = if function.fixed_point.contains(&def.value) {
+                        "  ; fixed-point"
+                    } else {
+                        ""
+                    };
+                    writeln!(f, "    {} = {op}{fixed}", def.value)?;
+                }
+                match block.term {
+                    Terminator::Jump(b) => writeln!(f, "    jump {b}")?,
+                    Terminator::Branch(v, a, b) => writeln!(f, "    branch {v} ? {a} : {b}")?,
+                    Terminator::Return(v) => writeln!(f, "    return {v}")?,
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
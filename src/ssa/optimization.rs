@@ -0,0 +1,178 @@
+use std::collections::{HashMap, HashSet};
+
+use super::{Function, Op, Program, Terminator, Value};
+use crate::arith::Arith;
+use crate::compiler::CompileOptions;
+use crate::types::Operator;
+
+impl Program {
+    /// Simplify all functions. Constants are only folded, equal values only computed once and
+    /// inputs only transferred once if the corresponding options are enabled.
+    pub fn optimize(&mut self, arith: Arith, options: &CompileOptions) {
+        for function in self.functions.iter_mut().chain([&mut self.main]) {
+            function.optimize(arith, options);
+        }
+    }
+}
+
+impl Function {
+    fn optimize(&mut self, arith: Arith, options: &CompileOptions) {
+        if options.load_inputs_once {
+            self.hoist_inputs();
+        }
+        loop {
+            let mut changed = self.remove_trivial_phis();
+            if options.do_constant_folding {
+                changed |= self.fold_constants(arith);
+            }
+            if options.do_common_subexpression_elimination {
+                changed |= self.number_values();
+            }
+            changed |= self.remove_dead_code();
+            if !changed {
+                break;
+            }
+        }
+    }
+
+    /// Remove phi functions that always pick the same value, e.g. for bindings that don't change
+    /// in a loop.
+    fn remove_trivial_phis(&mut self) -> bool {
+        let mut changed = false;
+        for b in 0..self.blocks.len() {
+            let mut i = 0;
+            while i < self.blocks[b].defs.len() {
+                let def = &self.blocks[b].defs[i];
+                let Op::Phi(incoming) = &def.op else {
+                    i += 1;
+                    continue;
+                };
+                // a loop can pass the phi's own value back to it
+                let mut values = incoming.iter().map(|(_, v)| *v).filter(|v| *v != def.value);
+                let Some(first) = values.next() else {
+                    i += 1;
+                    continue;
+                };
+                if values.any(|v| v != first) {
+                    i += 1;
+                    continue;
+                }
+
+                let phi = self.blocks[b].defs.remove(i).value;
+                self.replace_uses(phi, first);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Compute operations on constants at compile time, unless they fail.
+    fn fold_constants(&mut self, arith: Arith) -> bool {
+        let mut constants: HashMap<Value, i64> = self
+            .defs()
+            .filter_map(|def| match def.op {
+                Op::Const(n) => Some((def.value, n)),
+                _ => None,
+            })
+            .collect();
+
+        let mut changed = false;
+        for def in self.blocks.iter_mut().flat_map(|b| &mut b.defs) {
+            let folded = match def.op {
+                Op::Binary(op, a, b) => match (constants.get(&a), constants.get(&b)) {
+                    (Some(a), Some(b)) => arith.binary(op, *a, *b).ok(),
+                    _ => None,
+                },
+                Op::Not(a) => constants
+                    .get(&a)
+                    .and_then(|a| arith.unary(Operator::Not, *a).ok()),
+                _ => None,
+            };
+            if let Some(n) = folded {
+                def.op = Op::Const(n);
+                constants.insert(def.value, n);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Use the first of several values computed by the same operation on the same operands within a
+    /// block, which makes the others unused.
+    fn number_values(&mut self) -> bool {
+        let mut replaced = vec![];
+        for block in &self.blocks {
+            let mut numbers: HashMap<&Op, Value> = HashMap::new();
+            for def in &block.defs {
+                if matches!(def.op, Op::Phi(_)) {
+                    continue;
+                }
+                match numbers.get(&def.op) {
+                    Some(first) => replaced.push((def.value, *first)),
+                    None => {
+                        numbers.insert(&def.op, def.value);
+                    }
+                }
+            }
+        }
+
+        let changed = !replaced.is_empty();
+        for (value, first) in replaced {
+            self.replace_uses(value, first);
+        }
+        changed
+    }
+
+    /// Transfer each input variable once at the start, from where the value reaches all its uses.
+    fn hoist_inputs(&mut self) {
+        let mut first: HashMap<String, Value> = HashMap::new();
+        let mut hoisted = vec![];
+        let mut replaced = vec![];
+        for block in &mut self.blocks {
+            block.defs.retain(|def| {
+                let Op::Input(name) = &def.op else {
+                    return true;
+                };
+                match first.get(name) {
+                    Some(value) => replaced.push((def.value, *value)),
+                    None => {
+                        first.insert(name.clone(), def.value);
+                        hoisted.push(def.clone());
+                    }
+                }
+                false
+            });
+        }
+
+        self.blocks[0].defs.splice(0..0, hoisted);
+        for (value, first) in replaced {
+            self.replace_uses(value, first);
+        }
+    }
+
+    /// Remove definitions whose value is never used.
+    fn remove_dead_code(&mut self) -> bool {
+        let mut changed = false;
+        loop {
+            let mut used: HashSet<Value> = self.defs().flat_map(|def| def.op.operands()).collect();
+            for block in &self.blocks {
+                match block.term {
+                    Terminator::Branch(v, ..) | Terminator::Return(v) => {
+                        used.insert(v);
+                    }
+                    Terminator::Jump(_) => (),
+                }
+            }
+
+            let before: usize = self.blocks.iter().map(|b| b.defs.len()).sum();
+            for block in &mut self.blocks {
+                block.defs.retain(|def| used.contains(&def.value));
+            }
+            let after: usize = self.blocks.iter().map(|b| b.defs.len()).sum();
+            if before == after {
+                return changed;
+            }
+            changed = true;
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::HashSet;
+
+use super::{BlockId, Function, Op, Program, Terminator, Value};
+use crate::passes::virtual_register;
+use crate::types::{Inst, InstAddr, Reg};
+
+/// Machine code for a program in SSA form, still using a virtual register for each value.
+pub struct Selected {
+    pub code: Vec<Inst>,
+    /// The address and number of parameters of each function.
+    pub entries: Vec<(InstAddr, usize)>,
+    /// Instructions whose result is a fixed-point number.
+    pub fixed_point: HashSet<InstAddr>,
+    /// Input variables read by the program.
+    pub variables: HashSet<String>,
+}
+
+/// Translate `program` into instructions. The main program comes first and ends with the result,
+/// followed by the functions.
+///
+/// The code follows the same calling convention as the code generated from the AST, so that the
+/// register allocation can take care of the registers fixed by it.
+pub fn select(program: &Program) -> Selected {
+    let mut selection = Selection::default();
+    selection.function(&program.main, true);
+
+    let mut entries = Vec::with_capacity(program.functions.len());
+    for function in &program.functions {
+        entries.push((selection.code.len(), function.params));
+        selection.function(function, false);
+    }
+    for (pos, idx) in selection.calls.drain(..) {
+        selection.code[pos] = Inst::Call(entries[idx].0);
+    }
+
+    Selected {
+        code: selection.code,
+        entries,
+        fixed_point: selection.fixed_point,
+        variables: selection.variables,
+    }
+}
+
+#[derive(Default)]
+struct Selection {
+    code: Vec<Inst>,
+    /// Position of each call instruction, and the function it calls.
+    calls: Vec<(InstAddr, usize)>,
+    fixed_point: HashSet<InstAddr>,
+    variables: HashSet<String>,
+}
+
+fn reg(value: Value) -> Reg {
+    virtual_register(value.0)
+}
+
+impl Selection {
+    fn function(&mut self, function: &Function, main: bool) {
+        let mut starts = Vec::with_capacity(function.blocks.len());
+        // position of each jump, and the block it jumps to. The addresses of the blocks are only
+        // known once all of them are placed.
+        let mut jumps: Vec<(InstAddr, BlockId)> = vec![];
+        let mut temps = function.num_values;
+
+        for (idx, block) in function.blocks.iter().enumerate() {
+            starts.push(self.code.len());
+            for def in &block.defs {
+                let v = reg(def.value);
+                match &def.op {
+                    Op::Const(n) => self.code.push(Inst::Store(*n, v)),
+                    Op::Input(var) => {
+                        self.code.push(Inst::Transfer(var.clone(), v));
+                        self.variables.insert(var.clone());
+                    }
+                    Op::Param(i) => self.code.push(Inst::Copy(Reg::from(*i as u8), v)),
+                    Op::Binary(op, a, b) => {
+                        self.code.push(Inst::Copy(reg(*b), v));
+                        self.code.push(
+                            Inst::binary(*op, reg(*a), v)
+                                .expect("lowering only creates binary operators"),
+                        );
+                    }
+                    Op::Not(a) => {
+                        self.code.push(Inst::Copy(reg(*a), v));
+                        self.code.push(Inst::Not(v));
+                    }
+                    Op::Call(callee, args) => {
+                        for arg in args {
+                            self.code.push(Inst::Push(reg(*arg)));
+                        }
+                        for i in (0..args.len()).rev() {
+                            self.code.push(Inst::Pop(Reg::from(i as u8)));
+                        }
+                        self.calls.push((self.code.len(), *callee));
+                        self.code.push(Inst::Call(0));
+                        self.code.push(Inst::Copy(Reg(0), v));
+                    }
+                    // the predecessors copy the value into the phi's register
+                    Op::Phi(_) => (),
+                }
+
+                // copies keep the type of what they copy, so only computed values are marked
+                let computed = matches!(
+                    def.op,
+                    Op::Const(_) | Op::Input(_) | Op::Binary(..) | Op::Not(_)
+                );
+                if computed && function.fixed_point.contains(&def.value) {
+                    self.fixed_point.insert(self.code.len() - 1);
+                }
+            }
+
+            let next = BlockId(idx + 1);
+            match block.term {
+                Terminator::Jump(target) => {
+                    self.phi_copies(function, BlockId(idx), target, &mut temps);
+                    if target != next {
+                        jumps.push((self.code.len(), target));
+                        self.code.push(Inst::Jump(0));
+                    }
+                }
+                Terminator::Branch(cond, nonzero, zero) => {
+                    // only blocks ending with a jump lead to blocks with phi functions
+                    jumps.push((self.code.len(), zero));
+                    self.code.push(Inst::JumpIfZero(reg(cond), 0));
+                    if nonzero != next {
+                        jumps.push((self.code.len(), nonzero));
+                        self.code.push(Inst::Jump(0));
+                    }
+                }
+                Terminator::Return(v) if main => self.code.push(Inst::Result(reg(v))),
+                Terminator::Return(v) => {
+                    self.code.push(Inst::Copy(reg(v), Reg(0)));
+                    self.code.push(Inst::Ret);
+                }
+            }
+        }
+
+        for (pos, target) in jumps {
+            self.code[pos] = match self.code[pos] {
+                Inst::Jump(_) => Inst::Jump(starts[target.0]),
+                Inst::JumpIfZero(r, _) => Inst::JumpIfZero(r, starts[target.0]),
+                _ => unreachable!("only jumps are patched"),
+            };
+        }
+    }
+
+    /// Copy the values the phi functions of `to` pick when coming from `from` into their registers.
+    ///
+    /// The copies happen at the same time: a phi function can pick the value of another one of the
+    /// same block, from the previous iteration of a loop, which must not be overwritten before.
+    /// Then all values go through temporary registers first.
+    fn phi_copies(&mut self, function: &Function, from: BlockId, to: BlockId, temps: &mut usize) {
+        let copies: Vec<(Value, Value)> = function.blocks[to.0]
+            .defs
+            .iter()
+            .filter_map(|def| match &def.op {
+                Op::Phi(incoming) => incoming
+                    .iter()
+                    .find(|(b, _)| *b == from)
+                    .map(|(_, v)| (*v, def.value)),
+                _ => None,
+            })
+            .collect();
+
+        let overlapping = copies
+            .iter()
+            .any(|(src, _)| copies.iter().any(|(_, dst)| src == dst));
+        if !overlapping {
+            for (src, dst) in copies {
+                self.code.push(Inst::Copy(reg(src), reg(dst)));
+            }
+            return;
+        }
+
+        let temps: Vec<Reg> = copies
+            .iter()
+            .map(|_| {
+                *temps += 1;
+                virtual_register(*temps - 1)
+            })
+            .collect();
+        for ((src, _), temp) in copies.iter().zip(&temps) {
+            self.code.push(Inst::Copy(reg(*src), *temp));
+        }
+        for ((_, dst), temp) in copies.iter().zip(&temps) {
+            self.code.push(Inst::Copy(*temp, reg(*dst)));
+        }
+    }
+}
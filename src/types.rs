@@ -1,9 +1,11 @@
 use rust_i18n::t;
 use std::fmt::{Display, Formatter};
+use std::ops::Range;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum LpErr {
-    Parse(String),
+    /// All errors found by the parser, in the order they occur in the input.
+    Parse(Vec<ParseError>),
     IR(String),
     Interpret(String),
 }
@@ -11,23 +13,95 @@ pub enum LpErr {
 impl Display for LpErr {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            LpErr::Parse(e) => write!(f, "{e} (parse)"),
+            LpErr::Parse(errs) => {
+                for (i, e) in errs.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "{e}")?;
+                }
+                write!(f, " (parse)")
+            }
             LpErr::IR(e) => write!(f, "{e} (ir gen)"),
             LpErr::Interpret(e) => write!(f, "{e} (interpreter)"),
         }
     }
 }
 
+/// A single problem found by the parser, located by a byte range in the input.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ParseError {
+    /// Byte range of the offending input.
+    pub span: Range<usize>,
+    /// What the parser found instead, `None` meaning the end of the input.
+    pub found: Option<String>,
+    /// What the parser would have accepted at this position.
+    pub expected: Vec<String>,
+    /// Explanation for errors that aren't simply an unexpected token.
+    pub reason: Option<String>,
+}
+
+impl ParseError {
+    /// Localized explanation of the error, e.g. for hover texts in the editor.
+    pub fn message(&self) -> String {
+        if let Some(reason) = &self.reason {
+            return reason.clone();
+        }
+
+        let found = self
+            .found
+            .clone()
+            .unwrap_or_else(|| t!("parser.end_of_input").to_string());
+        if self.expected.is_empty() {
+            t!("parser.unexpected", found = found).to_string()
+        } else {
+            let expected = self.expected.join(&t!("parser.or"));
+            t!("parser.expected_found", expected = expected, found = found).to_string()
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}..{}: {}",
+            self.span.start,
+            self.span.end,
+            self.message()
+        )
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
 pub enum Operator {
     Add,
     Sub,
     Mul,
     Div,
+    /// Remainder of a division, aka %
+    Mod,
     /// Left Shift, aka <<
     Shl,
-    /// Right Shift, aka >>
+    /// Right Shift, aka >>, which keeps the sign of signed values
     Shr,
+    /// Logical right shift, aka >>>, which always shifts in zeros
+    LogicalShr,
+    /// Bitwise and, aka &
+    And,
+    /// Bitwise or, aka |
+    Or,
+    /// Bitwise exclusive or, aka ^
+    Xor,
+    /// Bitwise negation, aka ~ (unary only)
+    Not,
+    /// Comparisons, resulting in 1 if true and 0 if false.
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
 }
 
 impl TryFrom<char> for Operator {
@@ -39,6 +113,11 @@ impl TryFrom<char> for Operator {
             '-' => Ok(Operator::Sub),
             '*' => Ok(Operator::Mul),
             '/' => Ok(Operator::Div),
+            '%' => Ok(Operator::Mod),
+            '&' => Ok(Operator::And),
+            '|' => Ok(Operator::Or),
+            '^' => Ok(Operator::Xor),
+            '~' => Ok(Operator::Not),
             _ => Err(value),
         }
     }
@@ -51,8 +130,20 @@ impl Display for Operator {
             Operator::Sub => write!(f, "-"),
             Operator::Mul => write!(f, "*"),
             Operator::Div => write!(f, "/"),
+            Operator::Mod => write!(f, "%"),
             Operator::Shl => write!(f, "<<"),
             Operator::Shr => write!(f, ">>"),
+            Operator::LogicalShr => write!(f, ">>>"),
+            Operator::And => write!(f, "&"),
+            Operator::Or => write!(f, "|"),
+            Operator::Xor => write!(f, "^"),
+            Operator::Not => write!(f, "~"),
+            Operator::Eq => write!(f, "=="),
+            Operator::Ne => write!(f, "!="),
+            Operator::Lt => write!(f, "<"),
+            Operator::Le => write!(f, "<="),
+            Operator::Gt => write!(f, ">"),
+            Operator::Ge => write!(f, ">="),
         }
     }
 }
@@ -60,22 +151,174 @@ impl Display for Operator {
 /// The main AST struct for representing the IR.
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub enum Expr {
-    Num(i32),
+    Num(i64),
     Var(String),
     UnaryOp(Operator, Box<Expr>),
     BinaryOp(Box<Expr>, Operator, Box<Expr>),
+    /// Conditional expression `if c then a else b`, where any value but 0 counts as true.
+    If(Box<Expr>, Box<Expr>, Box<Expr>),
+    /// Call of a user-defined function with the given arguments, e.g. `sq(x + 1)`.
+    Call(String, Vec<Expr>),
+    /// A fixed-point number, stored as the integer value of the inner expression with
+    /// [`Width::frac_bits`](crate::arith::Width::frac_bits) bits after the binary point, e.g. `1.5`
+    /// is `98304` (`1.5 * 2^16`) with 32 bits.
+    ///
+    /// After [`FixedPointLowering`](crate::passes::FixedPointLowering), the inner expression only
+    /// uses integer arithmetic, and this merely marks which values are fixed-point numbers.
+    FixedPoint(Box<Expr>),
+}
+
+impl Expr {
+    /// Number of times the variable `name` occurs in this expression.
+    pub fn count_var(&self, name: &str) -> usize {
+        match self {
+            Expr::Num(_) => 0,
+            Expr::Var(v) => usize::from(v == name),
+            Expr::UnaryOp(_, e) => e.count_var(name),
+            Expr::BinaryOp(l, _, r) => l.count_var(name) + r.count_var(name),
+            Expr::If(c, a, b) => c.count_var(name) + a.count_var(name) + b.count_var(name),
+            Expr::Call(_, args) => args.iter().map(|a| a.count_var(name)).sum(),
+            Expr::FixedPoint(e) => e.count_var(name),
+        }
+    }
+
+    /// Number of registers needed to compute this expression without spilling, if the operand that
+    /// needs more registers is computed first (its Ershov number). Both operands of an operation
+    /// are needed at the same time, so if they need the same number of registers, one more is
+    /// needed to hold the first result while computing the second one.
+    pub fn register_need(&self) -> usize {
+        let both = |l: usize, r: usize| if l == r { l + 1 } else { l.max(r) };
+        match self {
+            Expr::Num(_) | Expr::Var(_) => 1,
+            // the negation subtracts from a 0 in a register of its own
+            Expr::UnaryOp(Operator::Sub, e) => both(1, e.register_need()),
+            Expr::UnaryOp(_, e) | Expr::FixedPoint(e) => e.register_need(),
+            Expr::BinaryOp(l, _, r) => both(l.register_need(), r.register_need()),
+            // all values are moved to RAM before the branches
+            Expr::If(c, a, b) => c
+                .register_need()
+                .max(a.register_need())
+                .max(b.register_need()),
+            // the arguments wait on the stack
+            Expr::Call(_, args) => args.iter().map(Expr::register_need).max().unwrap_or(1),
+        }
+    }
 }
 
-impl From<i32> for Expr {
-    fn from(value: i32) -> Self {
+impl From<i64> for Expr {
+    fn from(value: i64) -> Self {
         Expr::Num(value)
     }
 }
 
-pub type Reg = char;
+/// A statement, i.e. a part of a program that doesn't produce a value on its own.
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub enum Stmt {
+    /// Bind the value of an expression to a name for the rest of the program, e.g. `let t = x * 3;`.
+    Let(String, Expr),
+    /// Give a name bound by `let` a new value, e.g. `x = x + 1;`.
+    Assign(String, Expr),
+    /// Run the body as long as the condition is non-zero, e.g. `while x > 0 { x = x - 1; }`.
+    ///
+    /// `repeat n { ... }` loops are parsed into `while` loops with a hidden counter.
+    While(Expr, Vec<Stmt>),
+}
+
+/// A user-defined function, e.g. `fn sq(a) = a * a;`. The body can only use the parameters and
+/// call functions defined before it.
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Expr,
+}
+
+/// A whole program: function definitions and a list of statements, followed by the expression
+/// computing its result.
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub struct Program {
+    pub functions: Vec<Function>,
+    pub stmts: Vec<Stmt>,
+    pub result: Expr,
+}
+
+impl Program {
+    /// Apply `f` to every expression in the program, e.g. to run a pass that doesn't care about
+    /// bindings.
+    pub fn map_exprs(self, mut f: impl FnMut(Expr) -> Expr) -> Self {
+        let functions = self
+            .functions
+            .into_iter()
+            .map(|fun| Function {
+                body: f(fun.body),
+                ..fun
+            })
+            .collect();
+
+        Program {
+            functions,
+            stmts: map_stmt_exprs(self.stmts, &mut f),
+            result: f(self.result),
+        }
+    }
+}
+
+fn map_stmt_exprs(stmts: Vec<Stmt>, f: &mut impl FnMut(Expr) -> Expr) -> Vec<Stmt> {
+    stmts
+        .into_iter()
+        .map(|stmt| match stmt {
+            Stmt::Let(name, value) => Stmt::Let(name, f(value)),
+            Stmt::Assign(name, value) => Stmt::Assign(name, f(value)),
+            Stmt::While(cond, body) => {
+                let cond = f(cond);
+                Stmt::While(cond, map_stmt_exprs(body, f))
+            }
+        })
+        .collect()
+}
+
+impl From<Expr> for Program {
+    fn from(result: Expr) -> Self {
+        Program {
+            functions: vec![],
+            stmts: vec![],
+            result,
+        }
+    }
+}
+
+/// Smallest number of registers a machine can have, since a binary operation needs both operands
+/// in registers.
+pub const MIN_REGISTERS: u8 = 2;
+
+/// Largest number of registers a machine can have.
+pub const MAX_REGISTERS: u8 = 64;
+
+/// A register, numbered from 0. The first 26 registers are called `a` to `z`, the ones after them
+/// `r26`, `r27`, ...
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Reg(pub u32);
+
+impl From<u8> for Reg {
+    fn from(n: u8) -> Self {
+        Reg(n.into())
+    }
+}
+
+impl Display for Reg {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match char::from_u32(u32::from(b'a') + self.0) {
+            Some(name) if self.0 < 26 => write!(f, "{name}"),
+            _ => write!(f, "r{}", self.0),
+        }
+    }
+}
+
 pub type MemAddr = usize;
+/// Index of an instruction in the program.
+pub type InstAddr = usize;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Inst {
     /// Add two values, storing the result in Register #2.
     Add(Reg, Reg),
@@ -85,16 +328,59 @@ pub enum Inst {
     Mul(Reg, Reg),
     /// Divide two values, storing the result in Register #2.
     Div(Reg, Reg),
+    /// Divide two values, storing the remainder in Register #2.
+    Mod(Reg, Reg),
     /// Shift the value in register #2 to the left by the number of bits stated in register #1.
     Shl(Reg, Reg),
     /// Shift the value in register #2 to the right by the number of bits stated in register #1.
     Shr(Reg, Reg),
+    /// Shift the value in register #2 to the right by the number of bits stated in register #1,
+    /// shifting in zeros even for negative values.
+    LogicalShr(Reg, Reg),
+    /// Bitwise and of two values, storing the result in Register #2.
+    And(Reg, Reg),
+    /// Bitwise or of two values, storing the result in Register #2.
+    Or(Reg, Reg),
+    /// Bitwise exclusive or of two values, storing the result in Register #2.
+    Xor(Reg, Reg),
+    /// Invert all bits of the value in the register.
+    Not(Reg),
+    /// Compare two values, storing 1 in Register #2 if they are equal, otherwise 0.
+    Eq(Reg, Reg),
+    /// Compare two values, storing 1 in Register #2 if they are not equal, otherwise 0.
+    Ne(Reg, Reg),
+    /// Compare two values, storing 1 in Register #2 if value #1 is less than value #2, otherwise 0.
+    Lt(Reg, Reg),
+    /// Compare two values, storing 1 in Register #2 if value #1 is less than or equal to value #2,
+    /// otherwise 0.
+    Le(Reg, Reg),
+    /// Compare two values, storing 1 in Register #2 if value #1 is greater than value #2,
+    /// otherwise 0.
+    Gt(Reg, Reg),
+    /// Compare two values, storing 1 in Register #2 if value #1 is greater than or equal to
+    /// value #2, otherwise 0.
+    Ge(Reg, Reg),
+    /// Continue execution at the given instruction.
+    Jump(InstAddr),
+    /// Continue execution at the given instruction if the register contains 0.
+    JumpIfZero(Reg, InstAddr),
     /// Store a number in a register.
-    Store(i32, Reg),
+    Store(i64, Reg),
     /// Transfer a value into a register.
     Transfer(String, Reg),
+    /// Copy the value in register #1 to register #2.
+    Copy(Reg, Reg),
     /// Return the value in the given register and terminate computation.
     Result(Reg),
+    /// Push the address of the next instruction onto the stack and continue execution at the given
+    /// instruction.
+    Call(InstAddr),
+    /// Pop an instruction address from the stack and continue execution there.
+    Ret,
+    /// Push the contents of a register onto the stack.
+    Push(Reg),
+    /// Pop the topmost value from the stack into a register.
+    Pop(Reg),
 
     /// Write the contents of a register to main memory.
     Write(Reg, MemAddr),
@@ -102,8 +388,140 @@ pub enum Inst {
     Load(MemAddr, Reg),
 }
 
+impl Inst {
+    /// The instruction computing `b = a op b`, or `None` if `op` is not a binary operator.
+    pub fn binary(op: Operator, a: Reg, b: Reg) -> Option<Inst> {
+        Some(match op {
+            Operator::Add => Inst::Add(a, b),
+            Operator::Sub => Inst::Sub(a, b),
+            Operator::Mul => Inst::Mul(a, b),
+            Operator::Div => Inst::Div(a, b),
+            Operator::Mod => Inst::Mod(a, b),
+            Operator::Shl => Inst::Shl(a, b),
+            Operator::Shr => Inst::Shr(a, b),
+            Operator::LogicalShr => Inst::LogicalShr(a, b),
+            Operator::And => Inst::And(a, b),
+            Operator::Or => Inst::Or(a, b),
+            Operator::Xor => Inst::Xor(a, b),
+            Operator::Eq => Inst::Eq(a, b),
+            Operator::Ne => Inst::Ne(a, b),
+            Operator::Lt => Inst::Lt(a, b),
+            Operator::Le => Inst::Le(a, b),
+            Operator::Gt => Inst::Gt(a, b),
+            Operator::Ge => Inst::Ge(a, b),
+            Operator::Not => return None,
+        })
+    }
+
+    /// The register this instruction writes to, if any.
+    pub fn target(&self) -> Option<Reg> {
+        match self {
+            Inst::Add(_, b)
+            | Inst::Sub(_, b)
+            | Inst::Mul(_, b)
+            | Inst::Div(_, b)
+            | Inst::Mod(_, b)
+            | Inst::Shl(_, b)
+            | Inst::Shr(_, b)
+            | Inst::LogicalShr(_, b)
+            | Inst::And(_, b)
+            | Inst::Or(_, b)
+            | Inst::Xor(_, b)
+            | Inst::Eq(_, b)
+            | Inst::Ne(_, b)
+            | Inst::Lt(_, b)
+            | Inst::Le(_, b)
+            | Inst::Gt(_, b)
+            | Inst::Ge(_, b)
+            | Inst::Copy(_, b) => Some(*b),
+            Inst::Not(r)
+            | Inst::Store(_, r)
+            | Inst::Transfer(_, r)
+            | Inst::Pop(r)
+            | Inst::Load(_, r) => Some(*r),
+            Inst::Jump(_)
+            | Inst::JumpIfZero(..)
+            | Inst::Result(_)
+            | Inst::Call(_)
+            | Inst::Ret
+            | Inst::Push(_)
+            | Inst::Write(..) => None,
+        }
+    }
+
+    /// The registers this instruction reads from.
+    pub fn sources(&self) -> Vec<Reg> {
+        match self {
+            Inst::Add(a, b)
+            | Inst::Sub(a, b)
+            | Inst::Mul(a, b)
+            | Inst::Div(a, b)
+            | Inst::Mod(a, b)
+            | Inst::Shl(a, b)
+            | Inst::Shr(a, b)
+            | Inst::LogicalShr(a, b)
+            | Inst::And(a, b)
+            | Inst::Or(a, b)
+            | Inst::Xor(a, b)
+            | Inst::Eq(a, b)
+            | Inst::Ne(a, b)
+            | Inst::Lt(a, b)
+            | Inst::Le(a, b)
+            | Inst::Gt(a, b)
+            | Inst::Ge(a, b) => vec![*a, *b],
+            Inst::Not(r)
+            | Inst::JumpIfZero(r, _)
+            | Inst::Copy(r, _)
+            | Inst::Result(r)
+            | Inst::Push(r)
+            | Inst::Write(r, _) => vec![*r],
+            Inst::Jump(_)
+            | Inst::Store(..)
+            | Inst::Transfer(..)
+            | Inst::Call(_)
+            | Inst::Ret
+            | Inst::Pop(_)
+            | Inst::Load(..) => vec![],
+        }
+    }
+
+    /// This instruction with each register `r` replaced by `f(r)`.
+    pub fn map_regs(&self, f: impl Fn(Reg) -> Reg) -> Inst {
+        match self.clone() {
+            Inst::Add(a, b) => Inst::Add(f(a), f(b)),
+            Inst::Sub(a, b) => Inst::Sub(f(a), f(b)),
+            Inst::Mul(a, b) => Inst::Mul(f(a), f(b)),
+            Inst::Div(a, b) => Inst::Div(f(a), f(b)),
+            Inst::Mod(a, b) => Inst::Mod(f(a), f(b)),
+            Inst::Shl(a, b) => Inst::Shl(f(a), f(b)),
+            Inst::Shr(a, b) => Inst::Shr(f(a), f(b)),
+            Inst::LogicalShr(a, b) => Inst::LogicalShr(f(a), f(b)),
+            Inst::And(a, b) => Inst::And(f(a), f(b)),
+            Inst::Or(a, b) => Inst::Or(f(a), f(b)),
+            Inst::Xor(a, b) => Inst::Xor(f(a), f(b)),
+            Inst::Not(r) => Inst::Not(f(r)),
+            Inst::Eq(a, b) => Inst::Eq(f(a), f(b)),
+            Inst::Ne(a, b) => Inst::Ne(f(a), f(b)),
+            Inst::Lt(a, b) => Inst::Lt(f(a), f(b)),
+            Inst::Le(a, b) => Inst::Le(f(a), f(b)),
+            Inst::Gt(a, b) => Inst::Gt(f(a), f(b)),
+            Inst::Ge(a, b) => Inst::Ge(f(a), f(b)),
+            Inst::JumpIfZero(r, target) => Inst::JumpIfZero(f(r), target),
+            Inst::Store(n, r) => Inst::Store(n, f(r)),
+            Inst::Transfer(var, r) => Inst::Transfer(var, f(r)),
+            Inst::Copy(a, b) => Inst::Copy(f(a), f(b)),
+            Inst::Result(r) => Inst::Result(f(r)),
+            Inst::Push(r) => Inst::Push(f(r)),
+            Inst::Pop(r) => Inst::Pop(f(r)),
+            Inst::Write(r, addr) => Inst::Write(f(r), addr),
+            Inst::Load(addr, r) => Inst::Load(addr, f(r)),
+            inst @ (Inst::Jump(_) | Inst::Call(_) | Inst::Ret) => inst,
+        }
+    }
+}
+
 /// Localized user-friendly output, e.g. `t!("compiler.inst.add", ...)` becomes "add register a to
-/// register b" in English. Not to be used programmatically.
+/// register b" in English. Not to be used programmatically, see [`crate::assembly`] for that.
 impl Display for Inst {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -111,11 +529,32 @@ impl Display for Inst {
             Inst::Sub(a, b) => f.write_str(&t!("compiler.inst.sub", a = a, b = b)),
             Inst::Mul(a, b) => f.write_str(&t!("compiler.inst.mul", a = a, b = b)),
             Inst::Div(a, b) => f.write_str(&t!("compiler.inst.div", a = a, b = b)),
+            Inst::Mod(a, b) => f.write_str(&t!("compiler.inst.mod", a = a, b = b)),
             Inst::Shl(a, b) => f.write_str(&t!("compiler.inst.shl", a = a, b = b)),
             Inst::Shr(a, b) => f.write_str(&t!("compiler.inst.shr", a = a, b = b)),
+            Inst::LogicalShr(a, b) => f.write_str(&t!("compiler.inst.logical_shr", a = a, b = b)),
+            Inst::And(a, b) => f.write_str(&t!("compiler.inst.and", a = a, b = b)),
+            Inst::Or(a, b) => f.write_str(&t!("compiler.inst.or", a = a, b = b)),
+            Inst::Xor(a, b) => f.write_str(&t!("compiler.inst.xor", a = a, b = b)),
+            Inst::Not(r) => f.write_str(&t!("compiler.inst.not", r = r)),
+            Inst::Eq(a, b) => f.write_str(&t!("compiler.inst.eq", a = a, b = b)),
+            Inst::Ne(a, b) => f.write_str(&t!("compiler.inst.ne", a = a, b = b)),
+            Inst::Lt(a, b) => f.write_str(&t!("compiler.inst.lt", a = a, b = b)),
+            Inst::Le(a, b) => f.write_str(&t!("compiler.inst.le", a = a, b = b)),
+            Inst::Gt(a, b) => f.write_str(&t!("compiler.inst.gt", a = a, b = b)),
+            Inst::Ge(a, b) => f.write_str(&t!("compiler.inst.ge", a = a, b = b)),
+            Inst::Jump(target) => f.write_str(&t!("compiler.inst.jump", target = target)),
+            Inst::JumpIfZero(r, target) => {
+                f.write_str(&t!("compiler.inst.jump_if_zero", r = r, target = target))
+            }
             Inst::Store(n, r) => f.write_str(&t!("compiler.inst.store", n = n, r = r)),
             Inst::Transfer(v, r) => f.write_str(&t!("compiler.inst.transfer", v = v, r = r)),
+            Inst::Copy(a, b) => f.write_str(&t!("compiler.inst.copy", a = a, b = b)),
             Inst::Result(r) => f.write_str(&t!("compiler.inst.result", r = r)),
+            Inst::Call(target) => f.write_str(&t!("compiler.inst.call", target = target)),
+            Inst::Ret => f.write_str(&t!("compiler.inst.ret")),
+            Inst::Push(r) => f.write_str(&t!("compiler.inst.push", r = r)),
+            Inst::Pop(r) => f.write_str(&t!("compiler.inst.pop", r = r)),
             Inst::Write(r, addr) => f.write_str(&t!("compiler.inst.write", r = r, addr = addr)),
             Inst::Load(addr, r) => f.write_str(&t!("compiler.inst.load", addr = addr, r = r)),
         }